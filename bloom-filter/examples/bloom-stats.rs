@@ -0,0 +1,59 @@
+// Builds a filter tuned for every line of a file at a target false positive rate, adds the
+// lines, then prints how full it ended up. A quick way to see how real data fills a filter:
+//
+//     cargo run --example bloom-stats -- words.txt 0.01
+
+use bloom_filter::BloomFilter;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let (path, target_fpr) = match args.as_slice() {
+        [_, path, target_fpr] => match target_fpr.parse::<f64>() {
+            Ok(target_fpr) => (path, target_fpr),
+            Err(_) => exit_with(&format!("{} isn't a false positive rate", target_fpr)),
+        },
+        _ => exit_with("Usage: bloom-stats <file of newline-delimited items> <target false positive rate>"),
+    };
+
+    // The filter has to be sized before anything goes in, so the lines are counted first
+    let line_count = open(path).split(b'\n').count();
+
+    let mut bf = match BloomFilter::tuned(line_count, target_fpr) {
+        Ok(bf) => bf,
+        Err(e) => exit_with(&e.to_string()),
+    };
+
+    let added = match bf.add_lines(open(path)) {
+        Ok(added) => added,
+        Err(e) => exit_with(&format!("Couldn't read {}: {}", path, e)),
+    };
+
+    let stats = bf.stats();
+
+    println!("Lines added:          {}", added);
+    println!("Bits:                 {} ({} bytes)", stats.bit_len, stats.bit_len / 8);
+    println!("Hashers:              {}", stats.hasher_count);
+    println!("Set bits:             {}", stats.set_bits);
+    println!("Fill ratio:           {:.4}", stats.fill_ratio);
+    println!("Estimated items:      {:.1}", stats.estimated_len);
+    println!("False positive rate:  {:.6} (target {})", stats.false_positive_rate, target_fpr);
+    println!("Bits per element:     {:.2}", stats.bits_per_element);
+    println!("Memory efficiency:    {:.3}", stats.memory_efficiency);
+}
+
+fn open(path: &str) -> BufReader<File> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => exit_with(&format!("Couldn't open {}: {}", path, e)),
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
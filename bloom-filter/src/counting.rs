@@ -0,0 +1,236 @@
+use std::time::{Duration, Instant};
+use crate::{hash_positions, BloomCheckResult, BloomError, FULL_HASH_BITS};
+
+// A bloom filter that keeps a counter per position instead of a single bit, so it can answer
+// "roughly how many times has this been added" as well as "has this been added".
+//
+// Counts can be decayed over time so that items which stop being added fade out, which gives an
+// approximate "recently seen N times" structure (handy for rate limiting). The count for an item
+// is the smallest of its counters, so like a regular bloom filter it can overestimate (when other
+// items share all of its positions) but never underestimate, at least until decay is involved.
+// Decay floors every counter separately, so after decaying, an item's count is only approximately
+// its count multiplied by the decay factors.
+pub struct CountingBloomFilter {
+    counters: Vec<u32>,
+    hasher_count: usize,
+    hasher_range_in_bits: u32,
+    decay_schedule: Option<DecaySchedule>,
+}
+
+struct DecaySchedule {
+    interval: Duration,
+    factor: f64,
+    last_decay: Instant,
+}
+
+impl CountingBloomFilter {
+    pub fn build(hasher_range_in_bits: u32, hasher_count: usize) -> Result<CountingBloomFilter, BloomError> {
+        let required_bits = u32::try_from(hasher_count).ok()
+            .and_then(|hasher_count| hasher_range_in_bits.checked_mul(hasher_count));
+
+        if required_bits.is_none_or(|required_bits| required_bits > FULL_HASH_BITS) {
+            return Err(BloomError::ExceedsHashBudget {
+                required_bits: required_bits.unwrap_or(u32::MAX),
+                available_bits: FULL_HASH_BITS,
+            });
+        }
+
+        // 2 ^ hasher_range_in_bits has to fit in a usize for the counters to be indexed
+        if hasher_range_in_bits >= usize::BITS {
+            return Err(BloomError::ExceedsAddressableRange { hasher_range_in_bits });
+        }
+
+        Ok(
+            CountingBloomFilter {
+                counters: vec![0; 2_usize.pow(hasher_range_in_bits)],
+                hasher_count,
+                hasher_range_in_bits,
+                decay_schedule: None,
+            }
+        )
+    }
+
+    pub fn add<T: AsRef<[u8]>>(&mut self, t: &T) {
+        for i in hash_positions(t, self.hasher_count, self.hasher_range_in_bits) {
+            self.counters[i] = self.counters[i].saturating_add(1);
+        }
+    }
+
+    // The (approximate) number of times the given value has been added
+    pub fn count<T: AsRef<[u8]>>(&self, t: &T) -> u32 {
+        hash_positions(t, self.hasher_count, self.hasher_range_in_bits)
+            .into_iter()
+            .map(|i| self.counters[i])
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn is_present<T: AsRef<[u8]>>(&self, t: &T) -> BloomCheckResult {
+        if self.count(t) == 0 {
+            BloomCheckResult::No
+        } else {
+            BloomCheckResult::Maybe
+        }
+    }
+
+    // Multiplies every counter by [factor] (which must be in [0, 1]), rounding down. Repeatedly
+    // decaying with a factor below 1 eventually zeroes out anything that isn't being re-added.
+    pub fn decay(&mut self, factor: f64) {
+        assert!((0.0..=1.0).contains(&factor), "decay factor must be between 0 and 1, got {}", factor);
+
+        for counter in self.counters.iter_mut() {
+            *counter = (*counter as f64 * factor).floor() as u32;
+        }
+    }
+
+    // Decays the filter by [factor] once every [interval], counting from now. The decay is
+    // applied lazily by [decay_if_due], so callers need to call that periodically (e.g. on
+    // every add).
+    pub fn set_decay_schedule(&mut self, interval: Duration, factor: f64) {
+        assert!((0.0..=1.0).contains(&factor), "decay factor must be between 0 and 1, got {}", factor);
+        assert!(!interval.is_zero(), "decay interval must be longer than zero");
+
+        self.decay_schedule = Some(DecaySchedule {
+            interval,
+            factor,
+            last_decay: Instant::now(),
+        });
+    }
+
+    // Applies any decays that have come due as of [now], catching up on every interval that
+    // has passed since the last one. Returns the number of decays applied.
+    pub fn decay_if_due(&mut self, now: Instant) -> u32 {
+        let Some(schedule) = &mut self.decay_schedule else {
+            return 0;
+        };
+
+        let elapsed = now.saturating_duration_since(schedule.last_decay);
+        let due = (elapsed.as_nanos() / schedule.interval.as_nanos()) as u32;
+
+        if due == 0 {
+            return 0;
+        }
+
+        schedule.last_decay += schedule.interval * due;
+        let factor = schedule.factor.powi(due as i32);
+
+        self.decay(factor);
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_size_and_hasher_count() {
+        if CountingBloomFilter::build(200, 7).is_ok() {
+            panic!("Should have rejected invalid input");
+        }
+    }
+
+    #[test]
+    fn rejects_hasher_counts_that_overflow_the_hash_budget() {
+        let overflowing = [u32::MAX as usize + 1, u32::MAX as usize];
+
+        for hasher_count in overflowing {
+            assert_eq!(
+                CountingBloomFilter::build(10, hasher_count).err(),
+                Some(BloomError::ExceedsHashBudget { required_bits: u32::MAX, available_bits: FULL_HASH_BITS })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_too_big_to_index() {
+        assert_eq!(
+            CountingBloomFilter::build(usize::BITS, 1).err(),
+            Some(BloomError::ExceedsAddressableRange { hasher_range_in_bits: usize::BITS })
+        );
+    }
+
+    #[test]
+    fn counts_additions() {
+        let mut cbf = CountingBloomFilter::build(10, 3)
+            .expect("should have built a counting bloom filter");
+
+        for _ in 0..5 {
+            cbf.add(&"foo");
+        }
+        cbf.add(&"bar");
+
+        assert_eq!(cbf.count(&"foo"), 5);
+        assert_eq!(cbf.count(&"bar"), 1);
+        assert_eq!(cbf.count(&"baz"), 0);
+        assert_eq!(cbf.is_present(&"foo"), BloomCheckResult::Maybe);
+        assert_eq!(cbf.is_present(&"baz"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn decay_scales_counts_down() {
+        let mut cbf = CountingBloomFilter::build(10, 3)
+            .expect("should have built a counting bloom filter");
+
+        for _ in 0..10 {
+            cbf.add(&"foo");
+        }
+
+        cbf.decay(0.5);
+
+        assert_eq!(cbf.count(&"foo"), 5);
+    }
+
+    #[test]
+    fn repeated_decay_zeroes_out_stale_entries() {
+        let mut cbf = CountingBloomFilter::build(10, 3)
+            .expect("should have built a counting bloom filter");
+
+        for _ in 0..100 {
+            cbf.add(&"stale");
+        }
+
+        for round in 0.. {
+            cbf.add(&"fresh");
+            cbf.decay(0.5);
+
+            if cbf.count(&"stale") == 0 {
+                break;
+            }
+
+            assert!(round < 100, "Stale entry should have decayed away");
+        }
+
+        assert_eq!(cbf.is_present(&"stale"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn scheduled_decay_catches_up_on_missed_intervals() {
+        let mut cbf = CountingBloomFilter::build(10, 3)
+            .expect("should have built a counting bloom filter");
+
+        for _ in 0..16 {
+            cbf.add(&"foo");
+        }
+
+        cbf.set_decay_schedule(Duration::from_secs(60), 0.5);
+        let start = Instant::now();
+
+        assert_eq!(cbf.decay_if_due(start + Duration::from_secs(59)), 0);
+        assert_eq!(cbf.count(&"foo"), 16);
+
+        assert_eq!(cbf.decay_if_due(start + Duration::from_secs(150)), 2);
+        assert_eq!(cbf.count(&"foo"), 4);
+    }
+
+    #[test]
+    fn no_schedule_means_no_decay() {
+        let mut cbf = CountingBloomFilter::build(10, 3)
+            .expect("should have built a counting bloom filter");
+        cbf.add(&"foo");
+
+        assert_eq!(cbf.decay_if_due(Instant::now() + Duration::from_secs(3600)), 0);
+        assert_eq!(cbf.count(&"foo"), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{hash_position_iter, BloomCheckResult, BloomFilter, FULL_HASH_BITS};
+
+// A bloom filter whose size is fixed at compile time, with its bits in an array rather than a
+// heap-allocated BitVec, so that it can live on the stack or in static memory and never
+// allocates. It hashes exactly like [BloomFilter], so given the same number of bits and hashers,
+// the two agree on every item.
+//
+// The size is given in bytes: an array of `BITS / 8` bytes would need the unstable
+// generic_const_exprs feature. [Self::BITS] has to be a power of two, since each hasher takes its
+// position from a whole number of hash bits, and any other size fails to compile.
+pub struct FixedBloomFilter<const BYTES: usize> {
+    bits: [u8; BYTES],
+    hasher_count: usize,
+}
+
+impl<const BYTES: usize> FixedBloomFilter<BYTES> {
+    pub const BITS: usize = BYTES * 8;
+
+    const HASHER_RANGE_IN_BITS: u32 = Self::BITS.trailing_zeros();
+
+    // Evaluated (and so checked) for every size that's built
+    const VALID_SIZE: () = assert!(
+        BYTES > 0 && BYTES.is_power_of_two(),
+        "A FixedBloomFilter needs a power of two number of bytes, so that its bits are too"
+    );
+
+    // A const fn, so that a filter can be built into a static
+    pub const fn build(hasher_count: usize) -> Result<FixedBloomFilter<BYTES>, &'static str> {
+        let () = Self::VALID_SIZE;
+
+        // TryFrom isn't usable in a const fn, so the count is checked against u32::MAX by hand
+        let required_bits = match hasher_count <= u32::MAX as usize {
+            true => Self::HASHER_RANGE_IN_BITS.checked_mul(hasher_count as u32),
+            false => None,
+        };
+
+        match required_bits {
+            Some(required_bits) if required_bits <= FULL_HASH_BITS => {}
+            _ => return Err("The bloom filter is too large for the underlying hashers"),
+        }
+
+        Ok(FixedBloomFilter { bits: [0; BYTES], hasher_count })
+    }
+
+    pub fn add<T: AsRef<[u8]>>(&mut self, t: &T) {
+        for i in hash_position_iter(t, self.hasher_count, Self::HASHER_RANGE_IN_BITS) {
+            let (byte, mask) = bit_location(i);
+            self.bits[byte] |= mask;
+        }
+    }
+
+    pub fn is_present<T: AsRef<[u8]>>(&self, t: &T) -> BloomCheckResult {
+        let all_set = hash_position_iter(t, self.hasher_count, Self::HASHER_RANGE_IN_BITS)
+            .all(|i| {
+                let (byte, mask) = bit_location(i);
+                self.bits[byte] & mask != 0
+            });
+
+        if all_set {
+            BloomCheckResult::Maybe
+        } else {
+            BloomCheckResult::No
+        }
+    }
+
+    // [is_present] as a bool: true for [BloomCheckResult::Maybe], as for [BloomFilter::contains]
+    pub fn contains<T: AsRef<[u8]>>(&self, t: &T) -> bool {
+        self.is_present(t) == BloomCheckResult::Maybe
+    }
+
+    pub fn clear(&mut self) {
+        self.bits = [0; BYTES];
+    }
+
+    pub fn hasher_count(&self) -> usize {
+        self.hasher_count
+    }
+
+    // The raw bits, e.g. for saving the filter. [BloomFilter::from_legacy_bytes] turns them back
+    // into a filter.
+    pub fn as_bytes(&self) -> &[u8; BYTES] {
+        &self.bits
+    }
+}
+
+// A heap filter that agrees with [filter] on every item, for moving off the fixed size
+impl<const BYTES: usize> From<&FixedBloomFilter<BYTES>> for BloomFilter {
+    fn from(filter: &FixedBloomFilter<BYTES>) -> BloomFilter {
+        BloomFilter::from_legacy_bytes(&filter.bits, filter.hasher_count, BYTES)
+            .expect("a fixed filter's size and hasher count were checked when it was built")
+    }
+}
+
+// The byte that bit [i] is in, and its mask within that byte. Bits run from the most significant
+// end of each byte, as in a BitVec, so the bytes are laid out the same as a [BloomFilter]'s.
+fn bit_location(i: usize) -> (usize, u8) {
+    (i / 8, 0b1000_0000 >> (i % 8))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn agrees_with_a_heap_filter_of_the_same_size() {
+        let mut fixed = FixedBloomFilter::<32>::build(3).expect("should have built the filter");
+        // 32 bytes is 256 bits, or 2 ^ 8
+        let mut heap = BloomFilter::build(8, 3).expect("should have built the filter");
+
+        for i in 0..40 {
+            let item = format!("item {}", i);
+            fixed.add(&item);
+            heap.add(&item);
+        }
+
+        for i in 0..200 {
+            let item = format!("item {}", i);
+            assert_eq!(fixed.is_present(&item), heap.is_present(&item));
+            assert_eq!(fixed.contains(&item), heap.contains(&item));
+        }
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut bf = FixedBloomFilter::<8>::build(3).expect("should have built the filter");
+        bf.add(&"foo");
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::Maybe);
+
+        bf.clear();
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn rejects_too_many_hashers() {
+        if FixedBloomFilter::<8>::build(100).is_ok() {
+            panic!("Should have failed to build a filter that needs more than 512 hash bits");
+        }
+
+        // Counts that would wrap around as a u32, or overflow it once multiplied by the range
+        if FixedBloomFilter::<8>::build(u32::MAX as usize + 1).is_ok() || FixedBloomFilter::<8>::build(u32::MAX as usize).is_ok() {
+            panic!("Should have failed to build a filter whose hash bits don't fit in a u32");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{BloomCheckResult, BloomFilter};
+
+// A ring of bloom filters, one per time window ("generation"), for an approximate "seen in the
+// last N windows" structure with bounded memory. Items are added to the current generation, and
+// [tick] moves on to the next one, clearing it first, so an item that isn't added again expires
+// exactly N ticks after it was last added. [is_present] checks every generation, so false
+// positives are a little more likely than with a single filter of the same size.
+pub struct GenerationalBloomFilter {
+    generations: Vec<BloomFilter>,
+    current: usize,
+}
+
+impl GenerationalBloomFilter {
+    pub fn build(generation_count: usize, hasher_range_in_bits: u32, hasher_count: usize) -> Result<GenerationalBloomFilter, &'static str> {
+        if generation_count == 0 {
+            return Err("A generational bloom filter needs at least one generation");
+        }
+
+        let generations = (0..generation_count)
+            .map(|_| BloomFilter::build(hasher_range_in_bits, hasher_count))
+            .collect::<Result<Vec<BloomFilter>, &'static str>>()?;
+
+        Ok(GenerationalBloomFilter { generations, current: 0 })
+    }
+
+    // The number of generations, which is how many ticks an item lasts for
+    pub fn generation_count(&self) -> usize {
+        self.generations.len()
+    }
+
+    pub fn add<T: AsRef<[u8]>>(&mut self, t: &T) {
+        self.generations[self.current].add(t);
+    }
+
+    pub fn is_present<T: AsRef<[u8]>>(&self, t: &T) -> BloomCheckResult {
+        if self.generations.iter().any(|generation| generation.is_present(t) == BloomCheckResult::Maybe) {
+            BloomCheckResult::Maybe
+        } else {
+            BloomCheckResult::No
+        }
+    }
+
+    // Moves on to the next generation, forgetting everything that was added to it N ticks ago
+    pub fn tick(&mut self) {
+        self.current = (self.current + 1) % self.generations.len();
+        self.generations[self.current].clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_generations() {
+        if GenerationalBloomFilter::build(0, 10, 3).is_ok() {
+            panic!("Should have rejected a filter without any generations");
+        }
+    }
+
+    #[test]
+    fn items_expire_after_exactly_n_ticks() {
+        let mut filter = GenerationalBloomFilter::build(3, 10, 3).expect("should have built the filter");
+        filter.add(&"foo");
+
+        for _ in 0..2 {
+            filter.tick();
+            assert_eq!(filter.is_present(&"foo"), BloomCheckResult::Maybe);
+        }
+
+        filter.tick();
+        assert_eq!(filter.is_present(&"foo"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn adding_again_renews_an_item() {
+        let mut filter = GenerationalBloomFilter::build(2, 10, 3).expect("should have built the filter");
+        filter.add(&"foo");
+        filter.tick();
+        filter.add(&"foo");
+        filter.tick();
+
+        assert_eq!(filter.is_present(&"foo"), BloomCheckResult::Maybe);
+
+        filter.tick();
+        assert_eq!(filter.is_present(&"foo"), BloomCheckResult::No);
+    }
+}
@@ -1,49 +1,302 @@
 use std::error::Error;
-use std::hash::Hash;
 use sha2::{Sha512, Digest};
 use std::fmt;
+use std::io::{self, BufRead, Read, Write};
 use bit_vec::BitVec;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::hint;
+use std::time::{Duration, Instant};
+
+pub mod counting;
+pub use counting::CountingBloomFilter;
+pub mod fixed;
+pub use fixed::FixedBloomFilter;
+pub mod generational;
+pub use generational::GenerationalBloomFilter;
 
 pub struct BloomFilter {
     bits: BitVec, // the bits that actually make up the bloom filter
     hasher_count: usize, // the number of hashers
     hasher_range_in_bits: u32, // the number of bits for each hash value. bits is effectively 2 ^ this value long
+    set_bits: usize, // how many of bits are set, kept up to date by everything that changes them so it never needs a scan
+    separator: Vec<u8>, // what [add_parts] and [contains_parts] put between the parts of a key
+    // Every item added with [add], to check the filter against. Items that only made it in
+    // through [or_mask], [from_compact_bytes] or [from_legacy_bytes] aren't known, so aren't
+    // tracked.
+    #[cfg(feature = "debug-tracking")]
+    inserted: HashSet<Vec<u8>>,
 }
 
-const FULL_HASH_BYTES: u32 = 512;
+// The number of bits in each SHA512 hash, which all of the hashers share
+const FULL_HASH_BITS: u32 = 512;
+// The same, in bytes
+const DIGEST_LEN: usize = FULL_HASH_BITS as usize / 8;
+
+// Layout of the compact serialized form's header, all integers little-endian:
+// [flags: u8][hasher_range_in_bits: u32][hasher_count: u32][bit length: u64]
+const COMPACT_HEADER_LEN: usize = 1 + 4 + 4 + 8;
+// Set in the flags byte when trailing zero bytes were trimmed from the bits
+const COMPACT_FLAG_TRIMMED: u8 = 0b0000_0001;
+// Layout of a config token's bytes, before they're base64 encoded:
+// [version: u8][hasher_range_in_bits: u8][hasher_count: u32 little-endian][separator: the rest]
+const CONFIG_TOKEN_VERSION: u8 = 1;
+const CONFIG_TOKEN_HEADER_LEN: usize = 1 + 1 + 4;
+// The URL-safe base64 alphabet, so tokens can go in URLs and environment variables as they are
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+// How many bytes of bits [write_to] and [read_from] hold at once
+const STREAM_BUFFER_LEN: usize = 64 * 1024;
+// What goes between the parts of a composite key unless [BloomFilter::with_separator] says
+// otherwise: ASCII's unit separator, which text almost never contains
+const DEFAULT_SEPARATOR: &[u8] = &[0x1f];
+// How long the keys [BloomFilter::find_false_positive] tries are. Long enough that a random one
+// is never one that was actually added.
+const RANDOM_KEY_LEN: usize = 16;
+// Seeds the keys [BloomFilter::benchmark_hash_throughput] hashes, so every run hashes the same ones
+const BENCHMARK_SEED: u64 = 0x6d61_6e69_6665_7374;
+
+// Grey levels used by [to_pgm]. White doubles as the image's max value.
+const PGM_BLACK: u8 = 0;
+const PGM_WHITE: u8 = 255;
+const PGM_PADDING: u8 = 128;
 
 impl BloomFilter { 
     pub fn build(hasher_range_in_bits: u32, hasher_count: usize) -> Result<BloomFilter, &'static str> {
-        if hasher_range_in_bits * (hasher_count as u32) > FULL_HASH_BYTES {
+        let required_bits = u32::try_from(hasher_count).ok()
+            .and_then(|hasher_count| hasher_range_in_bits.checked_mul(hasher_count));
+
+        if required_bits.is_none_or(|required_bits| required_bits > FULL_HASH_BITS) {
             return Err("The bloom filter is too large for the underlying hashers");
         }
 
-        let mut bits = BitVec::from_elem(2_usize.pow(hasher_range_in_bits), false);
+        // 2 ^ hasher_range_in_bits has to fit in a usize for the bits to be indexed
+        if hasher_range_in_bits >= usize::BITS {
+            return Err("The bloom filter is too large to fit in memory");
+        }
+
+        let bits = BitVec::from_elem(2_usize.pow(hasher_range_in_bits), false);
 
         Ok(
             BloomFilter { 
                 bits,
                 hasher_count, 
                 hasher_range_in_bits, // TODO make this variable
+                set_bits: 0,
+                separator: DEFAULT_SEPARATOR.to_vec(),
+                #[cfg(feature = "debug-tracking")]
+                inserted: HashSet::new(),
             }
         )
     }
 
+    // Like [build], but refuses (before allocating anything) to build a filter whose bits would
+    // take up more than [max_bytes], for when the parameters come from somewhere untrusted.
+    pub fn build_capped(hasher_range_in_bits: u32, hasher_count: usize, max_bytes: usize) -> Result<BloomFilter, BloomError> {
+        // 2 ^ hasher_range_in_bits bits, 8 to a byte. Anything too big for a usize to index is
+        // certainly over the cap.
+        let requested_bytes = match hasher_range_in_bits < usize::BITS {
+            true => (1_u128 << hasher_range_in_bits).div_ceil(8),
+            false => u128::MAX,
+        };
+
+        if requested_bytes > max_bytes as u128 {
+            return Err(BloomError::ExceedsSizeCap { requested_bytes, max_bytes });
+        }
+
+        let required_bits = hasher_range_in_bits.saturating_mul(hasher_count.try_into().unwrap_or(u32::MAX));
+
+        if required_bits > FULL_HASH_BITS {
+            return Err(BloomError::ExceedsHashBudget { required_bits, available_bits: FULL_HASH_BITS });
+        }
+
+        Ok(
+            BloomFilter::build(hasher_range_in_bits, hasher_count)
+                .expect("parameters within the cap and the hash budget should always build")
+        )
+    }
+
+    // Starts a [BloomFilterBuilder] for a filter with these parameters, which can be told to
+    // round parameters that don't fit together (e.g. from config) rather than refuse them
+    pub fn builder(hasher_range_in_bits: u32, hasher_count: usize) -> BloomFilterBuilder {
+        BloomFilterBuilder { hasher_range_in_bits, hasher_count, coerce: false }
+    }
+
+    // Builds a filter sized to hold [expected_items] with a false positive rate of at most
+    // [target_fpr]. The optimal bit length (m = -n ln(p) / ln(2)^2) is rounded up to a power of
+    // two, and the hasher count is chosen to be optimal for that rounded-up length
+    // (k = m / n * ln(2)), so the actual rate is usually a little better than the target.
+    pub fn tuned(expected_items: usize, target_fpr: f64) -> Result<BloomFilter, BloomError> {
+        if expected_items == 0 {
+            return Err(BloomError::InvalidTuning("expected_items must be at least 1"));
+        }
+
+        if !(target_fpr > 0.0 && target_fpr < 1.0) {
+            return Err(BloomError::InvalidTuning("target_fpr must be between 0 and 1 (exclusive)"));
+        }
+
+        let n = expected_items as f64;
+        let optimal_bit_len = -n * target_fpr.ln() / std::f64::consts::LN_2.powi(2);
+        let hasher_range_in_bits = (optimal_bit_len.log2().ceil() as u32).max(1);
+
+        if hasher_range_in_bits >= usize::BITS {
+            return Err(BloomError::InvalidTuning("too many expected items to fit a filter in memory"));
+        }
+
+        let bit_len = 2_f64.powi(hasher_range_in_bits as i32);
+        let hasher_count = ((bit_len / n * std::f64::consts::LN_2).round() as usize).max(1);
+        let required_bits = hasher_range_in_bits.saturating_mul(hasher_count.try_into().unwrap_or(u32::MAX));
+
+        if required_bits > FULL_HASH_BITS {
+            return Err(BloomError::TuningExceedsHashBudget { target_fpr, required_bits, available_bits: FULL_HASH_BITS });
+        }
+
+        Ok(
+            BloomFilter::build(hasher_range_in_bits, hasher_count)
+                .expect("parameters within the hash budget should always build")
+        )
+    }
+
+    // Builds a [tuned] filter holding every item in [items], sized for the number of distinct
+    // items rather than the total, and hashing each distinct item only once. The items are read
+    // in a single pass, and every distinct one is held in a HashSet until the filter is built, so
+    // this needs memory for all of the distinct items on top of the filter itself. For inputs
+    // too big for that, size a filter with [tuned] and [add] the items as they come instead.
+    pub fn build_from_deduped<T, I>(items: I, fpr: f64) -> Result<BloomFilter, BloomError>
+    where
+        T: AsRef<[u8]> + Eq + Hash,
+        I: IntoIterator<Item = T>,
+    {
+        let distinct: HashSet<T> = items.into_iter().collect();
+        let mut bf = BloomFilter::tuned(distinct.len(), fpr)?;
+
+        for item in &distinct {
+            bf.add(item);
+        }
+
+        Ok(bf)
+    }
+
+    // The number of hashers each item is hashed with
+    pub fn hasher_count(&self) -> usize {
+        self.hasher_count
+    }
+
     // Adds the given string to the bloom filter
     pub fn add<T: AsRef<[u8]>>(&mut self, t: &T) {
-        let t_hash = self.hash(t);
+        for i in hash_position_iter(t, self.hasher_count, self.hasher_range_in_bits) {
+            if !self.bits[i] {
+                self.bits.set(i, true);
+                self.set_bits += 1;
+            }
+        }
 
-        for i in t_hash {
-            self.bits.set(i, true);
+        #[cfg(feature = "debug-tracking")]
+        self.inserted.insert(t.as_ref().to_vec());
+    }
+
+    // Sets what [add_parts] and [contains_parts] put between the parts of a composite key, which
+    // can be more than one byte. Pick something the parts never contain: a part that does
+    // contain it can't be told apart from two parts, so ["a,b", "c"] and ["a", "b,c"] (with a
+    // separator of ",") make the same key, and checking for either says Maybe once the other is
+    // added. That's an extra false positive rather than a false negative, but it's one that no
+    // amount of bits will fix. The separator isn't part of the compact form, so a filter that's
+    // read back needs it set again before checking composite keys.
+    pub fn with_separator(mut self, separator: &[u8]) -> Result<BloomFilter, BloomError> {
+        if separator.is_empty() {
+            return Err(BloomError::InvalidSeparator("it can't be empty"));
+        }
+
+        self.separator = separator.to_vec();
+
+        Ok(self)
+    }
+
+    pub fn separator(&self) -> &[u8] {
+        &self.separator
+    }
+
+    // Adds a composite key made of [parts] (e.g. a user and a resource), joined with the
+    // filter's separator. Check for it with [contains_parts], which joins them the same way.
+    pub fn add_parts<T: AsRef<[u8]>>(&mut self, parts: &[T]) {
+        let key = self.join_parts(parts);
+
+        self.add(&key);
+    }
+
+    // Whether the composite key made of [parts] may have been added with [add_parts]
+    pub fn contains_parts<T: AsRef<[u8]>>(&self, parts: &[T]) -> bool {
+        self.contains(&self.join_parts(parts))
+    }
+
+    fn join_parts<T: AsRef<[u8]>>(&self, parts: &[T]) -> Vec<u8> {
+        let mut key = Vec::new();
+
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                key.extend_from_slice(&self.separator);
+            }
+
+            key.extend_from_slice(part.as_ref());
+        }
+
+        key
+    }
+
+    // Adds every line from the reader (without its trailing newline) to the bloom filter,
+    // returning the number of lines added. Lines are read one at a time, so this works for
+    // inputs much larger than memory. Lines don't need to be valid UTF-8.
+    pub fn add_lines<R: BufRead>(&mut self, mut reader: R) -> io::Result<usize> {
+        let mut line: Vec<u8> = Vec::new();
+        let mut count = 0;
+
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            if line.ends_with(b"\n") {
+                line.pop();
+
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+
+            self.add(&line);
+            count += 1;
+            line.clear();
         }
+
+        Ok(count)
     }
 
     pub fn is_present<T: AsRef<[u8]>>(&self, t: &T) -> BloomCheckResult {
-        let t_hash = self.hash(t);
+        self.is_present_partial(t, self.hasher_count)
+    }
+
+    // [is_present] as a bool: true for [BloomCheckResult::Maybe]. Like every lookup, it works out
+    // the item's positions one at a time as it checks them, so it never allocates and stops at the
+    // first unset bit.
+    pub fn contains<T: AsRef<[u8]>>(&self, t: &T) -> bool {
+        self.is_present(t) == BloomCheckResult::Maybe
+    }
+
+    // Like [is_present], but only checks the first [max_hashers] of the item's positions, for a
+    // cheap pre-filter before the full check. A [BloomCheckResult::No] is still certain, but a
+    // [BloomCheckResult::Maybe] is weaker than a full check's: fewer bits had to be set, so false
+    // positives are more likely. A [max_hashers] of 0 checks nothing and always says Maybe.
+    pub fn is_present_partial<T: AsRef<[u8]>>(&self, t: &T, max_hashers: usize) -> BloomCheckResult {
+        // Each hasher's position comes from its own slice of the hash, so the first few are the
+        // same however many are computed
+        let t_hash = hash_position_iter(t, max_hashers.min(self.hasher_count), self.hasher_range_in_bits);
 
         for i in t_hash {
             if !self.bits.get(i)
                 .expect("the values produced by the hashers should be in the bounds of the bit array") {
+                #[cfg(feature = "debug-tracking")]
+                assert!(
+                    !self.inserted.contains(t.as_ref()),
+                    "False negative: {:?} was added but the filter says it wasn't",
+                    String::from_utf8_lossy(t.as_ref())
+                );
+
                 return BloomCheckResult::No;
             }
         }
@@ -51,116 +304,1869 @@ impl BloomFilter {
         BloomCheckResult::Maybe
     }
 
-    // Each bloom filter has [hasher_count] hashers, each of which hash a given value
-    // to a single position in a bit vector. This method calculates those positions
-    // for each of the hashers. In reality, this method is implemented by computing a 
-    // single SHA512 hash value and using the necessary number of bits of the resulting
-    // hash for each hasher. 
-    //
-    // As an example, for a bloom filter consisting of a bit vector with length 8, 3 bits
-    // of the SHA512 hash will be used for each "hasher" because 2 ^ 3 == 8. The number in
-    // [0 - 7] represented by each of those slices of three bits is the position of a 1 
-    // in the final hash.
-    //
-    // The Vector returned from this method is a list of the positions of the 1s in the 
-    // final hash for this value.
+    // Panics if any item that was added is reported as [BloomCheckResult::No]. Only available
+    // with the `debug-tracking` feature, which keeps a copy of every added item to check against.
+    #[cfg(feature = "debug-tracking")]
+    pub fn assert_no_false_negatives(&self) {
+        for item in &self.inserted {
+            let all_set = self.hash(item).into_iter()
+                .all(|i| self.bits.get(i).unwrap_or(false));
+
+            assert!(all_set, "False negative: {:?} was added but the filter says it wasn't", String::from_utf8_lossy(item));
+        }
+    }
+
+    // Checks items by their SHA512 hashes, worked out somewhere else (e.g. by an earlier stage of
+    // a pipeline that already had them), rather than hashing the items again. Each hash is sliced
+    // into positions exactly as [is_present] would slice the item's, so the answers are the same
+    // as checking the items themselves. The hashes have to be the full 64 bytes, which their type
+    // makes sure of.
+    pub fn contains_prehashed_all<I: IntoIterator<Item = [u8; 64]>>(&self, hashes: I) -> Vec<BloomCheckResult> {
+        hashes.into_iter()
+            .map(|hash| {
+                let all_set = digest_position_iter(hash, self.hasher_count, self.hasher_range_in_bits)
+                    .all(|i| self.bits.get(i).expect("the values produced by the hashers should be in the bounds of the bit array"));
+
+                if all_set { BloomCheckResult::Maybe } else { BloomCheckResult::No }
+            })
+            .collect()
+    }
+
+    // Gives a precise answer by running [verify] (e.g. a database lookup) to settle any
+    // [BloomCheckResult::Maybe]. On [BloomCheckResult::No] the item definitely wasn't added, so
+    // [verify] is skipped entirely; that's the whole point of putting a bloom filter in front of
+    // an expensive lookup.
+    pub fn contains_verified<T, F>(&self, t: &T, verify: F) -> bool
+    where
+        T: AsRef<[u8]>,
+        F: FnOnce(&T) -> bool,
+    {
+        match self.is_present(t) {
+            BloomCheckResult::No => false,
+            BloomCheckResult::Maybe => verify(t),
+        }
+    }
+
+    // A key that answers [BloomCheckResult::Maybe] without having been added, for testing what
+    // happens on a false positive, or None if none of [max_tries] random keys did. The same
+    // [rng_seed] always tries the same keys, so it finds the same one on the same filter. With the
+    // `debug-tracking` feature, keys that really were added are passed over; without it, the keys
+    // are random enough that they never will have been.
+    pub fn find_false_positive(&self, rng_seed: u64, max_tries: usize) -> Option<Vec<u8>> {
+        let mut rng_state = rng_seed;
+
+        for _ in 0..max_tries {
+            let key: Vec<u8> = (0..RANDOM_KEY_LEN / 8)
+                .flat_map(|_| splitmix64(&mut rng_state).to_le_bytes())
+                .collect();
+
+            #[cfg(feature = "debug-tracking")]
+            if self.inserted.contains(&key) {
+                continue;
+            }
+
+            if self.contains(&key) {
+                return Some(key);
+            }
+        }
+
+        None
+    }
+
+    // A rough, built-in micro-benchmark for capacity planning: how many items a second can be
+    // hashed with this filter's parameters, on a single thread of this machine. [sample_count]
+    // random keys (as long as [find_false_positive]'s) are hashed to all of their positions without
+    // the bits being touched, so this is a ceiling for [add] and [contains] rather than a measure
+    // of either. Results vary from run to run and with whatever else the machine is doing, and
+    // debug builds are many times slower, so measure a release build and take the best of a few
+    // runs. Panics if [sample_count] is 0.
+    pub fn benchmark_hash_throughput(&self, sample_count: usize) -> f64 {
+        assert!(sample_count > 0, "The benchmark needs at least one sample");
+
+        // Made up front, so that making them isn't timed
+        let mut rng_state = BENCHMARK_SEED;
+        let keys: Vec<[u8; RANDOM_KEY_LEN]> = (0..sample_count)
+            .map(|_| {
+                let mut key = [0; RANDOM_KEY_LEN];
+                for chunk in key.chunks_mut(8) {
+                    chunk.copy_from_slice(&splitmix64(&mut rng_state).to_le_bytes());
+                }
+                key
+            })
+            .collect();
+
+        let start = Instant::now();
+
+        for key in &keys {
+            // black_box stops the unused positions (and so the hashing) being optimised away
+            for position in hash_position_iter(key, self.hasher_count, self.hasher_range_in_bits) {
+                hint::black_box(position);
+            }
+        }
+
+        // A clock too coarse to see the run at all would otherwise make it infinitely fast
+        let elapsed = start.elapsed().max(Duration::from_nanos(1));
+
+        sample_count as f64 / elapsed.as_secs_f64()
+    }
+
+    // Counts how many of the given items answer [BloomCheckResult::Maybe]. Because of false
+    // positives, this is an upper bound on how many of them were actually added; it's a cheap
+    // way to estimate the size of an intersection.
+    pub fn count_present<T: AsRef<[u8]>, I: IntoIterator<Item = T>>(&self, items: I) -> usize {
+        items.into_iter()
+            .filter(|item| self.is_present(item) == BloomCheckResult::Maybe)
+            .count()
+    }
+
+    // How many distinct bits adding all of [items] would set (whether or not they're set already),
+    // for sizing a filter before building it: divide by [bit_len] for the fill ratio an empty
+    // filter would end up with. This doesn't change the filter. Takes O(items × hasher_count).
+    pub fn would_set_bits<T: AsRef<[u8]>, I: IntoIterator<Item = T>>(&self, items: I) -> usize {
+        let mut positions: HashSet<usize> = HashSet::new();
+
+        for item in items {
+            positions.extend(self.hash(&item));
+        }
+
+        positions.len()
+    }
+
+    // A diagnostic for working out why a dataset has more false positives than expected: hashes
+    // each of [items] (which should be the items that were added) with this filter's parameters
+    // and reports how many pairs of them land on exactly the same bits, so can't be told apart,
+    // and how many bits are shared by more than one of them. This doesn't look at or change the
+    // filter's bits. Repeated items are only counted once.
+    pub fn collision_report<T: AsRef<[u8]>, I: IntoIterator<Item = T>>(&self, items: I) -> CollisionReport {
+        let mut seen: HashSet<Vec<u8>> = HashSet::new();
+        let mut position_sets: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut position_counts: HashMap<usize, usize> = HashMap::new();
+
+        for item in items {
+            if !seen.insert(item.as_ref().to_vec()) {
+                continue;
+            }
+
+            let mut positions = self.hash(&item);
+            positions.sort_unstable();
+            positions.dedup();
+
+            for position in &positions {
+                *position_counts.entry(*position).or_default() += 1;
+            }
+
+            *position_sets.entry(positions).or_default() += 1;
+        }
+
+        CollisionReport {
+            items: seen.len(),
+            fully_overlapping_pairs: position_sets.values().map(|n| n * (n - 1) / 2).sum(),
+            indistinguishable_items: position_sets.values().filter(|n| **n > 1).sum(),
+            shared_positions: position_counts.values().filter(|n| **n > 1).count(),
+        }
+    }
+
+    // Whether moving the filter's items to different bit positions (by hashing them with a new
+    // seed, a different hash or a different size) needs the original items. It always does: the
+    // filter only knows which bits are set, and each set bit could have come from any number of
+    // items. See [try_reseed].
+    pub const fn reseed_requires_items() -> bool {
+        true
+    }
+
+    // Always fails with [BloomError::OperationRequiresSourceItems], explaining why. This exists
+    // so that anyone looking for a way to re-seed a populated filter finds out that there isn't
+    // one, rather than reaching for something like [or_mask] that would silently give wrong
+    // answers.
+    pub fn try_reseed(&self) -> Result<(), BloomError> {
+        Err(BloomError::OperationRequiresSourceItems("re-seed"))
+    }
+
+    // The number of bits backing the filter
+    pub fn bit_len(&self) -> usize {
+        self.bits.len()
+    }
+
+    // The number of bits that are set. This is cached rather than counted, so it's cheap enough
+    // to call as often as you like.
+    pub fn set_bits_count(&self) -> usize {
+        self.set_bits
+    }
+
+    // Unsets every bit, leaving the filter as it was when it was built
+    pub fn clear(&mut self) {
+        self.bits.clear();
+        self.set_bits = 0;
+
+        #[cfg(feature = "debug-tracking")]
+        self.inserted.clear();
+    }
+
+    // A snapshot of the filter's health. Uses the cached [set_bits_count], so it doesn't need to
+    // scan the bits.
+    pub fn stats(&self) -> FilterStats {
+        let bit_len = self.bits.len();
+        let set_bits = self.set_bits;
+        let fill_ratio = set_bits as f64 / bit_len as f64;
+        let hasher_count = self.hasher_count as f64;
+        let estimated_len = estimate_len(bit_len, set_bits, self.hasher_count);
+        let false_positive_rate = fill_ratio.powf(hasher_count);
+
+        // The fewest bits that could hold [estimated_len] items at this rate, which is only
+        // defined while the filter is neither empty nor full
+        let memory_efficiency = if estimated_len > 0.0 && estimated_len.is_finite() {
+            -estimated_len * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2) / bit_len as f64
+        } else {
+            0.0
+        };
+
+        FilterStats {
+            bit_len,
+            set_bits,
+            fill_ratio,
+            hasher_count: self.hasher_count,
+            estimated_len,
+            false_positive_rate,
+            bits_per_element: if set_bits == 0 { f64::INFINITY } else { bit_len as f64 / estimated_len },
+            memory_efficiency,
+        }
+    }
+
+    // Estimates how many more distinct items can be added before the false positive rate (as
+    // [stats] works it out) goes over [target_fpr]. The rate reaches the target once the fill
+    // ratio is target_fpr^(1/k), so this is [estimate_len] at that fill ratio less the estimate
+    // for the filter as it is, and 0 once the filter is already over the target. [target_fpr]
+    // must be between 0 and 1 (exclusive), as for [tuned].
+    pub fn remaining_capacity(&self, target_fpr: f64) -> Result<usize, BloomError> {
+        if !(target_fpr > 0.0 && target_fpr < 1.0) {
+            return Err(BloomError::InvalidTuning("target_fpr must be between 0 and 1 (exclusive)"));
+        }
+
+        let bit_len = self.bits.len();
+        let target_fill_ratio = target_fpr.powf(1.0 / self.hasher_count as f64);
+        let target_set_bits = (target_fill_ratio * bit_len as f64).floor() as usize;
+
+        let capacity = estimate_len(bit_len, target_set_bits, self.hasher_count);
+        let current = estimate_len(bit_len, self.set_bits, self.hasher_count);
+
+        Ok((capacity - current).max(0.0).floor() as usize)
+    }
+
+    // Estimates how many distinct items were added to either filter, from the bits set in the
+    // union of the two. Both filters must have been built with the same parameters. If every
+    // bit of the union is set, there's no telling, and this is usize::MAX.
+    pub fn estimated_union_len(&self, other: &BloomFilter) -> Result<usize, BloomError> {
+        if self.hasher_range_in_bits != other.hasher_range_in_bits || self.hasher_count != other.hasher_count {
+            return Err(BloomError::ParameterMismatch);
+        }
+
+        let set_bits = self.bits.blocks()
+            .zip(other.bits.blocks())
+            .map(|(a, b)| (a | b).count_ones() as usize)
+            .sum();
+
+        Ok(estimate_len(self.bit_len(), set_bits, self.hasher_count).round() as usize)
+    }
+
+    // ORs an externally-computed mask into the filter, as if every item that produced those
+    // bits had been added. The mask must be exactly [bit_len] bits long.
+    pub fn or_mask(&mut self, mask: &BitVec) -> Result<(), BloomError> {
+        if mask.len() != self.bit_len() {
+            return Err(BloomError::LengthMismatch { expected: self.bit_len(), actual: mask.len() });
+        }
+
+        self.bits.or(mask);
+        self.set_bits = count_set_bits(&self.bits);
+
+        Ok(())
+    }
+
+    // Renders the bits as a binary (P5) PGM image, [width] bits to a row, with set bits black and
+    // unset bits white. If the bits don't fill the last row, the rest of it is grey so that it
+    // can't be mistaken for unset bits. Panics if [width] is 0.
+    pub fn to_pgm(&self, width: usize) -> Vec<u8> {
+        assert!(width > 0, "A PGM needs to be at least one pixel wide");
+
+        let height = self.bits.len().div_ceil(width);
+        let header = format!("P5\n{} {}\n{}\n", width, height, PGM_WHITE);
+
+        let mut bytes = Vec::with_capacity(header.len() + width * height);
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend(self.bits.iter().map(|bit| if bit { PGM_BLACK } else { PGM_WHITE }));
+        bytes.resize(header.len() + width * height, PGM_PADDING);
+
+        bytes
+    }
+
     fn hash<T: AsRef<[u8]>>(&self, t: &T) -> Vec<usize> {
-        let mut hasher = Sha512::new();
-        hasher.update(&t);
-        let full_hash = hasher.finalize();
+        hash_positions(t, self.hasher_count, self.hasher_range_in_bits)
+    }
 
-        let mut computed_hash: Vec<usize> = vec![0; self.hasher_count];
-        // This moves along the full hash, keeping track of the bit we're working on
-        let mut full_hash_ptr = 0;
+    // Serializes the filter into a header followed by the raw bits, packed 8 to a byte.
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        self.compact_bytes(false)
+    }
+
+    // Like [to_compact_bytes], but drops the trailing run of zero bytes. Lightly-loaded filters
+    // are mostly zeros, so this can be much smaller. The logical bit length is kept in the
+    // header, so [from_compact_bytes] restores the dropped zeros.
+    pub fn to_trimmed_compact_bytes(&self) -> Vec<u8> {
+        self.compact_bytes(true)
+    }
+
+    fn compact_bytes(&self, trim: bool) -> Vec<u8> {
+        let mut bit_bytes = self.bits.to_bytes();
+
+        if trim {
+            let used_len = bit_bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            bit_bytes.truncate(used_len);
+        }
+
+        let flags = if trim { COMPACT_FLAG_TRIMMED } else { 0 };
+
+        let mut bytes = Vec::with_capacity(COMPACT_HEADER_LEN + bit_bytes.len());
+        bytes.extend_from_slice(&self.compact_header(flags));
+        bytes.extend_from_slice(&bit_bytes);
 
-        for bloom_hasher_index in 0..self.hasher_count {
-            // The position of the 1 for this hasher
-            let mut hasher_value: usize = 0;
-            
-            for _ in 0..self.hasher_range_in_bits {
-                // The SHA512 hashes are grouped into bytes, so find the byte and bit
-                // within that byte that we're considering.
-                let byte_index: usize = (full_hash_ptr / 8).try_into().unwrap();
-                let bit_in_byte = full_hash_ptr % 8;
+        bytes
+    }
+
+    fn compact_header(&self, flags: u8) -> [u8; COMPACT_HEADER_LEN] {
+        let mut header = [0; COMPACT_HEADER_LEN];
+        header[0] = flags;
+        header[1..5].copy_from_slice(&self.hasher_range_in_bits.to_le_bytes());
+        header[5..9].copy_from_slice(&(self.hasher_count as u32).to_le_bytes());
+        header[9..17].copy_from_slice(&(self.bits.len() as u64).to_le_bytes());
+
+        header
+    }
 
-                // Check the bit under consideration.
-                let bit_mask: u8 = 2_u8.pow(bit_in_byte);
-                let bit: bool = full_hash[full_hash.len() - byte_index - 1] & bit_mask != 0;
+    // Writes the same bytes as [to_compact_bytes] to [writer], a buffer's worth at a time, so
+    // that serializing a huge filter doesn't need a second copy of its bits in memory. The
+    // writer isn't buffered or flushed here.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.compact_header(0))?;
 
-                // Add the bit to the hasher's value.
-                hasher_value = (hasher_value << 1) + (bit as usize);
-                full_hash_ptr += 1;
+        let mut remaining = self.bits.len().div_ceil(8);
+        let mut buffer: Vec<u8> = Vec::with_capacity(STREAM_BUFFER_LEN);
+
+        for block in self.bits.blocks() {
+            // The bits in a block go from least to most significant, but in the compact form
+            // each byte starts with its most significant bit
+            for byte in block.to_le_bytes().into_iter().take(remaining) {
+                buffer.push(byte.reverse_bits());
             }
+            remaining -= remaining.min(4);
 
-            computed_hash[bloom_hasher_index] = hasher_value;
+            if buffer.len() + 4 > STREAM_BUFFER_LEN {
+                writer.write_all(&buffer)?;
+                buffer.clear();
+            }
         }
-        
-        computed_hash
+
+        writer.write_all(&buffer)
     }
-}
 
-impl fmt::Debug for BloomFilter {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut s = String::new();
+    // Rebuilds a filter from either of the compact serialized forms, read from [reader] a
+    // buffer's worth at a time. The filter's bits are allocated from the header before any of
+    // them are read, so a header from somewhere untrusted can ask for a huge filter. The untrimmed
+    // form is read up to the end of its bits, leaving anything after it in [reader]; the trimmed
+    // form doesn't say how long it is, so it's read to the end of [reader].
+    pub fn read_from<R: Read>(mut reader: R) -> Result<BloomFilter, BloomError> {
+        let mut header = [0; COMPACT_HEADER_LEN];
 
-        for b in &self.bits {
-            let v = if b {
-                1
-            } else {
-                0
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => BloomError::Malformed("too short to contain a header"),
+            kind => BloomError::Io(kind),
+        })?;
+
+        let (hasher_range_in_bits, hasher_count, bit_len, trimmed) = read_compact_header(&header)?;
+        let mut filter = BloomFilter::build(hasher_range_in_bits, hasher_count).map_err(BloomError::Malformed)?;
+        let full_byte_len = bit_len.div_ceil(8);
+
+        // One byte more than a trimmed filter can have, to tell when there's too much
+        let mut reader = reader.take((full_byte_len + trimmed as usize) as u64);
+        let mut buffer = vec![0; STREAM_BUFFER_LEN.min(full_byte_len + 1)];
+        let mut byte_index = 0;
+
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(BloomError::Io(e.kind())),
             };
 
-            s.push_str(&format!("{} ", v));
+            for byte in &buffer[..read] {
+                for bit in 0..8 {
+                    let i = byte_index * 8 + bit;
+
+                    // Bits past the logical end are padding
+                    if byte & (0x80 >> bit) != 0 && i < filter.bits.len() {
+                        filter.bits.set(i, true);
+                        filter.set_bits += 1;
+                    }
+                }
+
+                byte_index += 1;
+            }
         }
 
-        write!(f, "{}", s)
+        if byte_index > full_byte_len || (!trimmed && byte_index != full_byte_len) {
+            return Err(BloomError::Malformed("wrong number of bytes for the bit length"));
+        }
+
+        Ok(filter)
     }
-}
 
-#[derive(PartialEq, Debug)]
-pub enum BloomCheckResult {
-    No,
-    Maybe
-}
+    // Rebuilds a filter from either of the compact serialized forms.
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<BloomFilter, BloomError> {
+        if bytes.len() < COMPACT_HEADER_LEN {
+            return Err(BloomError::Malformed("too short to contain a header"));
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        let (hasher_range_in_bits, hasher_count, bit_len, trimmed) =
+            read_compact_header(bytes[..COMPACT_HEADER_LEN].try_into().unwrap())?;
+        let bit_bytes = &bytes[COMPACT_HEADER_LEN..];
+        let full_byte_len = bit_len.div_ceil(8);
 
-    #[test]
-    fn rejects_invalid_size_and_hasher_count() {
-        if let Ok(_) = BloomFilter::build(200, 7) {
-            panic!("Should have rejected invalid input");
+        // Checked before the filter is built, so that a header asking for far more bits than
+        // came with it doesn't get them allocated
+        if bit_bytes.len() > full_byte_len || (!trimmed && bit_bytes.len() != full_byte_len) {
+            return Err(BloomError::Malformed("wrong number of bytes for the bit length"));
         }
+
+        let mut filter = BloomFilter::build(hasher_range_in_bits, hasher_count).map_err(BloomError::Malformed)?;
+
+        let mut bits = BitVec::from_bytes(bit_bytes);
+        // from_bytes works in whole bytes, so pad out anything that was trimmed and drop any
+        // padding bits past the logical end.
+        bits.grow(full_byte_len * 8 - bits.len(), false);
+        bits.truncate(bit_len);
+
+        filter.set_bits = count_set_bits(&bits);
+        filter.bits = bits;
+
+        Ok(filter)
     }
 
-    #[test]
-    fn accepts_valid_size_and_hasher_count() {
-        if let Err(_) = BloomFilter::build(4, 6) { 
-            panic!("Should have accepted valid input");
+    // Rebuilds a filter from the raw parts of a byte-based filter (a [FixedBloomFilter], or bytes
+    // saved from one): its bytes, hasher count and byte count. The two hash items the same way and
+    // lay their bits out in the same order, so the result agrees with the original on every item.
+    // Bytes from a filter that hashed items some other way can't be converted like this, since
+    // there's no telling which items set which bits; add the original items to a new filter
+    // instead (e.g. with [build_from_deduped] or [add_lines]).
+    pub fn from_legacy_bytes(bytes: &[u8], hasher_count: usize, byte_count: usize) -> Result<BloomFilter, BloomError> {
+        if bytes.len() != byte_count {
+            return Err(BloomError::LengthMismatch { expected: byte_count * 8, actual: bytes.len() * 8 });
+        }
+
+        if !byte_count.is_power_of_two() {
+            return Err(BloomError::Malformed("a byte-based filter has a power of two number of bytes"));
         }
+
+        let hasher_range_in_bits = (byte_count * 8).trailing_zeros();
+        let mut filter = BloomFilter::build(hasher_range_in_bits, hasher_count)
+            .map_err(BloomError::Malformed)?;
+
+        filter.bits = BitVec::from_bytes(bytes);
+        filter.set_bits = count_set_bits(&filter.bits);
+
+        Ok(filter)
     }
 
-    #[test]
-    fn no_false_negatives() {
-        let mut bf = BloomFilter::build(4, 2)
-            .expect("should have built a bloom filte");
+    // A short token for the filter's parameters without any of its bits, e.g. "AQoDAAAAHw" for
+    // 3 hashers of 10 bits each, for services to agree on compatible filters by. It covers
+    // everything that decides where an item's bits go: the hasher count, the hasher range and the
+    // separator [add_parts] joins composite keys with.
+    pub fn config_token(&self) -> String {
+        let mut bytes = vec![CONFIG_TOKEN_VERSION];
+        // A range that doesn't fit in a u8 would never have fit in memory either
+        bytes.push(self.hasher_range_in_bits as u8);
+        bytes.extend_from_slice(&(self.hasher_count as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.separator);
 
-        bf.add(&String::from("foo"));
-        bf.add(&String::from("bar"));
-        bf.add(&String::from("baz"));
-        bf.add(&String::from("Green eggs and ham"));
+        base64_encode(&bytes)
+    }
 
-        dbg!("{:?}", &bf);
+    // Builds an empty filter with the parameters in a [config_token]
+    pub fn from_config_token(token: &str) -> Result<BloomFilter, BloomError> {
+        let bytes = base64_decode(token).ok_or(BloomError::Malformed("config tokens are URL-safe base64"))?;
 
-        assert_eq!(bf.is_present(&String::from("foo")), BloomCheckResult::Maybe);
-        assert_eq!(bf.is_present(&String::from("bar")), BloomCheckResult::Maybe);
-        assert_eq!(bf.is_present(&String::from("baz")), BloomCheckResult::Maybe);
-        assert_eq!(bf.is_present(&String::from("Green eggs and ham")), BloomCheckResult::Maybe);
+        if bytes.len() < CONFIG_TOKEN_HEADER_LEN {
+            return Err(BloomError::Malformed("too short to be a config token"));
+        }
 
-        assert_eq!(bf.is_present(&String::from("not present")), BloomCheckResult::No);
-        assert_eq!(bf.is_present(&String::from("nor I")), BloomCheckResult::No);
-        assert_eq!(bf.is_present(&String::from("Green eggs and jam")), BloomCheckResult::No);
+        if bytes[0] != CONFIG_TOKEN_VERSION {
+            return Err(BloomError::Malformed("unknown config token version"));
+        }
+
+        let hasher_range_in_bits = bytes[1] as u32;
+        let hasher_count = u32::from_le_bytes(bytes[2..6].try_into().unwrap());
+
+        if hasher_range_in_bits >= usize::BITS {
+            return Err(BloomError::Malformed("the hasher range is too large to fit a filter in memory"));
+        }
+
+        if hasher_range_in_bits.checked_mul(hasher_count).is_none_or(|required_bits| required_bits > FULL_HASH_BITS) {
+            return Err(BloomError::Malformed("the hashers need more hash bits than there are"));
+        }
+
+        BloomFilter::build(hasher_range_in_bits, hasher_count as usize)
+            .map_err(BloomError::Malformed)?
+            .with_separator(&bytes[CONFIG_TOKEN_HEADER_LEN..])
     }
 }
 
+// The parameters in a compact header: the hasher range, the hasher count, the bit length and
+// whether the bits were trimmed. Nothing is allocated for them, so the caller can check them
+// against what came with the header first.
+fn read_compact_header(header: &[u8; COMPACT_HEADER_LEN]) -> Result<(u32, usize, usize, bool), BloomError> {
+    let flags = header[0];
+    let hasher_range_in_bits = u32::from_le_bytes(header[1..5].try_into().unwrap());
+    let hasher_count = u32::from_le_bytes(header[5..9].try_into().unwrap());
+    let bit_len = u64::from_le_bytes(header[9..17].try_into().unwrap());
+
+    if flags & !COMPACT_FLAG_TRIMMED != 0 {
+        return Err(BloomError::Malformed("unknown flags in the header"));
+    }
+
+    if hasher_range_in_bits >= usize::BITS {
+        return Err(BloomError::Malformed("the hasher range is too large to fit a filter in memory"));
+    }
+
+    if hasher_range_in_bits.checked_mul(hasher_count).is_none_or(|required_bits| required_bits > FULL_HASH_BITS) {
+        return Err(BloomError::Malformed("the hashers need more hash bits than there are"));
+    }
+
+    if bit_len != 1 << hasher_range_in_bits {
+        return Err(BloomError::Malformed("bit length doesn't match the filter's parameters"));
+    }
+
+    Ok((hasher_range_in_bits, hasher_count as usize, bit_len as usize, flags & COMPACT_FLAG_TRIMMED != 0))
+}
+
+// Counts the set bits a whole block at a time. For when too many bits have changed at once to
+// keep track of them one by one.
+fn count_set_bits(bits: &BitVec) -> usize {
+    bits.blocks().map(|block| block.count_ones() as usize).sum()
+}
+
+// [bytes] in URL-safe base64, without padding
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate().fold(0_u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+
+        // Every byte in the group needs at least one more character after the first
+        for i in 0..=group.len() {
+            encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+
+    encoded
+}
+
+// The bytes in URL-safe base64 [text], without padding, or None if it isn't
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3 + 2);
+
+    for group in text.as_bytes().chunks(4) {
+        // A lone character can't hold a whole byte
+        if group.len() == 1 {
+            return None;
+        }
+
+        let mut n = 0_u32;
+
+        for (i, c) in group.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+
+        for i in 0..group.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+// The next number from the SplitMix64 generator at [state]. It's nowhere near good enough for
+// anything secret, but it's small, fast and the same everywhere for a given seed.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    z ^ (z >> 31)
+}
+
+// Swamidass & Baldi's estimate of how many distinct items were added to a filter with
+// [set_bits] of its [bit_len] bits set: n = -(m / k) ln(1 - X / m)
+fn estimate_len(bit_len: usize, set_bits: usize, hasher_count: usize) -> f64 {
+    let fill_ratio = set_bits as f64 / bit_len as f64;
+
+    -(bit_len as f64 / hasher_count as f64) * (1.0 - fill_ratio).ln()
+}
+
+// Each bloom filter has [hasher_count] hashers, each of which hash a given value
+// to a single position in a bit vector. This method calculates those positions
+// for each of the hashers. In reality, this method is implemented by computing a 
+// single SHA512 hash value and using the necessary number of bits of the resulting
+// hash for each hasher. 
+//
+// As an example, for a bloom filter consisting of a bit vector with length 8, 3 bits
+// of the SHA512 hash will be used for each "hasher" because 2 ^ 3 == 8. The number in
+// [0 - 7] represented by each of those slices of three bits is the position of a 1 
+// in the final hash.
+//
+// The Vector returned from this method is a list of the positions of the 1s in the 
+// final hash for this value.
+pub(crate) fn hash_positions<T: AsRef<[u8]>>(t: &T, hasher_count: usize, hasher_range_in_bits: u32) -> Vec<usize> {
+    hash_position_iter(t, hasher_count, hasher_range_in_bits).collect()
+}
+
+// Like [hash_positions], but hands the positions out one at a time rather than allocating a
+// Vector for them, for lookups and [FixedBloomFilter]
+pub(crate) fn hash_position_iter<T: AsRef<[u8]>>(t: &T, hasher_count: usize, hasher_range_in_bits: u32) -> impl Iterator<Item = usize> {
+    let mut hasher = Sha512::new();
+    hasher.update(t);
+
+    digest_position_iter(hasher.finalize().into(), hasher_count, hasher_range_in_bits)
+}
+
+// The positions for an item whose SHA512 hash has already been worked out, for
+// [BloomFilter::contains_prehashed_all]
+fn digest_position_iter(full_hash: [u8; DIGEST_LEN], hasher_count: usize, hasher_range_in_bits: u32) -> impl Iterator<Item = usize> {
+    // Every hasher's slice has to fit in the hash, which [BloomFilter::build] makes sure of
+    assert!(
+        hasher_count as u32 * hasher_range_in_bits <= FULL_HASH_BITS,
+        "{} hashers of {} bits each need more than a {} bit hash",
+        hasher_count, hasher_range_in_bits, FULL_HASH_BITS
+    );
+
+    (0..hasher_count).map(move |hasher_index| {
+        // Each hasher uses the bits of the full hash after the ones the hasher before it used
+        let first_bit = hasher_index as u32 * hasher_range_in_bits;
+        // The position of the 1 for this hasher
+        let mut hasher_value: usize = 0;
+        
+        // This moves along the full hash, keeping track of the bit we're working on
+        for full_hash_ptr in first_bit..first_bit + hasher_range_in_bits {
+            // The SHA512 hashes are grouped into bytes, so find the byte and bit
+            // within that byte that we're considering.
+            let byte_index: usize = (full_hash_ptr / 8).try_into().unwrap();
+            let bit_in_byte = full_hash_ptr % 8;
+
+            // Check the bit under consideration.
+            let bit_mask: u8 = 2_u8.pow(bit_in_byte);
+            let bit: bool = full_hash[full_hash.len() - byte_index - 1] & bit_mask != 0;
+
+            // Add the bit to the hasher's value.
+            hasher_value = (hasher_value << 1) + (bit as usize);
+        }
+
+        hasher_value
+    })
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = String::new();
+
+        for b in &self.bits {
+            let v = if b {
+                1
+            } else {
+                0
+            };
+
+            s.push_str(&format!("{} ", v));
+        }
+
+        write!(f, "{}", s)
+    }
+}
+
+// See [BloomFilter::builder]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BloomFilterBuilder {
+    hasher_range_in_bits: u32,
+    hasher_count: usize,
+    coerce: bool,
+}
+
+impl BloomFilterBuilder {
+    // Whether parameters that need more hash bits than there are get rounded to ones that fit
+    // (true) or are an error, as they are for [BloomFilter::build] (false, the default). The
+    // range is kept, since it's what decides how much memory the filter takes, and the hasher
+    // count is lowered until the hashers fit. A range too big for a filter to be addressed at
+    // all is lowered too.
+    pub fn coerce(mut self, coerce: bool) -> BloomFilterBuilder {
+        self.coerce = coerce;
+        self
+    }
+
+    // Builds the filter, along with what was changed to make it fit when the parameters were
+    // coerced
+    pub fn build(self) -> Result<(BloomFilter, Option<Coercion>), BloomError> {
+        let required_bits = self.hasher_range_in_bits.saturating_mul(self.hasher_count.try_into().unwrap_or(u32::MAX));
+
+        if required_bits <= FULL_HASH_BITS {
+            return Ok((self.build_unchecked(self.hasher_range_in_bits, self.hasher_count), None));
+        }
+
+        if !self.coerce {
+            return Err(BloomError::ExceedsHashBudget { required_bits, available_bits: FULL_HASH_BITS });
+        }
+
+        // 2 ^ the range has to fit in a usize for the filter's bits to be indexed
+        let hasher_range_in_bits = self.hasher_range_in_bits.min(usize::BITS - 1);
+        let hasher_count = self.hasher_count.min((FULL_HASH_BITS / hasher_range_in_bits) as usize);
+        let coercion = Coercion {
+            requested_hasher_range_in_bits: self.hasher_range_in_bits,
+            requested_hasher_count: self.hasher_count,
+            hasher_range_in_bits,
+            hasher_count,
+        };
+
+        Ok((self.build_unchecked(hasher_range_in_bits, hasher_count), Some(coercion)))
+    }
+
+    fn build_unchecked(&self, hasher_range_in_bits: u32, hasher_count: usize) -> BloomFilter {
+        BloomFilter::build(hasher_range_in_bits, hasher_count)
+            .expect("parameters within the hash budget should always build")
+    }
+}
+
+// What [BloomFilterBuilder::build] changed to make coerced parameters fit
+#[derive(PartialEq, Debug)]
+pub struct Coercion {
+    pub requested_hasher_range_in_bits: u32,
+    pub requested_hasher_count: usize,
+    pub hasher_range_in_bits: u32,
+    pub hasher_count: usize,
+}
+
+impl fmt::Display for Coercion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} hashers of {} bits each need more than the {} hash bits available, so the filter was built with {} hashers of {} bits each instead",
+            self.requested_hasher_count, self.requested_hasher_range_in_bits, FULL_HASH_BITS,
+            self.hasher_count, self.hasher_range_in_bits
+        )
+    }
+}
+
+// See [BloomFilter::stats]
+#[derive(Serialize, PartialEq, Debug)]
+pub struct FilterStats {
+    pub bit_len: usize,
+    pub set_bits: usize,
+    // set_bits / bit_len
+    pub fill_ratio: f64,
+    pub hasher_count: usize,
+    // Roughly how many distinct items have been added. This is infinite once every bit is set.
+    pub estimated_len: f64,
+    // The chance that an item that wasn't added is reported as [BloomCheckResult::Maybe]
+    pub false_positive_rate: f64,
+    // bit_len / estimated_len: how many bits each item is getting. This is infinite while the
+    // filter is empty.
+    pub bits_per_element: f64,
+    // The theoretical minimum bits for [estimated_len] items at [false_positive_rate]
+    // (-n ln(p) / ln(2)^2) over [bit_len]. It's at most 1, which a filter reaches when it's half
+    // full; far below 1 means the filter is much bigger than it needs to be. It's 0 while the
+    // filter is empty or once every bit is set.
+    pub memory_efficiency: f64,
+}
+
+// See [BloomFilter::collision_report]
+#[derive(Serialize, PartialEq, Debug)]
+pub struct CollisionReport {
+    // How many distinct items were checked
+    pub items: usize,
+    // Pairs of items that set exactly the same bits
+    pub fully_overlapping_pairs: usize,
+    // Items that set exactly the same bits as at least one other item
+    pub indistinguishable_items: usize,
+    // Bits that more than one item sets
+    pub shared_positions: usize,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum BloomCheckResult {
+    No,
+    Maybe
+}
+
+#[derive(PartialEq, Debug)]
+pub enum BloomError {
+    // Serialized bytes couldn't be turned back into a filter
+    Malformed(&'static str),
+    // A bit array didn't have the same length as the filter's
+    LengthMismatch { expected: usize, actual: usize },
+    // The parameters passed to [BloomFilter::tuned] (or the target passed to
+    // [BloomFilter::remaining_capacity]) can't describe a filter
+    InvalidTuning(&'static str),
+    // Two filters that were built with different parameters can't be combined
+    ParameterMismatch,
+    // The filter's hashers need more hash bits than a single SHA512 hash provides
+    ExceedsHashBudget { required_bits: u32, available_bits: u32 },
+    // [BloomFilter::tuned] worked out hashers that need more hash bits than a single SHA512 hash provides
+    TuningExceedsHashBudget { target_fpr: f64, required_bits: u32, available_bits: u32 },
+    // The operation (named here) needs every item that was added, which the filter doesn't keep
+    OperationRequiresSourceItems(&'static str),
+    // [BloomFilter::build_capped] was asked for a filter bigger than its cap
+    ExceedsSizeCap { requested_bytes: u128, max_bytes: usize },
+    // [BloomFilter::read_from] couldn't read from its reader
+    Io(io::ErrorKind),
+    // [BloomFilter::with_separator] was given a separator it can't use
+    InvalidSeparator(&'static str),
+    // The filter would have more positions than a usize can index
+    ExceedsAddressableRange { hasher_range_in_bits: u32 },
+}
+
+impl fmt::Display for BloomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BloomError::Malformed(reason) => write!(f, "Malformed bloom filter bytes: {}", reason),
+            BloomError::LengthMismatch { expected, actual } =>
+                write!(f, "Expected {} bits but got {}", expected, actual),
+            BloomError::ParameterMismatch =>
+                write!(f, "The bloom filters were built with different parameters"),
+            BloomError::InvalidTuning(reason) => write!(f, "Can't tune a bloom filter: {}", reason),
+            BloomError::ExceedsHashBudget { required_bits, available_bits } => write!(
+                f,
+                "The filter needs {} hash bits but only {} are available. Use fewer hashers or a smaller \
+                 hasher range, so that the two multiplied together come to at most {}",
+                required_bits, available_bits, available_bits
+            ),
+            BloomError::TuningExceedsHashBudget { target_fpr, required_bits, available_bits } => write!(
+                f,
+                "A filter tuned for a false positive rate of {} needs {} hash bits but only {} are available. \
+                 Raise the target false positive rate (each halving of it costs about one more hasher) or \
+                 lower the number of expected items",
+                target_fpr, required_bits, available_bits
+            ),
+            BloomError::OperationRequiresSourceItems(operation) => write!(
+                f,
+                "Can't {} a bloom filter that already has items in it: a filter only keeps the bits its items \
+                 set, not the items, so it can't work out where they'd go. Build a new filter and add the \
+                 original items to it again (e.g. with add_lines)",
+                operation
+            ),
+            BloomError::ExceedsSizeCap { requested_bytes, max_bytes } => write!(
+                f,
+                "The filter would need {} bytes, but is capped at {} bytes",
+                requested_bytes, max_bytes
+            ),
+            BloomError::Io(kind) => write!(f, "Couldn't read the bloom filter: {}", kind),
+            BloomError::InvalidSeparator(reason) => write!(f, "Invalid composite key separator: {}", reason),
+            BloomError::ExceedsAddressableRange { hasher_range_in_bits } => write!(
+                f,
+                "A hasher range of {} bits is too large to index; it can be at most {}",
+                hasher_range_in_bits, usize::BITS - 1
+            ),
+        }
+    }
+}
+
+impl Error for BloomError {}
+
+#[cfg(test)]
+// The oldest tests match on build's result the long way round, and are kept as they were written
+#[allow(clippy::redundant_pattern_matching)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_size_and_hasher_count() {
+        if let Ok(_) = BloomFilter::build(200, 7) {
+            panic!("Should have rejected invalid input");
+        }
+    }
+
+    #[test]
+    fn accepts_valid_size_and_hasher_count() {
+        if let Err(_) = BloomFilter::build(4, 6) { 
+            panic!("Should have accepted valid input");
+        }
+    }
+
+    #[test]
+    fn build_capped_accepts_filters_within_the_cap() {
+        let bf = BloomFilter::build_capped(13, 3, 1024).expect("should have built a filter of exactly the cap");
+
+        assert_eq!(bf.bit_len(), 8192);
+    }
+
+    #[test]
+    fn build_capped_rejects_filters_over_the_cap() {
+        assert_eq!(
+            BloomFilter::build_capped(14, 3, 1024).err(),
+            Some(BloomError::ExceedsSizeCap { requested_bytes: 2048, max_bytes: 1024 })
+        );
+        // Far too big to ever allocate, let alone fit under the cap
+        assert_eq!(
+            BloomFilter::build_capped(200, 1, usize::MAX).err(),
+            Some(BloomError::ExceedsSizeCap { requested_bytes: u128::MAX, max_bytes: usize::MAX })
+        );
+        assert_eq!(
+            BloomFilter::build_capped(14, 3, 1024).unwrap_err().to_string(),
+            "The filter would need 2048 bytes, but is capped at 1024 bytes"
+        );
+    }
+
+    #[test]
+    fn the_builder_is_strict_by_default() {
+        assert_eq!(
+            BloomFilter::builder(10, 60).build().err(),
+            Some(BloomError::ExceedsHashBudget { required_bits: 600, available_bits: FULL_HASH_BITS })
+        );
+
+        let (bf, coercion) = BloomFilter::builder(10, 51).build().expect("should have built a filter within the budget");
+        assert_eq!((bf.bit_len(), bf.hasher_count()), (1024, 51));
+        assert_eq!(coercion, None);
+    }
+
+    #[test]
+    fn coercing_lowers_the_hasher_count_until_it_fits() {
+        let (mut bf, coercion) = BloomFilter::builder(10, 60).coerce(true).build().expect("should have coerced the parameters");
+
+        assert_eq!((bf.bit_len(), bf.hasher_count()), (1024, 51));
+        assert_eq!(
+            coercion,
+            Some(Coercion { requested_hasher_range_in_bits: 10, requested_hasher_count: 60, hasher_range_in_bits: 10, hasher_count: 51 })
+        );
+        assert_eq!(
+            coercion.unwrap().to_string(),
+            "60 hashers of 10 bits each need more than the 512 hash bits available, so the filter was built with 51 hashers of 10 bits each instead"
+        );
+
+        bf.add(&"tunnels");
+        assert_eq!(bf.is_present(&"tunnels"), BloomCheckResult::Maybe);
+    }
+
+    #[test]
+    fn coercing_leaves_parameters_that_fit_alone() {
+        let (bf, coercion) = BloomFilter::builder(4, 6).coerce(true).build().unwrap();
+
+        assert_eq!((bf.bit_len(), bf.hasher_count()), (16, 6));
+        assert_eq!(coercion, None);
+    }
+
+    #[test]
+    fn build_capped_still_checks_the_hash_budget() {
+        assert_eq!(
+            BloomFilter::build_capped(10, 60, 1024).err(),
+            Some(BloomError::ExceedsHashBudget { required_bits: 600, available_bits: FULL_HASH_BITS })
+        );
+        // A count that would wrap around to 0 as a u32
+        assert_eq!(
+            BloomFilter::build_capped(10, u32::MAX as usize + 1, 1024).err(),
+            Some(BloomError::ExceedsHashBudget { required_bits: u32::MAX, available_bits: FULL_HASH_BITS })
+        );
+        assert!(BloomFilter::build(10, u32::MAX as usize + 1).is_err());
+        assert_eq!(
+            BloomFilter::build_capped(10, 60, 1024).unwrap_err().to_string(),
+            "The filter needs 600 hash bits but only 512 are available. Use fewer hashers or a smaller hasher \
+             range, so that the two multiplied together come to at most 512"
+        );
+    }
+
+    #[test]
+    fn build_refuses_ranges_too_big_to_index() {
+        assert!(BloomFilter::build(usize::BITS, 1).is_err());
+        assert!(matches!(BloomFilter::build_capped(usize::BITS, 1, usize::MAX), Err(BloomError::ExceedsSizeCap { .. })));
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let mut bf = BloomFilter::build(4, 2)
+            .expect("should have built a bloom filte");
+
+        bf.add(&String::from("foo"));
+        bf.add(&String::from("bar"));
+        bf.add(&String::from("baz"));
+        bf.add(&String::from("Green eggs and ham"));
+
+        dbg!("{:?}", &bf);
+
+        assert_eq!(bf.is_present(&String::from("foo")), BloomCheckResult::Maybe);
+        assert_eq!(bf.is_present(&String::from("bar")), BloomCheckResult::Maybe);
+        assert_eq!(bf.is_present(&String::from("baz")), BloomCheckResult::Maybe);
+        assert_eq!(bf.is_present(&String::from("Green eggs and ham")), BloomCheckResult::Maybe);
+
+        assert_eq!(bf.is_present(&String::from("not present")), BloomCheckResult::No);
+        assert_eq!(bf.is_present(&String::from("nor I")), BloomCheckResult::No);
+        assert_eq!(bf.is_present(&String::from("Green eggs and jam")), BloomCheckResult::No);
+    }
+
+    fn filter_with(hasher_range_in_bits: u32, items: &[&str]) -> BloomFilter {
+        let mut bf = BloomFilter::build(hasher_range_in_bits, 3)
+            .expect("should have built a bloom filter");
+
+        for item in items {
+            bf.add(item);
+        }
+
+        bf
+    }
+
+    fn assert_round_trips(bf: &BloomFilter, bytes: &[u8]) {
+        let restored = BloomFilter::from_compact_bytes(bytes)
+            .expect("should have deserialized the filter");
+
+        assert_eq!(restored.bits, bf.bits);
+        assert_eq!(restored.hasher_count, bf.hasher_count);
+        assert_eq!(restored.hasher_range_in_bits, bf.hasher_range_in_bits);
+    }
+
+    #[test]
+    fn compact_bytes_round_trip() {
+        let bf = filter_with(6, &["foo", "bar", "baz"]);
+
+        assert_round_trips(&bf, &bf.to_compact_bytes());
+        assert_round_trips(&bf, &bf.to_trimmed_compact_bytes());
+    }
+
+    #[test]
+    fn trimmed_compact_bytes_round_trip_when_sparse() {
+        let bf = filter_with(12, &["foo"]);
+        let trimmed = bf.to_trimmed_compact_bytes();
+
+        assert!(trimmed.len() < bf.to_compact_bytes().len());
+        assert_round_trips(&bf, &trimmed);
+    }
+
+    #[test]
+    fn trimmed_compact_bytes_round_trip_when_dense() {
+        let mut bf = filter_with(4, &[]);
+        bf.bits.set_all();
+
+        assert_eq!(bf.to_trimmed_compact_bytes().len(), bf.to_compact_bytes().len());
+        assert_round_trips(&bf, &bf.to_trimmed_compact_bytes());
+    }
+
+    #[test]
+    fn trimmed_compact_bytes_round_trip_when_empty() {
+        let bf = filter_with(10, &[]);
+        let trimmed = bf.to_trimmed_compact_bytes();
+
+        assert_eq!(trimmed.len(), COMPACT_HEADER_LEN);
+        assert_round_trips(&bf, &trimmed);
+    }
+
+    #[test]
+    fn compact_bytes_round_trip_when_shorter_than_a_byte() {
+        let bf = filter_with(2, &["foo"]);
+
+        assert_round_trips(&bf, &bf.to_compact_bytes());
+        assert_round_trips(&bf, &bf.to_trimmed_compact_bytes());
+    }
+
+    #[test]
+    fn rejects_malformed_compact_bytes() {
+        let bf = filter_with(6, &["foo"]);
+        let bytes = bf.to_compact_bytes();
+
+        assert!(BloomFilter::from_compact_bytes(&bytes[..5]).is_err());
+        assert!(BloomFilter::from_compact_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_compact_headers_asking_for_more_bits_than_they_came_with() {
+        // A header for 2^40 bits, with none of them after it. Building the filter first would
+        // try to allocate all of them.
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&40_u32.to_le_bytes());
+        bytes.extend_from_slice(&1_u32.to_le_bytes());
+        bytes.extend_from_slice(&(1_u64 << 40).to_le_bytes());
+
+        assert_eq!(
+            BloomFilter::from_compact_bytes(&bytes).err(),
+            Some(BloomError::Malformed("wrong number of bytes for the bit length"))
+        );
+    }
+
+    #[test]
+    fn config_tokens_round_trip_the_parameters_but_not_the_bits() {
+        let mut bf = BloomFilter::build(10, 3).unwrap().with_separator(b"::").unwrap();
+        bf.add(&"tunnels");
+
+        let token = bf.config_token();
+        let rebuilt = BloomFilter::from_config_token(&token).expect("should have read the token back");
+
+        assert_eq!(token, "AQoDAAAAOjo");
+        assert_eq!((rebuilt.bit_len(), rebuilt.hasher_count(), rebuilt.separator()), (1024, 3, &b"::"[..]));
+        assert_eq!(rebuilt.set_bits_count(), 0);
+        assert_eq!(rebuilt.to_compact_bytes(), BloomFilter::build(10, 3).unwrap().to_compact_bytes());
+    }
+
+    #[test]
+    fn default_config_tokens_are_short() {
+        assert_eq!(BloomFilter::build(10, 3).unwrap().config_token(), "AQoDAAAAHw");
+    }
+
+    #[test]
+    fn rejects_malformed_config_tokens() {
+        assert_eq!(BloomFilter::from_config_token("AQoDAAAAHw!").err(), Some(BloomError::Malformed("config tokens are URL-safe base64")));
+        assert_eq!(BloomFilter::from_config_token("AQoD").err(), Some(BloomError::Malformed("too short to be a config token")));
+        assert_eq!(BloomFilter::from_config_token("AgoDAAAAHw").err(), Some(BloomError::Malformed("unknown config token version")));
+        // 200 hashers of 10 bits
+        assert!(BloomFilter::from_config_token("AQrIAAAAHw").is_err());
+        // u32::MAX hashers of 10 bits, which overflows a u32 of hash bits
+        assert_eq!(
+            BloomFilter::from_config_token("AQr_____Hw").err(),
+            Some(BloomError::Malformed("the hashers need more hash bits than there are"))
+        );
+        // A 64 bit range
+        assert_eq!(
+            BloomFilter::from_config_token("AUABAAAAHw").err(),
+            Some(BloomError::Malformed("the hasher range is too large to fit a filter in memory"))
+        );
+        // No separator
+        assert_eq!(BloomFilter::from_config_token("AQoDAAAA").err(), Some(BloomError::InvalidSeparator("it can't be empty")));
+    }
+
+    #[test]
+    fn base64_round_trips_every_length() {
+        let bytes: Vec<u8> = (0..=255).collect();
+
+        for len in 0..8 {
+            assert_eq!(base64_decode(&base64_encode(&bytes[250 - len..250])), Some(bytes[250 - len..250].to_vec()));
+        }
+
+        assert_eq!(base64_encode(b"Many hands"), "TWFueSBoYW5kcw");
+    }
+
+    #[test]
+    fn legacy_bytes_convert_to_an_equivalent_filter() {
+        let mut fixed = FixedBloomFilter::<64>::build(4).expect("should have built the filter");
+        for i in 0..50 {
+            fixed.add(&format!("item {}", i));
+        }
+
+        let bf = BloomFilter::from(&fixed);
+        let from_bytes = BloomFilter::from_legacy_bytes(fixed.as_bytes(), fixed.hasher_count(), 64)
+            .expect("should have converted the bytes");
+
+        assert_eq!(bf.bit_len(), FixedBloomFilter::<64>::BITS);
+        assert_eq!(bf.to_compact_bytes(), from_bytes.to_compact_bytes());
+        assert_eq!(bf.set_bits_count(), fixed.as_bytes().iter().map(|byte| byte.count_ones() as usize).sum::<usize>());
+        for i in 0..500 {
+            let item = format!("item {}", i);
+            assert_eq!(bf.is_present(&item), fixed.is_present(&item));
+        }
+    }
+
+    #[test]
+    fn rejects_legacy_bytes_that_dont_match_their_counts() {
+        assert!(matches!(
+            BloomFilter::from_legacy_bytes(&[0; 8], 3, 16),
+            Err(BloomError::LengthMismatch { expected: 128, actual: 64 })
+        ));
+        assert!(matches!(BloomFilter::from_legacy_bytes(&[0; 12], 3, 12), Err(BloomError::Malformed(_))));
+        assert!(matches!(BloomFilter::from_legacy_bytes(&[0; 8], 100, 8), Err(BloomError::Malformed(_))));
+    }
+
+    #[test]
+    fn write_to_matches_compact_bytes() {
+        for hasher_range_in_bits in [2, 6, 12, 20] {
+            let bf = filter_with(hasher_range_in_bits, &["foo", "bar", "baz"]);
+            let mut written: Vec<u8> = Vec::new();
+
+            bf.write_to(&mut written).expect("should have written the filter");
+
+            assert_eq!(written, bf.to_compact_bytes());
+        }
+    }
+
+    #[test]
+    fn read_from_round_trips_both_forms() {
+        for hasher_range_in_bits in [2, 6, 20] {
+            let bf = filter_with(hasher_range_in_bits, &["foo", "bar", "baz"]);
+
+            for bytes in [bf.to_compact_bytes(), bf.to_trimmed_compact_bytes()] {
+                let restored = BloomFilter::read_from(bytes.as_slice()).expect("should have read the filter");
+
+                assert_eq!(restored.bits, bf.bits);
+                assert_eq!(restored.hasher_count, bf.hasher_count);
+                assert_eq!(restored.set_bits_count(), bf.set_bits_count());
+            }
+        }
+    }
+
+    #[test]
+    fn read_from_leaves_what_follows_an_untrimmed_filter() {
+        let bf = filter_with(6, &["foo"]);
+        let mut bytes = bf.to_compact_bytes();
+        bytes.extend_from_slice(b"next");
+        let mut reader = bytes.as_slice();
+
+        BloomFilter::read_from(&mut reader).expect("should have read the filter");
+
+        assert_eq!(reader, b"next");
+    }
+
+    #[test]
+    fn read_from_rejects_malformed_input() {
+        let bf = filter_with(6, &["foo"]);
+        let bytes = bf.to_compact_bytes();
+        let mut too_long = bf.to_trimmed_compact_bytes();
+        too_long.resize(bytes.len() + 1, 0);
+
+        assert_eq!(BloomFilter::read_from(&bytes[..5]).err(), Some(BloomError::Malformed("too short to contain a header")));
+        assert_eq!(
+            BloomFilter::read_from(&bytes[..bytes.len() - 1]).err(),
+            Some(BloomError::Malformed("wrong number of bytes for the bit length"))
+        );
+        assert_eq!(
+            BloomFilter::read_from(too_long.as_slice()).err(),
+            Some(BloomError::Malformed("wrong number of bytes for the bit length"))
+        );
+
+        // Headers with parameters no filter could have
+        let with_parameters = |hasher_range_in_bits: u32, hasher_count: u32| {
+            let mut header = bytes[..COMPACT_HEADER_LEN].to_vec();
+            header[1..5].copy_from_slice(&hasher_range_in_bits.to_le_bytes());
+            header[5..9].copy_from_slice(&hasher_count.to_le_bytes());
+            header
+        };
+
+        assert_eq!(
+            BloomFilter::read_from(with_parameters(64, 1).as_slice()).err(),
+            Some(BloomError::Malformed("the hasher range is too large to fit a filter in memory"))
+        );
+        assert_eq!(
+            BloomFilter::read_from(with_parameters(10, 0x1999999A).as_slice()).err(),
+            Some(BloomError::Malformed("the hashers need more hash bits than there are"))
+        );
+        assert_eq!(
+            BloomFilter::from_compact_bytes(&with_parameters(10, 0x1999999A)).err(),
+            Some(BloomError::Malformed("the hashers need more hash bits than there are"))
+        );
+    }
+
+    #[test]
+    fn or_mask_seeds_the_filter() {
+        let mut bf = filter_with(4, &[]);
+        let mut mask = BitVec::from_elem(bf.bit_len(), false);
+
+        for i in bf.hash(&"foo") {
+            mask.set(i, true);
+        }
+
+        bf.or_mask(&mask).expect("should have accepted a mask of the right length");
+
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::Maybe);
+    }
+
+    #[test]
+    fn or_mask_keeps_existing_bits() {
+        let mut bf = filter_with(4, &["foo"]);
+
+        bf.or_mask(&BitVec::from_elem(bf.bit_len(), false))
+            .expect("should have accepted a mask of the right length");
+
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::Maybe);
+    }
+
+    #[test]
+    fn or_mask_rejects_wrong_length() {
+        let mut bf = filter_with(4, &[]);
+
+        assert_eq!(
+            bf.or_mask(&BitVec::from_elem(8, true)),
+            Err(BloomError::LengthMismatch { expected: 16, actual: 8 })
+        );
+    }
+
+    #[test]
+    fn count_present_counts_maybes() {
+        let bf = filter_with(10, &["foo", "bar", "baz"]);
+
+        assert_eq!(bf.count_present(["foo", "bar", "not present", "nor I"]), 2);
+        assert_eq!(bf.count_present(Vec::<String>::new()), 0);
+    }
+
+    #[test]
+    fn contains_agrees_with_is_present() {
+        let bf = filter_with(10, &["foo", "bar", "baz"]);
+
+        for item in ["foo", "bar", "baz", "not present", "nor I"] {
+            assert_eq!(bf.contains(&item), bf.is_present(&item) == BloomCheckResult::Maybe);
+        }
+
+        assert!(bf.contains(&"foo"));
+        assert!(!bf.contains(&"not present"));
+    }
+
+    #[test]
+    fn contains_prehashed_all_agrees_with_is_present() {
+        let bf = filter_with(10, &["foo", "bar", "baz"]);
+        let items = ["foo", "bar", "baz", "not present", "nor I"];
+        let hashes = items.iter().map(|item| Sha512::digest(item).into());
+
+        assert_eq!(
+            bf.contains_prehashed_all(hashes),
+            items.iter().map(|item| bf.is_present(item)).collect::<Vec<BloomCheckResult>>()
+        );
+        assert!(bf.contains_prehashed_all(Vec::new()).is_empty());
+    }
+
+    // A benchmark rather than a test, comparing lookups against the positions being collected into
+    // a Vec first, as they used to be. Run it with
+    // `cargo test --release lookup_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn lookup_benchmark() {
+        const QUERIES: usize = 1_000_000;
+        let mut bf = BloomFilter::build(20, 16).expect("should have built a bloom filter");
+
+        for i in 0..10_000 {
+            bf.add(&format!("item {}", i));
+        }
+
+        let keys: Vec<String> = (0..QUERIES).map(|i| format!("item {}", i)).collect();
+
+        let start = Instant::now();
+        let allocating = keys.iter()
+            .filter(|key| bf.hash(key).into_iter().all(|i| bf.bits[i]))
+            .count();
+        let allocating_time = start.elapsed();
+
+        let start = Instant::now();
+        let found = keys.iter().filter(|key| bf.contains(key)).count();
+        let contains_time = start.elapsed();
+
+        assert_eq!(found, allocating);
+        println!(
+            "{} lookups with 16 hashers: {:?} collecting positions into a Vec, {:?} with contains",
+            QUERIES, allocating_time, contains_time
+        );
+    }
+
+    #[test]
+    fn add_lines_adds_each_line_without_its_newline() {
+        let mut bf = filter_with(10, &[]);
+
+        let count = bf.add_lines("foo\nbar\r\nbaz".as_bytes())
+            .expect("should have read every line");
+
+        assert_eq!(count, 3);
+        assert_eq!(bf.count_present(["foo", "bar", "baz"]), 3);
+        assert_eq!(bf.is_present(&"foo\n"), BloomCheckResult::No);
+        assert_eq!(bf.is_present(&"bar\r"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn add_lines_on_empty_input_adds_nothing() {
+        let mut bf = filter_with(10, &[]);
+
+        assert_eq!(bf.add_lines(io::empty()).expect("should have read nothing"), 0);
+        assert_eq!(bf.to_trimmed_compact_bytes().len(), COMPACT_HEADER_LEN);
+    }
+
+    #[test]
+    fn contains_verified_skips_verifier_on_no() {
+        let bf = filter_with(10, &["foo"]);
+
+        assert!(!bf.contains_verified(&"not present", |_| panic!("Shouldn't have verified a No")));
+    }
+
+    #[test]
+    fn contains_verified_defers_to_verifier_on_maybe() {
+        let bf = filter_with(10, &["foo"]);
+
+        assert!(bf.contains_verified(&"foo", |_| true));
+        assert!(!bf.contains_verified(&"foo", |_| false));
+    }
+
+    #[test]
+    fn tuned_picks_power_of_two_length_and_optimal_hashers() {
+        // m = -1000 ln(0.01) / ln(2)^2 ~= 9585, rounded up to 2^14, and k = 16384 / 1000 * ln(2)
+        let bf = BloomFilter::tuned(1000, 0.01).expect("should have tuned a filter");
+
+        assert_eq!(bf.bit_len(), 16384);
+        assert_eq!(bf.hasher_count(), 11);
+    }
+
+    #[test]
+    fn tuned_filter_meets_its_target_rate() {
+        let mut bf = BloomFilter::tuned(1000, 0.01).expect("should have tuned a filter");
+
+        for i in 0..1000 {
+            bf.add(&format!("added {}", i));
+        }
+
+        let false_positives = bf.count_present((0..10000).map(|i| format!("not added {}", i)));
+
+        assert!(false_positives < 100, "Expected under 1% false positives but got {}", false_positives);
+    }
+
+    #[test]
+    fn tuned_rejects_invalid_parameters() {
+        for (expected_items, target_fpr) in [(0, 0.01), (1000, 0.0), (1000, 1.0), (1000, f64::NAN)] {
+            match BloomFilter::tuned(expected_items, target_fpr) {
+                Err(BloomError::InvalidTuning(_)) => {}
+                _ => panic!("Should have rejected {} items at {}", expected_items, target_fpr),
+            }
+        }
+    }
+
+    #[test]
+    fn tuned_rejects_filters_beyond_the_hash_budget() {
+        match BloomFilter::tuned(1_000_000, 1e-12) {
+            Err(e @ BloomError::TuningExceedsHashBudget { required_bits, available_bits, .. }) => {
+                assert!(required_bits > available_bits);
+                assert_eq!(available_bits, FULL_HASH_BITS);
+                assert!(e.to_string().contains("Raise the target false positive rate"));
+            }
+            _ => panic!("Should have run out of hash bits"),
+        }
+    }
+
+    #[test]
+    fn build_from_deduped_sizes_for_distinct_items() {
+        let items = (0..10000).map(|i| format!("item {}", i % 1000));
+        let bf = BloomFilter::build_from_deduped(items, 0.01).expect("should have built a filter");
+        let tuned = BloomFilter::tuned(1000, 0.01).expect("should have tuned a filter");
+
+        assert_eq!(bf.bit_len(), tuned.bit_len());
+        assert_eq!(bf.hasher_count(), tuned.hasher_count());
+        assert_eq!(bf.count_present((0..1000).map(|i| format!("item {}", i))), 1000);
+    }
+
+    #[test]
+    fn build_from_deduped_matches_adding_each_distinct_item() {
+        let bf = BloomFilter::build_from_deduped(["foo", "bar", "foo", "foo", "baz"], 0.01)
+            .expect("should have built a filter");
+        let mut expected = BloomFilter::tuned(3, 0.01).expect("should have tuned a filter");
+
+        for item in ["foo", "bar", "baz"] {
+            expected.add(&item);
+        }
+
+        assert_eq!(bf.to_compact_bytes(), expected.to_compact_bytes());
+    }
+
+    #[test]
+    fn build_from_deduped_rejects_empty_input() {
+        match BloomFilter::build_from_deduped(Vec::<String>::new(), 0.01) {
+            Err(BloomError::InvalidTuning(_)) => {}
+            _ => panic!("Should have rejected an empty list"),
+        }
+    }
+
+    #[test]
+    fn reseeding_needs_the_source_items() {
+        let bf = filter_with(4, &["foo"]);
+
+        assert!(BloomFilter::reseed_requires_items());
+        assert_eq!(bf.try_reseed(), Err(BloomError::OperationRequiresSourceItems("re-seed")));
+        assert!(bf.try_reseed().unwrap_err().to_string().contains("add the original items"));
+    }
+
+    #[test]
+    fn stats_of_an_empty_filter() {
+        let stats = filter_with(10, &[]).stats();
+
+        assert_eq!(stats, FilterStats {
+            bit_len: 1024,
+            set_bits: 0,
+            fill_ratio: 0.0,
+            hasher_count: 3,
+            estimated_len: 0.0,
+            false_positive_rate: 0.0,
+            bits_per_element: f64::INFINITY,
+            memory_efficiency: 0.0,
+        });
+    }
+
+    #[test]
+    fn stats_estimate_the_number_of_items() {
+        let items: Vec<String> = (0..100).map(|i| format!("item {}", i)).collect();
+        let mut bf = BloomFilter::build(12, 3).expect("should have built a bloom filter");
+
+        for item in &items {
+            bf.add(item);
+        }
+
+        let stats = bf.stats();
+
+        assert_eq!(stats.bit_len, 4096);
+        assert!(stats.set_bits <= 300);
+        assert_eq!(stats.fill_ratio, stats.set_bits as f64 / 4096.0);
+        assert!((90.0..110.0).contains(&stats.estimated_len), "Estimated {} items", stats.estimated_len);
+        assert!((stats.false_positive_rate - stats.fill_ratio.powi(3)).abs() < 1e-12);
+        assert_eq!(stats.bits_per_element, 4096.0 / stats.estimated_len);
+    }
+
+    #[test]
+    fn memory_efficiency_is_high_for_well_sized_filters() {
+        let mut bf = BloomFilter::tuned(1000, 0.01).expect("should have built a bloom filter");
+
+        for i in 0..bf.remaining_capacity(0.01).unwrap() {
+            bf.add(&format!("item {}", i));
+        }
+
+        let memory_efficiency = bf.stats().memory_efficiency;
+        assert!((0.9..=1.0).contains(&memory_efficiency), "Efficiency was {}", memory_efficiency);
+    }
+
+    #[test]
+    fn memory_efficiency_is_low_for_oversized_filters() {
+        let mut bf = BloomFilter::build(20, 3).expect("should have built a bloom filter");
+
+        for i in 0..100 {
+            bf.add(&format!("item {}", i));
+        }
+
+        let memory_efficiency = bf.stats().memory_efficiency;
+        assert!(memory_efficiency > 0.0 && memory_efficiency < 0.01, "Efficiency was {}", memory_efficiency);
+    }
+
+    #[test]
+    fn remaining_capacity_fills_the_filter_up_to_the_target() {
+        let mut bf = BloomFilter::tuned(1000, 0.01).expect("should have built a bloom filter");
+        let capacity = bf.remaining_capacity(0.01).unwrap();
+
+        // Tuning rounds the filter up, so it has at least the room it was tuned for
+        assert!(capacity >= 1000, "Only room for {} items", capacity);
+
+        for i in 0..capacity {
+            bf.add(&format!("item {}", i));
+        }
+
+        let false_positive_rate = bf.stats().false_positive_rate;
+        assert!((0.008..0.012).contains(&false_positive_rate), "False positive rate was {}", false_positive_rate);
+        assert!(bf.remaining_capacity(0.01).unwrap() < capacity / 20);
+    }
+
+    #[test]
+    fn remaining_capacity_is_zero_once_over_the_target() {
+        let mut bf = BloomFilter::build(8, 3).expect("should have built a bloom filter");
+
+        for i in 0..200 {
+            bf.add(&format!("item {}", i));
+        }
+
+        assert!(bf.stats().false_positive_rate > 0.01);
+        assert_eq!(bf.remaining_capacity(0.01), Ok(0));
+        assert!(bf.remaining_capacity(0.9).unwrap() > 0);
+    }
+
+    #[test]
+    fn remaining_capacity_rejects_rates_outside_zero_and_one() {
+        let bf = filter_with(10, &[]);
+
+        for target_fpr in [0.0, 1.0, f64::NAN] {
+            assert_eq!(
+                bf.remaining_capacity(target_fpr),
+                Err(BloomError::InvalidTuning("target_fpr must be between 0 and 1 (exclusive)"))
+            );
+        }
+    }
+
+    fn scanned_set_bits(bf: &BloomFilter) -> usize {
+        bf.bits.iter().filter(|bit| *bit).count()
+    }
+
+    #[test]
+    fn set_bits_count_tracks_adds() {
+        let mut bf = BloomFilter::build(8, 3).expect("should have built a bloom filter");
+
+        for i in 0..50 {
+            bf.add(&format!("item {}", i));
+            assert_eq!(bf.set_bits_count(), scanned_set_bits(&bf));
+        }
+
+        // Adding the same item again doesn't set any new bits
+        let before = bf.set_bits_count();
+        bf.add(&"item 0");
+        assert_eq!(bf.set_bits_count(), before);
+    }
+
+    #[test]
+    fn set_bits_count_survives_or_mask_and_clear() {
+        let mut bf = filter_with(10, &["foo", "bar"]);
+        let mut mask = BitVec::from_elem(bf.bit_len(), false);
+        mask.set(0, true);
+        mask.set(1023, true);
+
+        bf.or_mask(&mask).expect("should have accepted a mask of the right length");
+        assert_eq!(bf.set_bits_count(), scanned_set_bits(&bf));
+
+        bf.clear();
+        assert_eq!(bf.set_bits_count(), 0);
+        assert_eq!(scanned_set_bits(&bf), 0);
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::No);
+
+        bf.add(&"foo");
+        assert_eq!(bf.set_bits_count(), scanned_set_bits(&bf));
+    }
+
+    #[test]
+    fn set_bits_count_survives_compact_bytes() {
+        let bf = filter_with(10, &["foo", "bar", "baz"]);
+        let restored = BloomFilter::from_compact_bytes(&bf.to_trimmed_compact_bytes())
+            .expect("should have restored the filter");
+
+        assert_eq!(restored.set_bits_count(), bf.set_bits_count());
+        assert_eq!(restored.set_bits_count(), scanned_set_bits(&restored));
+    }
+
+    #[test]
+    fn is_present_partial_never_rejects_added_items() {
+        let bf = filter_with(10, &["foo", "bar", "baz"]);
+
+        for item in ["foo", "bar", "baz"] {
+            for max_hashers in 0..=4 {
+                assert_eq!(bf.is_present_partial(&item, max_hashers), BloomCheckResult::Maybe);
+            }
+        }
+    }
+
+    #[test]
+    fn is_present_partial_checks_at_most_max_hashers() {
+        let mut bf = BloomFilter::build(10, 3).expect("should have built the filter");
+        let positions = bf.hash(&"foo");
+
+        // Only the first position is set, so only a check limited to it can be fooled
+        let mut mask = BitVec::from_elem(bf.bit_len(), false);
+        mask.set(positions[0], true);
+        bf.or_mask(&mask).expect("should have accepted a mask of the right length");
+
+        assert_eq!(bf.is_present_partial(&"foo", 1), BloomCheckResult::Maybe);
+        assert_eq!(bf.is_present_partial(&"foo", 3), BloomCheckResult::No);
+        assert_eq!(bf.is_present(&"foo"), BloomCheckResult::No);
+    }
+
+    #[test]
+    fn collision_report_counts_items_with_the_same_bits() {
+        // 4 bits and one hasher, so the 10 items have to share
+        let bf = BloomFilter::build(2, 1).expect("should have built the filter");
+        let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+
+        let report = bf.collision_report(&items);
+
+        let positions: Vec<Vec<usize>> = items.iter().map(|item| bf.hash(item)).collect();
+        let expected_pairs = (0..items.len())
+            .flat_map(|a| (a + 1..items.len()).map(move |b| (a, b)))
+            .filter(|(a, b)| positions[*a] == positions[*b])
+            .count();
+
+        assert_eq!(report.items, 10);
+        assert_eq!(report.fully_overlapping_pairs, expected_pairs);
+        assert!(report.fully_overlapping_pairs > 0);
+        assert!(report.indistinguishable_items > 0);
+        assert!(report.shared_positions > 0);
+    }
+
+    #[test]
+    fn collision_report_ignores_repeated_items() {
+        let bf = BloomFilter::build(20, 3).expect("should have built the filter");
+
+        let report = bf.collision_report(["foo", "bar", "foo"]);
+
+        assert_eq!(report, CollisionReport {
+            items: 2,
+            fully_overlapping_pairs: 0,
+            indistinguishable_items: 0,
+            shared_positions: 0,
+        });
+    }
+
+    #[test]
+    fn would_set_bits_matches_adding_to_an_empty_filter() {
+        let items: Vec<String> = (0..50).map(|i| format!("item {}", i)).collect();
+        let empty = BloomFilter::build(8, 3).expect("should have built the filter");
+
+        let predicted = empty.would_set_bits(&items);
+        let mut bf = BloomFilter::build(8, 3).expect("should have built the filter");
+        for item in &items {
+            bf.add(item);
+        }
+
+        assert_eq!(predicted, bf.set_bits_count());
+        assert_eq!(empty.set_bits_count(), 0);
+    }
+
+    #[test]
+    fn to_pgm_draws_set_bits_black_in_rows() {
+        let mut bf = BloomFilter::build(3, 1).expect("should have built the filter");
+        let mut mask = BitVec::from_elem(8, false);
+        mask.set(0, true);
+        mask.set(5, true);
+        bf.or_mask(&mask).expect("should have accepted a mask of the right length");
+
+        let pgm = bf.to_pgm(3);
+        let header = b"P5\n3 3\n255\n";
+
+        assert_eq!(&pgm[..header.len()], header);
+        assert_eq!(&pgm[header.len()..], &[0, 255, 255, 255, 255, 0, 255, 255, 128]);
+    }
+
+    #[test]
+    fn benchmark_hash_throughput_reports_a_rate() {
+        let bf = BloomFilter::build(20, 16).expect("should have built a bloom filter");
+
+        let rate = bf.benchmark_hash_throughput(1_000);
+
+        assert!(rate.is_finite() && rate > 0.0);
+        assert_eq!(bf.set_bits_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one sample")]
+    fn benchmark_hash_throughput_rejects_zero_samples() {
+        BloomFilter::build(4, 4).unwrap().benchmark_hash_throughput(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one pixel wide")]
+    fn to_pgm_rejects_zero_width() {
+        filter_with(4, &["foo"]).to_pgm(0);
+    }
+
+    #[test]
+    fn stats_serialize_to_json() {
+        let json = serde_json::to_value(filter_with(10, &["foo"]).stats())
+            .expect("should have serialized the stats");
+
+        assert_eq!(json["bit_len"], 1024);
+        assert_eq!(json["set_bits"], 3);
+        assert_eq!(json["hasher_count"], 3);
+    }
+
+    #[test]
+    fn estimated_union_len_counts_shared_items_once() {
+        let mut a = BloomFilter::build(12, 3).expect("should have built a bloom filter");
+        let mut b = BloomFilter::build(12, 3).expect("should have built a bloom filter");
+
+        for i in 0..100 {
+            a.add(&format!("item {}", i));
+        }
+
+        for i in 50..150 {
+            b.add(&format!("item {}", i));
+        }
+
+        let union_len = a.estimated_union_len(&b).expect("should have estimated the union");
+
+        assert!((140..160).contains(&union_len), "Estimated {} items", union_len);
+        assert_eq!(union_len, b.estimated_union_len(&a).unwrap());
+    }
+
+    #[test]
+    fn estimated_union_len_of_empty_filters_is_zero() {
+        let a = filter_with(10, &[]);
+
+        assert_eq!(a.estimated_union_len(&filter_with(10, &[])), Ok(0));
+    }
+
+    #[test]
+    fn estimated_union_len_rejects_mismatched_filters() {
+        let a = filter_with(10, &["foo"]);
+
+        assert_eq!(a.estimated_union_len(&filter_with(11, &["foo"])), Err(BloomError::ParameterMismatch));
+        assert_eq!(
+            a.estimated_union_len(&BloomFilter::build(10, 4).unwrap()),
+            Err(BloomError::ParameterMismatch)
+        );
+    }
+
+    #[test]
+    fn composite_keys_use_the_same_separator_to_add_and_check() {
+        let mut bf = BloomFilter::build(12, 3).unwrap().with_separator(b"::").unwrap();
+        bf.add_parts(&["alice", "doc-1"]);
+
+        assert_eq!(bf.separator(), b"::");
+        assert!(bf.contains_parts(&["alice", "doc-1"]));
+        assert!(bf.contains(&"alice::doc-1"));
+        assert!(!bf.contains_parts(&["alice", "doc-2"]));
+        assert!(!bf.contains_parts(&["alice"]));
+    }
+
+    #[test]
+    fn parts_containing_the_separator_collide() {
+        let mut bf = BloomFilter::build(12, 3).unwrap().with_separator(b",").unwrap();
+        bf.add_parts(&["a,b", "c"]);
+
+        assert!(bf.contains_parts(&["a", "b,c"]));
+
+        // The default separator keeps them apart
+        let mut bf = BloomFilter::build(12, 3).unwrap();
+        bf.add_parts(&["a,b", "c"]);
+
+        assert!(!bf.contains_parts(&["a", "b,c"]));
+    }
+
+    #[test]
+    fn rejects_empty_separators() {
+        let result = BloomFilter::build(12, 3).unwrap().with_separator(b"");
+
+        assert_eq!(result.err(), Some(BloomError::InvalidSeparator("it can't be empty")));
+    }
+
+    #[test]
+    fn finds_false_positives_deterministically() {
+        // With 16 bits and 3 hashers, 200 items fill most of the filter
+        let items: Vec<String> = (0..200).map(|i| format!("item {}", i)).collect();
+        let mut bf = BloomFilter::build(4, 3).unwrap();
+        for item in &items {
+            bf.add(item);
+        }
+
+        let key = bf.find_false_positive(42, 1000).expect("should have found a false positive");
+
+        assert_eq!(bf.is_present(&key), BloomCheckResult::Maybe);
+        assert!(!items.iter().any(|item| item.as_bytes() == key));
+        assert_eq!(bf.find_false_positive(42, 1000), Some(key));
+    }
+
+    #[test]
+    fn gives_up_on_false_positives_after_max_tries() {
+        let bf = filter_with(10, &["foo"]);
+
+        assert_eq!(bf.find_false_positive(42, 1000), None);
+        assert_eq!(filter_with(10, &[]).find_false_positive(7, 0), None);
+    }
+
+    #[cfg(feature = "debug-tracking")]
+    #[test]
+    fn tracking_finds_no_false_negatives() {
+        let mut bf = filter_with(10, &["foo", "bar"]);
+        bf.add_lines("baz\nqux\n".as_bytes()).unwrap();
+
+        bf.assert_no_false_negatives();
+        assert_eq!(bf.is_present(&"qux"), BloomCheckResult::Maybe);
+    }
+
+    #[cfg(feature = "debug-tracking")]
+    #[test]
+    #[should_panic(expected = "False negative")]
+    fn tracking_catches_false_negatives() {
+        let mut bf = filter_with(10, &["foo"]);
+        // Simulates a bug that loses bits
+        bf.bits.clear();
+
+        bf.assert_no_false_negatives();
+    }
+
+    #[cfg(feature = "debug-tracking")]
+    #[test]
+    #[should_panic(expected = "False negative")]
+    fn tracking_checks_no_answers() {
+        let mut bf = filter_with(10, &["foo"]);
+        bf.bits.clear();
+
+        bf.is_present(&"foo");
+    }
+}
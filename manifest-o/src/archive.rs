@@ -0,0 +1,328 @@
+// Reads documents out of zip archives, which is how some parties publish their manifestos. A
+// .zip input is summarised member by member, as though each were its own input, and
+// `archive.zip!path/in/archive.pdf` picks out a single member. PDFs have their text extracted
+// on the way out, so that they can be summarised like any other text. Every member is read into
+// memory, so each is held to --max-input-bytes like any other input, and --max-archive-bytes
+// caps the total decompressed size so that a zip bomb can't exhaust memory. The sizes in an
+// archive's headers can lie, so both limits are checked against the bytes actually
+// decompressed.
+
+use regex::Regex;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use zip::ZipArchive;
+
+pub const DEFAULT_MAX_ARCHIVE_BYTES: u64 = 200 * 1024 * 1024;
+
+// Separates the archive's path from a member's in `archive.zip!member`
+const MEMBER_SEPARATOR: char = '!';
+const PDF_MAGIC: &[u8] = b"%PDF-";
+// Byte order marks for UTF-16, which is the only text that's expected to have NUL bytes in it
+const UTF_16_BOMS: [&[u8]; 2] = [b"\xff\xfe", b"\xfe\xff"];
+
+#[derive(Clone, Debug)]
+pub struct ArchiveInput {
+    pub path: PathBuf,
+    // From `archive.zip!member`: only this member is read
+    pub member: Option<String>,
+    // Set with --include (which can be repeated): only members matching one of these globs are
+    // read. A glob without a `/` is matched against the member's file name, wherever it is.
+    pub include: Vec<String>,
+    // Set with --max-archive-bytes
+    pub max_total_bytes: u64,
+}
+
+// One member's name and (decompressed) contents
+pub struct Member {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+// What was read from an archive
+pub struct ReadArchive {
+    pub members: Vec<Member>,
+    // The members that were left out, and why
+    pub skipped: Vec<(String, String)>,
+}
+
+impl ArchiveInput {
+    // [input] as an archive, if it's a .zip (or a member of one). Anything else is None.
+    pub fn parse(input: &str) -> Option<ArchiveInput> {
+        let (path, member) = match input.split_once(MEMBER_SEPARATOR) {
+            Some((path, member)) if is_zip(path) && !member.is_empty() => (path, Some(String::from(member))),
+            _ if is_zip(input) => (input, None),
+            _ => return None,
+        };
+
+        Some(ArchiveInput {
+            path: PathBuf::from(path),
+            member,
+            include: Vec::new(),
+            max_total_bytes: DEFAULT_MAX_ARCHIVE_BYTES,
+        })
+    }
+
+    // Reads every member to summarise, in the order they're stored, each no bigger than
+    // [max_member_bytes]. Directories are passed over, and members that are encrypted, too big,
+    // compressed in a way that isn't supported, or are neither text nor a PDF are skipped. It's an error if the
+    // members add up to more than [max_total_bytes], or if a member asked for by name can't be
+    // read.
+    pub fn read(&self, max_member_bytes: u64) -> Result<ReadArchive, String> {
+        let file = File::open(&self.path).map_err(|e| format!("couldn't open the archive: {}", e))?;
+        let mut archive = ZipArchive::new(file).map_err(|e| format!("couldn't read the archive: {}", e))?;
+        let include = self.include.iter().map(|glob| glob_to_regex(glob)).collect::<Vec<Regex>>();
+
+        let mut read = ReadArchive { members: Vec::new(), skipped: Vec::new() };
+        let mut total_bytes: u64 = 0;
+
+        for i in 0..archive.len() {
+            let (name, encrypted) = match archive.by_index_raw(i) {
+                Ok(member) if member.is_dir() => continue,
+                Ok(member) => (String::from(member.name()), member.encrypted()),
+                Err(e) => return Err(format!("couldn't read the archive: {}", e)),
+            };
+
+            let wanted = match &self.member {
+                Some(member) => *member == name,
+                None => include.is_empty() || include.iter().any(|glob| glob.is_match(&name)),
+            };
+
+            if !wanted {
+                continue;
+            }
+
+            if encrypted {
+                read.skipped.push((name, String::from("it's encrypted")));
+                continue;
+            }
+
+            let mut member = match archive.by_index(i) {
+                Ok(member) => member,
+                Err(e) => {
+                    read.skipped.push((name, format!("it can't be decompressed: {}", e)));
+                    continue;
+                }
+            };
+
+            // Never decompress more than either limit allows, whatever the header says
+            let limit = max_member_bytes.min(self.max_total_bytes - total_bytes);
+            let mut bytes = Vec::new();
+
+            if let Err(e) = (&mut member).take(limit + 1).read_to_end(&mut bytes) {
+                read.skipped.push((name, format!("it can't be decompressed: {}", e)));
+                continue;
+            }
+
+            if bytes.len() as u64 > max_member_bytes {
+                read.skipped.push((name, format!(
+                    "it's more than the limit of {} bytes; raise it with --max-input-bytes",
+                    max_member_bytes
+                )));
+                continue;
+            }
+
+            if bytes.len() as u64 > limit {
+                return Err(format!(
+                    "the archive decompresses to more than the limit of {} bytes; raise it with --max-archive-bytes",
+                    self.max_total_bytes
+                ));
+            }
+
+            total_bytes += bytes.len() as u64;
+
+            let bytes = match extract_text(bytes) {
+                Ok(bytes) => bytes,
+                Err(reason) => {
+                    read.skipped.push((name, reason));
+                    continue;
+                }
+            };
+
+            read.members.push(Member { name, bytes });
+        }
+
+        if let Some(member) = &self.member {
+            if let Some((_, reason)) = read.skipped.iter().find(|(name, _)| name == member) {
+                return Err(format!("couldn't read {}: {}", member, reason));
+            }
+
+            if read.members.is_empty() {
+                return Err(format!("there's no {} in the archive", member));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+fn is_zip(path: &str) -> bool {
+    path.to_lowercase().ends_with(".zip")
+}
+
+// [bytes] as text that can be summarised, or why they can't be
+fn extract_text(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if bytes.starts_with(PDF_MAGIC) {
+        return pdf_extract::extract_text_from_mem(&bytes)
+            .map(String::into_bytes)
+            .map_err(|e| format!("couldn't extract the PDF's text: {}", e));
+    }
+
+    if bytes.contains(&0) && !UTF_16_BOMS.iter().any(|bom| bytes.starts_with(bom)) {
+        return Err(String::from("it doesn't look like text"));
+    }
+
+    Ok(bytes)
+}
+
+// `*` and `?` match within a directory, and `**` across them. A glob without a `/` in it
+// matches the file name on its own, in any directory.
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from(if glob.contains('/') { "^" } else { "^(.*/)?" });
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    pattern.push('$');
+
+    Regex::new(&pattern).expect("escaped globs should always be valid regexes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    // A text file and a PDF, as a party might publish them
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/manifestos.zip");
+
+    fn fixture(dir: &std::path::Path) -> ArchiveInput {
+        let path = dir.join("manifestos.zip");
+        std::fs::write(&path, FIXTURE).unwrap();
+
+        ArchiveInput::parse(&path.to_string_lossy()).expect("should have been an archive")
+    }
+
+    // An archive of [members], deflated
+    fn archive(dir: &std::path::Path, members: &[(&str, &[u8])]) -> ArchiveInput {
+        let path = dir.join("crafted.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+
+        for (name, bytes) in members {
+            writer.start_file(*name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(bytes).unwrap();
+        }
+
+        writer.finish().unwrap();
+
+        ArchiveInput::parse(&path.to_string_lossy()).expect("should have been an archive")
+    }
+
+    #[test]
+    fn parses_archive_paths() {
+        let input = ArchiveInput::parse("party.ZIP!docs/manifesto.txt").expect("should have been an archive");
+
+        assert_eq!(input.path, PathBuf::from("party.ZIP"));
+        assert_eq!(input.member.as_deref(), Some("docs/manifesto.txt"));
+        assert_eq!(ArchiveInput::parse("party.zip").and_then(|input| input.member), None);
+        assert!(ArchiveInput::parse("manifesto.txt").is_none());
+        assert!(ArchiveInput::parse("wow!.txt").is_none());
+    }
+
+    #[test]
+    fn reads_text_and_pdf_members() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let read = fixture(dir.path()).read(1024 * 1024).expect("should have read the archive");
+
+        assert!(read.skipped.is_empty());
+        assert_eq!(read.members.len(), 2);
+        assert_eq!(read.members[0].name, "green/manifesto.txt");
+        assert!(String::from_utf8_lossy(&read.members[0].bytes).starts_with("# Green Party 2024"));
+        assert_eq!(read.members[1].name, "green/manifesto.pdf");
+        assert!(String::from_utf8_lossy(&read.members[1].bytes).contains("plant more trees"));
+    }
+
+    #[test]
+    fn reads_one_member_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut input = fixture(dir.path());
+        input.member = Some(String::from("green/manifesto.txt"));
+
+        assert_eq!(input.read(1024 * 1024).expect("should have read the member").members.len(), 1);
+
+        input.member = Some(String::from("green/manifesto.pdf"));
+        assert!(input.read(16).is_err());
+
+        input.member = Some(String::from("missing.txt"));
+        assert!(input.read(1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn filters_members_by_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut input = archive(dir.path(), &[("a.txt", b"one"), ("docs/b.txt", b"two"), ("docs/c.md", b"three")]);
+        let names = |input: &ArchiveInput| -> Vec<String> {
+            input.read(1024).unwrap().members.into_iter().map(|member| member.name).collect()
+        };
+
+        input.include = vec![String::from("*.txt")];
+        assert_eq!(names(&input), vec!["a.txt", "docs/b.txt"]);
+
+        input.include = vec![String::from("docs/*")];
+        assert_eq!(names(&input), vec!["docs/b.txt", "docs/c.md"]);
+
+        input.include = vec![String::from("**.md"), String::from("a.???")];
+        assert_eq!(names(&input), vec!["a.txt", "docs/c.md"]);
+    }
+
+    #[test]
+    fn skips_members_over_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        // Compresses to almost nothing, but decompresses to a megabyte
+        let bomb = vec![b'a'; 1024 * 1024];
+        let input = archive(dir.path(), &[("bomb.txt", &bomb), ("small.txt", b"fine")]);
+
+        let read = input.read(1024).expect("should have read the archive");
+
+        assert_eq!(read.members.len(), 1);
+        assert_eq!(read.members[0].name, "small.txt");
+        assert_eq!(read.skipped[0].0, "bomb.txt");
+    }
+
+    #[test]
+    fn fails_when_the_archive_decompresses_past_the_total_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = vec![b'a'; 600];
+        let mut input = archive(dir.path(), &[("a.txt", &part), ("b.txt", &part)]);
+        input.max_total_bytes = 1000;
+
+        match input.read(1024) {
+            Err(e) => assert!(e.contains("--max-archive-bytes")),
+            Ok(_) => panic!("Should have refused to decompress past the limit"),
+        }
+    }
+
+    #[test]
+    fn skips_binary_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = archive(dir.path(), &[("image.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), ("utf16.txt", b"\xff\xfeh\0i\0")]);
+
+        let read = input.read(1024).expect("should have read the archive");
+
+        assert_eq!(read.members.len(), 1);
+        assert_eq!(read.members[0].name, "utf16.txt");
+        assert_eq!(read.skipped[0], (String::from("image.png"), String::from("it doesn't look like text")));
+    }
+}
@@ -0,0 +1,73 @@
+// Writes files that later runs read back (recordings, --resume state, the watcher's maps and the
+// run report) so that they're either all there or not there at all. The contents go to a temp
+// file next to the real one, which is synced and then renamed over it: a rename is atomic, so a
+// run that's killed part way through a write leaves the old file (or none) plus a stray temp
+// file, never a half-written file that would trip up the next run.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Ends every temp file's name, so that readers can tell them apart from finished files
+pub const TEMP_EXTENSION: &str = "tmp";
+
+// Numbers temp files, so that threads writing the same file at once don't share one
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temp_path = temp_path_for(path);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+// Next to [path], so that the rename doesn't cross filesystems
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut file_name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}-{}.{}", process::id(), NEXT_TEMP.fetch_add(1, Ordering::Relaxed), TEMP_EXTENSION));
+
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replaces_the_file_and_leaves_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "old").unwrap();
+
+        write(&path, "new").expect("should have written the file");
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_writes_leave_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "old").unwrap();
+        // The temp file can't be created in a directory that isn't there
+        let unreachable = dir.path().join("missing").join("state.json");
+
+        assert!(write(&unreachable, "new").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,137 @@
+// Which OpenAI organization and project a run's usage is billed to (--org and --project, or
+// OPENAI_ORG_ID and OPENAI_PROJECT_ID). Both are sent as headers on every request when they're
+// set. They aren't secrets, but they do identify the account, so --print-config and the run
+// report only show their ends.
+
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+pub const ORGANIZATION_HEADER: &str = "OpenAI-Organization";
+pub const PROJECT_HEADER: &str = "OpenAI-Project";
+pub const ORGANIZATION_ENV_VAR: &str = "OPENAI_ORG_ID";
+pub const PROJECT_ENV_VAR: &str = "OPENAI_PROJECT_ID";
+
+// How many characters are shown at each end of a masked ID
+const SHOWN_CHARS: usize = 4;
+const MASKED: &str = "[masked]";
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Attribution {
+    organization: Option<String>,
+    project: Option<String>,
+}
+
+impl Attribution {
+    // Fails if either ID couldn't be sent as a header
+    pub fn new(organization: Option<String>, project: Option<String>) -> Result<Attribution, &'static str> {
+        if organization.as_deref().is_some_and(|id| !is_valid_id(id)) {
+            return Err("--org can only have printable characters, without spaces");
+        }
+
+        if project.as_deref().is_some_and(|id| !is_valid_id(id)) {
+            return Err("--project can only have printable characters, without spaces");
+        }
+
+        Ok(Attribution { organization, project })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.organization.is_none() && self.project.is_none()
+    }
+
+    // Adds a header to [headers] for each ID that's set
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        let ids = [(ORGANIZATION_HEADER, &self.organization), (PROJECT_HEADER, &self.project)];
+
+        for (name, id) in ids {
+            if let Some(id) = id {
+                let value = HeaderValue::from_str(id).expect("IDs are checked when they're parsed");
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+// Header values can't have control characters, and OpenAI's IDs are only ever printable ASCII.
+// Anything else is most likely a stray newline or quote from a copy and paste.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+// e.g. "org-…7xQz". IDs too short to show both ends of without giving most of them away are
+// masked entirely.
+fn mask(id: &str) -> String {
+    let chars: Vec<char> = id.chars().collect();
+
+    if chars.len() < SHOWN_CHARS * 3 {
+        return String::from(MASKED);
+    }
+
+    let start: String = chars[..SHOWN_CHARS].iter().collect();
+    let end: String = chars[chars.len() - SHOWN_CHARS..].iter().collect();
+
+    format!("{}…{}", start, end)
+}
+
+// What the run report shows
+#[derive(Serialize)]
+struct Masked {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    organization: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+}
+
+impl Attribution {
+    fn masked(&self) -> Masked {
+        Masked {
+            organization: self.organization.as_deref().map(mask),
+            project: self.project.as_deref().map(mask),
+        }
+    }
+}
+
+impl fmt::Debug for Attribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let masked = self.masked();
+
+        f.debug_struct("Attribution")
+            .field("organization", &masked.organization)
+            .field("project", &masked.project)
+            .finish()
+    }
+}
+
+impl Serialize for Attribution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.masked().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ORGANIZATION: &str = "org-AbCdEfGhIjKl7xQz";
+
+    #[test]
+    fn rejects_ids_that_cant_be_headers() {
+        assert!(Attribution::new(Some(String::from("org-abc\n")), None).is_err());
+        assert!(Attribution::new(None, Some(String::from("proj abc"))).is_err());
+        assert!(Attribution::new(None, Some(String::new())).is_err());
+        assert!(Attribution::new(Some(String::from(ORGANIZATION)), Some(String::from("proj_abc123"))).is_ok());
+    }
+
+    #[test]
+    fn only_shows_the_ends_of_ids() {
+        let attribution = Attribution::new(Some(String::from(ORGANIZATION)), Some(String::from("proj_abc"))).unwrap();
+
+        assert_eq!(format!("{:?}", attribution), "Attribution { organization: Some(\"org-…7xQz\"), project: Some(\"[masked]\") }");
+        assert_eq!(
+            serde_json::to_value(&attribution).unwrap(),
+            serde_json::json!({ "organization": "org-…7xQz", "project": "[masked]" })
+        );
+        assert!(!format!("{:#?}", attribution).contains(ORGANIZATION));
+    }
+}
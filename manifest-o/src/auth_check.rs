@@ -0,0 +1,293 @@
+// `manifest-o check-auth`, and the --preflight check before a --batch run: makes the cheapest
+// authenticated call there is (listing the models the key can use) to confirm that the key works
+// and can use the summary model, before hours of requests depend on it. With
+// --probe-completion, a one-token chat completion is sent as well, since a project can be kept
+// from a model that still shows up in the list. Each common way this goes wrong has its own
+// message and exit code, so that scripts can tell them apart.
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+use std::process;
+use crate::arg_parsing::CheckAuthArgs;
+use crate::open_ai::{self, ChatRequestBuilder, OpenAiErrorResponse};
+use crate::runtime;
+use crate::transport::{self, CHAT_PATH};
+
+const MODELS_PATH: &str = "/models";
+// The header OpenAI names the key's organization in
+const ORGANIZATION_HEADER: &str = "openai-organization";
+
+const EXIT_INVALID_KEY: i32 = 2;
+const EXIT_NO_ACCESS: i32 = 3;
+const EXIT_UNREACHABLE: i32 = 4;
+const EXIT_NOT_THE_API: i32 = 5;
+const EXIT_OTHER: i32 = 1;
+
+// What a successful check found out
+#[derive(Debug, PartialEq)]
+pub struct AuthInfo {
+    pub organization: Option<String>,
+    // How many models the key can use
+    pub model_count: usize,
+    // Set by --probe-completion: the model that answered the probe, as OpenAI named it
+    pub probed_model: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthFailure {
+    // 401: the key is wrong, revoked or missing
+    InvalidKey(String),
+    // 403, or the summary model isn't one the key can use
+    NoAccess(String),
+    // The request never got a response
+    Unreachable(String),
+    // Something other than the API answered, e.g. a proxy's HTML login page
+    NotTheApi(String),
+    // Any other failure, with its status
+    Other(u16, String),
+}
+
+impl AuthFailure {
+    // The process's exit code for this failure, distinct for each kind
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AuthFailure::InvalidKey(_) => EXIT_INVALID_KEY,
+            AuthFailure::NoAccess(_) => EXIT_NO_ACCESS,
+            AuthFailure::Unreachable(_) => EXIT_UNREACHABLE,
+            AuthFailure::NotTheApi(_) => EXIT_NOT_THE_API,
+            AuthFailure::Other(..) => EXIT_OTHER,
+        }
+    }
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthFailure::InvalidKey(message) => write!(
+                f,
+                "OpenAI rejected the key (401): {}. Check OPENAI_API_KEY, --api-key or --key-file, or store a new key with `manifest-o key set`",
+                message
+            ),
+            AuthFailure::NoAccess(message) => write!(
+                f,
+                "The key works, but can't use what's needed: {}. Check the project's model permissions on the OpenAI dashboard",
+                message
+            ),
+            AuthFailure::Unreachable(message) => write!(
+                f,
+                "Couldn't reach OpenAI: {}. Check the network connection, and any proxy settings (HTTPS_PROXY)",
+                message
+            ),
+            AuthFailure::NotTheApi(url) => write!(
+                f,
+                "{} answered with a web page rather than the API; something, like a proxy or captive portal, is in the way",
+                url
+            ),
+            AuthFailure::Other(status, message) => write!(f, "The check failed with {}: {}", status, message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ProbeResponse {
+    model: String,
+}
+
+pub fn run_check_auth_command(args: CheckAuthArgs) -> Result<(), &'static str> {
+    let client = crate::build_openai_client(&args.openai_key, &args.attribution, None, 1, crate::DEFAULT_POOL_IDLE_TIMEOUT, false);
+
+    match runtime::block_on(check(&client, transport::OPENAI_BASE_URL, open_ai::GPT_4_MODEL_NAME, args.probe_completion)) {
+        Ok(info) => {
+            println!("{}", format_info(&info));
+            Ok(())
+        }
+        Err(failure) => {
+            eprintln!("{}", failure);
+            process::exit(failure.exit_code());
+        }
+    }
+}
+
+pub fn format_info(info: &AuthInfo) -> String {
+    let mut lines = vec![format!(
+        "The key works: {} can use {} models, including {}",
+        info.organization.as_deref().unwrap_or("its organization"),
+        info.model_count,
+        open_ai::GPT_4_MODEL_NAME
+    )];
+
+    if let Some(model) = &info.probed_model {
+        lines.push(format!("A one-token completion was answered by {}", model));
+    }
+
+    lines.join("\n")
+}
+
+// Checks that [client] can reach the API at [base_url] and use [model], also sending a
+// one-token completion to [model] if [probe_completion] is set
+pub async fn check(client: &reqwest::Client, base_url: &str, model: &str, probe_completion: bool) -> Result<AuthInfo, AuthFailure> {
+    let url = format!("{}{}", base_url, MODELS_PATH);
+    let response = client.get(&url).send().await.map_err(|e| AuthFailure::Unreachable(e.to_string()))?;
+    let organization = response.headers().get(ORGANIZATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let models: ModelList = read_json(response, &url).await?;
+
+    if !models.data.iter().any(|listed| listed.id == model) {
+        return Err(AuthFailure::NoAccess(format!("{} isn't one of the {} models the key can use", model, models.data.len())));
+    }
+
+    let probed_model = match probe_completion {
+        true => Some(probe(client, base_url, model).await?),
+        false => None,
+    };
+
+    Ok(AuthInfo { organization, model_count: models.data.len(), probed_model })
+}
+
+// Sends [model] the smallest completion it can answer, returning the model that answered
+async fn probe(client: &reqwest::Client, base_url: &str, model: &str) -> Result<String, AuthFailure> {
+    let mut body = ChatRequestBuilder::new()
+        .model(model)
+        .user("Hi")
+        .build()
+        .expect("the probe always has a model and a message");
+    body.max_tokens = Some(1);
+
+    let url = format!("{}{}", base_url, CHAT_PATH);
+    let response = client.post(&url).json(&body).send().await.map_err(|e| AuthFailure::Unreachable(e.to_string()))?;
+
+    Ok(read_json::<ProbeResponse>(response, &url).await?.model)
+}
+
+async fn read_json<R: DeserializeOwned>(response: reqwest::Response, url: &str) -> Result<R, AuthFailure> {
+    let status = response.status().as_u16();
+    let html = response.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    let text = response.text().await.map_err(|e| AuthFailure::Unreachable(e.to_string()))?;
+
+    if html || text.trim_start().starts_with('<') {
+        return Err(AuthFailure::NotTheApi(String::from(url)));
+    }
+
+    if (200..300).contains(&status) {
+        return serde_json::from_str(&text).map_err(|_| AuthFailure::NotTheApi(String::from(url)));
+    }
+
+    let error = serde_json::from_str::<OpenAiErrorResponse>(&text).ok().map(|response| response.error);
+    let message = error.as_ref().map_or(text.clone(), |error| error.message.clone());
+
+    match (status, error.and_then(|error| error.code).as_deref()) {
+        (401, _) => Err(AuthFailure::InvalidKey(message)),
+        (403, _) | (404, Some("model_not_found")) => Err(AuthFailure::NoAccess(message)),
+        _ => Err(AuthFailure::Other(status, message)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::open_ai::GPT_4_MODEL_NAME;
+    use crate::transport::fixtures;
+
+    fn models(ids: &[&str]) -> String {
+        serde_json::json!({
+            "object": "list",
+            "data": ids.iter().map(|id| serde_json::json!({ "id": id, "object": "model" })).collect::<Vec<_>>(),
+        }).to_string()
+    }
+
+    fn check_against(responses: Vec<MockResponse>, probe_completion: bool) -> Result<AuthInfo, AuthFailure> {
+        let server = MockServer::start(responses);
+
+        runtime::block_on(check(&mock_server::client(), &server.url, GPT_4_MODEL_NAME, probe_completion))
+    }
+
+    #[test]
+    fn reports_the_organization_and_models() {
+        let info = check_against(vec![
+            MockResponse::new(200, &models(&["gpt-4-turbo", "gpt-3.5-turbo"])).header(ORGANIZATION_HEADER, "org-abc"),
+        ], false).expect("the check should have passed");
+
+        assert_eq!(info, AuthInfo { organization: Some(String::from("org-abc")), model_count: 2, probed_model: None });
+    }
+
+    #[test]
+    fn probes_the_model_with_a_completion() {
+        let mut completion: serde_json::Value = serde_json::from_str(&fixtures::chat_completion("H")).unwrap();
+        completion["model"] = serde_json::json!("gpt-4-turbo-2024-04-09");
+
+        let info = check_against(vec![
+            MockResponse::new(200, &models(&["gpt-4-turbo"])),
+            MockResponse::new(200, &completion.to_string()),
+        ], true).expect("the check should have passed");
+
+        assert_eq!(info.probed_model.as_deref(), Some("gpt-4-turbo-2024-04-09"));
+    }
+
+    #[test]
+    fn maps_a_401_to_an_invalid_key() {
+        let failure = check_against(vec![
+            MockResponse::new(401, &fixtures::api_error("invalid_api_key", "Incorrect API key provided")),
+        ], false).expect_err("the check should have failed");
+
+        assert_eq!(failure, AuthFailure::InvalidKey(String::from("Incorrect API key provided")));
+        assert_eq!(failure.exit_code(), EXIT_INVALID_KEY);
+    }
+
+    #[test]
+    fn maps_missing_models_and_403s_to_no_access() {
+        let missing = check_against(vec![MockResponse::new(200, &models(&["gpt-3.5-turbo"]))], false)
+            .expect_err("the check should have failed");
+        let forbidden = check_against(vec![
+            MockResponse::new(200, &models(&["gpt-4-turbo"])),
+            MockResponse::new(403, &fixtures::api_error("model_not_found", "The project does not have access to gpt-4-turbo")),
+        ], true).expect_err("the check should have failed");
+
+        assert!(matches!(missing, AuthFailure::NoAccess(_)));
+        assert_eq!(forbidden, AuthFailure::NoAccess(String::from("The project does not have access to gpt-4-turbo")));
+        assert_eq!(forbidden.exit_code(), EXIT_NO_ACCESS);
+    }
+
+    #[test]
+    fn maps_connection_failures_to_unreachable() {
+        let failure = runtime::block_on(check(&mock_server::client(), "http://127.0.0.1:1", GPT_4_MODEL_NAME, false))
+            .expect_err("the check should have failed");
+
+        assert!(matches!(failure, AuthFailure::Unreachable(_)));
+        assert_eq!(failure.exit_code(), EXIT_UNREACHABLE);
+    }
+
+    #[test]
+    fn maps_html_pages_to_not_the_api() {
+        let failure = check_against(vec![
+            MockResponse::new(200, "<!DOCTYPE html><html><body>Please log in</body></html>"),
+        ], false).expect_err("the check should have failed");
+
+        assert!(matches!(failure, AuthFailure::NotTheApi(_)));
+        assert_eq!(failure.exit_code(), EXIT_NOT_THE_API);
+    }
+
+    #[test]
+    fn passes_other_errors_through_with_their_status() {
+        let failure = check_against(vec![
+            MockResponse::new(500, &fixtures::api_error("server_error", "Something went wrong")),
+        ], false).expect_err("the check should have failed");
+
+        assert_eq!(failure, AuthFailure::Other(500, String::from("Something went wrong")));
+        assert_eq!(failure.exit_code(), EXIT_OTHER);
+    }
+}
@@ -0,0 +1,284 @@
+// Interactive conversations about a manifesto (--chat). The manifesto is sent at the start of
+// every request, followed by the whole conversation so far, so that follow-up questions can refer
+// back to earlier answers.
+//
+// With --session, the conversation is saved when it ends and picked back up on the next run. The
+// manifesto isn't saved with it, only the hash of its contents: it's read from its file again on
+// the next run, and checked against the hash.
+
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use crate::error::ManifestoError;
+use crate::open_ai::{ChatRequestBuilder, OpenAiUsage, Role, GPT_4_MODEL_NAME};
+use crate::state;
+use crate::transport::ChatTransport;
+
+const CHAT_SYSTEM_PROMPT: &str = "You are a careful researcher who answers questions about the manifestos of political parties using only the text you are given. If the manifesto doesn't address a question, say so.";
+const DOCUMENT_INTRODUCTION: &str = "The questions that follow are about this manifesto:";
+
+const HISTORY_COMMAND: &str = "/history";
+const QUIT_COMMAND: &str = "/quit";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Turn {
+    pub role: Role,
+    pub content: String,
+    // The tokens used by the request that got an assistant turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<OpenAiUsage>,
+}
+
+// What's written to the --session file
+#[derive(Serialize, Deserialize)]
+struct Session {
+    system: String,
+    // Stands in for the manifesto, which is read from its file again when the session is loaded
+    document_hash: String,
+    turns: Vec<Turn>,
+    usage: OpenAiUsage,
+}
+
+pub struct Conversation {
+    system: String,
+    document: String,
+    turns: Vec<Turn>,
+    usage: OpenAiUsage,
+}
+
+impl Conversation {
+    pub fn new(document: &str) -> Conversation {
+        Conversation {
+            system: String::from(CHAT_SYSTEM_PROMPT),
+            document: String::from(document),
+            turns: Vec::new(),
+            usage: OpenAiUsage::default(),
+        }
+    }
+
+    // Picks up the conversation saved at [path], about [document]. If the document has changed
+    // since the conversation was saved, it's still picked up (against the document as it is now),
+    // along with a warning to show.
+    pub fn load(path: &Path, document: &str) -> io::Result<(Conversation, Option<String>)> {
+        let session: Session = serde_json::from_str(&fs::read_to_string(path)?).map_err(io::Error::other)?;
+
+        let warning = (session.document_hash != state::input_hash(document)).then(|| format!(
+            "the manifesto has changed since {} was saved, so earlier answers may not match it",
+            path.display()
+        ));
+
+        let conversation = Conversation {
+            system: session.system,
+            document: String::from(document),
+            turns: session.turns,
+            usage: session.usage,
+        };
+
+        Ok((conversation, warning))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let session = Session {
+            system: self.system.clone(),
+            document_hash: state::input_hash(&self.document),
+            turns: self.turns.clone(),
+            usage: self.usage,
+        };
+
+        fs::write(path, serde_json::to_string_pretty(&session).expect("sessions should always serialize"))
+    }
+
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    // The tokens used by every turn, including those from before the session was loaded
+    pub fn usage(&self) -> OpenAiUsage {
+        self.usage
+    }
+
+    // Asks [question] after the conversation so far. The question and its answer are only added
+    // to the conversation if the request succeeds.
+    pub fn ask(&mut self, transport: &impl ChatTransport, question: &str) -> Result<String, ManifestoError> {
+        let builder = ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .system(self.system.as_str())
+            .user(format!("{}\n\n{}", DOCUMENT_INTRODUCTION, self.document));
+
+        let req = self.turns.iter()
+            .fold(builder, |builder, turn| builder.message(turn.role, turn.content.as_str()))
+            .user(question)
+            .build()
+            .expect("requests should always have a model and messages");
+
+        let resp = transport.post_chat(&req)?;
+
+        if resp.content_filtered() {
+            return Err(ManifestoError::ContentFiltered);
+        }
+
+        let answer = match resp.choices.into_iter().next() {
+            Some(choice) => choice.message.content,
+            None => return Err(ManifestoError::EmptyResponse),
+        };
+
+        if let Some(usage) = &resp.usage {
+            self.usage.add(usage);
+        }
+
+        self.turns.push(Turn { role: Role::User, content: String::from(question), usage: None });
+        self.turns.push(Turn { role: Role::Assistant, content: answer.clone(), usage: resp.usage });
+
+        Ok(answer)
+    }
+}
+
+// Each question and answer so far, one after another (as in [crate::qa::format_answers])
+pub fn format_history(turns: &[Turn]) -> String {
+    if turns.is_empty() {
+        return String::from("Nothing has been asked yet");
+    }
+
+    turns.iter()
+        .map(|turn| match turn.role {
+            Role::User => format!("Q: {}", turn.content),
+            _ => format!("A: {}", turn.content),
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+// Answers each question read from [input] on [output], until the input runs out, /quit is
+// entered or the run is [interrupted]. A question that fails is reported and left out of the
+// conversation, so it can be asked again.
+pub fn run_repl(conversation: &mut Conversation, transport: &impl ChatTransport, mut input: impl BufRead, output: &mut impl Write, interrupted: &CancellationToken) -> io::Result<()> {
+    writeln!(output, "Ask about the manifesto ({} shows the conversation so far, {} stops)", HISTORY_COMMAND, QUIT_COMMAND)?;
+
+    let mut line = String::new();
+
+    while !interrupted.is_cancelled() {
+        write!(output, "> ")?;
+        output.flush()?;
+
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        match line.trim() {
+            "" => {}
+            QUIT_COMMAND => break,
+            HISTORY_COMMAND => writeln!(output, "{}", format_history(conversation.turns()))?,
+            question => match conversation.ask(transport, question) {
+                Ok(answer) => writeln!(output, "{}", answer)?,
+                Err(e) => eprintln!("{}", e),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    const MANIFESTO: &str = "We will build more trains.";
+
+    #[test]
+    fn asks_with_the_whole_conversation() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("More trains."))
+            .respond(200, &fixtures::chat_completion("By 2030."));
+        let mut conversation = Conversation::new(MANIFESTO);
+
+        conversation.ask(&transport, "What about transport?").expect("should have answered");
+        conversation.ask(&transport, "When?").expect("should have answered");
+
+        let messages = &transport.requests()[1]["messages"];
+        assert_eq!(messages[1]["content"], format!("{}\n\n{}", DOCUMENT_INTRODUCTION, MANIFESTO));
+        assert_eq!(messages[2]["content"], "What about transport?");
+        assert_eq!(messages[3]["role"], "assistant");
+        assert_eq!(messages[3]["content"], "More trains.");
+        assert_eq!(messages[4]["content"], "When?");
+        assert_eq!(conversation.turns().len(), 4);
+    }
+
+    #[test]
+    fn sessions_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("More trains."))
+            .respond(200, &fixtures::chat_completion("By 2030."));
+
+        let mut conversation = Conversation::new(MANIFESTO);
+        conversation.ask(&transport, "What about transport?").expect("should have answered");
+        conversation.save(&path).expect("should have saved the session");
+
+        // The manifesto is only referenced by its hash
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains(MANIFESTO));
+        assert!(saved.contains(&state::input_hash(MANIFESTO)));
+
+        let (mut loaded, warning) = Conversation::load(&path, MANIFESTO).expect("should have loaded the session");
+        assert_eq!(warning, None);
+        assert_eq!(loaded.turns(), conversation.turns());
+        assert_eq!(loaded.usage(), conversation.usage());
+
+        loaded.ask(&transport, "When?").expect("should have answered");
+
+        let messages = &transport.requests()[1]["messages"];
+        assert_eq!(messages[1]["content"], format!("{}\n\n{}", DOCUMENT_INTRODUCTION, MANIFESTO));
+        assert_eq!(messages[3]["content"], "More trains.");
+        assert_eq!(loaded.usage().total_tokens, 2 * conversation.usage().total_tokens);
+    }
+
+    #[test]
+    fn warns_when_the_document_has_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        Conversation::new(MANIFESTO).save(&path).expect("should have saved the session");
+
+        let (loaded, warning) = Conversation::load(&path, "We will build more roads.").expect("should have loaded the session");
+
+        assert!(warning.expect("should have warned").contains("has changed"));
+        assert!(loaded.turns().is_empty());
+    }
+
+    #[test]
+    fn repl_answers_questions_until_quit() {
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("More trains."));
+        let mut conversation = Conversation::new(MANIFESTO);
+        let mut output = Vec::new();
+
+        run_repl(
+            &mut conversation,
+            &transport,
+            "What about transport?\n\n/history\n/quit\nNever asked\n".as_bytes(),
+            &mut output,
+            &CancellationToken::new(),
+        ).expect("should have run the conversation");
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("> More trains.\n"));
+        assert!(output.contains("Q: What about transport?\n\nA: More trains.\n"));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn failed_questions_are_left_out() {
+        let transport = MockTransport::new()
+            .respond(400, &fixtures::api_error("invalid_request_error", "Bad request"))
+            .respond(200, &fixtures::chat_completion("More trains."));
+        let mut conversation = Conversation::new(MANIFESTO);
+
+        run_repl(&mut conversation, &transport, "Bad?\nGood?\n".as_bytes(), &mut Vec::new(), &CancellationToken::new())
+            .expect("should have run the conversation");
+
+        assert_eq!(format_history(conversation.turns()), "Q: Good?\n\nA: More trains.");
+    }
+}
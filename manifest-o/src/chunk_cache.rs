@@ -0,0 +1,138 @@
+// Keeps chunk summaries between runs (--chunk-cache <dir>), so that re-summarising an edited
+// manifesto only pays for the chunks that changed. The resume state and --batch's skipping both
+// go by the hash of the whole document, so a one-word edit throws all of their work away; here,
+// each chunk's summary is filed under its own key instead, made from the model, a hash of the
+// prompt around the chunk and a hash of the chunk's text. Chunks that come out the same are read
+// back, and only the changed ones (and the request that combines them) are sent.
+
+use crate::atomic_write;
+use crate::open_ai::{ChatDefaults, OpenAiRequestBody};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Stands in for the chunk's text in the prompt hash, so that the same prompt hashes the same
+// whatever chunk it's wrapped around
+const CHUNK_PLACEHOLDER: &str = "{chunk}";
+
+pub struct ChunkCache {
+    dir: PathBuf,
+    // The run's --max-tokens, --stop and --seed, which change replies without being in the
+    // messages
+    settings_hash: String,
+    lookups: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    model: String,
+    summary: String,
+}
+
+impl ChunkCache {
+    // Creates [dir] if it isn't there yet
+    pub fn open(dir: &Path, defaults: &ChatDefaults) -> io::Result<ChunkCache> {
+        fs::create_dir_all(dir)?;
+
+        let settings = format!("{:?}\n{:?}\n{:?}", defaults.max_tokens, defaults.stop, defaults.seed);
+
+        Ok(ChunkCache {
+            dir: dir.to_path_buf(),
+            settings_hash: hash(&settings),
+            lookups: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    // The key for [body], the request for a chunk whose text is [chunk_text]. The chunk's
+    // position isn't part of it, so a chunk that moves (because text was added before it) is
+    // still found.
+    pub fn key(&self, body: &OpenAiRequestBody, chunk_text: &str) -> String {
+        let prompt: Vec<String> = body.messages.iter()
+            .map(|message| format!("{:?}:{}", message.role, message.content.replace(chunk_text, CHUNK_PLACEHOLDER)))
+            .collect();
+
+        hash(&format!("{}\n{}\n{}\n{}", body.model, hash(&prompt.join("\n")), hash(chunk_text), self.settings_hash))
+    }
+
+    // The summary saved under [key], if there is one. Entries that can't be read are treated as
+    // missing, and get overwritten once the chunk is summarised again.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+
+        let entry: Entry = fs::read_to_string(self.path_for(key)).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())?;
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(entry.summary)
+    }
+
+    pub fn put(&self, key: &str, model: &str, summary: &str) -> io::Result<()> {
+        let entry = Entry { model: String::from(model), summary: String::from(summary) };
+        let contents = serde_json::to_string_pretty(&entry).expect("entries should always serialize");
+
+        atomic_write::write(&self.path_for(key), contents)
+    }
+
+    // How many chunks were found, out of how many were looked up
+    pub fn stats(&self) -> (usize, usize) {
+        (self.hits.load(Ordering::Relaxed), self.lookups.load(Ordering::Relaxed))
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+fn hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::open_ai::{ChatRequestBuilder, GPT_4_MODEL_NAME};
+
+    fn request(instruction: &str, chunk_text: &str) -> OpenAiRequestBody {
+        ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .system("You summarise manifestos")
+            .user(instruction)
+            .user(chunk_text)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn keys_change_with_the_chunk_the_prompt_and_the_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::open(dir.path(), &ChatDefaults::default()).unwrap();
+        let seeded = ChunkCache::open(dir.path(), &ChatDefaults { seed: Some(42), ..ChatDefaults::default() }).unwrap();
+
+        let key = cache.key(&request("Summarise this", "Tunnels for all"), "Tunnels for all");
+
+        assert_eq!(key, cache.key(&request("Summarise this", "Tunnels for all"), "Tunnels for all"));
+        assert_ne!(key, cache.key(&request("Summarise this", "Bridges for all"), "Bridges for all"));
+        assert_ne!(key, cache.key(&request("Summarise this briefly", "Tunnels for all"), "Tunnels for all"));
+        assert_ne!(key, seeded.key(&request("Summarise this", "Tunnels for all"), "Tunnels for all"));
+    }
+
+    #[test]
+    fn finds_what_was_put_and_counts_hits() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::open(&dir.path().join("chunks"), &ChatDefaults::default()).unwrap();
+
+        assert_eq!(cache.get("abc"), None);
+        cache.put("abc", GPT_4_MODEL_NAME, "The tunnel part").unwrap();
+        assert_eq!(cache.get("abc").as_deref(), Some("The tunnel part"));
+
+        fs::write(dir.path().join("chunks").join("def.json"), "{\"summ").unwrap();
+        assert_eq!(cache.get("def"), None);
+
+        assert_eq!(cache.stats(), (1, 3));
+    }
+}
@@ -0,0 +1,286 @@
+// Splits documents into pieces small enough to send to the model on their own.
+
+use regex::Regex;
+use crate::sections::{self, PREAMBLE_HEADING};
+
+// A rough rule of thumb for English text with OpenAI's tokenizers
+pub const CHARS_PER_TOKEN: usize = 4;
+
+// Roughly how many tokens the text will take up
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+// Splits the text into chunks of at most [max_tokens] (estimated) tokens each. Chunks break on
+// paragraph boundaries where possible, then on lines, then on whitespace, and only split a word
+// when it's longer than a whole chunk.
+pub fn split_into_chunks(text: &str, max_tokens: usize) -> Vec<String> {
+    let max_chars = (max_tokens * CHARS_PER_TOKEN).max(1);
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for piece in split_keeping_separators(text, max_chars) {
+        if current.chars().count() + piece.chars().count() > max_chars && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        current.push_str(piece);
+    }
+
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
+
+// One piece of a document, along with the headings of the sections it came from (if the
+// document has any). [part] is (n, of) when a section was too long for one chunk and had to be
+// split.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    pub sections: Vec<String>,
+    pub part: Option<(usize, usize)>,
+}
+
+impl Chunk {
+    // Describes which part of the document this is, e.g. `the section on "Health"`, for the
+    // chunk's prompt. None when the chunk isn't from any named section.
+    pub fn label(&self) -> Option<String> {
+        let quoted: Vec<String> = self.sections.iter().map(|heading| format!("\"{}\"", heading)).collect();
+
+        match (quoted.as_slice(), self.part) {
+            ([], _) => None,
+            ([heading], Some((n, of))) => Some(format!("part {} of {} of the section on {}", n, of, heading)),
+            ([heading], None) => Some(format!("the section on {}", heading)),
+            ([init @ .., last], _) => Some(format!("the sections on {} and {}", init.join(", "), last)),
+        }
+    }
+}
+
+// Splits the text into chunks of at most [max_tokens] (estimated) tokens along the section
+// boundaries found by [heading_pattern]. With [pack], whole sections are packed into each chunk
+// while they fit; otherwise every section gets its own chunk. A section is only split (as in
+// [split_into_chunks]) when it's too long for a chunk of its own. Without any headings, this is
+// just [split_into_chunks].
+pub fn split_into_section_chunks(text: &str, heading_pattern: &Regex, max_tokens: usize, pack: bool) -> Vec<Chunk> {
+    let Some(sections) = sections::split_into_sections(text, heading_pattern) else {
+        return split_into_unlabelled_chunks(text, max_tokens);
+    };
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut current = Chunk { text: String::new(), sections: Vec::new(), part: None };
+
+    for section in sections {
+        // The preamble's heading is made up, so it's left out of the text and the labels
+        let (text, heading) = if section.heading == PREAMBLE_HEADING {
+            (format!("{}\n\n", section.body), None)
+        } else {
+            (format!("{}\n\n{}\n\n", section.heading, section.body), Some(section.heading))
+        };
+
+        if estimate_tokens(&text) > max_tokens {
+            if !current.text.is_empty() {
+                chunks.push(std::mem::replace(&mut current, Chunk { text: String::new(), sections: Vec::new(), part: None }));
+            }
+
+            let parts = split_into_chunks(&text, max_tokens);
+            let part_count = parts.len();
+
+            chunks.extend(parts.into_iter().enumerate().map(|(i, text)| Chunk {
+                text,
+                sections: heading.iter().cloned().collect(),
+                part: if part_count > 1 { Some((i + 1, part_count)) } else { None },
+            }));
+
+            continue;
+        }
+
+        if (!pack || estimate_tokens(&current.text) + estimate_tokens(&text) > max_tokens) && !current.text.is_empty() {
+            chunks.push(std::mem::replace(&mut current, Chunk { text: String::new(), sections: Vec::new(), part: None }));
+        }
+
+        current.text.push_str(&text);
+        current.sections.extend(heading);
+    }
+
+    if !current.text.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+// [split_into_chunks], for when the sections aren't wanted
+pub fn split_into_unlabelled_chunks(text: &str, max_tokens: usize) -> Vec<Chunk> {
+    split_into_chunks(text, max_tokens).into_iter()
+        .map(|text| Chunk { text, sections: Vec::new(), part: None })
+        .collect()
+}
+
+// Lists each chunk with its estimated size and where it came from, for --dry-run
+pub fn format_chunk_plan(chunks: &[Chunk]) -> String {
+    chunks.iter().enumerate()
+        .map(|(i, chunk)| format!(
+            "Chunk {}: ~{} tokens, {}",
+            i + 1,
+            estimate_tokens(&chunk.text),
+            chunk.label().unwrap_or_else(|| String::from("no section")),
+        ))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Breaks the text into pieces no longer than [max_chars], each keeping its trailing separator so
+// that joining them gives back the original text.
+fn split_keeping_separators(text: &str, max_chars: usize) -> Vec<&str> {
+    const SEPARATORS: [&str; 3] = ["\n\n", "\n", " "];
+
+    fn split<'a>(text: &'a str, max_chars: usize, separators: &[&str], out: &mut Vec<&'a str>) {
+        if text.chars().count() <= max_chars {
+            out.push(text);
+            return;
+        }
+
+        match separators.split_first() {
+            Some((separator, rest)) => {
+                for piece in text.split_inclusive(separator) {
+                    split(piece, max_chars, rest, out);
+                }
+            }
+            None => {
+                let mut start = 0;
+
+                for (count, (i, _)) in text.char_indices().enumerate() {
+                    if count > 0 && count % max_chars == 0 {
+                        out.push(&text[start..i]);
+                        start = i;
+                    }
+                }
+
+                out.push(&text[start..]);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    split(text, max_chars, &SEPARATORS, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split_into_chunks("A short manifesto", 100), vec!["A short manifesto"]);
+    }
+
+    #[test]
+    fn splits_on_paragraphs() {
+        let text = "First paragraph here.\n\nSecond paragraph here.\n\nThird paragraph here.";
+        let chunks = split_into_chunks(text, 12);
+
+        assert_eq!(chunks, vec![
+            "First paragraph here.\n\nSecond paragraph here.\n\n",
+            "Third paragraph here.",
+        ]);
+    }
+
+    #[test]
+    fn chunks_respect_the_limit() {
+        let text = "word ".repeat(1000);
+
+        for chunk in split_into_chunks(&text, 10) {
+            assert!(chunk.chars().count() <= 10 * CHARS_PER_TOKEN);
+        }
+    }
+
+    #[test]
+    fn splits_words_longer_than_a_chunk() {
+        let chunks = split_into_chunks(&"x".repeat(10), 1);
+
+        assert_eq!(chunks, vec!["xxxx", "xxxx", "xx"]);
+    }
+
+    fn markdown_manifesto() -> String {
+        [
+            "Our promises to you.",
+            "# Health\nFree clinics in every town.",
+            "# Education\nSmaller classes.",
+            "# Transport\nFree buses for everyone, in every town and city, every day of the week.\nMore trains on every line, running through the night. Safer cycle lanes on every main road.",
+            "# Housing\nA million new homes.",
+        ].join("\n")
+    }
+
+    #[test]
+    fn packs_whole_sections_into_chunks() {
+        let chunks = split_into_section_chunks(&markdown_manifesto(), &Regex::new(sections::DEFAULT_HEADING_PATTERN).unwrap(), 25, true);
+
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(texts, vec![
+            "Our promises to you.\n\nHealth\n\nFree clinics in every town.\n\nEducation\n\nSmaller classes.\n\n",
+            "Transport\n\nFree buses for everyone, in every town and city, every day of the week.\n",
+            "More trains on every line, running through the night. Safer cycle lanes on every main road.\n\n",
+            "Housing\n\nA million new homes.\n\n",
+        ]);
+
+        let labels: Vec<Option<String>> = chunks.iter().map(Chunk::label).collect();
+        assert_eq!(labels, vec![
+            Some(String::from("the sections on \"Health\" and \"Education\"")),
+            Some(String::from("part 1 of 2 of the section on \"Transport\"")),
+            Some(String::from("part 2 of 2 of the section on \"Transport\"")),
+            Some(String::from("the section on \"Housing\"")),
+        ]);
+
+        for chunk in &chunks {
+            assert!(estimate_tokens(&chunk.text) <= 25, "Chunk is too long: {:?}", chunk.text);
+        }
+    }
+
+    #[test]
+    fn gives_each_section_its_own_chunk_without_packing() {
+        let chunks = split_into_section_chunks(&markdown_manifesto(), &Regex::new(sections::DEFAULT_HEADING_PATTERN).unwrap(), 25, false);
+
+        let labels: Vec<Option<String>> = chunks.iter().map(Chunk::label).collect();
+        assert_eq!(labels, vec![
+            None,
+            Some(String::from("the section on \"Health\"")),
+            Some(String::from("the section on \"Education\"")),
+            Some(String::from("part 1 of 2 of the section on \"Transport\"")),
+            Some(String::from("part 2 of 2 of the section on \"Transport\"")),
+            Some(String::from("the section on \"Housing\"")),
+        ]);
+        assert_eq!(chunks[0].text, "Our promises to you.\n\n");
+    }
+
+    #[test]
+    fn documents_without_headings_are_chunked_as_usual() {
+        let text = "First paragraph here.\n\nSecond paragraph here.\n\nThird paragraph here.";
+        let chunks = split_into_section_chunks(text, &Regex::new(sections::DEFAULT_HEADING_PATTERN).unwrap(), 12, true);
+
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(texts, split_into_chunks(text, 12));
+        assert!(chunks.iter().all(|chunk| chunk.label().is_none()));
+    }
+
+    #[test]
+    fn formats_the_chunk_plan() {
+        let chunks = vec![
+            Chunk { text: "x".repeat(40), sections: vec![String::from("Health")], part: None },
+            Chunk { text: "x".repeat(8), sections: Vec::new(), part: None },
+        ];
+
+        assert_eq!(format_chunk_plan(&chunks), "Chunk 1: ~10 tokens, the section on \"Health\"\nChunk 2: ~2 tokens, no section");
+    }
+
+    #[test]
+    fn chunks_reassemble_to_the_original() {
+        let text = "Para one.\nStill one.\n\nPara two is a little longer than the others.\n\nThree.";
+
+        assert_eq!(split_into_chunks(text, 5).concat(), text);
+    }
+}
@@ -0,0 +1,212 @@
+// Tidies up text extracted from PDFs before it's sent to the model. Extraction leaves behind
+// running headers and footers on every page, bare page numbers and piles of blank lines, all of
+// which cost tokens without saying anything. Cleaning is on by default (--no-clean skips it),
+// and --dry-run shows what it would do.
+
+use regex::Regex;
+use std::collections::BTreeMap;
+use crate::chunking;
+
+// PDF extraction separates pages with form feeds
+const PAGE_SEPARATOR: char = '\u{000c}';
+// With fewer pages than this, a repeated line is as likely to be content as boilerplate
+const MIN_PAGES_FOR_BOILERPLATE: usize = 3;
+// "12", "Page 12", "page 3 of 40", "- 7 -", "12/40"
+const PAGE_NUMBER_PATTERN: &str = r"(?i)^\s*(page\s+)?-?\s*\d+\s*-?(\s*(of|/)\s*\d+)?\s*$";
+
+#[derive(Debug, PartialEq)]
+pub struct Cleaned {
+    pub text: String,
+    // Each distinct header or footer line that was removed from the pages, in the order they
+    // first appeared
+    pub removed_boilerplate: Vec<String>,
+    pub removed_page_numbers: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+}
+
+impl Cleaned {
+    pub fn tokens_saved(&self) -> usize {
+        self.tokens_before.saturating_sub(self.tokens_after)
+    }
+}
+
+pub fn clean(text: &str) -> Cleaned {
+    let page_number = Regex::new(PAGE_NUMBER_PATTERN).expect("the page number pattern should be valid");
+    let pages: Vec<&str> = text.split(PAGE_SEPARATOR).collect();
+    let boilerplate = find_boilerplate(&pages, &page_number);
+
+    let mut removed_boilerplate: Vec<String> = Vec::new();
+    let mut removed_normalised: Vec<String> = Vec::new();
+    let mut removed_page_numbers = 0;
+    let mut lines: Vec<&str> = Vec::new();
+
+    for page in &pages {
+        for line in page.lines() {
+            if page_number.is_match(line) {
+                removed_page_numbers += 1;
+            } else if boilerplate.contains_key(&normalise(line)) {
+                if !removed_normalised.contains(&normalise(line)) {
+                    removed_normalised.push(normalise(line));
+                    removed_boilerplate.push(String::from(line.trim()));
+                }
+            } else {
+                lines.push(line);
+            }
+        }
+
+        // Keeps pages apart after their separator is dropped
+        lines.push("");
+    }
+
+    let cleaned_text = collapse_blank_lines(&lines);
+
+    Cleaned {
+        tokens_before: chunking::estimate_tokens(text),
+        tokens_after: chunking::estimate_tokens(&cleaned_text),
+        text: cleaned_text,
+        removed_boilerplate,
+        removed_page_numbers,
+    }
+}
+
+// Describes what cleaning removed, for --dry-run
+pub fn format_report(cleaned: &Cleaned) -> String {
+    let mut report = format!(
+        "Cleaning saves ~{} tokens (~{} -> ~{}), removing {} page numbers and {} repeated lines",
+        cleaned.tokens_saved(), cleaned.tokens_before, cleaned.tokens_after,
+        cleaned.removed_page_numbers, cleaned.removed_boilerplate.len()
+    );
+
+    for line in &cleaned.removed_boilerplate {
+        report.push_str(&format!("\n  removed: {}", line));
+    }
+
+    report
+}
+
+// Lines (normalised) that show up on at least half of the pages. Counted once per page, so a
+// line repeated within one page isn't mistaken for a header.
+fn find_boilerplate(pages: &[&str], page_number: &Regex) -> BTreeMap<String, usize> {
+    if pages.len() < MIN_PAGES_FOR_BOILERPLATE {
+        return BTreeMap::new();
+    }
+
+    let mut page_counts: BTreeMap<String, usize> = BTreeMap::new();
+
+    for page in pages {
+        let mut seen: Vec<String> = page.lines()
+            .filter(|line| !line.trim().is_empty() && !page_number.is_match(line))
+            .map(normalise)
+            .collect();
+        seen.sort();
+        seen.dedup();
+
+        for line in seen {
+            *page_counts.entry(line).or_default() += 1;
+        }
+    }
+
+    let threshold = pages.len().div_ceil(2).max(2);
+    page_counts.retain(|_, count| *count >= threshold);
+    page_counts
+}
+
+// Headers and footers often include the page number ("Our Plan - page 3"), so digits are
+// ignored when comparing lines
+fn normalise(line: &str) -> String {
+    line.trim()
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .collect()
+}
+
+// Joins the lines back up with runs of blank lines collapsed into one, and without any at the
+// start or end
+fn collapse_blank_lines(lines: &[&str]) -> String {
+    let mut text = String::new();
+    let mut pending_blank = false;
+
+    for line in lines {
+        if line.trim().is_empty() {
+            pending_blank = !text.is_empty();
+            continue;
+        }
+
+        if pending_blank {
+            text.push('\n');
+            pending_blank = false;
+        }
+
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paginated_document() -> String {
+        [
+            "THE EXAMPLE PARTY MANIFESTO\n\nWe will build more houses.\n\n\n\nWe will fix the roads.\nThe Example Party - Page 1\n",
+            "THE EXAMPLE PARTY MANIFESTO\nHealthcare will be free.\n\n2\n",
+            "THE EXAMPLE PARTY MANIFESTO\nSchools will get more teachers.\nThe Example Party - Page 3\n",
+            "THE EXAMPLE PARTY MANIFESTO\nTaxes will stay the same.\nThe Example Party - Page 4\nPage 4 of 4\n",
+        ].join("\u{000c}")
+    }
+
+    #[test]
+    fn removes_boilerplate_page_numbers_and_blank_lines() {
+        let document = paginated_document();
+        let cleaned = clean(&document);
+
+        assert_eq!(
+            cleaned.text,
+            "We will build more houses.\n\nWe will fix the roads.\n\nHealthcare will be free.\n\nSchools will get more teachers.\n\nTaxes will stay the same.\n"
+        );
+        assert_eq!(cleaned.removed_boilerplate, vec!["THE EXAMPLE PARTY MANIFESTO", "The Example Party - Page 1"]);
+        assert_eq!(cleaned.removed_page_numbers, 2);
+    }
+
+    #[test]
+    fn reports_the_token_delta() {
+        let document = paginated_document();
+        let cleaned = clean(&document);
+
+        assert_eq!(cleaned.tokens_before, chunking::estimate_tokens(&document));
+        assert_eq!(cleaned.tokens_after, chunking::estimate_tokens(&cleaned.text));
+        assert_eq!(cleaned.tokens_saved(), cleaned.tokens_before - cleaned.tokens_after);
+        assert!(cleaned.tokens_saved() > 40, "Only saved {} tokens", cleaned.tokens_saved());
+        assert!(format_report(&cleaned).starts_with(&format!("Cleaning saves ~{} tokens", cleaned.tokens_saved())));
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let document = paginated_document();
+
+        assert_eq!(clean(&document), clean(&document));
+    }
+
+    #[test]
+    fn leaves_repeated_lines_alone_without_pages() {
+        let document = "We will.\n\nWe will.\n\nWe will.\n";
+
+        assert_eq!(clean(document).text, document);
+    }
+
+    #[test]
+    fn recognises_page_numbers() {
+        let page_number = Regex::new(PAGE_NUMBER_PATTERN).unwrap();
+
+        for line in ["12", "  Page 12 ", "page 3 of 40", "- 7 -", "12/40"] {
+            assert!(page_number.is_match(line), "Should have matched {:?}", line);
+        }
+
+        for line in ["12 new hospitals", "Page one", "2024 is the year"] {
+            assert!(!page_number.is_match(line), "Shouldn't have matched {:?}", line);
+        }
+    }
+}
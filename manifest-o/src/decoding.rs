@@ -0,0 +1,235 @@
+// Turns input files into UTF-8 text. Files exported from older systems are often Latin-1 or
+// UTF-16, so the encoding is detected (or can be picked with --encoding) instead of the files
+// having to be converted by hand.
+
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Larger inputs are refused rather than read into memory
+pub const DEFAULT_MAX_INPUT_BYTES: u64 = 20 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    // Set with --encoding. Without it, the encoding is detected.
+    pub encoding: Option<&'static Encoding>,
+    // Set with --max-input-bytes
+    pub max_bytes: u64,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            encoding: None,
+            max_bytes: DEFAULT_MAX_INPUT_BYTES,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub text: String,
+    // The encoding the text was decoded from, whether it was given or detected
+    pub encoding: &'static Encoding,
+}
+
+pub fn read_input(path: &Path, options: &ReadOptions) -> Result<Decoded, String> {
+    decode_input(&read_limited(path, options.max_bytes)?, options.encoding)
+}
+
+// Reads the whole file, as long as it's no bigger than [max_bytes]
+pub fn read_limited(path: &Path, max_bytes: u64) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|e| format!("couldn't open the file: {}", e))?;
+    let too_large = |len: u64| format!(
+        "the file is {} bytes, which is more than the limit of {}; raise it with --max-input-bytes",
+        len, max_bytes
+    );
+
+    if let Ok(metadata) = file.metadata() {
+        if metadata.len() > max_bytes {
+            return Err(too_large(metadata.len()));
+        }
+    }
+
+    // The file could grow after checking its size, so never read past the limit either way
+    let mut bytes = Vec::new();
+    file.take(max_bytes + 1).read_to_end(&mut bytes)
+        .map_err(|e| format!("couldn't read the file: {}", e))?;
+
+    if bytes.len() as u64 > max_bytes {
+        return Err(too_large(bytes.len() as u64));
+    }
+
+    Ok(bytes)
+}
+
+// Decodes the input as [encoding] if given, or otherwise as whatever its byte order mark says,
+// as UTF-8 if it's valid UTF-8, or failing all that as Windows-1252 (a superset of Latin-1 that
+// can decode any bytes). Control characters other than whitespace are dropped, since they're
+// only noise to the model.
+pub fn decode_input(bytes: &[u8], encoding: Option<&'static Encoding>) -> Result<Decoded, String> {
+    let encoding = encoding.unwrap_or_else(|| detect_encoding(bytes));
+    let text = decode(bytes, encoding)?;
+
+    Ok(Decoded {
+        text: strip_control_chars(&text),
+        encoding,
+    })
+}
+
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((bom_encoding, _)) = Encoding::for_bom(bytes) {
+        return bom_encoding;
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        UTF_8
+    } else {
+        WINDOWS_1252
+    }
+}
+
+// Drops control characters other than whitespace (form feeds between pages are kept)
+pub fn strip_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || c.is_whitespace())
+        .collect()
+}
+
+// Looks up an encoding by any of its WHATWG labels (e.g. "utf-8", "latin1", "utf-16le")
+pub fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+// Decodes [bytes] as [encoding], dropping a leading byte order mark for that encoding. Any
+// sequence that isn't valid in the encoding is an error rather than being replaced, so that a
+// wrong --encoding doesn't silently produce garbage.
+pub fn decode(bytes: &[u8], encoding: &'static Encoding) -> Result<String, String> {
+    let bytes = match Encoding::for_bom(bytes) {
+        Some((bom_encoding, bom_len)) if bom_encoding == encoding => &bytes[bom_len..],
+        _ => bytes,
+    };
+
+    encoding.decode_without_bom_handling_and_without_replacement(bytes)
+        .map(|text| text.into_owned())
+        .ok_or_else(|| match first_invalid_offset(bytes, encoding) {
+            Some(offset) => format!("the file isn't valid {} (first invalid byte at offset {})", encoding.name(), offset),
+            None => format!("the file isn't valid {}", encoding.name()),
+        })
+}
+
+// Decodes until the first malformed sequence, to say where it is
+fn first_invalid_offset(bytes: &[u8], encoding: &'static Encoding) -> Option<usize> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut output = String::with_capacity(decoder.max_utf8_buffer_length(bytes.len())?);
+
+    match decoder.decode_to_string_without_replacement(bytes, &mut output, true) {
+        (encoding_rs::DecoderResult::Malformed(bad_len, consumed_after), read) =>
+            Some(read - bad_len as usize - consumed_after as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use encoding_rs::UTF_16LE;
+    use std::fs;
+
+    #[test]
+    fn looks_up_labels() {
+        assert_eq!(encoding_for_label("UTF-8"), Some(UTF_8));
+        assert_eq!(encoding_for_label("latin1"), Some(WINDOWS_1252));
+        assert_eq!(encoding_for_label("utf-16le"), Some(UTF_16LE));
+        assert_eq!(encoding_for_label("klingon"), None);
+    }
+
+    #[test]
+    fn decodes_latin1() {
+        let bytes = b"Caf\xe9 cr\xe8me";
+
+        assert_eq!(decode(bytes, WINDOWS_1252), Ok(String::from("Café crème")));
+    }
+
+    #[test]
+    fn decodes_utf16_with_and_without_a_bom() {
+        let mut bytes: Vec<u8> = "Hé".encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+
+        assert_eq!(decode(&bytes, UTF_16LE), Ok(String::from("Hé")));
+
+        bytes.splice(0..0, [0xff, 0xfe]);
+        assert_eq!(decode(&bytes, UTF_16LE), Ok(String::from("Hé")));
+    }
+
+    #[test]
+    fn strips_a_utf8_bom() {
+        assert_eq!(decode(b"\xef\xbb\xbfhello", UTF_8), Ok(String::from("hello")));
+    }
+
+    #[test]
+    fn rejects_invalid_sequences_with_their_offset() {
+        match decode(b"Caf\xe9", UTF_8) {
+            Err(e) => assert_eq!(e, "the file isn't valid UTF-8 (first invalid byte at offset 3)"),
+            Ok(text) => panic!("Should have rejected invalid UTF-8 but got {}", text),
+        }
+    }
+
+    fn fixture(dir: &tempfile::TempDir, name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn falls_back_to_windows_1252_for_latin1_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "latin1.txt", b"Caf\xe9 cr\xe8me\n");
+
+        assert_eq!(read_input(&path, &ReadOptions::default()), Ok(Decoded {
+            text: String::from("Café crème\n"),
+            encoding: WINDOWS_1252,
+        }));
+    }
+
+    #[test]
+    fn detects_bom_utf8_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "bom.txt", "\u{feff}Café".as_bytes());
+
+        assert_eq!(read_input(&path, &ReadOptions::default()), Ok(Decoded {
+            text: String::from("Café"),
+            encoding: UTF_8,
+        }));
+    }
+
+    #[test]
+    fn explicit_encodings_are_strict() {
+        match decode_input(b"Caf\xe9", Some(UTF_8)) {
+            Err(_) => {}
+            Ok(decoded) => panic!("Should have rejected invalid UTF-8 but got {}", decoded.text),
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "big.txt", &[b'a'; 1025]);
+        let options = ReadOptions { max_bytes: 1024, ..ReadOptions::default() };
+
+        match read_input(&path, &options) {
+            Err(e) => assert!(e.contains("1025 bytes"), "Unexpected error: {}", e),
+            Ok(_) => panic!("Should have refused the oversized file"),
+        }
+
+        let exact = fixture(&dir, "exact.txt", &[b'a'; 1024]);
+        assert!(read_input(&exact, &options).is_ok());
+    }
+
+    #[test]
+    fn strips_control_characters_but_not_whitespace() {
+        let decoded = decode_input(b"Page\x00 one\x07\tend\r\n\x0cPage two", None).unwrap();
+
+        assert_eq!(decoded.text, "Page one\tend\r\n\x0cPage two");
+    }
+}
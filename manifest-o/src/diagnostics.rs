@@ -0,0 +1,73 @@
+// Warnings and errors from along the run, printed to stderr as they happen and also kept, so that
+// --json can report them. That's --json's promise to scripts: stdout carries exactly one JSON
+// document, whatever happens. Rather than being printed straight away, the report is held here
+// (see [hold_report]), and main prints it once the run is over with the warnings and errors
+// added, or prints an object with just those if the run failed before it had a report.
+// Notes about what the run is doing aren't kept, and are left out altogether with --quiet or
+// --json (see [set_quiet]).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+struct Collected {
+    report: Option<serde_json::Value>,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+}
+
+static COLLECTED: Mutex<Collected> = Mutex::new(Collected { report: None, warnings: Vec::new(), errors: Vec::new() });
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Whether [note]s are left out, as they are under --quiet and --json
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+// Prints a note about what the run is doing (e.g. what cleaning saved), unless [set_quiet]
+pub fn note(message: impl AsRef<str>) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{}", message.as_ref());
+    }
+}
+
+// Prints a warning, and keeps it for the --json output
+pub fn warn(message: impl Into<String>) {
+    let message = message.into();
+    eprintln!("Warning: {}", message);
+    record_warning(message);
+}
+
+// Keeps a warning for the --json output, for warnings that print themselves
+pub fn record_warning(message: impl Into<String>) {
+    COLLECTED.lock().unwrap().warnings.push(message.into());
+}
+
+// Prints why something failed, and keeps it for the --json output
+pub fn error(message: impl Into<String>) {
+    let message = message.into();
+    eprintln!("{}", message);
+    COLLECTED.lock().unwrap().errors.push(message);
+}
+
+// Holds the --json report back until the run is over (see [json_document])
+pub fn hold_report(output: &str) {
+    let report = serde_json::from_str(output).expect("--json output should always be JSON");
+    COLLECTED.lock().unwrap().report = Some(report);
+}
+
+// The one JSON document --json prints: the report (or, without one, an empty object) with every
+// warning and error added, the last error being why the run failed
+pub fn json_document(result: Result<(), &str>) -> String {
+    let mut collected = COLLECTED.lock().unwrap();
+    let mut errors = std::mem::take(&mut collected.errors);
+    errors.extend(result.err().map(String::from));
+
+    let mut report = match collected.report.take() {
+        Some(report @ serde_json::Value::Object(_)) => report,
+        _ => serde_json::json!({}),
+    };
+    report["warnings"] = serde_json::json!(std::mem::take(&mut collected.warnings));
+    report["errors"] = serde_json::json!(errors);
+
+    serde_json::to_string_pretty(&report).expect("JSON values should always serialize")
+}
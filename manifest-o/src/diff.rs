@@ -0,0 +1,393 @@
+// Summaries of what changed between two versions of a manifesto (--diff-from). The versions are
+// compared paragraph by paragraph (a longest common subsequence, so moved paragraphs show up as
+// removed in one place and added in another), and only the paragraphs that changed are sent to
+// the model, with a note of how much was left alone. Versions that are the same are reported as
+// such without asking the model, and when they're largely the same, it's told so, so that it
+// doesn't make much of a few reworded sentences.
+//
+// Summaries can also be compared with the one saved from an older version (--diff-against),
+// word by word, and with --diff-llm, by the model in a paragraph.
+
+use serde::Serialize;
+use crate::error::ManifestoError;
+use crate::open_ai::GPT_4_MODEL_NAME;
+use crate::summary;
+use crate::transport::ChatTransport;
+
+const DIFF_SYSTEM_PROMPT: &str = "You are a political journalist who tracks how parties' manifestos change between elections";
+const DIFF_INSTRUCTION: &str = "Below are the paragraphs that changed between two versions of a manifesto: lines starting with \"-\" were removed from the old version and lines starting with \"+\" were added in the new one, and everything else stayed the same. Summarise what changed in the party's policies and priorities, grouped by topic. Only describe changes that are in the diff. If the changes are only to wording, formatting or figures that don't change a policy, say that the versions are largely the same rather than describing them as changes of policy.";
+const SUMMARY_DIFF_INSTRUCTION: &str = "Below are two summaries of different versions of the same manifesto, the old one first. In one paragraph, describe what changed between them: policies that were added, dropped or changed. Only describe what the summaries say. If they say the same things in different words, say that nothing of substance changed.";
+// Given instead of a summary when nothing changed
+pub const IDENTICAL_NOTE: &str = "The two versions are the same: nothing changed.";
+
+// At least this share of the words unchanged and the model is told the versions are largely
+// the same
+const LARGELY_THE_SAME: f64 = 0.9;
+
+#[derive(Debug, PartialEq)]
+pub enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl<'a> Change<'a> {
+    fn text(&self) -> &'a str {
+        match self {
+            Change::Same(text) | Change::Removed(text) | Change::Added(text) => text,
+        }
+    }
+}
+
+// See [word_diff]
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct WordDiff {
+    pub marked_up: String,
+    pub unchanged_words: usize,
+    pub deleted_words: usize,
+    pub inserted_words: usize,
+}
+
+// How many paragraphs changed, for the --json report
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiffStats {
+    pub unchanged: usize,
+    pub removed: usize,
+    pub added: usize,
+    // The share of the new version's words that were in the old version too
+    pub unchanged_share: f64,
+}
+
+pub struct ChangeSummary {
+    pub summary: String,
+    pub stats: DiffStats,
+}
+
+// Summarises how [new] differs from [old]
+pub fn summarise_changes(transport: &impl ChatTransport, old: &str, new: &str) -> Result<ChangeSummary, ManifestoError> {
+    let changes = diff_paragraphs(old, new);
+    let stats = stats(&changes);
+
+    if stats.removed == 0 && stats.added == 0 {
+        return Ok(ChangeSummary { summary: String::from(IDENTICAL_NOTE), stats });
+    }
+
+    let instruction = match stats.unchanged_share >= LARGELY_THE_SAME {
+        true => format!(
+            "{} The versions are largely the same: {:.0}% of the new version's words are unchanged.",
+            DIFF_INSTRUCTION,
+            stats.unchanged_share * 100.0
+        ),
+        false => String::from(DIFF_INSTRUCTION),
+    };
+
+    let summary = summary::complete(transport, GPT_4_MODEL_NAME, DIFF_SYSTEM_PROMPT, &instruction, &format_diff(&changes), 1, false)?
+        .swap_remove(0);
+
+    Ok(ChangeSummary { summary, stats })
+}
+
+// The paragraphs of [old] and [new], in order, each marked as the same in both, removed from
+// [old] or added in [new]. Paragraphs that differ only in whitespace count as the same.
+pub fn diff_paragraphs<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    diff_by(&paragraphs(old), &paragraphs(new), normalise)
+}
+
+// [old] and [new] marked up word by word, with each run of removed words [-like this-] and
+// each run of added words {+like this+}, as `git diff --word-diff` does. The new text's line
+// breaks are kept.
+pub fn word_diff(old: &str, new: &str) -> WordDiff {
+    let changes = diff_by(&words(old), &words(new), |word| String::from(word.trim_end()));
+    let mut diff = WordDiff::default();
+    let mut i = 0;
+
+    while i < changes.len() {
+        let run: Vec<&Change> = changes[i..].iter()
+            .take_while(|change| std::mem::discriminant(*change) == std::mem::discriminant(&changes[i]))
+            .collect();
+        i += run.len();
+
+        let text: String = run.iter().map(|change| change.text()).collect();
+        // The whitespace after the run goes outside its markers
+        let (words, space) = text.split_at(text.trim_end().len());
+
+        match run[0] {
+            Change::Same(_) => {
+                diff.unchanged_words += run.len();
+                diff.marked_up.push_str(&text);
+            }
+            Change::Removed(_) => {
+                diff.deleted_words += run.len();
+                // An added run straight after takes the removed one's place, so it goes right up
+                // against it, and otherwise the old text's line breaks don't matter
+                let space = match changes.get(i) {
+                    Some(Change::Added(_)) | None => "",
+                    Some(_) => " ",
+                };
+                // The new text can end without the space the old one had before these words
+                if !diff.marked_up.is_empty() && !diff.marked_up.ends_with(char::is_whitespace) {
+                    diff.marked_up.push(' ');
+                }
+
+                diff.marked_up.push_str(&format!("[-{}-]{}", words, space));
+            }
+            Change::Added(_) => {
+                diff.inserted_words += run.len();
+                diff.marked_up.push_str(&format!("{{+{}+}}{}", words, space));
+            }
+        }
+    }
+
+    diff.marked_up.truncate(diff.marked_up.trim_end().len());
+    diff
+}
+
+// Asks the model what changed between two summaries, in a paragraph
+pub fn describe_summary_changes(transport: &impl ChatTransport, old_summary: &str, new_summary: &str) -> Result<String, ManifestoError> {
+    let text = format!("=== Old summary ===\n{}\n\n=== New summary ===\n{}", old_summary.trim(), new_summary.trim());
+
+    Ok(summary::complete(transport, GPT_4_MODEL_NAME, DIFF_SYSTEM_PROMPT, SUMMARY_DIFF_INSTRUCTION, &text, 1, false)?.swap_remove(0))
+}
+
+// The longest common subsequence of [old] and [new], comparing them by [key]
+fn diff_by<'a, K: PartialEq>(old: &[&'a str], new: &[&'a str], key: impl Fn(&str) -> K) -> Vec<Change<'a>> {
+    let old_keys: Vec<K> = old.iter().map(|item| key(item)).collect();
+    let new_keys: Vec<K> = new.iter().map(|item| key(item)).collect();
+
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0_u32; new.len() + 1]; old.len() + 1];
+
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old_keys[i] == new_keys[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old_keys[i] == new_keys[j] {
+            changes.push(Change::Same(new[j]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    changes
+}
+
+// Only the paragraphs that changed, with each line marked "-" or "+", and a note of how many
+// unchanged paragraphs were left out between them
+pub fn format_diff(changes: &[Change]) -> String {
+    let mut blocks: Vec<String> = Vec::new();
+    let mut unchanged = 0;
+
+    for change in changes {
+        let (marker, paragraph) = match change {
+            Change::Same(_) => {
+                unchanged += 1;
+                continue;
+            }
+            Change::Removed(paragraph) => ("-", paragraph),
+            Change::Added(paragraph) => ("+", paragraph),
+        };
+
+        if unchanged > 0 {
+            blocks.push(format!("[{} unchanged paragraph(s)]", unchanged));
+            unchanged = 0;
+        }
+
+        blocks.push(paragraph.lines().map(|line| format!("{} {}", marker, line)).collect::<Vec<String>>().join("\n"));
+    }
+
+    if unchanged > 0 {
+        blocks.push(format!("[{} unchanged paragraph(s)]", unchanged));
+    }
+
+    blocks.join("\n\n")
+}
+
+fn stats(changes: &[Change]) -> DiffStats {
+    let mut stats = DiffStats::default();
+    let (mut unchanged_words, mut new_words) = (0, 0);
+
+    for change in changes {
+        match change {
+            Change::Same(paragraph) => {
+                stats.unchanged += 1;
+                unchanged_words += paragraph.split_whitespace().count();
+                new_words += paragraph.split_whitespace().count();
+            }
+            Change::Removed(_) => stats.removed += 1,
+            Change::Added(paragraph) => {
+                stats.added += 1;
+                new_words += paragraph.split_whitespace().count();
+            }
+        }
+    }
+
+    stats.unchanged_share = match new_words {
+        0 => 0.0,
+        _ => unchanged_words as f64 / new_words as f64,
+    };
+
+    stats
+}
+
+fn paragraphs(text: &str) -> Vec<&str> {
+    text.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect()
+}
+
+// Each word with the whitespace after it, so that the words can be put back together as they were
+fn words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = text.len() - text.trim_start().len();
+
+    while start < text.len() {
+        let word_len = text[start..].find(char::is_whitespace).unwrap_or(text.len() - start);
+        let rest = &text[start + word_len..];
+        let end = start + word_len + (rest.len() - rest.trim_start().len());
+
+        words.push(&text[start..end]);
+        start = end;
+    }
+
+    words
+}
+
+fn normalise(paragraph: &str) -> String {
+    paragraph.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    const OLD: &str = "# Health\n\nWe will hire 5,000 nurses.\n\n# Housing\n\nWe will build 100,000 homes.\n\nRents will be capped.";
+    const NEW: &str = "# Health\n\nWe will hire 10,000 nurses.\n\n# Housing\n\nWe will build   100,000 homes.\n\nRents will be capped.\n\n# Transport\n\nBuses will be free.";
+
+    #[test]
+    fn diffs_paragraphs() {
+        assert_eq!(diff_paragraphs(OLD, NEW), vec![
+            Change::Same("# Health"),
+            Change::Removed("We will hire 5,000 nurses."),
+            Change::Added("We will hire 10,000 nurses."),
+            Change::Same("# Housing"),
+            Change::Same("We will build   100,000 homes."),
+            Change::Same("Rents will be capped."),
+            Change::Added("# Transport"),
+            Change::Added("Buses will be free."),
+        ]);
+    }
+
+    #[test]
+    fn formats_only_the_changes() {
+        assert_eq!(
+            format_diff(&diff_paragraphs(OLD, NEW)),
+            "[1 unchanged paragraph(s)]\n\n- We will hire 5,000 nurses.\n\n+ We will hire 10,000 nurses.\n\n\
+             [3 unchanged paragraph(s)]\n\n+ # Transport\n\n+ Buses will be free."
+        );
+    }
+
+    #[test]
+    fn sends_the_diff_with_the_instruction() {
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Nurses doubled; buses are new."));
+
+        let changes = summarise_changes(&transport, OLD, NEW).expect("should have summarised the changes");
+
+        assert_eq!(changes.summary, "Nurses doubled; buses are new.");
+        assert_eq!((changes.stats.unchanged, changes.stats.removed, changes.stats.added), (4, 1, 3));
+
+        let request = &transport.requests()[0];
+        let instruction = request["messages"][1]["content"].as_str().unwrap();
+        let diff = request["messages"][2]["content"].as_str().unwrap();
+        assert_eq!(instruction, DIFF_INSTRUCTION);
+        assert!(diff.contains("+ Buses will be free."));
+        assert!(!diff.contains("Rents will be capped."));
+    }
+
+    const OLD_SUMMARY: &str = include_str!("../fixtures/summary_2019.txt");
+    const NEW_SUMMARY: &str = include_str!("../fixtures/summary_2024.txt");
+
+    #[test]
+    fn marks_up_changed_words() {
+        let diff = word_diff(OLD_SUMMARY, NEW_SUMMARY);
+
+        assert_eq!(
+            diff.marked_up,
+            "The Example Party promises to hire [-5,000-]{+10,000+} nurses and to build 100,000 homes a year.\n\n\
+             It would cap rents for new tenancies and [-scrap tuition fees.-]{+make buses free for under-18s.+}"
+        );
+        assert_eq!((diff.unchanged_words, diff.deleted_words, diff.inserted_words), (22, 4, 6));
+    }
+
+    #[test]
+    fn marks_up_pure_insertions_and_deletions() {
+        assert_eq!(word_diff("Free buses.", "Free buses and trains.").marked_up, "Free [-buses.-]{+buses and trains.+}");
+        assert_eq!(word_diff("Free buses for all.", "Free buses.").marked_up, "Free [-buses for all.-]{+buses.+}");
+        assert_eq!(word_diff("Free buses for all.", "Free buses for everyone under 18 for all.").marked_up, "Free buses for {+everyone under 18 for+} all.");
+        assert_eq!(word_diff("Cheap and free buses.", "Free buses.").marked_up, "[-Cheap and free-]{+Free+} buses.");
+        assert_eq!(word_diff("Vote for us. Please.", "Vote for us.").marked_up, "Vote for us. [-Please.-]");
+    }
+
+    #[test]
+    fn unchanged_summaries_have_no_markup() {
+        let diff = word_diff(OLD_SUMMARY, OLD_SUMMARY);
+
+        assert_eq!(diff.marked_up, OLD_SUMMARY.trim_end());
+        assert_eq!((diff.deleted_words, diff.inserted_words), (0, 0));
+    }
+
+    #[test]
+    fn sends_both_summaries_to_describe_the_changes() {
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Nurses doubled; fees stay."));
+
+        let described = describe_summary_changes(&transport, OLD_SUMMARY, NEW_SUMMARY).expect("should have described the changes");
+
+        assert_eq!(described, "Nurses doubled; fees stay.");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request["messages"][0]["content"], DIFF_SYSTEM_PROMPT);
+        assert_eq!(request["messages"][1]["content"], SUMMARY_DIFF_INSTRUCTION);
+        assert_eq!(
+            request["messages"][2]["content"],
+            format!("=== Old summary ===\n{}\n\n=== New summary ===\n{}", OLD_SUMMARY.trim(), NEW_SUMMARY.trim())
+        );
+    }
+
+    #[test]
+    fn identical_versions_are_reported_without_the_model() {
+        let transport = MockTransport::new();
+
+        let changes = summarise_changes(&transport, OLD, &OLD.replace("\n\n", "\n\n\n")).expect("should have compared the versions");
+
+        assert_eq!(changes.summary, IDENTICAL_NOTE);
+        assert!(transport.requests().is_empty());
+    }
+
+    #[test]
+    fn tells_the_model_when_the_versions_are_largely_the_same() {
+        let shared = (1..=20).map(|n| format!("Policy number {} stays exactly as it was before.", n)).collect::<Vec<String>>().join("\n\n");
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("The versions are largely the same."));
+
+        summarise_changes(&transport, &format!("{}\n\nVote for us.", shared), &format!("{}\n\nPlease vote for us.", shared))
+            .expect("should have summarised the changes");
+
+        let request = &transport.requests()[0];
+        assert!(request["messages"][1]["content"].as_str().unwrap().contains("The versions are largely the same: 9"));
+    }
+}
@@ -0,0 +1,247 @@
+// The `embed` and `similar` subcommands: embedding passages from manifestos into a local index
+// and searching it for the passages most similar to a query.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use crate::arg_parsing::{EmbedArgs, SimilarArgs};
+use crate::chunking;
+use crate::error::ManifestoError;
+use crate::open_ai::*;
+use crate::transport::{self, ChatTransport, ReqwestTransport};
+
+// Passages are kept small so that each one is about a single topic
+const PASSAGE_TOKENS: usize = 200;
+
+// One line of the JSONL index
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub source: String,
+    pub passage: String,
+    pub embedding: Vec<f32>,
+}
+
+pub fn run_embed_command(args: EmbedArgs) -> Result<(), &'static str> {
+    let transport = ReqwestTransport::new(
+        crate::build_openai_client(&args.openai_key, &args.attribution, None, 1, crate::DEFAULT_POOL_IDLE_TIMEOUT, false),
+        transport::OPENAI_BASE_URL,
+        None,
+        false,
+    );
+
+    let mut documents: Vec<(String, String)> = Vec::new();
+
+    for path in &args.file_paths {
+        let contents = fs::read_to_string(path).map_err(|_| "Failed to read a file to embed")?;
+        documents.push((path.clone(), contents));
+    }
+
+    let entries = embed_documents(&transport, &documents, args.batch_size)
+        .map_err(|e| {
+            eprintln!("{}", e);
+            "Failed to embed the documents"
+        })?;
+
+    append_to_index(&args.index_path, &entries).map_err(|_| "Failed to write the index")?;
+
+    let usage = transport.total_usage();
+    eprintln!(
+        "Embedded {} passages from {} files into {} ({} tokens)",
+        entries.len(), documents.len(), args.index_path, usage.total_tokens
+    );
+
+    Ok(())
+}
+
+pub fn run_similar_command(args: SimilarArgs) -> Result<(), &'static str> {
+    let index = read_index(&args.index_path).map_err(|_| "Failed to read the index")?;
+
+    let transport = ReqwestTransport::new(
+        crate::build_openai_client(&args.openai_key, &args.attribution, None, 1, crate::DEFAULT_POOL_IDLE_TIMEOUT, false),
+        transport::OPENAI_BASE_URL,
+        None,
+        false,
+    );
+
+    let query_embedding = embed_batch(&transport, &[args.query.as_str()])
+        .map_err(|e| {
+            eprintln!("{}", e);
+            "Failed to embed the query"
+        })?
+        .swap_remove(0);
+
+    for (score, entry) in most_similar(&index, &query_embedding, args.top_k) {
+        println!("[{:.3}] {}\n{}\n", score, entry.source, entry.passage.trim());
+    }
+
+    Ok(())
+}
+
+// Splits each (source, contents) document into passages and embeds them, [batch_size] passages
+// per request.
+pub fn embed_documents(transport: &impl ChatTransport, documents: &[(String, String)], batch_size: usize) -> Result<Vec<IndexEntry>, ManifestoError> {
+    let passages: Vec<(&str, String)> = documents.iter()
+        .flat_map(|(source, contents)| {
+            chunking::split_into_chunks(contents, PASSAGE_TOKENS)
+                .into_iter()
+                .map(move |passage| (source.as_str(), passage))
+        })
+        .collect();
+
+    let mut entries = Vec::with_capacity(passages.len());
+
+    for batch in passages.chunks(batch_size.max(1)) {
+        let inputs: Vec<&str> = batch.iter().map(|(_, passage)| passage.as_str()).collect();
+        let embeddings = embed_batch(transport, &inputs)?;
+
+        for ((source, passage), embedding) in batch.iter().zip(embeddings) {
+            entries.push(IndexEntry {
+                source: String::from(*source),
+                passage: passage.clone(),
+                embedding,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+// Embeds every input in one request, returning the embeddings in the same order as the inputs
+fn embed_batch(transport: &impl ChatTransport, inputs: &[&str]) -> Result<Vec<Vec<f32>>, ManifestoError> {
+    let response = transport.post_embeddings(&EmbeddingRequest {
+        model: EMBEDDING_MODEL_NAME,
+        input: inputs.to_vec(),
+    })?;
+
+    let mut embeddings: Vec<Option<Vec<f32>>> = vec![None; inputs.len()];
+
+    for embedding in response.data {
+        if let Some(slot) = embeddings.get_mut(embedding.index) {
+            *slot = Some(embedding.embedding);
+        }
+    }
+
+    embeddings.into_iter()
+        .collect::<Option<Vec<Vec<f32>>>>()
+        .ok_or(ManifestoError::EmptyResponse)
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+// The [k] entries closest to the query, most similar first
+pub fn most_similar<'a>(index: &'a [IndexEntry], query: &[f32], k: usize) -> Vec<(f32, &'a IndexEntry)> {
+    let mut scored: Vec<(f32, &IndexEntry)> = index.iter()
+        .map(|entry| (cosine_similarity(&entry.embedding, query), entry))
+        .collect();
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.truncate(k);
+    scored
+}
+
+fn append_to_index(path: &str, entries: &[IndexEntry]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    for entry in entries {
+        let line = serde_json::to_string(entry).expect("index entries should always serialize");
+        writeln!(file, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+fn read_index(path: &str) -> io::Result<Vec<IndexEntry>> {
+    BufReader::new(fs::File::open(path)?)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| serde_json::from_str(&line?).map_err(io::Error::other))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    fn entry(source: &str, embedding: &[f32]) -> IndexEntry {
+        IndexEntry {
+            source: String::from(source),
+            passage: format!("Passage from {}", source),
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    #[test]
+    fn cosine_similarity_math() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[1.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[3.0, 4.0], &[6.0, 8.0]) - 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[1.0, 0.0]) - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn ranks_by_similarity() {
+        let index = vec![
+            entry("far.txt", &[0.0, 1.0]),
+            entry("closest.txt", &[1.0, 0.1]),
+            entry("close.txt", &[1.0, 0.5]),
+        ];
+
+        let ranked: Vec<&str> = most_similar(&index, &[1.0, 0.0], 2).iter()
+            .map(|(_, entry)| entry.source.as_str())
+            .collect();
+
+        assert_eq!(ranked, vec!["closest.txt", "close.txt"]);
+    }
+
+    #[test]
+    fn embeds_passages_in_batches() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::embeddings(&[&[1.0, 0.0], &[0.0, 1.0]]))
+            .respond(200, &fixtures::embeddings(&[&[0.5, 0.5]]));
+        let documents = vec![
+            (String::from("a.txt"), String::from("Healthcare for all.")),
+            (String::from("b.txt"), String::from("Jobs for all.")),
+            (String::from("c.txt"), String::from("Houses for all.")),
+        ];
+
+        let entries = embed_documents(&transport, &documents, 2)
+            .expect("should have embedded the documents");
+
+        assert_eq!(entries, vec![
+            IndexEntry { source: String::from("a.txt"), passage: String::from("Healthcare for all."), embedding: vec![1.0, 0.0] },
+            IndexEntry { source: String::from("b.txt"), passage: String::from("Jobs for all."), embedding: vec![0.0, 1.0] },
+            IndexEntry { source: String::from("c.txt"), passage: String::from("Houses for all."), embedding: vec![0.5, 0.5] },
+        ]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["model"], EMBEDDING_MODEL_NAME);
+        assert_eq!(requests[0]["input"], serde_json::json!(["Healthcare for all.", "Jobs for all."]));
+        assert_eq!(transport.total_usage().prompt_tokens, 15);
+    }
+
+    #[test]
+    fn index_round_trips_through_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        let path = path.to_str().unwrap();
+
+        append_to_index(path, &[entry("a.txt", &[1.0, 0.0])]).unwrap();
+        append_to_index(path, &[entry("b.txt", &[0.0, 1.0])]).unwrap();
+
+        assert_eq!(read_index(path).unwrap(), vec![entry("a.txt", &[1.0, 0.0]), entry("b.txt", &[0.0, 1.0])]);
+    }
+}
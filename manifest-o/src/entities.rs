@@ -0,0 +1,325 @@
+// A glossary of the names and acronyms a manifesto mentions, for --appendix. It's made locally,
+// without the model: runs of two or more capitalised words are taken as names (of parties, people,
+// programmes and the like), and short ALL-CAPS words as acronyms, expanded when the document
+// spells one out next to it, as in "National Health Service (NHS)" or "GDP (Gross Domestic
+// Product)". It's a single pass over the text, and the same document always gives the same
+// appendix.
+
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// How many entities the appendix lists, most mentioned first
+pub const MAX_ENTITIES: usize = 50;
+
+// Names can't run across these (or a blank line)
+const BREAK_PATTERN: &str = r#"[.,;:!?()\[\]{}"“”/|—–]+|\n[ \t]*\n"#;
+// An acronym followed by its expansion in brackets, and an acronym in brackets after its
+// expansion
+const EXPANSION_AFTER_PATTERN: &str = r"\b([A-Z][A-Z0-9&]*[A-Z0-9])\s*\(([^()\n]{3,80})\)";
+const EXPANSION_BEFORE_PATTERN: &str = r"\(([A-Z][A-Z0-9&]*[A-Z0-9])\)";
+
+// Lowercase words that can sit inside a name, as in "Department for Education". "and" isn't one,
+// so that "Labour and Plaid Cymru" is two names.
+const NAME_CONNECTORS: &[&str] = &["of", "for", "the", "&"];
+// Words that an acronym's letters can skip, as in "research and development (R&D)"
+const CONNECTORS: &[&str] = &["of", "for", "the", "and", "on", "in", "to", "&"];
+// Words that are capitalised because they start a sentence, not because they're part of a name
+const SENTENCE_STARTERS: &[&str] = &[
+    "a", "after", "all", "also", "an", "and", "as", "at", "before", "but", "by", "each", "every",
+    "for", "from", "here", "how", "i", "if", "in", "it", "its", "my", "no", "not", "now", "of",
+    "on", "our", "since", "so", "some", "that", "the", "their", "then", "there", "these", "they",
+    "this", "those", "through", "to", "under", "we", "what", "when", "while", "who", "why",
+    "with", "you", "your",
+];
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityKind {
+    Name,
+    Acronym,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Entity {
+    pub name: String,
+    pub kind: EntityKind,
+    // How many times it's mentioned. An acronym's count includes its spelled-out form.
+    pub count: usize,
+    // What an acronym stands for, when the document says
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expansion: Option<String>,
+}
+
+// The names and acronyms in [text], most mentioned first (then alphabetically), up to
+// [MAX_ENTITIES]
+pub fn extract(text: &str) -> Vec<Entity> {
+    let mut names = count_names(text);
+    let acronyms = count_acronyms(text);
+    let expansions = find_expansions(text);
+
+    let mut entities: Vec<Entity> = acronyms.into_iter()
+        .map(|(acronym, count)| {
+            let expansion = expansions.get(&acronym).cloned();
+            // The spelled-out form is the same entity, so it's counted here rather than listed
+            let spelled_out = expansion.as_ref().and_then(|expansion| names.remove(expansion)).unwrap_or(0);
+
+            Entity { name: acronym, kind: EntityKind::Acronym, count: count + spelled_out, expansion }
+        })
+        .collect();
+
+    entities.extend(names.into_iter().map(|(name, count)| Entity { name, kind: EntityKind::Name, count, expansion: None }));
+    entities.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    entities.truncate(MAX_ENTITIES);
+
+    entities
+}
+
+// e.g.
+//
+//   ## Appendix: names and acronyms
+//
+//   - NHS (National Health Service): 4 mentions
+//   - Jane Smith: 2 mentions
+pub fn format_appendix(entities: &[Entity]) -> String {
+    let mut appendix = String::from("## Appendix: names and acronyms\n");
+
+    for entity in entities {
+        let name = match &entity.expansion {
+            Some(expansion) => format!("{} ({})", entity.name, expansion),
+            None => entity.name.clone(),
+        };
+        let mentions = if entity.count == 1 { "mention" } else { "mentions" };
+
+        appendix.push_str(&format!("\n- {}: {} {}", name, entity.count, mentions));
+    }
+
+    appendix
+}
+
+// Counts every run of two or more capitalised words (allowing connectors like "of" between
+// them), leaving out words that only start a sentence
+fn count_names(text: &str) -> BTreeMap<String, usize> {
+    let breaks = Regex::new(BREAK_PATTERN).expect("the break pattern should be valid");
+    let mut names: BTreeMap<String, usize> = BTreeMap::new();
+
+    for segment in breaks.split(text) {
+        let mut run: Vec<&str> = Vec::new();
+
+        for word in segment.split_whitespace().map(trim_word) {
+            if is_capitalised(word) || (!run.is_empty() && NAME_CONNECTORS.contains(&word)) {
+                run.push(word);
+            } else {
+                count_name(&mut names, &run);
+                run.clear();
+            }
+        }
+
+        count_name(&mut names, &run);
+    }
+
+    names
+}
+
+fn count_name(names: &mut BTreeMap<String, usize>, run: &[&str]) {
+    let mut run = run;
+
+    while let [first, rest @ ..] = run {
+        match SENTENCE_STARTERS.contains(&first.to_lowercase().as_str()) || NAME_CONNECTORS.contains(first) {
+            true => run = rest,
+            false => break,
+        }
+    }
+
+    while let [rest @ .., last] = run {
+        match NAME_CONNECTORS.contains(last) {
+            true => run = rest,
+            false => break,
+        }
+    }
+
+    if run.len() >= 2 {
+        *names.entry(run.join(" ")).or_insert(0) += 1;
+    }
+}
+
+// Counts the ALL-CAPS words, skipping lines that are all capitals (which are usually headings
+// rather than acronyms)
+fn count_acronyms(text: &str) -> BTreeMap<String, usize> {
+    let mut acronyms: BTreeMap<String, usize> = BTreeMap::new();
+
+    for line in text.lines().filter(|line| line.chars().any(char::is_lowercase)) {
+        for word in line.split_whitespace().map(trim_word) {
+            // Plurals, like GPs
+            let word = word.strip_suffix('s').filter(|singular| is_acronym(singular)).unwrap_or(word);
+
+            if is_acronym(word) {
+                *acronyms.entry(String::from(word)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    acronyms
+}
+
+// What each acronym stands for, from the first place the document spells it out
+fn find_expansions(text: &str) -> BTreeMap<String, String> {
+    let after = Regex::new(EXPANSION_AFTER_PATTERN).expect("the expansion pattern should be valid");
+    let before = Regex::new(EXPANSION_BEFORE_PATTERN).expect("the expansion pattern should be valid");
+    let mut found: Vec<(usize, String, String)> = Vec::new();
+
+    for captures in after.captures_iter(text) {
+        let (acronym, expansion) = (&captures[1], captures[2].trim());
+
+        if is_acronym(acronym) && initials_match(expansion, acronym) {
+            found.push((captures.get(0).unwrap().start(), String::from(acronym), String::from(expansion)));
+        }
+    }
+
+    for captures in before.captures_iter(text) {
+        let start = captures.get(0).unwrap().start();
+        let acronym = &captures[1];
+
+        if let Some(expansion) = is_acronym(acronym).then(|| expansion_before(&text[..start], acronym)).flatten() {
+            found.push((start, String::from(acronym), expansion));
+        }
+    }
+
+    found.sort();
+
+    let mut expansions: BTreeMap<String, String> = BTreeMap::new();
+
+    for (_, acronym, expansion) in found {
+        expansions.entry(acronym).or_insert(expansion);
+    }
+
+    expansions
+}
+
+// The words just before an acronym in brackets, if their initials spell it out
+fn expansion_before(before: &str, acronym: &str) -> Option<String> {
+    let letters = acronym.chars().filter(char::is_ascii_uppercase).count();
+    let mut words: Vec<&str> = Vec::new();
+    let mut significant = 0;
+
+    for word in before.split_whitespace().rev().take(letters * 2 + 2) {
+        words.push(word);
+
+        if !CONNECTORS.contains(&trim_word(word)) {
+            significant += 1;
+        }
+
+        if significant == letters {
+            break;
+        }
+    }
+
+    words.reverse();
+    let expansion = words.join(" ");
+    let expansion = expansion.trim_matches(|c: char| !c.is_alphanumeric());
+
+    (significant == letters && initials_match(expansion, acronym)).then(|| String::from(expansion))
+}
+
+// Whether the first letters of [expansion]'s words (other than connectors) are [acronym]'s letters
+fn initials_match(expansion: &str, acronym: &str) -> bool {
+    let initials = expansion.split_whitespace()
+        .map(trim_word)
+        .filter(|word| !word.is_empty() && !CONNECTORS.contains(word))
+        .map(|word| word.chars().next().unwrap().to_ascii_uppercase());
+
+    initials.eq(acronym.chars().filter(char::is_ascii_uppercase))
+}
+
+// Drops the punctuation around a word, and any possessive 's
+fn trim_word(word: &str) -> &str {
+    let word = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '&');
+
+    word.strip_suffix("'s").or_else(|| word.strip_suffix("’s")).unwrap_or(word)
+}
+
+// Starts with a capital and isn't all capitals (which would make it an acronym)
+fn is_capitalised(word: &str) -> bool {
+    word.chars().next().is_some_and(char::is_uppercase) && word.chars().any(char::is_lowercase)
+}
+
+// e.g. NHS, R&D or G7
+fn is_acronym(word: &str) -> bool {
+    (2..=8).contains(&word.len())
+        && word.starts_with(|c: char| c.is_ascii_uppercase())
+        && !word.ends_with('&')
+        && word.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '&')
+        && word.chars().filter(char::is_ascii_uppercase).count() >= 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFESTO: &str = include_str!("../fixtures/manifesto_with_entities.txt");
+
+    fn acronym(name: &str, count: usize, expansion: Option<&str>) -> Entity {
+        Entity { name: String::from(name), kind: EntityKind::Acronym, count, expansion: expansion.map(String::from) }
+    }
+
+    fn name(name: &str, count: usize) -> Entity {
+        Entity { name: String::from(name), kind: EntityKind::Name, count, expansion: None }
+    }
+
+    #[test]
+    fn extracts_names_and_acronyms_most_mentioned_first() {
+        assert_eq!(extract(MANIFESTO), vec![
+            acronym("NHS", 4, Some("National Health Service")),
+            name("Green Future Party", 3),
+            acronym("GDP", 2, Some("Gross Domestic Product")),
+            name("Green Investment Bank", 2),
+            name("Jane Smith", 2),
+            acronym("R&D", 2, Some("research and development")),
+            name("Department for Health", 1),
+            acronym("IFS", 1, None),
+            name("Prime Minister", 1),
+            name("Welsh Government", 1),
+        ]);
+    }
+
+    #[test]
+    fn words_that_only_start_a_sentence_arent_names() {
+        let names = count_names("In Wales, we will. The Prime Minister's office and Labour and Plaid Cymru.");
+
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), vec![
+            (String::from("Plaid Cymru"), 1),
+            (String::from("Prime Minister"), 1),
+        ]);
+    }
+
+    #[test]
+    fn brackets_only_expand_acronyms_that_match() {
+        let expansions = find_expansions("The NHS (see page 4) and the Bank of England (BoE) and Free Travel Passes (FTP).");
+
+        assert_eq!(expansions.into_iter().collect::<Vec<_>>(), vec![(String::from("FTP"), String::from("Free Travel Passes"))]);
+    }
+
+    #[test]
+    fn headings_in_capitals_arent_acronyms() {
+        assert_eq!(count_acronyms("OUR PLAN\nMore GPs for the NHS\n").into_iter().collect::<Vec<_>>(), vec![
+            (String::from("GP"), 1),
+            (String::from("NHS"), 1),
+        ]);
+    }
+
+    #[test]
+    fn long_documents_give_the_same_entities_with_bigger_counts() {
+        let long = MANIFESTO.repeat(2000);
+        let entities = extract(&long);
+
+        assert_eq!(entities[0], acronym("NHS", 8000, Some("National Health Service")));
+        assert_eq!(entities.len(), 10);
+    }
+
+    #[test]
+    fn formats_an_appendix() {
+        let appendix = format_appendix(&[acronym("NHS", 4, Some("National Health Service")), name("Jane Smith", 1)]);
+
+        assert_eq!(appendix, "## Appendix: names and acronyms\n\n- NHS (National Health Service): 4 mentions\n- Jane Smith: 1 mention");
+    }
+}
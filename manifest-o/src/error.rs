@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ManifestoError {
+    // The request never got a response (DNS, TLS, connection reset, ...)
+    Http(String),
+    // OpenAI responded with a non-2xx status
+    Api { status: u16, code: Option<String>, message: String },
+    // The response body wasn't what we expected
+    Deserialize(String),
+    // The response parsed, but had no choices in it
+    EmptyResponse,
+    // Every choice was stopped by OpenAI's content filter (finish_reason "content_filter")
+    ContentFiltered,
+    // The document was still too long for the model after falling back to smaller chunks
+    ContextLengthExceeded(String),
+    // The request couldn't be written to the --save-prompt log, so it wasn't sent
+    PromptLog(String),
+    // --replay was given a request that wasn't recorded
+    ReplayMiss(String),
+    // Ctrl+C was pressed, so no more requests were sent
+    Cancelled,
+    // The request's body came to more than --max-request-bytes, so it wasn't sent
+    RequestTooLarge { bytes: u64, limit: u64 },
+    // The response's body ran past --max-response-bytes, so the rest of it wasn't read
+    ResponseTooLarge { limit: u64 },
+}
+
+impl fmt::Display for ManifestoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestoError::Http(e) => write!(f, "Couldn't make request: {}", e),
+            ManifestoError::Api { status, code: Some(code), message } =>
+                write!(f, "OpenAI returned {} ({}): {}", status, code, message),
+            ManifestoError::Api { status, code: None, message } =>
+                write!(f, "OpenAI returned {}: {}", status, message),
+            ManifestoError::Deserialize(body) => write!(f, "Couldn't deserialize: {}", body),
+            ManifestoError::EmptyResponse => write!(f, "No choices in the response"),
+            ManifestoError::ContentFiltered => write!(
+                f,
+                "The model declined to summarize this content due to its policy (finish_reason: content_filter). \
+                 With --chunk-tokens, pass --skip-filtered to leave out just the chunks it declines"
+            ),
+            ManifestoError::ContextLengthExceeded(message) =>
+                write!(f, "The manifesto is too long for the model, even in smaller chunks: {}", message),
+            ManifestoError::PromptLog(e) => write!(f, "Couldn't save the prompt: {}", e),
+            ManifestoError::ReplayMiss(message) => write!(f, "Couldn't replay the request: {}", message),
+            ManifestoError::Cancelled => write!(f, "Interrupted before the run finished"),
+            ManifestoError::RequestTooLarge { bytes, limit } => write!(
+                f,
+                "The request would have been {} bytes, more than the limit of {}, so it wasn't sent. \
+                 Split the document into smaller requests with --chunk-tokens, or raise the limit with --max-request-bytes",
+                bytes, limit
+            ),
+            ManifestoError::ResponseTooLarge { limit } => write!(
+                f,
+                "The response was more than the limit of {} bytes, so it was abandoned; raise the limit with --max-response-bytes",
+                limit
+            ),
+        }
+    }
+}
+
+impl Error for ManifestoError {}
@@ -0,0 +1,263 @@
+// A rough summary made without the model, for --offline (or --fallback-offline when OpenAI
+// can't be reached). It's extractive: rather than writing anything, it picks the document's most
+// representative sentences and quotes them in their original order. Each sentence is scored
+// TF-IDF style, treating sentences as the "documents", so words that come up often in the
+// manifesto but aren't in every sentence count for the most. Sentences are picked section by
+// section, so that every section gets a say, up to a target length. Everything here is
+// deterministic: the same text always gives the same sentences.
+
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::sections::{self, SectionSummary};
+
+// How many words the summary aims for in all
+pub const DEFAULT_TARGET_WORDS: usize = 250;
+// Says what the output is, since it reads like a summary but isn't one
+pub const EXTRACTIVE_LABEL: &str = "[Extractive summary made offline: these are the manifesto's own sentences, picked without the model]";
+
+// Words too common to say anything about what a sentence is about
+const STOP_WORDS: &[&str] = &[
+    "a", "about", "all", "also", "an", "and", "are", "as", "at", "be", "been", "but", "by", "can",
+    "for", "from", "had", "has", "have", "it", "its", "in", "into", "is", "more", "not", "of",
+    "on", "or", "our", "so", "than", "that", "the", "their", "them", "they", "this", "to", "up",
+    "was", "we", "were", "which", "who", "will", "with", "would", "you", "your",
+];
+// Words that end in a full stop without ending the sentence
+const ABBREVIATIONS: &[&str] = &["dr", "e.g", "etc", "i.e", "mr", "mrs", "ms", "no", "st", "vs"];
+
+pub struct ExtractiveSummary {
+    // The picked sentences, under their section headings if the document has any
+    pub summary: String,
+    // The sentences picked from each section, or empty if the document has no headings
+    pub sections: Vec<SectionSummary>,
+}
+
+// Picks sentences from [text] adding up to about [target_words] words, split into sections at
+// [heading_pattern]
+pub fn summarise(text: &str, heading_pattern: &Regex, target_words: usize) -> ExtractiveSummary {
+    let sections = match sections::split_into_sections(text, heading_pattern) {
+        Some(sections) => sections.into_iter().map(|section| (Some(section.heading), section.body)).collect(),
+        None => vec![(None, String::from(text))],
+    };
+
+    let sentences: Vec<Vec<String>> = sections.iter().map(|(_, body)| split_sentences(body)).collect();
+    let idf = inverse_document_frequencies(sentences.iter().flatten());
+    let total_words: usize = sentences.iter().flatten().map(|sentence| word_count(sentence)).sum();
+
+    let picked: Vec<(Option<String>, String)> = sections.into_iter()
+        .zip(&sentences)
+        .filter(|(_, sentences)| !sentences.is_empty())
+        .map(|((heading, _), sentences)| {
+            // Each section's share of the target is its share of the words
+            let section_words: usize = sentences.iter().map(|sentence| word_count(sentence)).sum();
+            let budget = target_words * section_words / total_words.max(1);
+
+            (heading, pick_sentences(sentences, &idf, budget).join(" "))
+        })
+        .collect();
+
+    if picked.iter().all(|(heading, _)| heading.is_none()) {
+        let summary = picked.into_iter().map(|(_, sentences)| sentences).collect::<Vec<String>>().join("\n\n");
+
+        return ExtractiveSummary { summary, sections: Vec::new() };
+    }
+
+    let sections: Vec<SectionSummary> = picked.into_iter()
+        .map(|(heading, summary)| SectionSummary { heading: heading.unwrap_or_default(), summary })
+        .collect();
+
+    ExtractiveSummary { summary: sections::format_section_summaries(&sections), sections }
+}
+
+// The best-scoring of [sentences] that fit in [budget] words (but always at least one), in the
+// order they were written. Ties go to the earlier sentence.
+fn pick_sentences<'a>(sentences: &'a [String], idf: &BTreeMap<String, f64>, budget: usize) -> Vec<&'a str> {
+    let mut ranked: Vec<(usize, f64)> = sentences.iter()
+        .enumerate()
+        .map(|(i, sentence)| (i, score(sentence, idf)))
+        .collect();
+    ranked.sort_by(|(a_index, a_score), (b_index, b_score)| b_score.total_cmp(a_score).then(a_index.cmp(b_index)));
+
+    let mut picked: Vec<usize> = Vec::new();
+    let mut words = 0;
+
+    for (i, _) in ranked {
+        let sentence_words = word_count(&sentences[i]);
+
+        if !picked.is_empty() && words + sentence_words > budget {
+            continue;
+        }
+
+        picked.push(i);
+        words += sentence_words;
+    }
+
+    picked.sort();
+
+    picked.into_iter().map(|i| sentences[i].as_str()).collect()
+}
+
+// The sum of the TF-IDF of the sentence's terms, divided by the square root of its length so
+// that long sentences don't win just by being long
+fn score(sentence: &str, idf: &BTreeMap<String, f64>) -> f64 {
+    let terms = terms(sentence);
+
+    if terms.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = terms.iter().map(|term| idf.get(term).copied().unwrap_or(0.0)).sum();
+
+    total / (terms.len() as f64).sqrt()
+}
+
+// How rare each term is across the sentences: ln(N / sentences with the term) + 1, weighted by
+// how often the term comes up in the whole document, so that a manifesto's recurring themes
+// count for more than words that only come up once
+fn inverse_document_frequencies<'a>(sentences: impl Iterator<Item = &'a String>) -> BTreeMap<String, f64> {
+    let mut sentence_count = 0;
+    let mut document_frequency: BTreeMap<String, usize> = BTreeMap::new();
+    let mut term_frequency: BTreeMap<String, usize> = BTreeMap::new();
+
+    for sentence in sentences {
+        sentence_count += 1;
+        let terms = terms(sentence);
+
+        for term in &terms {
+            *term_frequency.entry(term.clone()).or_default() += 1;
+        }
+
+        for term in terms.into_iter().collect::<BTreeSet<String>>() {
+            *document_frequency.entry(term).or_default() += 1;
+        }
+    }
+
+    document_frequency.into_iter()
+        .map(|(term, frequency)| {
+            let idf = (sentence_count as f64 / frequency as f64).ln() + 1.0;
+            let weight = (term_frequency[&term] as f64).ln() + 1.0;
+
+            (term, idf * weight)
+        })
+        .collect()
+}
+
+// The lower-cased words in [sentence] that aren't stop words
+fn terms(sentence: &str) -> Vec<String> {
+    sentence.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+fn word_count(sentence: &str) -> usize {
+    sentence.split_whitespace().count()
+}
+
+// Splits [text] into sentences at a `.`, `!` or `?` followed by whitespace, and at blank lines
+// (so that headings and list items without a full stop stand alone). Line breaks within a
+// paragraph are joined up.
+fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+
+    for paragraph in text.split("\n\n") {
+        let words: Vec<&str> = paragraph.split_whitespace().collect();
+        let mut sentence: Vec<&str> = Vec::new();
+
+        for (i, word) in words.iter().enumerate() {
+            sentence.push(word);
+
+            let next_starts_sentence = words.get(i + 1)
+                .and_then(|next| next.chars().next())
+                .is_none_or(|c| !c.is_lowercase());
+
+            if ends_sentence(word) && next_starts_sentence {
+                sentences.push(sentence.join(" "));
+                sentence.clear();
+            }
+        }
+
+        if !sentence.is_empty() {
+            sentences.push(sentence.join(" "));
+        }
+    }
+
+    sentences
+}
+
+fn ends_sentence(word: &str) -> bool {
+    let word = word.trim_end_matches(['"', '\'', ')', '\u{201d}', '\u{2019}']);
+
+    if word.ends_with(['!', '?']) {
+        return true;
+    }
+
+    match word.strip_suffix('.') {
+        Some(stem) => !ABBREVIATIONS.contains(&stem.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sections::DEFAULT_HEADING_PATTERN;
+
+    const MANIFESTO: &str = "\
+# Health
+
+We will hire ten thousand nurses. Nurses are the backbone of the health service, and the health \
+service needs more of them. Parking at hospitals will be free. The weather has been nice.
+
+# Housing
+
+We will build three hundred thousand homes a year. Homes will be built on brownfield land first. \
+Dr. Smith will chair a review of housing standards. Rents will be capped for new homes.
+
+# Transport
+
+Buses will be free for under-18s. Trains will run on time.
+";
+
+    fn heading_pattern() -> Regex {
+        Regex::new(DEFAULT_HEADING_PATTERN).unwrap()
+    }
+
+    #[test]
+    fn picks_the_same_sentences_every_time() {
+        let extract = summarise(MANIFESTO, &heading_pattern(), 40);
+
+        assert_eq!(
+            extract.summary,
+            "## Health\n\nNurses are the backbone of the health service, and the health service needs more of them.\n\n\
+             ## Housing\n\nWe will build three hundred thousand homes a year. Homes will be built on brownfield land first.\n\n\
+             ## Transport\n\nBuses will be free for under-18s."
+        );
+        assert_eq!(extract.sections.len(), 3);
+        assert_eq!(summarise(MANIFESTO, &heading_pattern(), 40).summary, extract.summary);
+    }
+
+    #[test]
+    fn every_section_gets_at_least_one_sentence() {
+        let extract = summarise(MANIFESTO, &heading_pattern(), 1);
+
+        assert_eq!(extract.sections.len(), 3);
+        assert!(extract.sections.iter().all(|section| !section.summary.is_empty()));
+    }
+
+    #[test]
+    fn documents_without_headings_have_no_sections() {
+        let extract = summarise("Free buses for all. Cheaper trains for all. The end.", &heading_pattern(), 8);
+
+        assert!(extract.sections.is_empty());
+        assert_eq!(extract.summary, "Free buses for all. Cheaper trains for all.");
+    }
+
+    #[test]
+    fn splits_sentences_but_not_abbreviations() {
+        let sentences = split_sentences("Dr. Smith said so. Is it true? Yes! This one\ncontinues, e.g. here.\n\nA list item");
+
+        assert_eq!(sentences, vec!["Dr. Smith said so.", "Is it true?", "Yes!", "This one continues, e.g. here.", "A list item"]);
+    }
+}
@@ -0,0 +1,222 @@
+// Summarises manifestos that are published as web pages: a file_path starting with http:// or
+// https:// is fetched rather than read, so it doesn't have to be downloaded and converted first.
+// It's held to --max-input-bytes like a file is. HTML is turned into plain text: scripts, styles
+// and comments are dropped, headings become markdown headings (so that --per-section and the
+// like still find the sections), block elements start new lines, every other tag is removed and
+// entities are decoded. It's basic, but it's all the model needs.
+
+use regex::{Captures, Regex};
+use crate::decoding::{self, Decoded, ReadOptions};
+
+// Dropped along with everything inside them
+const HIDDEN_PATTERN: &str = r"(?is)<!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<head\b.*?</head\s*>|<noscript\b.*?</noscript\s*>|<template\b.*?</template\s*>";
+const HEADING_PATTERN: &str = r"(?i)<h([1-6])\b[^>]*>";
+const BLOCK_PATTERN: &str = r"(?i)</h[1-6]\s*>|</?(p|div|section|article|header|footer|main|aside|nav|ul|ol|table|blockquote|pre|figure|form)\b[^>]*>";
+const LINE_PATTERN: &str = r"(?i)<(br|hr|tr)\b[^>]*>";
+const LIST_ITEM_PATTERN: &str = r"(?i)<li\b[^>]*>";
+const TAG_PATTERN: &str = r"<[^>]*>";
+const ENTITY_PATTERN: &str = r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z]+);";
+
+pub fn is_url(input: &str) -> bool {
+    let lowercase = input.to_ascii_lowercase();
+
+    lowercase.starts_with("http://") || lowercase.starts_with("https://")
+}
+
+// A name for the page to keep its resume state under (and to pick its --prompts by), since a URL
+// isn't a path, e.g. example.org-manifesto.html for https://example.org/manifesto.html
+pub fn local_name(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let rest = rest.split(['?', '#']).next().unwrap_or(rest).trim_end_matches('/');
+
+    rest.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '-' })
+        .collect()
+}
+
+// The client pages are fetched with. It isn't the OpenAI client, which would send the key to
+// whoever serves the page.
+pub fn client(accept_invalid_certs: bool) -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(concat!("manifest-o/", env!("CARGO_PKG_VERSION")))
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .build()
+        .expect("Failed to build the client for fetching pages")
+}
+
+// Fetches [url], as long as it's no bigger than --max-input-bytes, and decodes it as a file would
+// be, stripping it down to its text if it's HTML
+pub async fn fetch(client: &reqwest::Client, url: &str, options: &ReadOptions) -> Result<Decoded, String> {
+    let mut response = client.get(url).send().await.map_err(|e| format!("couldn't fetch the page: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("the server answered {}", response.status()));
+    }
+
+    let too_large = |len: String| format!(
+        "the page is {} bytes, which is more than the limit of {}; raise it with --max-input-bytes",
+        len, options.max_bytes
+    );
+
+    if let Some(length) = response.content_length().filter(|length| *length > options.max_bytes) {
+        return Err(too_large(length.to_string()));
+    }
+
+    let html = response.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("html"));
+
+    // The page could be bigger than it said (or not say at all), so stop reading once it's
+    // past the limit either way
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = response.chunk().await.map_err(|e| format!("couldn't read the page: {}", e))? {
+        bytes.extend_from_slice(&chunk);
+
+        if bytes.len() as u64 > options.max_bytes {
+            return Err(too_large(format!("more than {}", options.max_bytes)));
+        }
+    }
+
+    let mut decoded = decoding::decode_input(&bytes, options.encoding)?;
+
+    if html || decoded.text.trim_start().starts_with('<') {
+        decoded.text = html_to_text(&decoded.text);
+    }
+
+    Ok(decoded)
+}
+
+// The text of an HTML page, with its headings as markdown headings and a line for each paragraph
+// or list item
+pub fn html_to_text(html: &str) -> String {
+    let html = Regex::new(HIDDEN_PATTERN).unwrap().replace_all(html, "");
+
+    // Line breaks in the source mean nothing, so they go before the tags add their own
+    let html = Regex::new(r"\s+").unwrap().replace_all(&html, " ");
+    let html = Regex::new(HEADING_PATTERN).unwrap().replace_all(&html, |captures: &Captures| {
+        format!("\n\n{} ", "#".repeat(captures[1].parse().unwrap()))
+    });
+    let html = Regex::new(BLOCK_PATTERN).unwrap().replace_all(&html, "\n\n");
+    let html = Regex::new(LINE_PATTERN).unwrap().replace_all(&html, "\n");
+    let html = Regex::new(LIST_ITEM_PATTERN).unwrap().replace_all(&html, "\n- ");
+    let text = Regex::new(TAG_PATTERN).unwrap().replace_all(&html, "");
+    let text = Regex::new(ENTITY_PATTERN).unwrap().replace_all(&text, |captures: &Captures| {
+        decode_entity(&captures[1]).map_or_else(|| String::from(&captures[0]), String::from)
+    });
+
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    let text = lines.join("\n");
+
+    Regex::new(r"\n{3,}").unwrap().replace_all(&text, "\n\n").trim().to_string()
+}
+
+// The character for an entity's name (without its & and ;), if it's one we know
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+
+    if let Some(decimal) = entity.strip_prefix('#') {
+        return decimal.parse().ok().and_then(char::from_u32);
+    }
+
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "ndash" => Some('–'),
+        "mdash" => Some('—'),
+        "lsquo" => Some('‘'),
+        "rsquo" => Some('’'),
+        "ldquo" => Some('“'),
+        "rdquo" => Some('”'),
+        "hellip" => Some('…'),
+        "pound" => Some('£'),
+        "euro" => Some('€'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::runtime;
+
+    const PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>Manifesto</title><style>p { color: red; }</style></head>
+<body>
+  <script>track();</script>
+  <h1>Our Manifesto</h1>
+  <!-- draft -->
+  <p>We will build tunnels
+     under every river.</p>
+  <h2>Health &amp; Care</h2>
+  <ul><li>More <b>GPs</b></li><li>Shorter&nbsp;waits</li></ul>
+  <p>Costs &pound;3bn&#8212;fully funded.<br>See page 2.</p>
+</body>
+</html>";
+
+    fn fetch_from(response: MockResponse, max_bytes: u64) -> Result<Decoded, String> {
+        let server = MockServer::start(vec![response]);
+        let options = ReadOptions { max_bytes, ..ReadOptions::default() };
+
+        runtime::block_on(fetch(&mock_server::client(), &server.url, &options))
+    }
+
+    #[test]
+    fn strips_html_down_to_its_text() {
+        assert_eq!(
+            html_to_text(PAGE),
+            "# Our Manifesto\n\nWe will build tunnels under every river.\n\n## Health & Care\n\n- More GPs\n- Shorter waits\n\nCosts £3bn—fully funded.\nSee page 2.",
+        );
+    }
+
+    #[test]
+    fn fetches_pages_as_text() {
+        let decoded = fetch_from(MockResponse::new(200, PAGE), 1024 * 1024).expect("should have fetched the page");
+
+        assert!(decoded.text.starts_with("# Our Manifesto\n\nWe will build tunnels"));
+    }
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        let decoded = fetch_from(MockResponse::new(200, "We promise <b>bold</b> things."), 1024).unwrap();
+
+        assert_eq!(decoded.text, "We promise <b>bold</b> things.");
+    }
+
+    #[test]
+    fn failed_fetches_are_errors() {
+        let error = fetch_from(MockResponse::new(404, "Not found"), 1024).unwrap_err();
+        assert!(error.contains("404"), "{}", error);
+
+        let error = runtime::block_on(fetch(&mock_server::client(), &mock_server::refusing_url(), &ReadOptions::default())).unwrap_err();
+        assert!(error.starts_with("couldn't fetch the page"), "{}", error);
+    }
+
+    #[test]
+    fn pages_over_the_size_limit_are_refused() {
+        let error = fetch_from(MockResponse::new(200, PAGE), 100).unwrap_err();
+        assert!(error.contains("more than the limit of 100"), "{}", error);
+
+        // Even when the server doesn't say how big the page is
+        let error = fetch_from(MockResponse::endless(200), 100 * 1024).unwrap_err();
+        assert!(error.contains("--max-input-bytes"), "{}", error);
+    }
+
+    #[test]
+    fn urls_get_local_names() {
+        assert!(is_url("https://example.org/manifesto"));
+        assert!(is_url("HTTP://example.org"));
+        assert!(!is_url("manifesto.txt"));
+
+        assert_eq!(local_name("https://example.org/2024/manifesto.html?lang=en#top"), "example.org-2024-manifesto.html");
+        assert_eq!(local_name("http://example.org/"), "example.org");
+    }
+}
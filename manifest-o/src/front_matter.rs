@@ -0,0 +1,142 @@
+// Front matter: an optional block of `key: value` lines between `---` lines at the very top of a
+// document, e.g.
+//
+//     ---
+//     party: Example Party
+//     year: 2024
+//     ---
+//
+// Only flat keys with plain or quoted values are supported, which covers the metadata blocks
+// manifestos are tagged with without pulling in a full YAML parser.
+
+use std::collections::BTreeMap;
+use crate::diagnostics;
+
+pub type Metadata = BTreeMap<String, String>;
+
+const DELIMITER: &str = "---";
+
+// Splits the front matter off the top of [text], returning its fields and the rest of the
+// document. Text without front matter (or with an unclosed block) comes back untouched.
+pub fn split_front_matter(text: &str) -> (Metadata, &str) {
+    let mut lines = text.split_inclusive('\n');
+
+    // The offset of the line after the last one looked at
+    let mut offset = match lines.next() {
+        Some(first) if first.trim_end() == DELIMITER => first.len(),
+        _ => return (Metadata::new(), text),
+    };
+
+    let mut metadata = Metadata::new();
+
+    for line in lines {
+        offset += line.len();
+
+        if line.trim_end() == DELIMITER {
+            return (metadata, &text[offset..]);
+        }
+
+        if let Some((key, value)) = parse_field(line) {
+            metadata.insert(key, value);
+        }
+    }
+
+    (Metadata::new(), text)
+}
+
+// Parses a `key: value` line, skipping blank lines and comments
+fn parse_field(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (key, value) = line.split_once(':')?;
+    let value = value.trim();
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value);
+
+    Some((String::from(key.trim()), String::from(unquoted)))
+}
+
+// Fills in every `{name}` in [template] with the metadata field of that name, or with [summary]
+// for `{summary}`. Unknown names render as nothing, with a warning.
+pub fn render_template(template: &str, metadata: &Metadata, summary: &str) -> String {
+    let mut rendered = String::with_capacity(template.len() + summary.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+
+        rendered.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + len];
+
+        match (name, metadata.get(name)) {
+            ("summary", _) => rendered.push_str(summary),
+            (_, Some(value)) => rendered.push_str(value),
+            (_, None) => diagnostics::warn(format!("the document has no '{}' for the template", name)),
+        }
+
+        rest = &rest[start + len + 1..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata(fields: &[(&str, &str)]) -> Metadata {
+        fields.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect()
+    }
+
+    #[test]
+    fn parses_front_matter() {
+        let text = "---\nparty: Example Party\ncountry: 'Ruritania'\n# a comment\nyear: \"2024\"\n---\nWe promise things.\n";
+
+        let (fields, body) = split_front_matter(text);
+
+        assert_eq!(fields, metadata(&[("party", "Example Party"), ("country", "Ruritania"), ("year", "2024")]));
+        assert_eq!(body, "We promise things.\n");
+    }
+
+    #[test]
+    fn handles_crlf_line_endings() {
+        let (fields, body) = split_front_matter("---\r\nparty: Example\r\n---\r\nBody\r\n");
+
+        assert_eq!(fields, metadata(&[("party", "Example")]));
+        assert_eq!(body, "Body\r\n");
+    }
+
+    #[test]
+    fn passes_documents_without_front_matter_through() {
+        for text in ["We promise things.\n---\nparty: no\n---\n", "", "---\nparty: unclosed\n"] {
+            let (fields, body) = split_front_matter(text);
+
+            assert!(fields.is_empty());
+            assert_eq!(body, text);
+        }
+    }
+
+    #[test]
+    fn renders_templates() {
+        let fields = metadata(&[("party", "Example Party"), ("year", "2024")]);
+
+        assert_eq!(
+            render_template("## {party} ({year})\n\n{summary}", &fields, "Good things."),
+            "## Example Party (2024)\n\nGood things."
+        );
+    }
+
+    #[test]
+    fn missing_template_fields_render_as_empty() {
+        assert_eq!(render_template("{party}: {summary} {unclosed", &Metadata::new(), "Good things."), ": Good things. {unclosed");
+    }
+}
@@ -0,0 +1,113 @@
+// Keeps a run from paying for the same chunk twice. Every chunk request gets an idempotency key
+// derived from what it asks for, which is sent along as the Idempotency-Key header, and the
+// [Ledger] remembers the reply to every key that's completed in this run. A request whose key
+// has already completed isn't sent again; the recorded reply is used instead.
+//
+// The run ID is the document's source-sha256 (the hash of its cleaned text), the same one the
+// resume state is keyed on, so a resumed run gives its chunks the same keys as the run it picks
+// up from.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::open_ai::OpenAiRequestBody;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+pub struct Ledger {
+    run_id: String,
+    // Whether to log suppressed duplicates (--verbose)
+    verbose: bool,
+    completed: Mutex<HashMap<String, String>>,
+}
+
+impl Ledger {
+    pub fn new(run_id: &str, verbose: bool) -> Ledger {
+        Ledger { run_id: String::from(run_id), verbose, completed: Mutex::new(HashMap::new()) }
+    }
+
+    // The key for [body] as the request for chunk [chunk] (or the whole document, for None).
+    // Only the model and messages go into it, so the same request always gets the same key.
+    pub fn key(&self, chunk: Option<usize>, body: &OpenAiRequestBody) -> String {
+        let messages = serde_json::to_string(&body.messages).expect("messages should always serialize");
+        let chunk = chunk.map_or(String::from("-"), |chunk| chunk.to_string());
+
+        let hash: String = Sha256::digest(format!("{}\n{}\n{}\n{}", self.run_id, chunk, body.model, messages).as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        format!("manifest-o-{}", &hash[..32])
+    }
+
+    // The reply already recorded for [key], if the request completed earlier in the run
+    pub fn completed(&self, key: &str) -> Option<String> {
+        let reply = self.completed.lock().unwrap().get(key).cloned();
+
+        if reply.is_some() && self.verbose {
+            eprintln!("Not sending request {} again; it already completed", key);
+        }
+
+        reply
+    }
+
+    // Notes (under --verbose) that [key]'s reply came in after it timed out, but before it was
+    // retried, so the retry wasn't sent
+    pub fn note_late_reply(&self, key: &str) {
+        if self.verbose {
+            eprintln!("Request {} replied late; not sending it again", key);
+        }
+    }
+
+    // Records the reply to [key]. Only the first reply to each key is kept; any later one is a
+    // duplicate (a retry and its slow original both finishing) and is ignored.
+    pub fn record(&self, key: &str, reply: &str) {
+        let mut completed = self.completed.lock().unwrap();
+
+        if completed.contains_key(key) {
+            if self.verbose {
+                eprintln!("Ignoring a duplicate reply to request {}", key);
+            }
+
+            return;
+        }
+
+        completed.insert(String::from(key), String::from(reply));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::open_ai::{ChatRequestBuilder, GPT_4_MODEL_NAME};
+
+    fn body(text: &str) -> OpenAiRequestBody {
+        ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .user(text)
+            .build()
+            .expect("should have built the request")
+    }
+
+    #[test]
+    fn keys_are_deterministic() {
+        let ledger = Ledger::new("run", false);
+
+        assert_eq!(ledger.key(Some(1), &body("Trains")), Ledger::new("run", false).key(Some(1), &body("Trains")));
+        assert_ne!(ledger.key(Some(1), &body("Trains")), ledger.key(Some(2), &body("Trains")));
+        assert_ne!(ledger.key(Some(1), &body("Trains")), ledger.key(Some(1), &body("Trees")));
+        assert_ne!(ledger.key(Some(1), &body("Trains")), Ledger::new("other run", false).key(Some(1), &body("Trains")));
+    }
+
+    #[test]
+    fn keeps_the_first_reply_to_each_key() {
+        let ledger = Ledger::new("run", false);
+
+        assert_eq!(ledger.completed("key"), None);
+
+        ledger.record("key", "First");
+        ledger.record("key", "Second");
+
+        assert_eq!(ledger.completed("key"), Some(String::from("First")));
+    }
+}
@@ -0,0 +1,57 @@
+// Spreads requests across several OpenAI keys (one per --key-file), each with its own rate limit.
+// The client's default Authorization header carries the first key; with a [KeyRotation], every
+// request overrides it with the next key in turn.
+
+use reqwest::header::HeaderValue;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::secret::SecretString;
+
+pub struct KeyRotation {
+    headers: Vec<HeaderValue>,
+    next: AtomicUsize,
+}
+
+impl KeyRotation {
+    // Panics if [keys] is empty
+    pub fn new<'a>(keys: impl IntoIterator<Item = &'a SecretString>) -> KeyRotation {
+        let headers: Vec<HeaderValue> = keys.into_iter().map(authorization_header).collect();
+        assert!(!headers.is_empty(), "a key rotation needs at least one key");
+
+        KeyRotation { headers, next: AtomicUsize::new(0) }
+    }
+
+    // The Authorization header for the next request, and which key (from 1) it's for
+    pub fn next(&self) -> (usize, HeaderValue) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.headers.len();
+
+        (index + 1, self.headers[index].clone())
+    }
+}
+
+// The only place a key is exposed. Marking the header as sensitive keeps it out of the client's
+// debug output too.
+pub fn authorization_header(key: &SecretString) -> HeaderValue {
+    let mut header_value = HeaderValue::from_str(&format!("Bearer {}", key.expose()))
+        .expect("Couldn't build the Authorization header; the OpenAI key has characters that aren't allowed in a header");
+    header_value.set_sensitive(true);
+
+    header_value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn takes_turns_with_every_key() {
+        let keys = [SecretString::from("sk-one"), SecretString::from("sk-two")];
+        let rotation = KeyRotation::new(&keys);
+
+        let used: Vec<(usize, HeaderValue)> = (0..3).map(|_| rotation.next()).collect();
+
+        assert_eq!(used[0], (1, HeaderValue::from_static("Bearer sk-one")));
+        assert_eq!(used[1], (2, HeaderValue::from_static("Bearer sk-two")));
+        assert_eq!(used[2], (1, HeaderValue::from_static("Bearer sk-one")));
+        assert!(used[0].1.is_sensitive());
+    }
+}
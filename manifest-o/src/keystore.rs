@@ -0,0 +1,144 @@
+// Storage for the OpenAI key in the OS keyring. Everything here is behind the `keyring` feature;
+// without it, the keyring is treated as always empty and the `key` subcommand is rejected.
+
+#[cfg(feature = "keyring")]
+use keyring::Entry;
+#[cfg(feature = "keyring")]
+use crate::diagnostics;
+
+#[cfg(feature = "keyring")]
+pub const KEYRING_SERVICE: &str = "manifest-o";
+#[cfg(feature = "keyring")]
+pub const KEYRING_USER: &str = "openai";
+
+// Handles `manifest-o key <set|clear>`. The remaining args are everything after `key`.
+#[cfg(feature = "keyring")]
+pub fn run_key_command(mut args: impl Iterator<Item = String>) -> Result<(), &'static str> {
+    let entry = Entry::new(KEYRING_SERVICE, KEYRING_USER)
+        .map_err(|_| "Couldn't open the OS keyring")?;
+
+    match args.next().as_deref() {
+        Some("set") => {
+            let key = rpassword::prompt_password("OpenAI key: ")
+                .map_err(|_| "Couldn't read the key from the terminal")?;
+
+            set_key(&entry, &key)?;
+            eprintln!("Stored the OpenAI key in the OS keyring");
+            Ok(())
+        }
+        Some("clear") => {
+            clear_key(&entry)?;
+            eprintln!("Removed the OpenAI key from the OS keyring");
+            Ok(())
+        }
+        _ => Err("Expected `key set` or `key clear`"),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn run_key_command(_args: impl Iterator<Item = String>) -> Result<(), &'static str> {
+    Err("manifest-o was built without keyring support; rebuild with `--features keyring`")
+}
+
+// Looks up the key in the OS keyring. Any keyring failure (locked, no backend, ...) is reported
+// as a warning and treated as a missing key so that a broken keyring never stops a run.
+#[cfg(feature = "keyring")]
+pub fn read_key_from_keyring() -> Option<String> {
+    match Entry::new(KEYRING_SERVICE, KEYRING_USER) {
+        Ok(entry) => get_key(&entry),
+        Err(e) => {
+            diagnostics::warn(format!("couldn't open the OS keyring: {}", e));
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn read_key_from_keyring() -> Option<String> {
+    None
+}
+
+#[cfg(feature = "keyring")]
+fn set_key(entry: &Entry, key: &str) -> Result<(), &'static str> {
+    let key = key.trim();
+
+    if key.is_empty() {
+        return Err("Refusing to store an empty key");
+    }
+
+    entry.set_password(key)
+        .map_err(|_| "Couldn't store the key in the OS keyring")
+}
+
+#[cfg(feature = "keyring")]
+fn clear_key(entry: &Entry) -> Result<(), &'static str> {
+    match entry.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(_) => Err("Couldn't remove the key from the OS keyring"),
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn get_key(entry: &Entry) -> Option<String> {
+    match entry.get_password() {
+        Ok(key) => Some(key),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            diagnostics::warn(format!("couldn't read the OpenAI key from the OS keyring: {}", e));
+            None
+        }
+    }
+}
+
+#[cfg(all(test, feature = "keyring"))]
+mod test {
+    use super::*;
+    use keyring::mock::{self, MockCredential};
+
+    // The mock backend doesn't persist between entries, so each test works on a single entry.
+    fn mock_entry() -> Entry {
+        keyring::set_default_credential_builder(mock::default_credential_builder());
+        Entry::new(KEYRING_SERVICE, KEYRING_USER).expect("should have built a mock entry")
+    }
+
+    #[test]
+    fn set_get_and_clear() {
+        let entry = mock_entry();
+
+        assert_eq!(get_key(&entry), None);
+
+        set_key(&entry, "sk-test\n").expect("should have stored the key");
+        assert_eq!(get_key(&entry), Some(String::from("sk-test")));
+
+        clear_key(&entry).expect("should have cleared the key");
+        assert_eq!(get_key(&entry), None);
+    }
+
+    #[test]
+    fn clearing_a_missing_key_is_fine() {
+        let entry = mock_entry();
+
+        clear_key(&entry).expect("should have ignored the missing key");
+    }
+
+    #[test]
+    fn rejects_empty_key() {
+        let entry = mock_entry();
+
+        if set_key(&entry, "  \n").is_ok() {
+            panic!("Should have rejected an empty key");
+        }
+    }
+
+    #[test]
+    fn keyring_errors_are_treated_as_missing() {
+        let entry = mock_entry();
+        set_key(&entry, "sk-test").expect("should have stored the key");
+
+        let mock: &MockCredential = entry.get_credential().downcast_ref()
+            .expect("should have been a mock credential");
+        mock.set_error(keyring::Error::NoStorageAccess("locked".into()));
+
+        assert_eq!(get_key(&entry), None);
+    }
+}
@@ -0,0 +1,17 @@
+// The manifest_o library: the pipeline a document goes through before it's summarised (see
+// [pipeline]), for programs that want to prepare manifestos the way manifest-o does, with steps
+// of their own slotted in. The binary is built on the same modules. The ones the pipeline's
+// stages are made from are public too, since a stage of your own will likely want them.
+
+pub mod chunking;
+pub mod cleaning;
+pub mod decoding;
+pub mod diagnostics;
+pub mod error;
+pub mod front_matter;
+pub mod pipeline;
+pub mod sections;
+pub mod truncation;
+
+pub use error::ManifestoError;
+pub use pipeline::{Document, Pipeline, PipelineStage};
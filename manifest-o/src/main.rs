@@ -1,159 +1,3373 @@
 use reqwest::header;
 use std::env;
 use std::fs;
-use arg_parsing::Args;
-use open_ai::*;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+// The modules shared with the manifest_o library (see lib.rs)
+use manifest_o::{chunking, cleaning, decoding, diagnostics, error, front_matter, pipeline, sections, truncation};
+use archive::ArchiveInput;
+use arg_parsing::{Args, CheckAuthArgs, EmbedArgs, Input, SimilarArgs};
+use attribution::Attribution;
+use chat::Conversation;
+use chunk_cache::ChunkCache;
+use error::ManifestoError;
+use front_matter::Metadata;
+use idempotency::Ledger;
+use key_rotation::KeyRotation;
+use model_fallback::FallbackTransport;
+use naming::TitleSource;
+use progress::Progress;
+use prompt_log::PromptLog;
+use prompts::Prompt;
+use request_log::RequestLog;
+use replay::{Recorder, RecordingTransport, Replayer, ReplayTransport};
+use open_ai::{ChatDefaults, OpenAiUsage};
+use output_checks::Expectations;
+use pipeline::{ChunkPlanning, Cleaning, Document, EncodingFixup, FrontMatter, Pipeline, Truncation};
+use report::{BatchReportRow, RunReport};
+use secret::SecretString;
+use state::Checkpoint;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use summary::{Critique, SummaryOptions};
+use tokio_util::sync::CancellationToken;
+use translation::Translation;
+use transport::{ChatTransport, ReqwestTransport};
+use watch::{Summarised, WatchOptions};
 
-const OPENAI_ENDPOINT: &str = "https://api.openai.com/v1/chat/completions";
+mod archive;
+mod atomic_write;
+mod attribution;
+mod auth_check;
+mod chat;
+mod chunk_cache;
+mod diff;
+mod embeddings;
+mod entities;
+mod extractive;
+mod fetch;
+mod idempotency;
+mod key_rotation;
+mod keystore;
+#[cfg(test)]
+mod mock_server;
+mod model_fallback;
+mod moderation;
+mod naming;
+mod open_ai;
+mod output_checks;
+mod pool;
+mod prompt_log;
+mod prices;
+mod progress;
+mod prompt_template;
+mod prompts;
+mod qa;
+mod rate_limits;
+mod replay;
+mod report;
+mod request_log;
+mod runtime;
+mod secret;
+mod state;
+mod summary;
+mod topics;
+mod translation;
+mod transport;
+mod watch;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// How long an idle pooled connection is kept open, unless --pool-idle-timeout says otherwise
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+// How many times a request that gets no response is retried, unless --net-retries says otherwise
+const DEFAULT_NET_RETRIES: u32 = 2;
+
+// The conventional exit code for a process stopped by Ctrl+C (128 + SIGINT)
+const EXIT_INTERRUPTED: i32 = 130;
+// How long a second Ctrl+C gives the run to write out what it has once its requests are abandoned
+const ABORT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+// Starts the line --stamp adds to the end of text output
+const SOURCE_STAMP_PREFIX: &str = "source-sha256: ";
 
 fn main() -> Result<(), &'static str> {
-    let args = Args::build(env::args().into_iter())?;
-    let file_contents: String =
-        fs::read_to_string(&args.file_path).expect("Failed to read file contents");
+    let raw_args: Vec<String> = env::args().collect();
+
+    match raw_args.get(1).map(String::as_str) {
+        Some("key") => return keystore::run_key_command(raw_args.into_iter().skip(2)),
+        Some("embed") => return embeddings::run_embed_command(EmbedArgs::build(raw_args.into_iter().skip(2))?),
+        Some("similar") => return embeddings::run_similar_command(SimilarArgs::build(raw_args.into_iter().skip(2))?),
+        Some("check-auth") => return auth_check::run_check_auth_command(CheckAuthArgs::build(raw_args.into_iter().skip(2))?),
+        _ => {}
+    }
+
+    // Worked out before the args are parsed, so that args that don't parse are reported as JSON
+    // too
+    let json = arg_parsing::wants_json(&raw_args, |name| env::var(name).ok());
+    let result = summarise_from_args(raw_args);
+
+    if json {
+        println!("{}", diagnostics::json_document(result));
+    }
+
+    result
+}
+
+// Everything main does for a summary (rather than a subcommand)
+fn summarise_from_args(raw_args: Vec<String>) -> Result<(), &'static str> {
+    let args = Args::build(raw_args.into_iter())?;
+    diagnostics::set_quiet(args.quiet || args.json);
+
+    if args.print_config {
+        println!("{:#?}", args);
+        return Ok(());
+    }
+
+    // Not an error: the run goes ahead, and its estimates (and --max-cost) are just left out
+    if args.prices.lookup(open_ai::GPT_4_MODEL_NAME).is_none() {
+        diagnostics::warn(format!("No price is known for {}, so its cost will show as unknown (add one with --price-file)", open_ai::GPT_4_MODEL_NAME));
+    }
+
+    // Before any requests are made, so that it's sized for --jobs
+    runtime::install(args.jobs);
+
+    let request_id_header = args.request_id.as_deref().filter(|_| args.send_request_id);
+    if args.danger_accept_invalid_certs {
+        eprintln!("\n*** WARNING: --danger-accept-invalid-certs is set, so TLS certificates aren't being checked ***");
+        eprintln!("*** Anyone on the network could read the OpenAI key and the manifesto. Only use it for local testing. ***\n");
+        diagnostics::record_warning("TLS certificates aren't being checked (--danger-accept-invalid-certs)");
+    }
+
+    let client = build_openai_client(&args.openai_key, &args.attribution, request_id_header, args.jobs, args.pool_idle_timeout, args.danger_accept_invalid_certs);
+
+    let prompt_log = match &args.save_prompt_path {
+        Some(path) => Some(Arc::new(PromptLog::create(Path::new(path), request_id_header).map_err(|e| {
+            diagnostics::error(format!("Couldn't create {}: {}", path, e));
+            "Failed to create the --save-prompt file"
+        })?)),
+        None => None,
+    };
+
+    let recorder = match &args.record_dir {
+        Some(dir) => Some(Arc::new(Recorder::create(Path::new(dir)).map_err(|e| {
+            diagnostics::error(format!("Couldn't create {}: {}", dir, e));
+            "Failed to create the --record directory"
+        })?)),
+        None => None,
+    };
+
+    let replayer = match &args.replay_dir {
+        Some(dir) => Some(Arc::new(Replayer::open(Path::new(dir)).map_err(|e| {
+            diagnostics::error(e.to_string());
+            "Failed to read the --replay directory"
+        })?)),
+        None => None,
+    };
+
+    let request_log = args.run_report_path.is_some().then(|| Arc::new(RequestLog::new(args.attribution.clone())));
+
+    let key_rotation = (!args.extra_keys.is_empty())
+        .then(|| Arc::new(KeyRotation::new(std::iter::once(&args.openai_key).chain(&args.extra_keys))));
+
+    let aborted = CancellationToken::new();
+    let transports = Transports {
+        client,
+        paused_until: Arc::default(),
+        prompt_log,
+        request_log,
+        recorder,
+        replayer,
+        aborted: aborted.clone(),
+        key_rotation,
+    };
+
+    // The first Ctrl+C stops new requests from being sent and lets the run write out what it has.
+    // A second one abandons the requests in flight as well, giving the run a moment to write out
+    // what it has before exiting regardless.
+    let interrupted = CancellationToken::new();
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if handler_interrupted.is_cancelled() {
+            aborted.cancel();
+            thread::sleep(ABORT_GRACE_PERIOD);
+            process::exit(EXIT_INTERRUPTED);
+        }
+
+        handler_interrupted.cancel();
+        eprintln!("Interrupted; finishing the requests in flight (press Ctrl+C again to stop now)");
+    }).map_err(|_| "Failed to set up the interrupt handler")?;
+
+    let result = run(&args, &transports, &interrupted);
+
+    if let (Some(path), Some(request_log)) = (&args.run_report_path, &transports.request_log) {
+        if let Err(e) = request_log.write_report(Path::new(path), result.err()) {
+            diagnostics::warn(format!("couldn't write the run report to {}: {}", path, e));
+        }
+    }
+
+    if interrupted.is_cancelled() {
+        if args.json {
+            println!("{}", diagnostics::json_document(result));
+        }
+
+        process::exit(EXIT_INTERRUPTED);
+    }
+
+    result
+}
+
+// Summarises the file, or every file in the directory, that [args] asks for
+fn run(args: &Args, transports: &Transports, interrupted: &CancellationToken) -> Result<(), &'static str> {
+    let file_path = match &args.input {
+        Input::File(file_path) => file_path,
+        Input::Watch(watch_options) => return run_watch(args, transports, watch_options, interrupted),
+        Input::Batch(batch_options) => return run_batch(args, transports, batch_options, interrupted),
+        Input::Archive(archive) => return run_archive(args, transports, archive, interrupted),
+    };
+
+    let decoded = read_document(args, file_path).map_err(|e| {
+        diagnostics::error(format!("Couldn't read {}: {}", file_path, e));
+        "Failed to read the manifesto"
+    })?;
+
+    if args.verbose {
+        eprintln!("Read {} as {}", file_path, decoded.encoding.name());
+    }
+
+    let file_contents = decoded.text;
+
+    if args.dry_run {
+        let doc = build_pipeline(args).run(Document::new(file_contents)).map_err(|e| {
+            diagnostics::error(e.to_string());
+            "Failed to prepare the manifesto"
+        })?;
+
+        write_output(args, &doc.text).map_err(output_not_written)?;
+
+        if let Some(chunks) = &doc.chunks {
+            eprintln!("{}", chunking::format_chunk_plan(chunks));
+        }
+
+        return Ok(());
+    }
+
+    let transport = transports.open(args);
+
+    if let Some(old_path) = &args.diff_from {
+        return run_diff(args, transports, &transport, old_path, &file_contents);
+    }
+
+    if args.chat {
+        return run_chat(args, &transport, &file_contents, interrupted);
+    }
+
+    // A URL isn't a path, so pages are named after it
+    let input_name = match fetch::is_url(file_path) {
+        true => fetch::local_name(file_path),
+        false => file_path.clone(),
+    };
+    let state_base_path = args.output_path.as_deref().unwrap_or(&input_name);
+    let summarised = summarise_document(args, &transport, &file_contents, Path::new(&input_name), state_base_path, Some(interrupted))
+        .map_err(|e| {
+            diagnostics::error(e.to_string());
+            "Failed to summarise the manifesto"
+        })?;
+
+    transports.record_source(&summarised.source_sha256);
+    write_output(args, &summarised.output).map_err(output_not_written)?;
+
+    write_translations(args.output_path.as_deref(), &summarised).map_err(|e| {
+        diagnostics::error(e.to_string());
+        "Failed to translate the summary into every --language"
+    })
+}
+
+// Reads the manifesto at [file_path], or fetches it if it's a URL (see [fetch])
+fn read_document(args: &Args, file_path: &str) -> Result<decoding::Decoded, String> {
+    match fetch::is_url(file_path) {
+        true => runtime::block_on(fetch::fetch(&fetch::client(args.danger_accept_invalid_certs), file_path, &args.read_options)),
+        false => decoding::read_input(Path::new(file_path), &args.read_options),
+    }
+}
+
+// Summarises what changed between the --diff-from version and [new_contents]. Both have their
+// front matter split off and are cleaned first, so that page numbers and the like don't show up
+// as changes.
+fn run_diff(args: &Args, transports: &Transports, transport: &impl ChatTransport, old_path: &str, new_contents: &str) -> Result<(), &'static str> {
+    let old_contents = read_document(args, old_path).map_err(|e| {
+        diagnostics::error(format!("Couldn't read {}: {}", old_path, e));
+        "Failed to read the old version of the manifesto"
+    })?.text;
+
+    let body = |contents: &str| {
+        let (metadata, body) = front_matter::split_front_matter(contents);
+        let body = if args.no_clean { String::from(body) } else { cleaning::clean(body).text };
+        (metadata, body)
+    };
+    let (_, old) = body(&old_contents);
+    let (metadata, new) = body(new_contents);
+
+    let changes = diff::summarise_changes(transport, &old, &new).map_err(|e| {
+        diagnostics::error(e.to_string());
+        "Failed to summarise the changes"
+    })?;
+
+    let source_sha256 = state::input_hash(&new);
+    transports.record_source(&source_sha256);
+
+    let output = if args.json {
+        let report = RunReport {
+            diff: Some(changes.stats),
+            ..RunReport::new(changes.summary, transport, metadata, &source_sha256)
+        };
+
+        serde_json::to_string_pretty(&report).expect("Failed to serialize the run report")
+    } else {
+        changes.summary
+    };
+
+    write_output(args, &output).map_err(output_not_written)
+}
+
+// Summarises each document in a zip archive in turn, as though it were its own input, then
+// writes them all out together under their names in the archive. A document that fails doesn't
+// stop the rest, and once [interrupted], no more are started.
+fn run_archive(args: &Args, transports: &Transports, archive: &ArchiveInput, interrupted: &CancellationToken) -> Result<(), &'static str> {
+    let read = archive.read(args.read_options.max_bytes).map_err(|e| {
+        diagnostics::error(format!("Couldn't read {}: {}", archive.path.display(), e));
+        "Failed to read the archive"
+    })?;
+
+    for (name, reason) in &read.skipped {
+        diagnostics::warn(format!("skipped {} in {}: {}", name, archive.path.display(), reason));
+    }
 
-    let client = build_openai_client(&args);
+    let (outputs, failed) = summarise_members(args, &read.members, &archive.path.to_string_lossy(), || transports.open(args), interrupted);
 
-    let manifesto_summary = get_manifesto_summary(&client, &file_contents)
-        .expect("Failed to summarise manifesto");
+    for (_, summarised) in &outputs {
+        transports.record_source(&summarised.source_sha256);
+    }
+
+    write_output(args, &format_archive_outputs(&outputs, args.json)).map_err(output_not_written)?;
+
+    if failed {
+        return Err("Some manifestos in the archive failed to summarise");
+    }
 
-    println!("{}", manifesto_summary);
+    if read.members.is_empty() {
+        return Err("There was nothing in the archive to summarise");
+    }
 
     Ok(())
 }
 
-fn build_openai_client(args: &Args) -> reqwest::blocking::Client {
-    let mut headers = reqwest::header::HeaderMap::new();
+// Each of [members]' names and outputs, and whether any failed. Each member gets its own
+// transport from [open_transport], as files in a --batch run do.
+fn summarise_members<T: ChatTransport>(args: &Args, members: &[archive::Member], archive_path: &str, open_transport: impl Fn() -> T, interrupted: &CancellationToken) -> (Vec<(String, SummaryOutput)>, bool) {
+    let mut outputs: Vec<(String, SummaryOutput)> = Vec::new();
+    let mut failed = false;
+
+    for member in members {
+        if interrupted.is_cancelled() {
+            break;
+        }
 
-    let header_value: String = format!("Bearer {}", args.openai_key);
+        let decoded = match decoding::decode_input(&member.bytes, args.read_options.encoding) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                diagnostics::error(format!("Couldn't read {} in {}: {}", member.name, archive_path, e));
+                failed = true;
+                continue;
+            }
+        };
 
-    let header_value = header::HeaderValue::from_str(&header_value)
-        .expect("Couldn't build header with OpenAI key");
+        if args.verbose {
+            eprintln!("Read {} in {} as {}", member.name, archive_path, decoded.encoding.name());
+        }
 
-    headers.insert(header::AUTHORIZATION, header_value);
+        // Members can share a name with files on disk, so their state is kept beside the output
+        // (or the archive), named after both
+        let state_base_path = format!(
+            "{}.{}",
+            args.output_path.as_deref().unwrap_or(archive_path),
+            member.name.replace('/', "_")
+        );
 
-    reqwest::blocking::Client::builder()
-        .default_headers(headers)
-        .timeout(None)
-        .build()
-        .expect("Failed to build OpenAI client")
+        match summarise_document(args, &open_transport(), &decoded.text, Path::new(&member.name), &state_base_path, Some(interrupted)) {
+            Ok(summarised) => outputs.push((member.name.clone(), summarised)),
+            Err(e) => {
+                diagnostics::error(format!("Failed to summarise {} in {}: {}", member.name, archive_path, e));
+                failed = true;
+            }
+        }
+    }
+
+    (outputs, failed)
+}
+
+// Each member's output under its name: as headed sections, or with --json, as one object keyed
+// by name
+fn format_archive_outputs(outputs: &[(String, SummaryOutput)], json: bool) -> String {
+    if json {
+        let reports: serde_json::Map<String, serde_json::Value> = outputs.iter()
+            .map(|(name, summarised)| {
+                let output = &summarised.output;
+                let report = serde_json::from_str(output).unwrap_or_else(|_| serde_json::Value::String(output.clone()));
+                (name.clone(), report)
+            })
+            .collect();
+
+        return serde_json::to_string_pretty(&reports).expect("JSON values should always serialize");
+    }
+
+    outputs.iter()
+        .map(|(name, summarised)| format!("=== {} ===\n\n{}", name, summarised.output))
+        .collect::<Vec<String>>()
+        .join("\n\n")
 }
 
-fn get_manifesto_summary(client: &reqwest::blocking::Client, manifesto: &str) -> Result<String, String> {
+// Answers questions about [contents] from stdin until they run out, /quit is entered or the run
+// is [interrupted]. With --session, the conversation is picked up from the session file, if
+// there is one, and saved back to it at the end.
+fn run_chat(args: &Args, transport: &impl ChatTransport, contents: &str, interrupted: &CancellationToken) -> Result<(), &'static str> {
+    let session_path = args.session_path.as_deref().map(Path::new);
+
+    let mut conversation = match session_path.filter(|path| path.exists()) {
+        Some(path) => {
+            let (conversation, warning) = Conversation::load(path, contents).map_err(|e| {
+                diagnostics::error(format!("Couldn't read {}: {}", path.display(), e));
+                "Failed to read the --session file"
+            })?;
 
-    let req = OpenAiRequestBody {
-        model: GPT_4_MODEL_NAME,
-        messages: vec![
-            OpenAiRequestMessage {
-                role: "system",
-                content: "You are an experienced political journalist that writes four-paragraph summaries of the manifestos of political parties"
-            },
-            OpenAiRequestMessage {
-                role: "user",
-                content: "Please summarise the following manifesto:"
-            },
-            OpenAiRequestMessage {
-                role: "user", 
-                content: &manifesto
+            if let Some(warning) = warning {
+                diagnostics::warn(warning);
             }
-        ]
+
+            diagnostics::note(format!("Picked up {} earlier turn(s) from {}", conversation.turns().len(), path.display()));
+            conversation
+        }
+        None => Conversation::new(contents),
     };
 
+    let result = chat::run_repl(&mut conversation, transport, io::stdin().lock(), &mut io::stdout(), interrupted)
+        .map_err(|_| "Failed to read a question");
+
+    if args.verbose {
+        eprintln!("The conversation has used {} tokens", conversation.usage().total_tokens);
+    }
+
+    // Saved even if reading failed, so that the answers so far aren't lost
+    if let Some(path) = session_path {
+        conversation.save(path).map_err(|e| {
+            diagnostics::error(format!("Couldn't save the conversation to {}: {}", path.display(), e));
+            "Failed to write the --session file"
+        })?;
+    }
 
-    let resp = client
-        .post(OPENAI_ENDPOINT)
-        .json(&req)
-        .send()
-        .expect("Couldn't make request");
+    result
+}
 
-    let text: String = resp.text().expect("No text in the response");
+// Summarises every new or changed document that shows up in the watched directory until
+// [interrupted]. Up to --jobs files are summarised at once, and the ones in progress are finished
+// first.
+fn run_watch(args: &Args, transports: &Transports, options: &WatchOptions, interrupted: &CancellationToken) -> Result<(), &'static str> {
+    let titles = args.name_from_title.then(|| transports.open(args));
+    let mut watcher = open_watcher(options, titles.as_ref())?;
 
-    if let Ok(json) = serde_json::from_str::<OpenAiResponse>(&text) {
-        if let Some(response_message) = json.choices.get(0)  {
-            Ok(response_message.message.content.clone())
-        } else {
-            Err(format!("No content in {}", text))
+    eprintln!("Watching {} for manifestos", options.dir.display());
+
+    watcher.run(interrupted, |input_path, contents, output_path| summarise_to_file(args, transports, input_path, contents, output_path));
+
+    Ok(())
+}
+
+// Summarises every file in a directory that hasn't already been summarised (with the same
+// contents) to --output-dir, up to --jobs at once, then writes the --report, if any. Once
+// [interrupted], no more files are started.
+fn run_batch(args: &Args, transports: &Transports, options: &WatchOptions, interrupted: &CancellationToken) -> Result<(), &'static str> {
+    if args.preflight {
+        let info = runtime::block_on(auth_check::check(&transports.client, transport::OPENAI_BASE_URL, open_ai::GPT_4_MODEL_NAME, false))
+            .map_err(|failure| {
+                diagnostics::error(failure.to_string());
+                "The --preflight check failed, so the batch wasn't started"
+            })?;
+
+        eprintln!("{}", auth_check::format_info(&info));
+    }
+
+    let titles = args.name_from_title.then(|| transports.open(args));
+    let mut watcher = open_watcher(options, titles.as_ref())?;
+
+    let polled = watcher
+        .poll(interrupted, &|input_path, contents, output_path| {
+            summarise_to_file(args, transports, input_path, contents, output_path)
+        })
+        .map_err(|_| "Failed to read the batch directory")?;
+
+    let rows: Vec<BatchReportRow> = polled.iter()
+        .map(|file| BatchReportRow::new(file, open_ai::GPT_4_MODEL_NAME, &args.prices))
+        .collect();
+
+    eprintln!("{}", report::format_batch_summary(&rows));
+
+    if let Some(report_path) = &args.report_path {
+        report::write_batch_report(Path::new(report_path), &rows)
+            .map_err(|_| "Failed to write the batch report")?;
+    }
+
+    if rows.iter().any(|row| row.status == "failed") {
+        return Err("Some manifestos failed to summarise");
+    }
+
+    Ok(())
+}
+
+// A watcher for [options]' directory that, with --name-from-title, names outputs after titles,
+// asking [titles] for them where a document has none of its own
+fn open_watcher<'a>(options: &WatchOptions, titles: Option<&'a impl TitleSource>) -> Result<watch::Watcher<'a>, &'static str> {
+    let watcher = watch::Watcher::open(options.clone())
+        .map_err(|_| "Failed to set up the output directory")?;
+
+    Ok(match titles {
+        Some(titles) => watcher.with_titles(titles),
+        None => watcher,
+    })
+}
+
+// Summarises one document from a watched or batched directory to [output_path], returning the
+// tokens it used and its fingerprint. Each document gets its own transport so that its usage is counted on its own,
+// but they all share the one client, rate limit pause, --save-prompt log and --record or
+// --replay directory.
+fn summarise_to_file(args: &Args, transports: &Transports, input_path: &Path, contents: &str, output_path: &Path) -> Result<Summarised, String> {
+    let transport = transports.open(args);
+    let output_path = output_path.to_string_lossy();
+
+    let summarised = summarise_document(args, &transport, contents, input_path, &output_path, None)?;
+    fs::write(output_path.as_ref(), format!("{}\n", summarised.output))
+        .map_err(|e| format!("Failed to write {}: {}", output_path, e))?;
+
+    transports.record_source(&summarised.source_sha256);
+    write_translations(Some(&output_path), &summarised)?;
+
+    Ok(Summarised { usage: transport.total_usage(), models_used: transport.models_used(), source_sha256: summarised.source_sha256 })
+}
+
+// What every transport in the run is built from
+struct Transports {
+    client: reqwest::Client,
+    // When OpenAI rate-limits any transport, they all wait
+    paused_until: Arc<Mutex<Option<Instant>>>,
+    prompt_log: Option<Arc<PromptLog>>,
+    request_log: Option<Arc<RequestLog>>,
+    recorder: Option<Arc<Recorder>>,
+    replayer: Option<Arc<Replayer>>,
+    // Cancelled by a second Ctrl+C, which abandons every request in flight
+    aborted: CancellationToken,
+    // With more than one --key-file, every transport takes turns with the same keys
+    key_rotation: Option<Arc<KeyRotation>>,
+}
+
+impl Transports {
+    // Notes the fingerprint of a document that was summarised in the --run-report, if there is one
+    fn record_source(&self, source_sha256: &str) {
+        if let Some(request_log) = &self.request_log {
+            request_log.record_source(source_sha256);
+        }
+    }
+
+    // A fresh transport, which talks to OpenAI unless --replay was given, recording everything
+    // it gets back under --record, and trying the --model-fallback models when a model can't answer
+    fn open(&self, args: &Args) -> Box<dyn ChatTransport> {
+        let transport = self.open_without_fallback(args);
+
+        match args.model_fallback.is_empty() {
+            true => transport,
+            false => Box::new(FallbackTransport::new(transport, args.model_fallback.clone())),
+        }
+    }
+
+    fn open_without_fallback(&self, args: &Args) -> Box<dyn ChatTransport> {
+        if let Some(replayer) = &self.replayer {
+            return Box::new(ReplayTransport::new(Arc::clone(replayer)));
+        }
+
+        let transport = ReqwestTransport::new(
+            self.client.clone(),
+            transport::OPENAI_BASE_URL,
+            args.request_id.clone(),
+            args.verbose,
+        )
+            .with_shared_pause(Arc::clone(&self.paused_until))
+            .with_prompt_log(self.prompt_log.clone())
+            .with_request_log(self.request_log.clone())
+            .with_chat_defaults(chat_defaults(args))
+            .with_size_limits(args.size_limits)
+            .with_cancellation(self.aborted.clone())
+            .with_key_rotation(self.key_rotation.clone())
+            .with_net_retries(args.net_retries);
+
+        match &self.recorder {
+            Some(recorder) => Box::new(RecordingTransport::new(transport, Arc::clone(recorder))),
+            None => Box::new(transport),
         }
-    } else {
-        Err(format!("Couldn't deserialize: {}", text))
     }
 }
 
-mod open_ai {
-    use serde::{ Serialize, Deserialize };
-    use std::fmt;
+// A document's output, and the fingerprint of the text it was made from
+#[derive(Debug)]
+struct SummaryOutput {
+    output: String,
+    // The SHA-256 of the text sent to the model (see [RunReport::source_sha256])
+    source_sha256: String,
+    // The output in each of the other --languages, by language code. With --json, they're in
+    // [output] instead, and this is empty.
+    translations: Vec<(String, String)>,
+    // The --languages the summary couldn't be translated into
+    untranslated: Vec<String>,
+}
+
+// Runs the whole pipeline (moderation, then sections or a summary) on one document and returns
+// the text to output. The text is prepared first by the stages from [build_pipeline]: unless
+// --no-clean is passed, it's cleaned up (see [cleaning]), and with --truncate, its middle is cut
+// out if it's too long (see [truncation]). Any front matter is kept away from the model, and is used to fill in the
+// --template (for text output) or added to the report (for --json). Checkpoints are kept next to
+// [state_base_path]. The prompts come from --prompts (by [input_path]'s extension) or
+// --prompt-template, if either was given. With --stamp, the text ends with the fingerprint of
+// what was summarised. Once [cancelled] is
+// set, no more chunks are started and the chunks that were finished are output instead (see
+// [format_incomplete]).
+fn summarise_document(args: &Args, transport: &impl ChatTransport, contents: &str, input_path: &Path, state_base_path: &str, cancelled: Option<&CancellationToken>) -> Result<SummaryOutput, String> {
+    let doc = build_pipeline(args).run(Document::new(contents)).map_err(|e| format!("Failed to prepare the manifesto: {}", e))?;
+    let body = doc.text.as_str();
+    let metadata = &doc.metadata;
+
+    // Taken from exactly what's sent to the model, so that it changes when cleaning does
+    let source_sha256 = state::input_hash(body);
+    let output = match args.offline {
+        true => summarise_offline(args, body, metadata, &source_sha256),
+        false => summarise_body(args, transport, &doc, input_path, state_base_path, cancelled)?,
+    };
+
+    // An incomplete summary isn't worth translating
+    let translations = match cancelled.is_some_and(|cancelled| cancelled.is_cancelled()) {
+        true => Vec::new(),
+        false => translate_summary(args, transport, &output),
+    };
+    let untranslated: Vec<String> = translations.iter()
+        .filter(|translation| translation.text.is_err())
+        .map(|translation| translation.language.clone())
+        .collect();
+
+    let (output, translations) = match args.json {
+        true => (add_translations_to_report(transport, &output, translations), Vec::new()),
+        false => (output, translations.into_iter()
+            .filter_map(|translation| Some((translation.language, translation.text.ok()?)))
+            .collect()),
+    };
+
+    let render = |output: String| match &args.template {
+        Some(template) if !args.json => front_matter::render_template(template, metadata, &output),
+        _ => output,
+    };
+    let output = render(output);
+    let translations = translations.into_iter().map(|(language, text)| (language, render(text))).collect();
+
+    let output = match args.appendix {
+        true => add_appendix(args.json, body, output),
+        false => output,
+    };
+
+    let output = match &args.diff_against {
+        Some(old_path) => append_summary_diff(args, transport, output, old_path)?,
+        None => output,
+    };
+
+    let output = match args.stamp {
+        true => format!("{}\n\n{}{}", output.trim_end(), SOURCE_STAMP_PREFIX, source_sha256),
+        false => output,
+    };
+
+    Ok(SummaryOutput { output, source_sha256, translations, untranslated })
+}
 
-    pub const GPT_35_MODEL_NAME: &str = "gpt-3.5-turbo";
-    pub const GPT_4_MODEL_NAME: &str = "gpt-4-turbo";
+// The stages every document goes through before it's summarised (see [pipeline]), as the flags
+// ask for them. --dry-run shows what cleaning would do, so it's always cleaned (with the full
+// report) and never truncated.
+fn build_pipeline(args: &Args) -> Pipeline {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(EncodingFixup);
+    pipeline.push(FrontMatter);
 
-    #[derive(Serialize)]
-    pub struct OpenAiRequestBody<'a> {
-        pub model: &'a str,
-        pub messages: Vec<OpenAiRequestMessage<'a>>,
+    if args.dry_run || !args.no_clean {
+        pipeline.push(Cleaning { full_report: args.dry_run });
     }
 
-    #[derive(Serialize)]
-    pub struct OpenAiRequestMessage<'a> {
-        pub role: &'a str,
-        pub content: &'a str,
+    if let Some(head_tail) = args.truncate.filter(|_| !args.dry_run) {
+        pipeline.push(Truncation(head_tail));
     }
 
-    #[derive(Deserialize)]
-    pub struct OpenAiResponse {
-        pub choices: Vec<OpenAiResponseMessage>,
+    if let Some(chunk_tokens) = args.chunk_tokens {
+        pipeline.push(ChunkPlanning {
+            chunk_tokens,
+            heading_pattern: args.heading_pattern.clone(),
+            per_section: args.per_section,
+        });
     }
 
-    impl fmt::Display for OpenAiResponse {
-        fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-            write!(formatter, "{}", self.choices[0].message.content)?;
+    pipeline
+}
+
+// Translates the summary in [output] (its `summary`, with --json) into every --language after
+// the first, reporting any that fail
+fn translate_summary(args: &Args, transport: &impl ChatTransport, output: &str) -> Vec<Translation> {
+    let Some((_, others)) = args.languages.split_first().filter(|(_, others)| !others.is_empty()) else {
+        return Vec::new();
+    };
+
+    let summary = match args.json {
+        true => serde_json::from_str::<serde_json::Value>(output).ok()
+            .and_then(|report| report["summary"].as_str().map(String::from))
+            .unwrap_or_default(),
+        false => String::from(output),
+    };
+
+    let translations = translation::translate(transport, &summary, others, args.chunk_jobs());
 
-            Ok(())
+    for translation in &translations {
+        if let Err(e) = &translation.text {
+            diagnostics::warn(format!("couldn't translate the summary into {}: {}", translation::describe(&translation.language), e));
         }
     }
 
-    #[derive(Deserialize)]
-    pub struct OpenAiResponseMessage {
-        pub message: OpenAiResponseMessageContent,
+    translations
+}
+
+// Adds [translations] to the --json report, keyed by language code, along with why any failed.
+// The usage is brought up to date too, since the report was made before they were sent.
+fn add_translations_to_report(transport: &impl ChatTransport, output: &str, translations: Vec<Translation>) -> String {
+    if translations.is_empty() {
+        return String::from(output);
     }
 
-    #[derive(Deserialize)]
-    pub struct OpenAiResponseMessageContent {
-        pub content: String
+    let mut translated = serde_json::Map::new();
+    let mut errors = serde_json::Map::new();
+
+    for translation in translations {
+        match translation.text {
+            Ok(text) => translated.insert(translation.language, serde_json::Value::String(text)),
+            Err(e) => errors.insert(translation.language, serde_json::Value::String(e.to_string())),
+        };
     }
-}
 
-mod arg_parsing {
-    use std::fs;
+    let mut report: serde_json::Value = serde_json::from_str(output).expect("--json output should always be JSON");
+    report["translations"] = serde_json::Value::Object(translated);
 
-    pub struct Args {
-        pub file_path: String,
-        pub openai_key: String,
+    if !errors.is_empty() {
+        report["translation_errors"] = serde_json::Value::Object(errors);
     }
 
-    impl Args {
-        pub fn build(mut args: impl Iterator<Item = String>) -> Result<Args, &'static str> {
-            args.next(); // First arg is the executable's name
+    report["usage"] = serde_json::to_value(transport.total_usage()).expect("usage should always serialize");
 
-            let file_path = match args.next() {
-                Some(arg) => arg,
-                None => return Err("Didn't get a file_path"),
-            };
+    let models_used = transport.models_used();
 
-            let openai_key_file_path = match args.next() {
-                Some(arg) => arg,
-                None => return Err("Didn't get a file path for the OpenAI key"),
-            };
+    if !models_used.is_empty() {
+        report["models"] = serde_json::to_value(models_used).expect("usage should always serialize");
+    }
+
+    serde_json::to_string_pretty(&report).expect("JSON values should always serialize")
+}
+
+// The progress display for a document's chunks. --watch and --batch can summarise several files
+// at once, whose displays would draw over each other, so they never show one.
+fn progress_for(args: &Args) -> Progress<'_> {
+    let visible = matches!(args.input, Input::File(_) | Input::Archive(_)) && Progress::should_show(args.quiet, args.json);
+
+    Progress::new(visible, &args.prices)
+}
+
+// Adds the names and acronyms in [text] (see [entities]) to the output: as an appendix after
+// the summary, or as the report's `entities` with --json
+fn add_appendix(json: bool, text: &str, output: String) -> String {
+    let entities = entities::extract(text);
+
+    if json {
+        let mut report: serde_json::Value = serde_json::from_str(&output).expect("--json output should always be JSON");
+        report["entities"] = serde_json::to_value(&entities).expect("entities should always serialize");
+
+        return serde_json::to_string_pretty(&report).expect("JSON values should always serialize");
+    }
 
-            let mut openai_key: String =
-                fs::read_to_string(openai_key_file_path).expect("Failed to read OpenAI key file");
+    match entities.is_empty() {
+        true => output,
+        false => format!("{}\n\n{}", output.trim_end(), entities::format_appendix(&entities)),
+    }
+}
 
-            if openai_key.ends_with('\n') {
-                openai_key.pop();
+// Writes each translation next to the --output (summary.md's Welsh goes to summary.cy.md), or
+// prints it after the summary under the language's name. Fails if any --language couldn't be
+// translated into, once the rest are written.
+fn write_translations(output_path: Option<&str>, summarised: &SummaryOutput) -> Result<(), String> {
+    for (language, output) in &summarised.translations {
+        match output_path {
+            Some(path) => {
+                let path = translated_output_path(Path::new(path), language);
+                fs::write(&path, format!("{}\n", output)).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
             }
+            None => println!("\n=== {} ===\n\n{}", translation::describe(language), output),
+        }
+    }
 
-            Ok(Args {
-                file_path,
-                openai_key,
-            })
+    match summarised.untranslated.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Couldn't translate the summary into {}", summarised.untranslated.join(", "))),
+    }
+}
+
+// [path] with [language] before its extension, e.g. summary.md -> summary.cy.md
+fn translated_output_path(path: &Path, language: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{}.{}.{}", stem, language, extension.to_string_lossy())),
+        None => path.with_file_name(format!("{}.{}", stem, language)),
+    }
+}
+
+// [output] followed by how its summary differs from the one saved at [old_path]: under a
+// `=== Changes since ... ===` heading in text, or as `summary_diff` in --json. The old summary can
+// be a --json output too, in which case its `summary` is compared.
+fn append_summary_diff(args: &Args, transport: &impl ChatTransport, output: String, old_path: &str) -> Result<String, String> {
+    let old = fs::read_to_string(old_path).map_err(|e| format!("Couldn't read the old summary {}: {}", old_path, e))?;
+    let summary_of = |output: &str| serde_json::from_str::<serde_json::Value>(output).ok()
+        .and_then(|report| report["summary"].as_str().map(String::from));
+
+    let old_summary = summary_of(&old).unwrap_or(old);
+    let new_summary = match args.json {
+        true => summary_of(&output).unwrap_or_default(),
+        false => output.clone(),
+    };
+
+    let word_diff = diff::word_diff(&old_summary, &new_summary);
+    let what_changed = match args.diff_llm {
+        true => Some(diff::describe_summary_changes(transport, &old_summary, &new_summary)
+            .map_err(|e| format!("Failed to describe what changed in the summary: {}", e))?),
+        false => None,
+    };
+
+    if args.json {
+        let mut report: serde_json::Value = serde_json::from_str(&output).expect("--json output should always be JSON");
+        report["summary_diff"] = serde_json::json!({
+            "against": old_path,
+            "word_diff": word_diff,
+            "what_changed": what_changed,
+        });
+
+        return Ok(serde_json::to_string_pretty(&report).expect("JSON values should always serialize"));
+    }
+
+    let mut sections = vec![output.trim_end().to_string(), format!("=== Changes since {} ===", old_path)];
+    sections.extend(what_changed);
+    sections.push(word_diff.marked_up);
+
+    Ok(sections.join("\n\n"))
+}
+
+// What every chat request gets unless it says otherwise (--max-tokens, --stop, --user-id and --seed)
+fn chat_defaults(args: &Args) -> ChatDefaults {
+    ChatDefaults {
+        max_tokens: args.max_tokens,
+        stop: (!args.stop.is_empty()).then(|| args.stop.clone()),
+        user: args.user_id.clone(),
+        seed: args.seed,
+    }
+}
+
+fn summarise_body(args: &Args, transport: &impl ChatTransport, doc: &Document, input_path: &Path, state_base_path: &str, cancelled: Option<&CancellationToken>) -> Result<String, String> {
+    let contents = doc.text.as_str();
+    let metadata = &doc.metadata;
+
+    // The same fingerprint as [summarise_document]'s, since [contents] is what's sent to the model
+    let source_sha256 = &state::input_hash(contents);
+
+    if args.moderate {
+        let flagged = moderation::moderate(transport, contents, args.moderation_threshold)
+            .map_err(|e| format!("Failed to moderate manifesto: {}", e))?;
+
+        if !flagged.is_empty() {
+            return Err(format!(
+                "Refusing to summarise a manifesto that was flagged by moderation:\n{}",
+                moderation::format_flagged_chunks(&flagged)
+            ));
+        }
+    }
+
+    if !args.questions.is_empty() {
+        return answer_document_questions(args, transport, contents, metadata, source_sha256);
+    }
+
+    if let Some(heading_pattern) = &args.section_pattern {
+        match sections::split_into_sections(contents, heading_pattern) {
+            Some(sections) => return summarise_document_sections(args, transport, &sections, metadata, source_sha256),
+            None => diagnostics::warn("no section headings found; summarising the whole manifesto instead"),
+        }
+    }
+
+    let checkpoint = Checkpoint::open(
+        state::state_path(state_base_path),
+        contents,
+        open_ai::GPT_4_MODEL_NAME,
+        args.resume,
+    );
+
+    let file_name = input_path.file_name().unwrap_or_default().to_string_lossy();
+    let prompt_template = args.prompt_template.as_ref().map(|template| template.bind(&file_name, contents, metadata));
+    let prompt = args.prompts.prompt_for(input_path);
+
+    // --paragraphs only changes the default system prompt; a custom one is left as it is
+    let default_system_prompt = prompt_template.as_ref().is_none_or(|template| template.system().is_none())
+        && prompt.is_none_or(|prompt| prompt.system.is_none());
+    let paragraphs_prompt = args.paragraphs.filter(|_| default_system_prompt).map(|paragraphs| Prompt {
+        system: Some(summary::system_prompt_for_paragraphs(paragraphs)),
+        instruction: prompt.and_then(|prompt| prompt.instruction.clone()),
+    });
+    let expected_paragraphs = args.paragraphs.or(default_system_prompt.then_some(summary::DEFAULT_PARAGRAPHS));
+
+    let ledger = Ledger::new(source_sha256, args.verbose);
+    let chunk_cache = match &args.chunk_cache_dir {
+        Some(dir) => Some(ChunkCache::open(Path::new(dir), &chat_defaults(args))
+            .map_err(|e| format!("Couldn't open the chunk cache at {}: {}", dir, e))?),
+        None => None,
+    };
+
+    let progress = progress_for(args);
+    let options = SummaryOptions {
+        chunk_tokens: args.chunk_tokens,
+        jobs: args.chunk_jobs(),
+        candidates: args.candidates,
+        checkpoint: Some(&checkpoint),
+        prompt: paragraphs_prompt.as_ref().or(prompt),
+        prompt_template: prompt_template.as_ref(),
+        heading_pattern: Some(&args.heading_pattern),
+        per_section: args.per_section,
+        cache_prompt: args.cache_prompt,
+        cancelled,
+        ledger: Some(&ledger),
+        chunk_cache: chunk_cache.as_ref(),
+        chunk_timeout: args.chunk_timeout,
+        topics: &args.topics,
+        skip_off_topic: !args.no_skip,
+        skip_filtered: args.skip_filtered,
+        planned_chunks: doc.chunks.as_deref(),
+        progress: Some(&progress),
+    };
+
+    let summarised = match args.chunk_tokens.filter(|_| args.per_section) {
+        Some(chunk_tokens) => summary::summarise_per_section(transport, contents, chunk_tokens, &options),
+        None => summary::summarise(transport, contents, &options).map(|candidates| (candidates, Vec::new())),
+    };
+
+    if let (Some(chunk_cache), true) = (&chunk_cache, args.verbose) {
+        let (hits, lookups) = chunk_cache.stats();
+        eprintln!("{} of {} chunks were chunk cache hits", hits, lookups);
+    }
+
+    if let (Err(ManifestoError::Http(e)), true) = (&summarised, args.fallback_offline) {
+        diagnostics::warn(format!("couldn't reach OpenAI ({}), so falling back to an extractive summary", e));
+        return Ok(summarise_offline(args, contents, metadata, source_sha256));
+    }
+
+    // The checkpoint is kept (whatever --keep-state says) so that --resume can finish the run
+    if let Err(ManifestoError::Cancelled) = summarised {
+        return Ok(format_incomplete(args, transport, &checkpoint, metadata, source_sha256));
+    }
+
+    let (candidates, section_summaries) = summarised.map_err(|e| format!("Failed to summarise manifesto: {}", e))?;
+
+    if args.per_section && section_summaries.is_empty() {
+        diagnostics::warn("no section headings found; only the overview was summarised");
+    }
+
+    let picked = if args.pick_best && candidates.len() > 1 {
+        let picked = summary::pick_best(transport, &candidates)
+            .map_err(|e| format!("Failed to pick the best summary: {}", e))?;
+
+        if args.verbose {
+            eprintln!("Picked candidate {}:\n{}", picked.index + 1, picked.reasoning);
         }
+
+        Some(picked.index)
+    } else {
+        None
+    };
+
+    let mut candidates = candidates;
+    let expectations = Expectations::new(expected_paragraphs, args.min_words);
+    check_output(args, transport, &checkpoint, contents, &options, &expectations, &mut candidates[picked.unwrap_or(0)])?;
+
+    let critique = if args.critique {
+        critique_summary(args, transport, &checkpoint, contents, &options, &mut candidates[picked.unwrap_or(0)])?
+    } else {
+        None
+    };
+
+    let mut usage = checkpoint.resumed_usage();
+    usage.add(&transport.total_usage());
+
+    let output = if args.json {
+        let report = RunReport {
+            candidates: if candidates.len() > 1 { candidates.clone() } else { Vec::new() },
+            sections: section_summaries,
+            critique,
+            usage,
+            topics: args.topics.clone(),
+            ..RunReport::new(candidates[picked.unwrap_or(0)].clone(), transport, metadata.clone(), source_sha256)
+        };
+
+        serde_json::to_string_pretty(&report).expect("Failed to serialize the run report")
+    } else {
+        let overview = if let Some(picked) = picked {
+            candidates[picked].clone()
+        } else if candidates.len() > 1 {
+            summary::format_candidates(&candidates)
+        } else {
+            candidates[0].clone()
+        };
+
+        let overview = match &critique {
+            Some(critique) if !critique.revised => format!("{}\n\n=== Critique ===\n{}", overview.trim_end(), critique.text),
+            _ => overview,
+        };
+
+        if section_summaries.is_empty() {
+            overview
+        } else {
+            format!("{}\n\n{}", overview.trim_end(), sections::format_section_summaries(&section_summaries))
+        }
+    };
+
+    checkpoint.finish(args.keep_state);
+
+    Ok(output)
+}
+
+// An extractive summary of [contents] made without the model (see [extractive]), labelled as
+// such in text output and marked `extractive` in --json
+fn summarise_offline(args: &Args, contents: &str, metadata: &Metadata, source_sha256: &str) -> String {
+    let extract = extractive::summarise(contents, &args.heading_pattern, extractive::DEFAULT_TARGET_WORDS);
+
+    if !args.json {
+        return format!("{}\n\n{}", extractive::EXTRACTIVE_LABEL, extract.summary);
+    }
+
+    // Nothing was sent to OpenAI, so there's no usage to report
+    let report = RunReport {
+        summary: extract.summary,
+        sections: extract.sections,
+        extractive: true,
+        metadata: metadata.clone(),
+        source_sha256: String::from(source_sha256),
+        ..RunReport::default()
+    };
+
+    serde_json::to_string_pretty(&report).expect("Failed to serialize the run report")
+}
+
+// What's output when a run is interrupted: a marker saying how far it got, then the summaries of
+// the chunks that were finished (including any from a resumed run)
+fn format_incomplete(args: &Args, transport: &impl ChatTransport, checkpoint: &Checkpoint, metadata: &Metadata, source_sha256: &str) -> String {
+    let chunk_summaries = checkpoint.completed_chunks();
+    let marker = format!(
+        "[INCOMPLETE: interrupted after {} of {} chunks; rerun with --resume to finish]",
+        chunk_summaries.len(), checkpoint.planned_chunk_count()
+    );
+
+    if !args.json {
+        return format!("{}\n\n{}", marker, chunk_summaries.join("\n\n"));
+    }
+
+    let mut usage = checkpoint.resumed_usage();
+    usage.add(&transport.total_usage());
+
+    let report = RunReport {
+        incomplete: true,
+        usage,
+        topics: args.topics.clone(),
+        ..RunReport::new(chunk_summaries.join("\n\n"), transport, metadata.clone(), source_sha256)
+    };
+
+    serde_json::to_string_pretty(&report).expect("Failed to serialize the run report")
+}
+
+// Checks [summary] against its source (the chunk summaries for a chunked run, so that the check
+// fits in the model's context) and, with --revise, rewrites it to address the critique. Each
+// request is skipped, with a warning, if it could take the run past --max-cost.
+fn critique_summary(args: &Args, transport: &impl ChatTransport, checkpoint: &Checkpoint, contents: &str, options: &SummaryOptions, summary: &mut String) -> Result<Option<Critique>, String> {
+    let chunk_summaries = checkpoint.completed_chunks();
+    let source = if chunk_summaries.is_empty() { String::from(contents) } else { chunk_summaries.join("\n\n") };
+
+    if !within_max_cost(args, transport, checkpoint, &[&source, summary], summary) {
+        diagnostics::warn("skipping the critique to stay under --max-cost");
+        return Ok(None);
+    }
+
+    let text = summary::critique(transport, &source, summary)
+        .map_err(|e| format!("Failed to critique the summary: {}", e))?;
+
+    if !args.revise {
+        return Ok(Some(Critique { text, revised: false }));
+    }
+
+    if !within_max_cost(args, transport, checkpoint, &[&source, summary, &text], summary) {
+        diagnostics::warn("skipping the revision to stay under --max-cost");
+        return Ok(Some(Critique { text, revised: false }));
+    }
+
+    *summary = summary::revise(transport, &source, summary, &text, options)
+        .map_err(|e| format!("Failed to revise the summary: {}", e))?;
+
+    if args.verbose {
+        eprintln!("Revised the summary to address this critique:\n{}", text);
+    }
+
+    Ok(Some(Critique { text, revised: true }))
+}
+
+// Warns about a summary that fails the [output_checks]. With --strict-output, the summary is
+// asked for again (once) with the problems described, and it's an error if that fails too.
+// Any reply in the run that was cut off counts against the summary, since a cut-off chunk
+// summary leaves a gap in it.
+fn check_output(args: &Args, transport: &impl ChatTransport, checkpoint: &Checkpoint, contents: &str, options: &SummaryOptions, expectations: &Expectations, summary: &mut String) -> Result<(), String> {
+    let cut_off_replies = transport.cut_off_replies();
+    let issues = output_checks::check_summary(summary, cut_off_replies > 0, expectations);
+
+    if issues.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("\n*** WARNING: the summary looks truncated or too short ***\n{}\n", output_checks::format_issues(&issues));
+    diagnostics::record_warning(format!("the summary looks truncated or too short: {}", output_checks::format_issues(&issues)));
+
+    if !args.strict_output {
+        return Ok(());
+    }
+
+    let chunk_summaries = checkpoint.completed_chunks();
+    let source = if chunk_summaries.is_empty() { String::from(contents) } else { chunk_summaries.join("\n\n") };
+
+    if !within_max_cost(args, transport, checkpoint, &[&source, summary], summary) {
+        return Err(String::from("The summary failed the output checks, and retrying it would go past --max-cost"));
+    }
+
+    eprintln!("Asking for the summary again (--strict-output)");
+
+    let retried = summary::retry(transport, &source, summary, &output_checks::format_issues(&issues), options)
+        .map_err(|e| format!("Failed to retry the summary: {}", e))?;
+    let issues = output_checks::check_summary(&retried, transport.cut_off_replies() > cut_off_replies, expectations);
+
+    if !issues.is_empty() {
+        return Err(format!(
+            "The summary still failed the output checks after a retry:\n{}",
+            output_checks::format_issues(&issues)
+        ));
+    }
+
+    *summary = retried;
+
+    Ok(())
+}
+
+// Whether a request sending [prompt] and getting back about as much as [expected_reply] would
+// keep the run's estimated cost within --max-cost (if any)
+fn within_max_cost(args: &Args, transport: &impl ChatTransport, checkpoint: &Checkpoint, prompt: &[&str], expected_reply: &str) -> bool {
+    let Some(max_cost) = args.max_cost else {
+        return true;
+    };
+
+    let prompt_tokens: usize = prompt.iter().map(|text| chunking::estimate_tokens(text)).sum();
+    let completion_tokens = chunking::estimate_tokens(expected_reply);
+
+    let mut usage = checkpoint.resumed_usage();
+    usage.add(&transport.total_usage());
+    usage.add(&OpenAiUsage {
+        prompt_tokens: prompt_tokens as u64,
+        completion_tokens: completion_tokens as u64,
+        total_tokens: (prompt_tokens + completion_tokens) as u64,
+    });
+
+    args.prices.estimated_cost_usd_by_model(&usage, &transport.models_used(), open_ai::GPT_4_MODEL_NAME)
+        .is_none_or(|cost| cost <= max_cost)
+}
+
+// Prints the output, or writes it to --output if given. With --json, it's printed at the end of
+// the run instead, along with any warnings and errors (see [diagnostics]).
+fn write_output(args: &Args, output: &str) -> Result<(), String> {
+    match (args.output_path.as_deref(), args.json) {
+        (Some(path), _) => fs::write(path, format!("{}\n", output)).map_err(|e| format!("Couldn't write {}: {}", path, e))?,
+        (None, true) => diagnostics::hold_report(output),
+        (None, false) => println!("{}", output),
+    }
+
+    Ok(())
+}
+
+// The error for a run whose output couldn't be written (see [write_output])
+fn output_not_written(error: String) -> &'static str {
+    diagnostics::error(error);
+    "Failed to write the output file"
+}
+
+fn answer_document_questions(args: &Args, transport: &impl ChatTransport, contents: &str, metadata: &Metadata, source_sha256: &str) -> Result<String, String> {
+    let answers = qa::answer_questions(transport, contents, &args.questions, args.chunk_tokens, args.chunk_jobs(), args.cache_prompt)
+        .map_err(|e| format!("Failed to answer the questions: {}", e))?;
+    let formatted = qa::format_answers(&answers);
+
+    if args.json {
+        let report = RunReport {
+            topics: args.topics.clone(),
+            answers,
+            ..RunReport::new(formatted, transport, metadata.clone(), source_sha256)
+        };
+
+        Ok(serde_json::to_string_pretty(&report).expect("Failed to serialize the run report"))
+    } else {
+        Ok(formatted)
+    }
+}
+
+fn summarise_document_sections(args: &Args, transport: &impl ChatTransport, sections: &[sections::Section], metadata: &Metadata, source_sha256: &str) -> Result<String, String> {
+    let section_summaries = summary::summarise_sections(transport, sections, args.chunk_jobs())
+        .map_err(|e| format!("Failed to summarise manifesto sections: {}", e))?;
+    let formatted = sections::format_section_summaries(&section_summaries);
+
+    if args.json {
+        let report = RunReport {
+            sections: section_summaries,
+            topics: args.topics.clone(),
+            ..RunReport::new(formatted, transport, metadata.clone(), source_sha256)
+        };
+
+        Ok(serde_json::to_string_pretty(&report).expect("Failed to serialize the run report"))
+    } else {
+        Ok(formatted)
+    }
+}
+
+// Builds the client used for every OpenAI request. [request_id_header] is sent as the
+// x-request-id header when set, and [pool_size] is the number of requests that may be in flight
+// at once. [accept_invalid_certs] (--danger-accept-invalid-certs) turns off TLS certificate
+// checks, and is only for testing against local stubs.
+fn build_openai_client(
+    openai_key: &SecretString,
+    attribution: &Attribution,
+    request_id_header: Option<&str>,
+    pool_size: usize,
+    pool_idle_timeout: Duration,
+    accept_invalid_certs: bool,
+) -> reqwest::Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+
+    headers.insert(header::AUTHORIZATION, key_rotation::authorization_header(openai_key));
+    attribution.add_headers(&mut headers);
+
+    if let Some(request_id) = request_id_header {
+        let header_value = header::HeaderValue::from_str(request_id)
+            .expect("Couldn't build header with the request ID");
+
+        headers.insert(REQUEST_ID_HEADER, header_value);
+    }
+
+    // The one client is shared by every request in the run (and every file in a --batch or
+    // --watch run), so keep enough idle connections around for each job to reuse rather than
+    // paying for a new TLS handshake per chunk.
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .pool_max_idle_per_host(pool_size)
+        .pool_idle_timeout(pool_idle_timeout)
+        .tcp_keepalive(Duration::from_secs(60))
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .build()
+        .expect("Failed to build OpenAI client")
+}
+
+// Logs a line to stderr tagged with the caller-supplied request ID. Without an ID, nothing is
+// logged so that the default output is unchanged.
+fn log_with_request_id(request_id: Option<&str>, message: &str) {
+    if let Some(request_id) = request_id {
+        eprintln!("[request_id={}] {}", request_id, message);
+    }
+}
+
+mod arg_parsing {
+    use regex::Regex;
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use crate::archive::{ArchiveInput, DEFAULT_MAX_ARCHIVE_BYTES};
+    use crate::attribution::{self, Attribution};
+    use crate::decoding::{self, ReadOptions};
+    use crate::diagnostics;
+    use crate::fetch;
+    use crate::keystore;
+    use crate::model_fallback;
+    use crate::secret::SecretString;
+    use crate::open_ai::{self, GPT_35_MODEL_NAME, GPT_4_MODEL_NAME};
+    use crate::prices::PriceTable;
+    use crate::prompt_template::PromptTemplate;
+    use crate::prompts::PromptConfig;
+    use crate::sections::DEFAULT_HEADING_PATTERN;
+    use crate::translation;
+    use crate::transport::SizeLimits;
+    use crate::truncation::HeadTail;
+    use crate::watch::WatchOptions;
+
+    const OPENAI_KEY_ENV_VAR: &str = "OPENAI_API_KEY";
+    // The environment's --key-file: a list of paths like PATH's (see [key_files_from_env])
+    const KEY_FILE_ENV_VAR: &str = "MANIFESTO_KEY_FILE";
+    const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+    // The (head, tail) percentages of --truncate-tokens kept by --truncate head-tail
+    const DEFAULT_TRUNCATE_PROPORTIONS: (usize, usize) = (70, 30);
+
+    // What to summarise: a single file (or web page, when it's a URL), everything that shows up
+    // in a watched directory, everything already in a directory, or the documents in a zip
+    // archive
+    #[derive(Debug)]
+    pub enum Input {
+        File(String),
+        Watch(WatchOptions),
+        Batch(WatchOptions),
+        Archive(ArchiveInput),
+    }
+
+    // Printed as-is by --print-config, which is safe because the key only ever prints as
+    // [redacted]
+    #[derive(Debug)]
+    pub struct Args {
+        pub input: Input,
+        // Where --batch writes its per-file report (CSV or JSON, by extension)
+        pub report_path: Option<String>,
+        // Set with --preflight: check that the key works before a --batch run starts
+        pub preflight: bool,
+        // Set with --name-from-title: --watch and --batch outputs are named after each
+        // document's title rather than its file name
+        pub name_from_title: bool,
+        // How input files are read and decoded (set with --encoding and --max-input-bytes)
+        pub read_options: ReadOptions,
+        // Wraps text output, e.g. "## {party} ({year})\n\n{summary}", filled in from the
+        // document's front matter
+        pub template: Option<String>,
+        // Prompts per file extension, from --prompts
+        pub prompts: PromptConfig,
+        // System and user message templates, from --prompt-template
+        pub prompt_template: Option<PromptTemplate>,
+        // Prices for cost estimates, with any from --price-file
+        pub prices: PriceTable,
+        // Set with --ask (which can be repeated): answer these rather than summarising
+        pub questions: Vec<String>,
+        // Set with --chat: answer questions about the manifesto interactively rather than
+        // summarising it
+        pub chat: bool,
+        // Set with --session: where the --chat conversation is saved when it ends, and picked
+        // back up from if it's already there
+        pub session_path: Option<String>,
+        // Set with --diff-from: an older version of the manifesto, in which case what changed
+        // since it is summarised rather than the manifesto itself
+        pub diff_from: Option<String>,
+        // Set with --topic (which can be repeated): summarise only what's said about these
+        pub topics: Vec<String>,
+        // Set with --language: the summary's own language, then each one it's translated into
+        pub languages: Vec<String>,
+        // Set with --appendix: list the names and acronyms the manifesto mentions after the
+        // summary, found locally rather than by the model
+        pub appendix: bool,
+        // Set with --no-skip: summarise every chunk, even ones that don't look like they're
+        // about any of the topics
+        pub no_skip: bool,
+        // Set with --skip-filtered: leave out the chunks that the model declines to summarise
+        // due to its content policy, rather than failing the run
+        pub skip_filtered: bool,
+        // Set with --stamp: end text output with a `source-sha256: <hash>` line
+        pub stamp: bool,
+        // Set with --diff-against: a summary saved from an older version, which the new summary
+        // is compared with word by word
+        pub diff_against: Option<String>,
+        // Set with --diff-llm: the model describes what changed since the --diff-against summary
+        pub diff_llm: bool,
+        // Set with --truncate head-tail: documents longer than this are cut down to their start
+        // and end before they're summarised
+        pub truncate: Option<HeadTail>,
+        // Set with --no-clean: send the text as it was read, without removing repeated page
+        // headers, page numbers and extra blank lines
+        pub no_clean: bool,
+        // Set with --dry-run: print the cleaned text rather than summarising it
+        pub dry_run: bool,
+        // Set with --offline: make an extractive summary locally rather than asking the model
+        pub offline: bool,
+        // Set with --fallback-offline: make an extractive summary if OpenAI can't be reached
+        pub fallback_offline: bool,
+        // Set with --save-prompt: every request sent to OpenAI is recorded here, for auditing
+        pub save_prompt_path: Option<String>,
+        // Set with --run-report: a JSON report of every request (without the document or key)
+        // is written here at the end of the run, whether or not it succeeded
+        pub run_report_path: Option<String>,
+        // Set with --record: every response from OpenAI is saved here, to be replayed later
+        pub record_dir: Option<String>,
+        // Set with --replay: responses are read from this directory rather than asked for
+        pub replay_dir: Option<String>,
+        // Set with --chunk-cache: chunk summaries are kept here between runs, and reused for
+        // chunks that haven't changed
+        pub chunk_cache_dir: Option<String>,
+        // Set with --print-config: print these args rather than doing anything with them
+        pub print_config: bool,
+        pub output_path: Option<String>,
+        pub openai_key: SecretString,
+        // Set with more than one --key-file: the keys after [openai_key], which requests take
+        // turns with it
+        pub extra_keys: Vec<SecretString>,
+        // Set with --org and --project: who the run's usage is billed to
+        pub attribution: Attribution,
+        pub request_id: Option<String>,
+        pub send_request_id: bool,
+        pub chunk_tokens: Option<usize>,
+        pub jobs: usize,
+        // How long pooled connections to OpenAI are kept open while idle (--pool-idle-timeout)
+        pub pool_idle_timeout: Duration,
+        // How many times a request is retried when the connection fails before any response
+        // (--net-retries), separately from retries after a 429
+        pub net_retries: u32,
+        // Set with --model-fallback: the models to send a chat request to, in order, when the one
+        // it asked for can't answer it
+        pub model_fallback: Vec<String>,
+        // Set with --danger-accept-invalid-certs: skip checking the server's TLS certificate, so
+        // that a local stub with a self-signed one can stand in for OpenAI
+        pub danger_accept_invalid_certs: bool,
+        // Set with --chunk-timeout: how long to wait for a chunk's summary before retrying it
+        pub chunk_timeout: Option<Duration>,
+        pub candidates: u32,
+        pub pick_best: bool,
+        // Set with --summarize-sections: summarise each section (starting at lines matching
+        // this) on its own
+        pub section_pattern: Option<Regex>,
+        // Where sections start (--section-regex), so that chunks can follow them
+        pub heading_pattern: Regex,
+        // Set with --per-section: output each section's chunk summary under its heading, as
+        // well as the overview
+        pub per_section: bool,
+        // Set with --cache-prompt: lay out requests so that OpenAI can cache the document
+        pub cache_prompt: bool,
+        // Set with --critique: have the model check the summary against the manifesto
+        pub critique: bool,
+        // Set with --revise: rewrite the summary to address the critique (implies --critique)
+        pub revise: bool,
+        // Set with --max-tokens: the most tokens any reply can have
+        pub max_tokens: Option<u32>,
+        // Set with --stop (which can be repeated): the model stops replying at any of these
+        pub stop: Vec<String>,
+        // Sent as the `user` on every chat request (--user-id), e.g. a hashed tenant ID
+        pub user_id: Option<String>,
+        // Set with --seed: sent on every chat request, for reproducible replies
+        pub seed: Option<u64>,
+        // The most bytes a request can send and a response can send back (--max-request-bytes
+        // and --max-response-bytes)
+        pub size_limits: SizeLimits,
+        // Set with --paragraphs: how many paragraphs the summary should have. It's asked for in
+        // the default system prompt, and checked for in the summary.
+        pub paragraphs: Option<usize>,
+        // Set with --min-words: summaries shorter than this are flagged (by default, a number of
+        // words per paragraph asked for)
+        pub min_words: Option<usize>,
+        // Set with --strict-output: a summary that fails the output checks is asked for again,
+        // and the run fails if it still doesn't pass
+        pub strict_output: bool,
+        // Set with --max-cost: optional extra requests (the critique and revision) are skipped
+        // rather than take the run's estimated cost in USD past this
+        pub max_cost: Option<f64>,
+        pub moderate: bool,
+        pub moderation_threshold: Option<f64>,
+        pub resume: bool,
+        pub keep_state: bool,
+        pub verbose: bool,
+        // Set with --quiet: don't show the progress display for chunked runs, or notes about what
+        // the run is doing (see [diagnostics::note])
+        pub quiet: bool,
+        pub json: bool,
+    }
+
+    impl Args {
+        // How many chunks of a document are summarised at once. In --watch and --batch, --jobs
+        // is how many files are summarised at once instead, and each file's chunks go one at a
+        // time, so that no more than --jobs requests are ever in flight.
+        pub fn chunk_jobs(&self) -> usize {
+            match self.input {
+                Input::File(_) | Input::Archive(_) => self.jobs,
+                Input::Watch(_) | Input::Batch(_) => 1,
+            }
+        }
+
+        pub fn build(args: impl Iterator<Item = String>) -> Result<Args, &'static str> {
+            let mut args = with_env_flags(args.collect(), |name| env::var(name).ok())?.into_iter();
+            args.next(); // First arg is the executable's name
+
+            let mut positional: Vec<String> = Vec::new();
+            let mut api_key: Option<String> = None;
+            let mut key_files: Vec<String> = Vec::new();
+            let mut organization: Option<String> = None;
+            let mut project: Option<String> = None;
+            let mut request_id: Option<String> = None;
+            let mut send_request_id = false;
+            let mut chunk_tokens: Option<usize> = None;
+            let mut jobs: usize = 1;
+            let mut pool_idle_timeout = crate::DEFAULT_POOL_IDLE_TIMEOUT;
+            let mut net_retries = crate::DEFAULT_NET_RETRIES;
+            let mut model_fallback: Vec<String> = Vec::new();
+            let mut danger_accept_invalid_certs = false;
+            let mut chunk_timeout: Option<Duration> = None;
+            let mut candidates: u32 = 1;
+            let mut pick_best = false;
+            let mut summarize_sections = false;
+            let mut per_section = false;
+            let mut cache_prompt = false;
+            let mut critique = false;
+            let mut no_critique = false;
+            let mut revise = false;
+            let mut max_cost: Option<f64> = None;
+            let mut max_tokens: Option<u32> = None;
+            let mut stop: Vec<String> = Vec::new();
+            let mut user_id: Option<String> = None;
+            let mut seed: Option<u64> = None;
+            let mut size_limits = SizeLimits::default();
+            let mut paragraphs: Option<usize> = None;
+            let mut min_words: Option<usize> = None;
+            let mut strict_output = false;
+            let mut section_regex = String::from(DEFAULT_HEADING_PATTERN);
+            let mut moderate = false;
+            let mut moderation_threshold: Option<f64> = None;
+            let mut output_path: Option<String> = None;
+            let mut resume = false;
+            let mut keep_state = false;
+            let mut verbose = false;
+            let mut quiet = false;
+            let mut json = false;
+            let mut watch_dir: Option<String> = None;
+            let mut batch_dir: Option<String> = None;
+            let mut report_path: Option<String> = None;
+            let mut preflight = false;
+            let mut name_from_title = false;
+            let mut read_options = ReadOptions::default();
+            let mut include: Vec<String> = Vec::new();
+            let mut max_archive_bytes: Option<u64> = None;
+            let mut template: Option<String> = None;
+            let mut questions: Vec<String> = Vec::new();
+            let mut chat = false;
+            let mut session_path: Option<String> = None;
+            let mut diff_from: Option<String> = None;
+            let mut topics: Vec<String> = Vec::new();
+            let mut languages: Vec<String> = Vec::new();
+            let mut appendix = false;
+            let mut no_skip = false;
+            let mut skip_filtered = false;
+            let mut stamp = false;
+            let mut diff_against: Option<String> = None;
+            let mut diff_llm = false;
+            let mut prompts = PromptConfig::default();
+            let mut prompt_template: Option<PromptTemplate> = None;
+            let mut prices = PriceTable::default();
+            let mut truncate = false;
+            let mut truncate_tokens: Option<usize> = None;
+            let mut truncate_proportions: Option<(usize, usize)> = None;
+            let mut no_clean = false;
+            let mut dry_run = false;
+            let mut offline = false;
+            let mut fallback_offline = false;
+            let mut save_prompt_path: Option<String> = None;
+            let mut run_report_path: Option<String> = None;
+            let mut record_dir: Option<String> = None;
+            let mut replay_dir: Option<String> = None;
+            let mut chunk_cache_dir: Option<String> = None;
+            let mut print_config = false;
+            let mut output_dir: Option<String> = None;
+            let mut poll_interval = DEFAULT_POLL_INTERVAL;
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--api-key" => match args.next() {
+                        Some(key) => api_key = Some(key),
+                        None => return Err("--api-key needs a value"),
+                    },
+                    "--key-file" => match args.next() {
+                        Some(path) => key_files.push(path),
+                        None => return Err("--key-file needs a path"),
+                    },
+                    "--org" => match args.next() {
+                        Some(id) => organization = Some(id),
+                        None => return Err("--org needs an organization ID"),
+                    },
+                    "--project" => match args.next() {
+                        Some(id) => project = Some(id),
+                        None => return Err("--project needs a project ID"),
+                    },
+                    "--request-id" => match args.next() {
+                        Some(id) => request_id = Some(id),
+                        None => return Err("--request-id needs a value"),
+                    },
+                    "--send-request-id" => send_request_id = true,
+                    "--chunk-tokens" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => chunk_tokens = Some(n),
+                        _ => return Err("--chunk-tokens needs a positive number"),
+                    },
+                    "--jobs" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => jobs = n,
+                        _ => return Err("--jobs needs a positive number"),
+                    },
+                    "--pool-idle-timeout" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => pool_idle_timeout = Duration::from_secs(n),
+                        _ => return Err("--pool-idle-timeout needs a positive number of seconds"),
+                    },
+                    "--net-retries" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) => net_retries = n,
+                        None => return Err("--net-retries needs a number (0 to never retry)"),
+                    },
+                    "--model-fallback" => match args.next() {
+                        Some(list) => model_fallback = model_fallback::parse_chain(&list)?,
+                        None => return Err("--model-fallback needs a comma-separated list of models"),
+                    },
+                    "--danger-accept-invalid-certs" => danger_accept_invalid_certs = true,
+                    "--chunk-timeout" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => chunk_timeout = Some(Duration::from_secs(n)),
+                        _ => return Err("--chunk-timeout needs a positive number of seconds"),
+                    },
+                    "--candidates" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => candidates = n,
+                        _ => return Err("--candidates needs a positive number"),
+                    },
+                    "--pick-best" => pick_best = true,
+                    "--summarize-sections" => summarize_sections = true,
+                    "--per-section" => per_section = true,
+                    "--cache-prompt" => cache_prompt = true,
+                    "--critique" => critique = true,
+                    "--no-critique" => no_critique = true,
+                    "--revise" => revise = true,
+                    "--max-cost" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n >= 0.0 => max_cost = Some(n),
+                        _ => return Err("--max-cost needs a non-negative number of dollars"),
+                    },
+                    "--max-tokens" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => max_tokens = Some(n),
+                        _ => return Err("--max-tokens needs a positive number"),
+                    },
+                    "--max-request-bytes" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => size_limits.max_request_bytes = n,
+                        _ => return Err("--max-request-bytes needs a positive number"),
+                    },
+                    "--max-response-bytes" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => size_limits.max_response_bytes = n,
+                        _ => return Err("--max-response-bytes needs a positive number"),
+                    },
+                    "--stop" => match args.next() {
+                        // As with --template, "\n" is a newline
+                        Some(sequence) if !sequence.is_empty() => stop.push(sequence.replace("\\n", "\n")),
+                        _ => return Err("--stop needs a sequence to stop at"),
+                    },
+                    "--user-id" => match args.next() {
+                        Some(id) if !id.trim().is_empty() => user_id = Some(id),
+                        _ => return Err("--user-id needs a value"),
+                    },
+                    "--seed" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) => seed = Some(n),
+                        None => return Err("--seed needs a whole number"),
+                    },
+                    "--paragraphs" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => paragraphs = Some(n),
+                        _ => return Err("--paragraphs needs a positive number"),
+                    },
+                    "--min-words" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) => min_words = Some(n),
+                        None => return Err("--min-words needs a number"),
+                    },
+                    "--strict-output" => strict_output = true,
+                    "--section-regex" => match args.next() {
+                        Some(regex) => section_regex = regex,
+                        None => return Err("--section-regex needs a value"),
+                    },
+                    "--moderate" => moderate = true,
+                    "--moderation-threshold" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if (0.0..=1.0).contains(&n) => moderation_threshold = Some(n),
+                        _ => return Err("--moderation-threshold needs a number between 0 and 1"),
+                    },
+                    "--output" => match args.next() {
+                        Some(path) => output_path = Some(path),
+                        None => return Err("--output needs a path"),
+                    },
+                    "--resume" => resume = true,
+                    "--keep-state" => keep_state = true,
+                    "--verbose" => verbose = true,
+                    "--quiet" => quiet = true,
+                    "--json" => json = true,
+                    "--truncate" => match args.next().as_deref() {
+                        Some("head-tail") => truncate = true,
+                        _ => return Err("--truncate needs a mode (only head-tail is supported)"),
+                    },
+                    "--truncate-tokens" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => truncate_tokens = Some(n),
+                        _ => return Err("--truncate-tokens needs a positive number"),
+                    },
+                    "--truncate-proportions" => match args.next().as_deref().and_then(HeadTail::parse_proportions) {
+                        Some(proportions) => truncate_proportions = Some(proportions),
+                        _ => return Err("--truncate-proportions needs head:tail percentages adding up to at most 100, like 70:30"),
+                    },
+                    "--no-clean" => no_clean = true,
+                    "--dry-run" => dry_run = true,
+                    "--offline" => offline = true,
+                    "--fallback-offline" => fallback_offline = true,
+                    "--print-config" => print_config = true,
+                    "--save-prompt" => match args.next() {
+                        Some(path) => save_prompt_path = Some(path),
+                        None => return Err("--save-prompt needs a path"),
+                    },
+                    "--run-report" => match args.next() {
+                        Some(path) => run_report_path = Some(path),
+                        None => return Err("--run-report needs a path"),
+                    },
+                    "--chunk-cache" => match args.next() {
+                        Some(dir) => chunk_cache_dir = Some(dir),
+                        None => return Err("--chunk-cache needs a directory"),
+                    },
+                    "--record" => match args.next() {
+                        Some(dir) => record_dir = Some(dir),
+                        None => return Err("--record needs a directory"),
+                    },
+                    "--replay" => match args.next() {
+                        Some(dir) => replay_dir = Some(dir),
+                        None => return Err("--replay needs a directory"),
+                    },
+                    "--watch" => match args.next() {
+                        Some(dir) => watch_dir = Some(dir),
+                        None => return Err("--watch needs a directory"),
+                    },
+                    "--batch" => match args.next() {
+                        Some(dir) => batch_dir = Some(dir),
+                        None => return Err("--batch needs a directory"),
+                    },
+                    "--report" => match args.next() {
+                        Some(path) if path.ends_with(".csv") || path.ends_with(".json") => report_path = Some(path),
+                        _ => return Err("--report needs a .csv or .json path"),
+                    },
+                    "--preflight" => preflight = true,
+                    "--name-from-title" => name_from_title = true,
+                    "--encoding" => match args.next().as_deref().and_then(decoding::encoding_for_label) {
+                        Some(found) => read_options.encoding = Some(found),
+                        None => return Err("--encoding needs a known encoding name, like utf-8, latin1 or utf-16le"),
+                    },
+                    "--prompts" => match args.next().map(|path| PromptConfig::load(Path::new(&path))) {
+                        Some(Ok(config)) => prompts = config,
+                        Some(Err(e)) => {
+                            diagnostics::error(format!("Couldn't load --prompts: {}", e));
+                            return Err("--prompts needs a JSON file mapping file extensions to prompts");
+                        }
+                        None => return Err("--prompts needs a path"),
+                    },
+                    "--prompt-template" => match args.next().map(|path| PromptTemplate::load(Path::new(&path))) {
+                        Some(Ok(template)) => prompt_template = Some(template),
+                        Some(Err(e)) => {
+                            diagnostics::error(format!("Couldn't load --prompt-template: {}", e));
+                            return Err("--prompt-template needs a valid template file");
+                        }
+                        None => return Err("--prompt-template needs a path"),
+                    },
+                    "--price-file" => match args.next().map(|path| PriceTable::load(Path::new(&path))) {
+                        Some(Ok(table)) => prices = table,
+                        Some(Err(e)) => {
+                            diagnostics::error(format!("Couldn't load --price-file: {}", e));
+                            return Err("--price-file needs a JSON file mapping models to input and output prices");
+                        }
+                        None => return Err("--price-file needs a path"),
+                    },
+                    "--ask" => match args.next() {
+                        Some(question) if !question.trim().is_empty() => questions.push(question),
+                        _ => return Err("--ask needs a question"),
+                    },
+                    "--chat" => chat = true,
+                    "--session" => match args.next() {
+                        Some(path) => session_path = Some(path),
+                        None => return Err("--session needs a path"),
+                    },
+                    "--diff-from" => match args.next() {
+                        Some(path) => diff_from = Some(path),
+                        None => return Err("--diff-from needs the path of the old version"),
+                    },
+                    "--topic" => match args.next() {
+                        Some(topic) if !topic.trim().is_empty() => topics.push(String::from(topic.trim())),
+                        _ => return Err("--topic needs a topic"),
+                    },
+                    "--no-skip" => no_skip = true,
+                    "--language" => match args.next() {
+                        Some(list) => languages = translation::parse_languages(&list)?,
+                        None => return Err("--language needs a comma-separated list of language codes"),
+                    },
+                    "--appendix" => appendix = true,
+                    "--skip-filtered" => skip_filtered = true,
+                    "--stamp" => stamp = true,
+                    "--diff-against" => match args.next() {
+                        Some(path) => diff_against = Some(path),
+                        None => return Err("--diff-against needs the path of the old summary"),
+                    },
+                    "--diff-llm" => diff_llm = true,
+                    "--max-input-bytes" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => read_options.max_bytes = n,
+                        _ => return Err("--max-input-bytes needs a positive number"),
+                    },
+                    "--include" => match args.next() {
+                        Some(glob) if !glob.is_empty() => include.push(glob),
+                        _ => return Err("--include needs a glob"),
+                    },
+                    "--max-archive-bytes" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => max_archive_bytes = Some(n),
+                        _ => return Err("--max-archive-bytes needs a positive number"),
+                    },
+                    "--template" => match args.next() {
+                        // Shells pass "\n" through literally, so treat it as a newline
+                        Some(text) => template = Some(text.replace("\\n", "\n")),
+                        None => return Err("--template needs a value"),
+                    },
+                    "--output-dir" => match args.next() {
+                        Some(dir) => output_dir = Some(dir),
+                        None => return Err("--output-dir needs a directory"),
+                    },
+                    "--poll-interval" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => poll_interval = Duration::from_secs(n),
+                        _ => return Err("--poll-interval needs a positive number of seconds"),
+                    },
+                    _ => positional.push(arg),
+                }
+            }
+
+            if send_request_id && request_id.is_none() {
+                return Err("--send-request-id needs a --request-id");
+            }
+
+            // It's sent as a header with --send-request-id, so it's held to the same rules as
+            // --org and --project
+            if request_id.as_deref().is_some_and(|id| !attribution::is_valid_id(id)) {
+                return Err("--request-id can only have printable ASCII characters, with no spaces");
+            }
+
+            if pick_best && candidates < 2 {
+                return Err("--pick-best needs --candidates of 2 or more");
+            }
+
+            if !questions.is_empty() && (summarize_sections || candidates > 1) {
+                return Err("--ask doesn't support --summarize-sections or --candidates");
+            }
+
+            if summarize_sections && candidates > 1 {
+                return Err("--summarize-sections doesn't support --candidates");
+            }
+
+            if per_section && chunk_tokens.is_none() {
+                return Err("--per-section needs --chunk-tokens");
+            }
+
+            if per_section && (summarize_sections || !questions.is_empty()) {
+                return Err("--per-section doesn't support --summarize-sections or --ask");
+            }
+
+            let truncate = match (truncate, truncate_tokens) {
+                (true, Some(max_tokens)) => {
+                    let (head_percent, tail_percent) = truncate_proportions.unwrap_or(DEFAULT_TRUNCATE_PROPORTIONS);
+                    Some(HeadTail { max_tokens, head_percent, tail_percent })
+                }
+                (true, None) => return Err("--truncate needs --truncate-tokens"),
+                (false, _) if truncate_tokens.is_some() || truncate_proportions.is_some() =>
+                    return Err("--truncate-tokens and --truncate-proportions need --truncate head-tail"),
+                (false, _) => None,
+            };
+
+            if truncate.is_some() && chunk_tokens.is_some() {
+                return Err("--truncate and --chunk-tokens can't be used together");
+            }
+
+            if prompt_template.is_some() && !prompts.is_empty() {
+                return Err("--prompt-template and --prompts can't be used together");
+            }
+
+            if let Some(max_tokens) = max_tokens {
+                // --pick-best asks a cheaper model, which has to be able to honour it too
+                let models = if pick_best { vec![GPT_4_MODEL_NAME, GPT_35_MODEL_NAME] } else { vec![GPT_4_MODEL_NAME] };
+
+                for model in models {
+                    open_ai::check_max_tokens(model, max_tokens).map_err(|e| {
+                        diagnostics::error(e.to_string());
+                        "--max-tokens is more than the model can reply with"
+                    })?;
+                }
+            }
+
+            // OpenAI's limit
+            if stop.len() > 4 {
+                return Err("--stop can be given at most 4 times");
+            }
+
+            if no_critique && (critique || revise) {
+                return Err("--no-critique can't be used with --critique or --revise");
+            }
+
+            if stamp && json {
+                return Err("--stamp is for text output; --json always includes the source_sha256");
+            }
+
+            if session_path.is_some() && !chat {
+                return Err("--session needs --chat");
+            }
+
+            if chat && (watch_dir.is_some() || batch_dir.is_some() || dry_run || json) {
+                return Err("--chat only works on a single file, without --dry-run or --json");
+            }
+
+            // Neither prints JSON, and --json promises nothing else on stdout
+            if json && (dry_run || print_config) {
+                return Err("--json can't be used with --dry-run or --print-config");
+            }
+
+            if chat && !questions.is_empty() {
+                return Err("Only one of --chat and --ask can be used");
+            }
+
+            if !topics.is_empty() && (summarize_sections || !questions.is_empty()) {
+                return Err("--topic doesn't support --summarize-sections or --ask");
+            }
+
+            if (critique || revise) && (summarize_sections || !questions.is_empty()) {
+                return Err("--critique and --revise don't support --summarize-sections or --ask");
+            }
+
+            let heading_pattern = Regex::new(&section_regex).map_err(|_| "--section-regex isn't a valid regex")?;
+            let section_pattern = summarize_sections.then(|| heading_pattern.clone());
+
+            if (watch_dir.is_some() || batch_dir.is_some()) && output_path.is_some() {
+                return Err("--watch and --batch write to --output-dir rather than --output");
+            }
+
+            if report_path.is_some() && batch_dir.is_none() {
+                return Err("--report needs --batch");
+            }
+
+            if preflight && batch_dir.is_none() {
+                return Err("--preflight needs --batch");
+            }
+
+            if preflight && (offline || replay_dir.is_some()) {
+                return Err("--preflight can't be used with --offline or --replay, which don't call OpenAI");
+            }
+
+            if name_from_title && watch_dir.is_none() && batch_dir.is_none() {
+                return Err("--name-from-title needs --watch or --batch");
+            }
+
+            if offline && (chat || !questions.is_empty() || moderate || critique || revise || pick_best || !topics.is_empty() || name_from_title) {
+                return Err("--offline can't be used with --chat, --ask, --moderate, --critique, --revise, --pick-best, --topic or --name-from-title, which need the model");
+            }
+
+            if offline && fallback_offline {
+                return Err("Only one of --offline and --fallback-offline can be used");
+            }
+
+            if dry_run && (watch_dir.is_some() || batch_dir.is_some()) {
+                return Err("--dry-run only works on a single file");
+            }
+
+            if record_dir.is_some() && replay_dir.is_some() {
+                return Err("Only one of --record and --replay can be used");
+            }
+
+            let mut positional = positional.into_iter();
+
+            let directory_options = |dir: String| match &output_dir {
+                Some(output_dir) => Ok(WatchOptions {
+                    dir: PathBuf::from(dir),
+                    output_dir: PathBuf::from(output_dir),
+                    poll_interval,
+                    output_extension: if json { "json" } else { "txt" },
+                    read_options,
+                    verbose,
+                    jobs,
+                }),
+                None => Err("--watch and --batch need an --output-dir"),
+            };
+
+            // When summarising a directory, there's no file_path, so the first positional arg is
+            // the key file
+            let input = match (watch_dir, batch_dir) {
+                (Some(_), Some(_)) => return Err("Only one of --watch and --batch can be used"),
+                (Some(dir), None) => Input::Watch(directory_options(dir)?),
+                (None, Some(dir)) => Input::Batch(directory_options(dir)?),
+                (None, None) => match positional.next() {
+                    // Even one ending in .zip, which is a page to fetch rather than an archive
+                    Some(arg) if fetch::is_url(&arg) => Input::File(arg),
+                    Some(arg) => match ArchiveInput::parse(&arg) {
+                        Some(mut archive) => {
+                            archive.include = include.clone();
+                            archive.max_total_bytes = max_archive_bytes.unwrap_or(DEFAULT_MAX_ARCHIVE_BYTES);
+                            Input::Archive(archive)
+                        }
+                        None => Input::File(arg),
+                    },
+                    None => return Err("Didn't get a file_path"),
+                },
+            };
+
+            let archive = matches!(input, Input::Archive(_));
+
+            if (!include.is_empty() || max_archive_bytes.is_some()) && !archive {
+                return Err("--include and --max-archive-bytes need a .zip file_path");
+            }
+
+            if languages.len() > 1 && (archive || chat || dry_run || offline || !questions.is_empty() || diff_from.is_some()) {
+                return Err("--language can't translate with --chat, --dry-run, --offline, --ask, --diff-from or .zip inputs");
+            }
+
+            if appendix && (chat || dry_run || diff_from.is_some()) {
+                return Err("--appendix can't be used with --chat, --dry-run or --diff-from");
+            }
+
+            if archive && (chat || dry_run) {
+                return Err("--chat and --dry-run don't support .zip inputs");
+            }
+
+            if diff_llm && diff_against.is_none() {
+                return Err("--diff-llm needs --diff-against");
+            }
+
+            if diff_against.is_some() && (!matches!(input, Input::File(_)) || chat || dry_run || diff_from.is_some()) {
+                return Err("--diff-against only works when summarising a single file, without --chat, --dry-run or --diff-from");
+            }
+
+            if diff_llm && offline {
+                return Err("--diff-llm can't be used with --offline, since it needs the model");
+            }
+
+            if diff_from.is_some() && !matches!(input, Input::File(_)) {
+                return Err("--diff-from only works on a single file");
+            }
+
+            if diff_from.is_some() && (chat || !questions.is_empty() || offline || dry_run || moderate || critique || revise || candidates > 1 || summarize_sections || per_section || !topics.is_empty()) {
+                return Err("--diff-from can't be used with --chat, --ask, --offline, --dry-run, --moderate, --critique, --revise, --candidates, --summarize-sections, --per-section or --topic");
+            }
+
+            // The positional key file (if any) comes after the --key-file ones. MANIFESTO_KEY_FILE
+            // only counts when neither was given, since flags win over the environment.
+            let mut key_files: Vec<String> = key_files.into_iter().chain(positional.next()).collect();
+
+            if key_files.is_empty() {
+                key_files = key_files_from_env(env::var_os(KEY_FILE_ENV_VAR));
+            }
+            let openai_keys = match key_files.len() {
+                0 | 1 => resolve_openai_key(
+                    api_key,
+                    env::var(OPENAI_KEY_ENV_VAR).ok(),
+                    key_files.into_iter().next(),
+                    keystore::read_key_from_keyring,
+                ).map(|key| vec![key]),
+                _ => read_key_files(&key_files),
+            };
+            // A dry run, --offline, --replay and --print-config don't call OpenAI, so they don't
+            // need a key
+            let openai_keys = if dry_run || offline || replay_dir.is_some() || print_config { openai_keys.unwrap_or_default() } else { openai_keys? };
+            let mut openai_keys = openai_keys.into_iter();
+            let openai_key = openai_keys.next().unwrap_or_default();
+            let extra_keys: Vec<SecretString> = openai_keys.collect();
+            let attribution = resolve_attribution(organization, project, |name| env::var(name).ok())?;
+
+            Ok(Args {
+                input,
+                report_path,
+                preflight,
+                name_from_title,
+                read_options,
+                template,
+                prompts,
+                prompt_template,
+                prices,
+                questions,
+                chat,
+                session_path,
+                diff_from,
+                topics,
+                languages,
+                appendix,
+                no_skip,
+                skip_filtered,
+                stamp,
+                diff_against,
+                diff_llm,
+                truncate,
+                no_clean,
+                dry_run,
+                offline,
+                fallback_offline,
+                save_prompt_path,
+                run_report_path,
+                record_dir,
+                replay_dir,
+                chunk_cache_dir,
+                print_config,
+                output_path,
+                openai_key,
+                extra_keys,
+                attribution,
+                request_id,
+                send_request_id,
+                chunk_tokens,
+                jobs,
+                pool_idle_timeout,
+                net_retries,
+                model_fallback,
+                danger_accept_invalid_certs,
+                chunk_timeout,
+                candidates,
+                pick_best,
+                section_pattern,
+                heading_pattern,
+                per_section,
+                cache_prompt,
+                critique: critique || revise,
+                revise,
+                max_cost,
+                max_tokens,
+                stop,
+                user_id,
+                seed,
+                size_limits,
+                paragraphs,
+                min_words,
+                strict_output,
+                moderate,
+                moderation_threshold,
+                resume,
+                keep_state,
+                verbose,
+                quiet,
+                json,
+            })
+        }
+    }
+
+    // Args for `manifest-o embed --index <path> <file>...`
+    #[derive(Debug)]
+    pub struct EmbedArgs {
+        pub index_path: String,
+        pub file_paths: Vec<String>,
+        pub batch_size: usize,
+        pub openai_key: SecretString,
+        pub attribution: Attribution,
+    }
+
+    impl EmbedArgs {
+        // [args] is everything after `embed`
+        pub fn build(mut args: impl Iterator<Item = String>) -> Result<EmbedArgs, &'static str> {
+            let mut file_paths: Vec<String> = Vec::new();
+            let mut index_path: Option<String> = None;
+            let mut batch_size: usize = 64;
+            let mut api_key: Option<String> = None;
+            let mut key_file: Option<String> = None;
+            let mut organization: Option<String> = None;
+            let mut project: Option<String> = None;
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--index" => match args.next() {
+                        Some(path) => index_path = Some(path),
+                        None => return Err("--index needs a path"),
+                    },
+                    "--batch-size" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => batch_size = n,
+                        _ => return Err("--batch-size needs a positive number"),
+                    },
+                    "--api-key" => match args.next() {
+                        Some(key) => api_key = Some(key),
+                        None => return Err("--api-key needs a value"),
+                    },
+                    "--key-file" => match args.next() {
+                        Some(path) => key_file = Some(path),
+                        None => return Err("--key-file needs a path"),
+                    },
+                    "--org" => match args.next() {
+                        Some(id) => organization = Some(id),
+                        None => return Err("--org needs an organization ID"),
+                    },
+                    "--project" => match args.next() {
+                        Some(id) => project = Some(id),
+                        None => return Err("--project needs a project ID"),
+                    },
+                    _ => file_paths.push(arg),
+                }
+            }
+
+            if file_paths.is_empty() {
+                return Err("embed needs at least one file to embed");
+            }
+
+            Ok(EmbedArgs {
+                index_path: index_path.ok_or("embed needs an --index to write to")?,
+                file_paths,
+                batch_size,
+                openai_key: resolve_openai_key(
+                    api_key,
+                    env::var(OPENAI_KEY_ENV_VAR).ok(),
+                    key_file,
+                    keystore::read_key_from_keyring,
+                )?,
+                attribution: resolve_attribution(organization, project, |name| env::var(name).ok())?,
+            })
+        }
+    }
+
+    // Args for `manifest-o similar --query "text" --index <path>`
+    #[derive(Debug)]
+    pub struct SimilarArgs {
+        pub index_path: String,
+        pub query: String,
+        pub top_k: usize,
+        pub openai_key: SecretString,
+        pub attribution: Attribution,
+    }
+
+    impl SimilarArgs {
+        // [args] is everything after `similar`
+        pub fn build(mut args: impl Iterator<Item = String>) -> Result<SimilarArgs, &'static str> {
+            let mut query: Option<String> = None;
+            let mut index_path: Option<String> = None;
+            let mut top_k: usize = 5;
+            let mut api_key: Option<String> = None;
+            let mut key_file: Option<String> = None;
+            let mut organization: Option<String> = None;
+            let mut project: Option<String> = None;
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--query" => match args.next() {
+                        Some(text) => query = Some(text),
+                        None => return Err("--query needs a value"),
+                    },
+                    "--index" => match args.next() {
+                        Some(path) => index_path = Some(path),
+                        None => return Err("--index needs a path"),
+                    },
+                    "--top-k" => match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => top_k = n,
+                        _ => return Err("--top-k needs a positive number"),
+                    },
+                    "--api-key" => match args.next() {
+                        Some(key) => api_key = Some(key),
+                        None => return Err("--api-key needs a value"),
+                    },
+                    "--key-file" => match args.next() {
+                        Some(path) => key_file = Some(path),
+                        None => return Err("--key-file needs a path"),
+                    },
+                    "--org" => match args.next() {
+                        Some(id) => organization = Some(id),
+                        None => return Err("--org needs an organization ID"),
+                    },
+                    "--project" => match args.next() {
+                        Some(id) => project = Some(id),
+                        None => return Err("--project needs a project ID"),
+                    },
+                    _ => return Err("Unexpected argument to similar"),
+                }
+            }
+
+            Ok(SimilarArgs {
+                index_path: index_path.ok_or("similar needs an --index to search")?,
+                query: query.ok_or("similar needs a --query")?,
+                top_k,
+                openai_key: resolve_openai_key(
+                    api_key,
+                    env::var(OPENAI_KEY_ENV_VAR).ok(),
+                    key_file,
+                    keystore::read_key_from_keyring,
+                )?,
+                attribution: resolve_attribution(organization, project, |name| env::var(name).ok())?,
+            })
+        }
+    }
+
+    // Args for `manifest-o check-auth [--probe-completion]`
+    #[derive(Debug)]
+    pub struct CheckAuthArgs {
+        // Also send a one-token completion, rather than just listing the models
+        pub probe_completion: bool,
+        pub openai_key: SecretString,
+        pub attribution: Attribution,
+    }
+
+    impl CheckAuthArgs {
+        // [args] is everything after `check-auth`
+        pub fn build(mut args: impl Iterator<Item = String>) -> Result<CheckAuthArgs, &'static str> {
+            let mut probe_completion = false;
+            let mut api_key: Option<String> = None;
+            let mut key_file: Option<String> = None;
+            let mut organization: Option<String> = None;
+            let mut project: Option<String> = None;
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--probe-completion" => probe_completion = true,
+                    "--api-key" => match args.next() {
+                        Some(key) => api_key = Some(key),
+                        None => return Err("--api-key needs a value"),
+                    },
+                    "--key-file" => match args.next() {
+                        Some(path) => key_file = Some(path),
+                        None => return Err("--key-file needs a path"),
+                    },
+                    "--org" => match args.next() {
+                        Some(id) => organization = Some(id),
+                        None => return Err("--org needs an organization ID"),
+                    },
+                    "--project" => match args.next() {
+                        Some(id) => project = Some(id),
+                        None => return Err("--project needs a project ID"),
+                    },
+                    _ => return Err("Unexpected argument to check-auth"),
+                }
+            }
+
+            Ok(CheckAuthArgs {
+                probe_completion,
+                openai_key: resolve_openai_key(
+                    api_key,
+                    env::var(OPENAI_KEY_ENV_VAR).ok(),
+                    key_file,
+                    keystore::read_key_from_keyring,
+                )?,
+                attribution: resolve_attribution(organization, project, |name| env::var(name).ok())?,
+            })
+        }
+    }
+
+    // Every flag of [Args] that can also be set from the environment, and whether it takes a
+    // value. New flags should be added here too. --api-key is left out, since OPENAI_API_KEY
+    // already does its job, and so is --danger-accept-invalid-certs, so that a forgotten
+    // variable can't quietly turn off TLS checks. --key-file is read separately (see
+    // [KEY_FILE_ENV_VAR]), since it can be given more than once, and a plain argument can be a
+    // key file too.
+    const ENV_FLAGS: &[(&str, bool)] = &[
+        ("--org", true),
+        ("--project", true),
+        ("--request-id", true),
+        ("--send-request-id", false),
+        ("--chunk-tokens", true),
+        ("--jobs", true),
+        ("--pool-idle-timeout", true),
+        ("--net-retries", true),
+        ("--model-fallback", true),
+        ("--chunk-timeout", true),
+        ("--candidates", true),
+        ("--pick-best", false),
+        ("--summarize-sections", false),
+        ("--per-section", false),
+        ("--cache-prompt", false),
+        ("--critique", false),
+        ("--no-critique", false),
+        ("--revise", false),
+        ("--max-cost", true),
+        ("--max-tokens", true),
+        ("--max-request-bytes", true),
+        ("--max-response-bytes", true),
+        ("--stop", true),
+        ("--user-id", true),
+        ("--seed", true),
+        ("--paragraphs", true),
+        ("--min-words", true),
+        ("--strict-output", false),
+        ("--section-regex", true),
+        ("--moderate", false),
+        ("--moderation-threshold", true),
+        ("--output", true),
+        ("--resume", false),
+        ("--keep-state", false),
+        ("--verbose", false),
+        ("--quiet", false),
+        ("--json", false),
+        ("--truncate", true),
+        ("--truncate-tokens", true),
+        ("--truncate-proportions", true),
+        ("--no-clean", false),
+        ("--dry-run", false),
+        ("--offline", false),
+        ("--fallback-offline", false),
+        ("--print-config", false),
+        ("--save-prompt", true),
+        ("--run-report", true),
+        ("--chunk-cache", true),
+        ("--record", true),
+        ("--replay", true),
+        ("--watch", true),
+        ("--batch", true),
+        ("--preflight", false),
+        ("--report", true),
+        ("--name-from-title", false),
+        ("--encoding", true),
+        ("--prompts", true),
+        ("--prompt-template", true),
+        ("--price-file", true),
+        ("--ask", true),
+        ("--chat", false),
+        ("--session", true),
+        ("--diff-from", true),
+        ("--topic", true),
+        ("--language", true),
+        ("--appendix", false),
+        ("--no-skip", false),
+        ("--skip-filtered", false),
+        ("--stamp", false),
+        ("--diff-against", true),
+        ("--diff-llm", false),
+        ("--max-input-bytes", true),
+        ("--include", true),
+        ("--max-archive-bytes", true),
+        ("--template", true),
+        ("--output-dir", true),
+        ("--poll-interval", true),
+    ];
+
+    // Whether the run was asked for --json (by flag or MANIFESTO_JSON), which is known even when
+    // the rest of the args don't parse
+    pub fn wants_json(args: &[String], env: impl Fn(&str) -> Option<String>) -> bool {
+        args.iter().any(|arg| arg == "--json")
+            || env(&env_var_for_flag("--json")).is_some_and(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+    }
+
+    // The environment variable for a flag, e.g. MANIFESTO_CHUNK_TOKENS for --chunk-tokens
+    fn env_var_for_flag(flag: &str) -> String {
+        format!("MANIFESTO_{}", flag.trim_start_matches('-').to_uppercase().replace('-', "_"))
+    }
+
+    // Adds a flag for every MANIFESTO_* variable that [env] has, unless the flag was passed
+    // explicitly, so that flags win over the environment, which wins over the defaults.
+    // Switches (flags without a value) are turned on by 1, true or yes and left off by 0, false,
+    // no or nothing.
+    fn with_env_flags(mut args: Vec<String>, env: impl Fn(&str) -> Option<String>) -> Result<Vec<String>, &'static str> {
+        let mut env_args: Vec<String> = Vec::new();
+
+        for (flag, takes_value) in ENV_FLAGS {
+            if args.iter().any(|arg| arg == flag) {
+                continue;
+            }
+
+            let name = env_var_for_flag(flag);
+            let Some(value) = env(&name) else {
+                continue;
+            };
+
+            if *takes_value {
+                env_args.push(String::from(*flag));
+                env_args.push(value);
+                continue;
+            }
+
+            match value.trim().to_lowercase().as_str() {
+                "1" | "true" | "yes" => env_args.push(String::from(*flag)),
+                "" | "0" | "false" | "no" => {}
+                _ => {
+                    diagnostics::error(format!("{} should be true or false, but was {:?}", name, value));
+                    return Err("A MANIFESTO_* switch has a value that isn't true or false");
+                }
+            }
+        }
+
+        // After the executable's name, so that they're read like any other flags
+        let insert_at = args.len().min(1);
+        args.splice(insert_at..insert_at, env_args);
+
+        Ok(args)
+    }
+
+    // Finds the OpenAI key, trying the --api-key flag, then the OPENAI_API_KEY env var, then the
+    // key file, and finally the OS keyring. The keyring is only consulted if everything else fails.
+    fn resolve_openai_key(
+        flag: Option<String>,
+        env: Option<String>,
+        key_file_path: Option<String>,
+        from_keyring: impl FnOnce() -> Option<String>,
+    ) -> Result<SecretString, &'static str> {
+        let from_file = || {
+            let path = key_file_path?;
+
+            match fs::read_to_string(&path) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    diagnostics::warn(format!("failed to read OpenAI key file {}: {}", path, e));
+                    None
+                }
+            }
+        };
+
+        let key = flag
+            .filter(|key| !key.trim().is_empty())
+            .or_else(|| env.filter(|key| !key.trim().is_empty()))
+            .or_else(from_file)
+            .or_else(from_keyring)
+            .ok_or("Couldn't find an OpenAI key in --api-key, OPENAI_API_KEY, a key file, or the OS keyring")?;
+
+        Ok(SecretString::new(key.trim_end_matches('\n').to_string()))
+    }
+
+    // The key files in MANIFESTO_KEY_FILE, which can hold several separated like PATH's entries
+    // (by colons, or semicolons on Windows), for rotating keys (see [read_key_files])
+    fn key_files_from_env(value: Option<OsString>) -> Vec<String> {
+        let Some(value) = value else {
+            return Vec::new();
+        };
+
+        env::split_paths(&value)
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    // Reads every key when there's more than one --key-file. Unlike with a single key file, none
+    // of them can be missing, since the run would quietly lose part of its rate limit.
+    fn read_key_files(paths: &[String]) -> Result<Vec<SecretString>, &'static str> {
+        paths.iter()
+            .map(|path| match fs::read_to_string(path) {
+                Ok(key) if !key.trim().is_empty() => Ok(SecretString::new(key.trim_end_matches('\n').to_string())),
+                Ok(_) => {
+                    diagnostics::error(format!("OpenAI key file {} is empty", path));
+                    Err("Couldn't read every --key-file")
+                }
+                Err(e) => {
+                    diagnostics::error(format!("Failed to read OpenAI key file {}: {}", path, e));
+                    Err("Couldn't read every --key-file")
+                }
+            })
+            .collect()
+    }
+
+    // The IDs usage is billed to, from --org and --project (or their MANIFESTO_* variables), then
+    // OPENAI_ORG_ID and OPENAI_PROJECT_ID
+    fn resolve_attribution(
+        organization: Option<String>,
+        project: Option<String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Attribution, &'static str> {
+        Attribution::new(
+            organization.or_else(|| env(attribution::ORGANIZATION_ENV_VAR)),
+            project.or_else(|| env(attribution::PROJECT_ENV_VAR)),
+        )
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn no_keyring() -> Option<String> {
+            None
+        }
+
+        #[test]
+        fn flag_wins_over_everything() {
+            let key = resolve_openai_key(
+                Some(String::from("from-flag")),
+                Some(String::from("from-env")),
+                None,
+                || panic!("Shouldn't have consulted the keyring"),
+            );
+
+            assert_eq!(key, Ok(SecretString::from("from-flag")));
+        }
+
+        #[test]
+        fn env_wins_over_file_and_keyring() {
+            let key = resolve_openai_key(
+                None,
+                Some(String::from("from-env")),
+                Some(String::from("/does/not/exist")),
+                || panic!("Shouldn't have consulted the keyring"),
+            );
+
+            assert_eq!(key, Ok(SecretString::from("from-env")));
+        }
+
+        #[test]
+        fn falls_back_to_keyring_when_file_is_unreadable() {
+            let key = resolve_openai_key(
+                None,
+                None,
+                Some(String::from("/does/not/exist")),
+                || Some(String::from("from-keyring")),
+            );
+
+            assert_eq!(key, Ok(SecretString::from("from-keyring")));
+        }
+
+        fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+            let vars: Vec<(String, String)> = vars.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+
+            move |name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+        }
+
+        fn argv(args: &[&str]) -> Vec<String> {
+            args.iter().map(|arg| String::from(*arg)).collect()
+        }
+
+        #[test]
+        fn env_vars_fill_in_flags_that_werent_passed() {
+            let args = with_env_flags(
+                argv(&["manifest-o", "manifesto.txt"]),
+                env(&[("MANIFESTO_CHUNK_TOKENS", "500"), ("MANIFESTO_JSON", "true"), ("MANIFESTO_VERBOSE", "0")]),
+            ).expect("should have read the environment");
+
+            assert_eq!(args, argv(&["manifest-o", "--chunk-tokens", "500", "--json", "manifesto.txt"]));
+        }
+
+        #[test]
+        fn flags_win_over_env_vars() {
+            let args = with_env_flags(
+                argv(&["manifest-o", "manifesto.txt", "--chunk-tokens", "100"]),
+                env(&[("MANIFESTO_CHUNK_TOKENS", "500")]),
+            ).expect("should have read the environment");
+
+            assert_eq!(args, argv(&["manifest-o", "manifesto.txt", "--chunk-tokens", "100"]));
+        }
+
+        #[test]
+        fn tls_checks_cant_be_turned_off_from_the_environment() {
+            let args = with_env_flags(
+                argv(&["manifest-o", "manifesto.txt"]),
+                env(&[("MANIFESTO_DANGER_ACCEPT_INVALID_CERTS", "true")]),
+            ).expect("should have read the environment");
+
+            assert_eq!(args, argv(&["manifest-o", "manifesto.txt"]));
+        }
+
+        #[test]
+        fn rejects_switches_that_arent_true_or_false() {
+            assert!(with_env_flags(argv(&["manifest-o"]), env(&[("MANIFESTO_JSON", "maybe")])).is_err());
+        }
+
+        #[test]
+        fn json_can_be_asked_for_by_flag_or_env_var() {
+            assert!(wants_json(&argv(&["manifest-o", "manifesto.txt", "--json"]), env(&[])));
+            assert!(wants_json(&argv(&["manifest-o", "manifesto.txt"]), env(&[("MANIFESTO_JSON", "Yes")])));
+            assert!(!wants_json(&argv(&["manifest-o", "manifesto.txt"]), env(&[("MANIFESTO_JSON", "0")])));
+        }
+
+        #[test]
+        fn env_var_names_follow_the_flags() {
+            assert_eq!(env_var_for_flag("--max-input-bytes"), "MANIFESTO_MAX_INPUT_BYTES");
+        }
+
+        #[test]
+        fn key_files_can_come_from_the_environment() {
+            let paths = env::join_paths(["first.key", "second.key"]).unwrap();
+
+            assert_eq!(key_files_from_env(Some(paths)), vec![String::from("first.key"), String::from("second.key")]);
+            assert_eq!(key_files_from_env(Some(OsString::from("only.key"))), vec![String::from("only.key")]);
+            assert!(key_files_from_env(Some(OsString::new())).is_empty());
+            assert!(key_files_from_env(None).is_empty());
+        }
+
+        #[test]
+        fn org_and_project_fall_back_to_openais_env_vars() {
+            let openai_env = env(&[("OPENAI_ORG_ID", "org-from-env"), ("OPENAI_PROJECT_ID", "proj_from_env")]);
+            let from_flags = resolve_attribution(Some(String::from("org-from-flag")), None, &openai_env).unwrap();
+            let expected = Attribution::new(Some(String::from("org-from-flag")), Some(String::from("proj_from_env"))).unwrap();
+
+            assert_eq!(from_flags, expected);
+            assert_eq!(resolve_attribution(None, None, env(&[])).unwrap(), Attribution::default());
+            assert!(resolve_attribution(Some(String::from("org-abc\r\nX-Injected: 1")), None, env(&[])).is_err());
+            assert!(resolve_attribution(None, None, env(&[("OPENAI_PROJECT_ID", "proj abc")])).is_err());
+        }
+
+        #[test]
+        fn errors_when_nothing_has_a_key() {
+            if resolve_openai_key(None, Some(String::from("  ")), None, no_keyring).is_ok() {
+                panic!("Should have failed without a key");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock_server::{MockResponse, MockServer};
+    use transport::{fixtures, MockTransport};
+
+    fn args(extra: &[&str]) -> Args {
+        let argv = ["manifest-o", "manifesto.txt", "--api-key", "sk-test"].iter().chain(extra);
+
+        Args::build(argv.map(|arg| String::from(*arg))).expect("should have parsed the args")
+    }
+
+    const MANIFESTO: &str = "---\nparty: Example Party\nyear: 2024\n---\nWe promise things.\n";
+
+    #[test]
+    fn front_matter_is_not_sent_to_the_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&[]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "Things are promised.");
+        let prompt = transport.requests()[0]["messages"].to_string();
+        assert!(prompt.contains("We promise things."));
+        assert!(!prompt.contains("Example Party"));
+    }
+
+    #[test]
+    fn front_matter_fills_in_the_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--template", "## {party} ({year})\\n{summary}"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "## Example Party (2024)\nThings are promised.");
+    }
+
+    #[test]
+    fn front_matter_is_included_in_json_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--json"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["metadata"]["party"], "Example Party");
+        assert_eq!(report["summary"], "Things are promised.");
+    }
+
+    // Page numbers and a blank-line pile that cleaning takes out
+    const UNCLEAN_MANIFESTO: &str = "We promise things.\n\n\n\n\n12\n\nAnd more things.\n";
+
+    #[test]
+    fn fingerprint_is_of_the_cleaned_text_sent_to_the_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+        let cleaned = cleaning::clean(UNCLEAN_MANIFESTO).text;
+        assert_ne!(cleaned, UNCLEAN_MANIFESTO);
+
+        let summarised = summarise_document(&args(&["--json"]), &transport, UNCLEAN_MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+        let report: serde_json::Value = serde_json::from_str(&summarised.output).unwrap();
+
+        assert_eq!(summarised.source_sha256, state::input_hash(&cleaned));
+        assert_eq!(report["source_sha256"], summarised.source_sha256);
+        let messages = transport.requests()[0]["messages"].clone();
+        assert!(messages.as_array().unwrap().iter().any(|message| message["content"].as_str().is_some_and(|content| content.contains(&cleaned))));
+
+        // Without cleaning, the fingerprint follows the raw text, which is then what's sent
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let summarised = summarise_document(&args(&["--no-clean"]), &transport, UNCLEAN_MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+        assert_eq!(summarised.source_sha256, state::input_hash(UNCLEAN_MANIFESTO));
+    }
+
+    #[test]
+    fn stamp_ends_text_output_with_the_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised.\n"));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let summarised = summarise_document(&args(&["--stamp"]), &transport, UNCLEAN_MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+
+        let expected = format!("Things are promised.\n\nsource-sha256: {}", state::input_hash(&cleaning::clean(UNCLEAN_MANIFESTO).text));
+        assert_eq!(summarised.output, expected);
+        assert!(Args::build(["manifest-o", "manifesto.txt", "--stamp", "--json", "--api-key", "sk-test"].map(String::from).into_iter()).is_err());
+    }
+
+    #[test]
+    fn request_ids_that_cant_be_sent_as_a_header_are_rejected() {
+        let argv = |id: &str| ["manifest-o", "manifesto.txt", "--api-key", "sk-test", "--send-request-id", "--request-id", id].map(String::from);
+        let error = Some("--request-id can only have printable ASCII characters, with no spaces");
+
+        assert_eq!(Args::build(argv("run-42\r\nX-Injected: 1").into_iter()).err(), error);
+        assert_eq!(Args::build(argv("läuft-42").into_iter()).err(), error);
+        assert_eq!(Args::build(argv("run-42").into_iter()).map(|args| args.request_id), Ok(Some(String::from("run-42"))));
+    }
+
+    #[test]
+    fn appendix_lists_names_after_the_summary_or_in_the_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_base = dir.path().join("manifesto.txt");
+        let manifesto = "The Green Future Party will protect the NHS. Jane Smith leads the Green Future Party.\n";
+        let summarise = |argv: &[&str]| {
+            let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+            let output = summarise_document(&args(argv), &transport, manifesto, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+                .expect("should have summarised the manifesto").output;
+
+            // Found locally, so no more requests than the summary's
+            assert_eq!(transport.requests().len(), 1);
+            output
+        };
+
+        assert_eq!(
+            summarise(&["--appendix"]),
+            "Things are promised.\n\n## Appendix: names and acronyms\n\n- Green Future Party: 2 mentions\n- Jane Smith: 1 mention\n- NHS: 1 mention",
+        );
+
+        let report: serde_json::Value = serde_json::from_str(&summarise(&["--appendix", "--json"])).unwrap();
+        assert_eq!(report["summary"], "Things are promised.");
+        assert_eq!(report["entities"][0], serde_json::json!({"name": "Green Future Party", "kind": "name", "count": 2}));
+        assert_eq!(report["entities"][2]["kind"], "acronym");
+    }
+
+    #[test]
+    fn offline_makes_an_extractive_summary_without_any_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--offline"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, format!("{}\n\nWe promise things.", extractive::EXTRACTIVE_LABEL));
+        assert!(transport.requests().is_empty());
+
+        // Nothing's sent, so no key is needed either
+        assert!(Args::build(["manifest-o", "manifesto.txt", "--offline"].map(String::from).into_iter()).is_ok());
+    }
+
+    #[test]
+    fn falls_back_to_an_extractive_summary_when_openai_cant_be_reached() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_base = dir.path().join("manifesto.txt");
+        let unreachable = || MockTransport::new().fail_to_connect("error sending request: connection refused");
+
+        let output = summarise_document(&args(&["--fallback-offline", "--json"]), &unreachable(), MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have fallen back to an extractive summary").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["extractive"], true);
+        assert_eq!(report["summary"], "We promise things.");
+        assert_eq!(report["metadata"]["party"], "Example Party");
+
+        let error = summarise_document(&args(&[]), &unreachable(), MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect_err("should have failed without --fallback-offline");
+        assert!(error.contains("connection refused"));
+    }
+
+    #[test]
+    fn writes_each_translation_next_to_the_output_and_keeps_them_when_one_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("summary.md");
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Tunnels for everyone"))
+            .respond(200, &fixtures::chat_completion("Twneli i bawb"))
+            .respond(500, &fixtures::api_error("server_error", "Something broke"));
+
+        let summarised = summarise_document(&args(&["--language", "en,cy,pl"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &output_path.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summarised.output, "Tunnels for everyone");
+        assert_eq!(summarised.untranslated, vec!["pl"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1]["messages"][2]["content"], "Tunnels for everyone");
+        assert!(requests[2]["messages"][1]["content"].as_str().unwrap().contains("into Polish"));
+
+        let error = write_translations(Some(&output_path.to_string_lossy()), &summarised).expect_err("should have reported the failed translation");
+        assert!(error.contains("pl"));
+        assert_eq!(fs::read_to_string(dir.path().join("summary.cy.md")).unwrap(), "Twneli i bawb\n");
+        assert!(!dir.path().join("summary.pl.md").exists());
+    }
+
+    #[test]
+    fn json_reports_have_translations_keyed_by_language() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_base = dir.path().join("manifesto.txt");
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Tunnels for everyone"))
+            .respond(500, &fixtures::api_error("server_error", "Something broke"))
+            .respond(200, &fixtures::chat_completion("Tunele dla wszystkich"));
+
+        let summarised = summarise_document(&args(&["--language", "en,cy,pl", "--json"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+        let report: serde_json::Value = serde_json::from_str(&summarised.output).unwrap();
+
+        assert_eq!(report["summary"], "Tunnels for everyone");
+        assert_eq!(report["translations"], serde_json::json!({ "pl": "Tunele dla wszystkich" }));
+        assert!(report["translation_errors"]["cy"].is_string());
+        assert!(summarised.translations.is_empty());
+        assert_eq!(summarised.untranslated, vec!["cy"]);
+    }
+
+    #[test]
+    fn reads_every_key_file_to_take_turns_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.key");
+        let second = dir.path().join("second.key");
+        fs::write(&first, "sk-one\n").unwrap();
+        fs::write(&second, "sk-two\n").unwrap();
+        let build = |key_files: [&std::path::PathBuf; 2]| Args::build([
+            "manifest-o", "--batch", "in", "--output-dir", "out",
+            "--key-file", &key_files[0].to_string_lossy(), "--key-file", &key_files[1].to_string_lossy(),
+        ].map(String::from).into_iter());
+
+        let args = build([&first, &second]).expect("should have parsed the args");
+        assert_eq!(args.openai_key.expose(), "sk-one");
+        assert_eq!(args.extra_keys, vec![SecretString::from("sk-two")]);
+
+        assert!(build([&first, &dir.path().join("missing.key")]).is_err());
+    }
+
+    const SECRET_KEY: &str = "sk-test-do-not-print-me";
+
+    #[test]
+    fn args_and_config_dumps_never_contain_the_key() {
+        let argv = ["manifest-o", "manifesto.txt", "--api-key", SECRET_KEY, "--print-config"];
+        let args = Args::build(argv.iter().map(|arg| String::from(*arg))).expect("should have parsed the args");
+
+        assert_eq!(args.openai_key.expose(), SECRET_KEY);
+        assert!(!format!("{:?}", args).contains(SECRET_KEY));
+        assert!(!format!("{:#?}", args).contains(SECRET_KEY));
+        assert!(format!("{:#?}", args).contains("openai_key: [redacted]"));
+
+        let org_args = Args::build(["manifest-o", "manifesto.txt", "--org", "org-AbCdEfGhIjKl7xQz", "--print-config"].iter().map(|arg| String::from(*arg)))
+            .expect("should have parsed the args");
+        assert!(!format!("{:#?}", org_args).contains("org-AbCdEfGhIjKl7xQz"));
+        assert!(format!("{:#?}", org_args).contains("org-…7xQz"));
+
+        let embed_args = EmbedArgs::build(["--index", "index.jsonl", "--api-key", SECRET_KEY, "a.txt"].iter().map(|arg| String::from(*arg)))
+            .expect("should have parsed the args");
+        assert!(!format!("{:?}", embed_args).contains(SECRET_KEY));
+    }
+
+    #[test]
+    fn client_and_request_errors_never_contain_the_key() {
+        let client = build_openai_client(&SecretString::from(SECRET_KEY), &Attribution::default(), None, 1, DEFAULT_POOL_IDLE_TIMEOUT, false);
+        assert!(!format!("{:?}", client).contains(SECRET_KEY));
+
+        // Nothing listens on port 1, so this fails without a response
+        let transport = ReqwestTransport::new(client, "http://127.0.0.1:1", None, false);
+        let error = summary::summarise(&transport, "Vote for us", &SummaryOptions::default())
+            .expect_err("should have failed to connect");
+
+        assert!(!error.to_string().contains(SECRET_KEY));
+        assert!(!format!("{:?}", error).contains(SECRET_KEY));
+    }
+
+    #[test]
+    fn sends_the_organization_and_project_only_when_set() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, &fixtures::chat_completion("Without")),
+            MockResponse::new(200, &fixtures::chat_completion("With")),
+        ]);
+        let attribution = Attribution::new(Some(String::from("org-abc123")), Some(String::from("proj_def456"))).unwrap();
+
+        for attribution in [Attribution::default(), attribution] {
+            let client = build_openai_client(&SecretString::from(SECRET_KEY), &attribution, None, 1, DEFAULT_POOL_IDLE_TIMEOUT, false);
+            let transport = ReqwestTransport::new(client, &server.url, None, false);
+            summary::summarise(&transport, "Vote for us", &SummaryOptions::default()).expect("should have summarised the manifesto");
+        }
+
+        assert_eq!(server.request_header(attribution::ORGANIZATION_HEADER), vec![None, Some(String::from("org-abc123"))]);
+        assert_eq!(server.request_header(attribution::PROJECT_HEADER), vec![None, Some(String::from("proj_def456"))]);
+    }
+
+    const SECTIONED_MANIFESTO: &str = "# Health\nFree clinics.\n# Transport\nFree buses.\n# Housing\nMore homes.\n";
+
+    fn per_section_transport() -> MockTransport {
+        MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Clinics."))
+            .respond(200, &fixtures::chat_completion("Buses."))
+            .respond(200, &fixtures::chat_completion("Homes."))
+            .respond(200, &fixtures::chat_completion("The overview."))
+    }
+
+    #[test]
+    fn per_section_output_has_every_section_and_the_overview() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = per_section_transport();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--per-section", "--chunk-tokens", "100"]), &transport, SECTIONED_MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "The overview.\n\n## Health\n\nClinics.\n\n## Transport\n\nBuses.\n\n## Housing\n\nHomes.");
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[test]
+    fn per_section_json_lists_the_sections() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = per_section_transport();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--per-section", "--chunk-tokens", "100", "--json"]), &transport, SECTIONED_MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["summary"], "The overview.");
+        assert_eq!(report["sections"], serde_json::json!([
+            { "heading": "Health", "summary": "Clinics." },
+            { "heading": "Transport", "summary": "Buses." },
+            { "heading": "Housing", "summary": "Homes." },
+        ]));
+    }
+
+    fn critique_transport() -> MockTransport {
+        MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Things are promised, and taxes cut."))
+            .respond(200, &fixtures::chat_completion("Nothing is said about taxes."))
+            .respond(200, &fixtures::chat_completion("Things are promised."))
+    }
+
+    #[test]
+    fn critique_is_printed_after_the_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = critique_transport();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--critique"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "Things are promised, and taxes cut.\n\n=== Critique ===\nNothing is said about taxes.");
+
+        let critique_prompt = transport.requests()[1]["messages"][2]["content"].as_str().unwrap().to_string();
+        assert!(critique_prompt.contains("We promise things."));
+        assert!(critique_prompt.contains("Things are promised, and taxes cut."));
+    }
+
+    #[test]
+    fn revise_replaces_the_summary_in_three_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = critique_transport();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--revise", "--json"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(transport.requests().len(), 3);
+        assert_eq!(report["summary"], "Things are promised.");
+        assert_eq!(report["critique"]["text"], "Nothing is said about taxes.");
+        assert_eq!(report["critique"]["revised"], true);
+        assert_eq!(report["usage"]["total_tokens"], transport.total_usage().total_tokens);
+    }
+
+    #[test]
+    fn critique_is_skipped_past_max_cost() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = critique_transport();
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--revise", "--max-cost", "0"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "Things are promised, and taxes cut.");
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn json_lists_the_topics() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--json", "--topic", "health", "--topic", "housing"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["topics"], serde_json::json!(["health", "housing"]));
+    }
+
+    #[test]
+    fn diff_against_follows_the_summary_with_the_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = dir.path().join("old.txt");
+        fs::write(&old_path, "Some things are promised.").unwrap();
+        let old_path = old_path.to_string_lossy();
+        let state_base = dir.path().join("manifesto.txt");
+        let summarise = |extra: &[&str], transport: &MockTransport| {
+            let args = args(&[&["--diff-against", &old_path], extra].concat());
+            summarise_document(&args, transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+                .expect("should have summarised the manifesto").output
+        };
+
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        assert_eq!(
+            summarise(&[], &transport),
+            format!("Things are promised.\n\n=== Changes since {} ===\n\n[-Some things-]{{+Things+}} are promised.", old_path)
+        );
+
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Things are promised."))
+            .respond(200, &fixtures::chat_completion("Nothing of substance changed."));
+        let report: serde_json::Value = serde_json::from_str(&summarise(&["--json", "--diff-llm"], &transport)).unwrap();
+
+        assert_eq!(report["summary"], "Things are promised.");
+        assert_eq!(report["summary_diff"]["what_changed"], "Nothing of substance changed.");
+        assert_eq!(report["summary_diff"]["word_diff"]["inserted_words"], 1);
+    }
+
+    #[test]
+    fn json_reports_the_system_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion_with_fingerprint("Things are promised.", "fp_44709d6fcb"));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--json", "--seed", "42"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["system_fingerprints"], serde_json::json!(["fp_44709d6fcb"]));
+    }
+
+    #[test]
+    fn json_says_which_model_answered_after_a_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockTransport::with_handler(|request| match request["model"].as_str() {
+            Some(open_ai::GPT_4_MODEL_NAME) => (404, fixtures::api_error("model_not_found", "The model `gpt-4-turbo` does not exist")),
+            _ => (200, fixtures::chat_completion("Things are promised.")),
+        });
+        let args = args(&["--json", "--model-fallback", "gpt-4o"]);
+        let transport = FallbackTransport::new(mock, args.model_fallback.clone());
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args, &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto with gpt-4o").output;
+        let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(report["summary"], "Things are promised.");
+        assert_eq!(report["models"][0]["model"], "gpt-4o");
+        assert_eq!(report["models"][0]["requests"], 1);
+    }
+
+    #[test]
+    fn strict_output_retries_a_short_summary_with_the_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Things are promised."))
+            .respond(200, &fixtures::chat_completion("Things are promised.\n\nMany things."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--strict-output", "--paragraphs", "2", "--min-words", "3"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "Things are promised.\n\nMany things.");
+        assert_eq!(transport.requests().len(), 2);
+        assert!(transport.requests()[0]["messages"][0]["content"].as_str().unwrap().contains("2-paragraph"));
+
+        let retry_prompt = transport.requests()[1]["messages"][2]["content"].as_str().unwrap().to_string();
+        assert!(retry_prompt.contains("We promise things."));
+        assert!(retry_prompt.contains("it has 1 paragraph(s) where 2 were asked for"));
+    }
+
+    #[test]
+    fn strict_output_fails_when_the_retry_is_still_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Things are promised."))
+            .respond(200, &fixtures::chat_completion("Things."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let error = summarise_document(&args(&["--strict-output"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect_err("should have failed the output checks");
+
+        assert!(error.contains("still failed the output checks"));
+        assert!(error.contains("where 4 were asked for"));
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn cut_off_summaries_only_warn_without_strict_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::cut_off_chat_completion("Things are"));
+        let state_base = dir.path().join("manifesto.txt");
+
+        let output = summarise_document(&args(&["--min-words", "0", "--paragraphs", "1"]), &transport, MANIFESTO, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto").output;
+
+        assert_eq!(output, "Things are");
+        assert_eq!(transport.cut_off_replies(), 1);
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn truncate_cuts_the_middle_out_of_long_manifestos() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+        let manifesto = format!("Our vision.\n\n{}\n\nVote for us.", "Filler policy. ".repeat(50));
+
+        summarise_document(&args(&["--truncate", "head-tail", "--truncate-tokens", "10", "--truncate-proportions", "50:50"]), &transport, &manifesto, Path::new("manifesto.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+
+        let sent = transport.requests()[0]["messages"][2]["content"].as_str().unwrap().to_string();
+        assert!(sent.starts_with("Our vision."));
+        assert!(sent.contains(truncation::TRUNCATION_MARKER));
+        assert!(sent.trim_end().ends_with("Vote for us."));
+        assert!(chunking::estimate_tokens(&sent) < 20);
+    }
+
+    #[test]
+    fn dry_runs_always_clean_and_never_truncate() {
+        let truncated = ["--no-clean", "--truncate", "head-tail", "--truncate-tokens", "10"];
+        let dry_run: Vec<&str> = truncated.iter().copied().chain(["--dry-run"]).collect();
+
+        assert_eq!(build_pipeline(&args(&truncated)).names(), vec![pipeline::ENCODING_FIXUP, pipeline::FRONT_MATTER, pipeline::TRUNCATION]);
+        assert_eq!(build_pipeline(&args(&dry_run)).names(), vec![pipeline::ENCODING_FIXUP, pipeline::FRONT_MATTER, pipeline::CLEANING]);
+    }
+
+    #[test]
+    fn interrupted_runs_output_the_finished_chunks_and_keep_their_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_base = dir.path().join("manifesto.txt");
+        let cancelled = CancellationToken::new();
+        let handler_cancelled = cancelled.clone();
+        // Ctrl+C is pressed while the first chunk is being summarised
+        let transport = MockTransport::with_handler(move |_| {
+            handler_cancelled.cancel();
+            (200, fixtures::chat_completion("The tunnel part"))
+        });
+        let manifesto = "We will build a secret tunnel.\n\nWe will also plant trees.\n\nAnd we will lower taxes.";
+
+        let output = summarise_document(&args(&["--chunk-tokens", "10"]), &transport, manifesto, Path::new("manifesto.txt"), &state_base.to_string_lossy(), Some(&cancelled))
+            .expect("should have output the finished chunks").output;
+
+        assert_eq!(output, "[INCOMPLETE: interrupted after 1 of 3 chunks; rerun with --resume to finish]\n\nThe tunnel part");
+        assert_eq!(transport.requests().len(), 1);
+        assert!(state::state_path(&state_base.to_string_lossy()).exists());
+    }
+
+    #[test]
+    fn no_critique_conflicts_with_revise() {
+        let argv = ["manifest-o", "manifesto.txt", "--api-key", "sk-test", "--no-critique", "--revise"];
+
+        assert!(Args::build(argv.iter().map(|arg| String::from(*arg))).is_err());
+    }
+
+    #[test]
+    fn prompt_template_replaces_the_prompts() {
+        let dir = tempfile::tempdir().unwrap();
+        let template_path = dir.path().join("summary.prompt");
+        fs::write(&template_path, "[system]\nYou write for {party}.\n[user]\nSummarise {filename} ({year}):\n\n{document}").unwrap();
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Things are promised."));
+        let state_base = dir.path().join("manifesto.txt");
+
+        summarise_document(&args(&["--prompt-template", &template_path.to_string_lossy()]), &transport, MANIFESTO, Path::new("manifestos/example.txt"), &state_base.to_string_lossy(), None)
+            .expect("should have summarised the manifesto");
+
+        let messages = &transport.requests()[0]["messages"];
+        assert_eq!(messages[0]["content"], "You write for Example Party.");
+        assert_eq!(messages[1]["content"], "Summarise example.txt (2024):\n\nWe promise things.\n");
+        assert_eq!(messages.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn jobs_are_files_at_once_in_batch_mode() {
+        let argv = ["manifest-o", "--batch", "in", "--output-dir", "out", "--jobs", "4", "--api-key", "sk-test"];
+        let args = Args::build(argv.iter().map(|arg| String::from(*arg))).expect("should have parsed the args");
+
+        match &args.input {
+            Input::Batch(options) => assert_eq!(options.jobs, 4),
+            _ => panic!("Should have been a batch run"),
+        }
+        assert_eq!(args.chunk_jobs(), 1);
+        assert_eq!(self::args(&["--jobs", "4"]).chunk_jobs(), 4);
+    }
+
+    #[test]
+    fn preflight_needs_a_batch_that_calls_openai() {
+        let build = |extra: &[&str]| {
+            let argv = ["manifest-o", "--preflight", "--api-key", "sk-test"].iter().chain(extra);
+            Args::build(argv.map(|arg| String::from(*arg)))
+        };
+
+        assert!(build(&["--batch", "in", "--output-dir", "out"]).expect("should have parsed the args").preflight);
+        assert_eq!(build(&["manifesto.txt"]).err(), Some("--preflight needs --batch"));
+        assert!(build(&["--batch", "in", "--output-dir", "out", "--offline"]).is_err());
+    }
+
+    #[test]
+    fn diff_from_needs_a_single_file() {
+        assert_eq!(args(&["--diff-from", "2019.txt"]).diff_from.as_deref(), Some("2019.txt"));
+
+        let argv = ["manifest-o", "--batch", "in", "--output-dir", "out", "--diff-from", "2019.txt", "--api-key", "sk-test"];
+        assert_eq!(Args::build(argv.iter().map(|arg| String::from(*arg))).err(), Some("--diff-from only works on a single file"));
+    }
+
+    #[test]
+    fn zip_inputs_summarise_every_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("manifestos.zip");
+        fs::write(&archive_path, include_bytes!("../fixtures/manifestos.zip")).unwrap();
+        let argv = ["manifest-o", &archive_path.to_string_lossy(), "--include", "*.???", "--api-key", "sk-test"].map(String::from);
+        let args = Args::build(argv.into_iter()).expect("should have parsed the args");
+
+        let Input::Archive(archive) = &args.input else {
+            panic!("Should have been an archive");
+        };
+        let read = archive.read(args.read_options.max_bytes).expect("should have read the archive");
+        let (outputs, failed) = summarise_members(
+            &args,
+            &read.members,
+            &archive_path.to_string_lossy(),
+            || MockTransport::new().respond(200, &fixtures::chat_completion("Trees are promised.")),
+            &CancellationToken::new(),
+        );
+
+        assert!(!failed);
+        let names: Vec<&str> = outputs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["green/manifesto.txt", "green/manifesto.pdf"]);
+        assert_eq!(
+            format_archive_outputs(&outputs, false),
+            "=== green/manifesto.txt ===\n\nTrees are promised.\n\n=== green/manifesto.pdf ===\n\nTrees are promised."
+        );
+    }
+
+    #[test]
+    fn archive_flags_need_a_zip_input() {
+        assert!(Args::build(["manifest-o", "manifesto.txt", "--include", "*.pdf", "--api-key", "sk-test"].map(String::from).into_iter()).is_err());
+        assert!(Args::build(["manifest-o", "party.zip", "--dry-run"].map(String::from).into_iter()).is_err());
+
+        match args(&[]).input {
+            Input::File(path) => assert_eq!(path, "manifesto.txt"),
+            _ => panic!("Should have been a file"),
+        }
+    }
+
+    #[test]
+    fn urls_are_pages_even_when_they_end_in_zip() {
+        let args = Args::build(["manifest-o", "https://example.org/manifesto.zip", "--api-key", "sk-test"].map(String::from).into_iter())
+            .expect("should have parsed the args");
+
+        match args.input {
+            Input::File(path) => assert_eq!(path, "https://example.org/manifesto.zip"),
+            _ => panic!("Should have been a page to fetch"),
+        }
+    }
+
+    #[test]
+    fn max_tokens_past_the_models_cap_is_rejected() {
+        let argv = |max_tokens: &str| ["manifest-o", "manifesto.txt", "--api-key", "sk-test", "--max-tokens", max_tokens].map(String::from);
+
+        assert_eq!(Args::build(argv("4096").into_iter()).expect("should have parsed the args").max_tokens, Some(4096));
+        assert!(Args::build(argv("5000").into_iter()).is_err());
+    }
+
+    #[test]
+    fn tls_certificates_are_checked_unless_asked_not_to() {
+        assert!(!args(&[]).danger_accept_invalid_certs);
+        assert!(args(&["--danger-accept-invalid-certs"]).danger_accept_invalid_certs);
+    }
+
+    #[test]
+    fn pool_idle_timeout_defaults_to_90_seconds() {
+        assert_eq!(args(&[]).pool_idle_timeout, Duration::from_secs(90));
+        assert_eq!(args(&["--pool-idle-timeout", "600"]).pool_idle_timeout, Duration::from_secs(600));
     }
 }
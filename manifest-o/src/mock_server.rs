@@ -0,0 +1,174 @@
+// A tiny HTTP server for tests that need to exercise the real reqwest transport. It answers
+// each incoming connection with the next canned response and then closes the connection.
+// A response can also be endless, streaming its body until the client hangs up, stalled,
+// never coming at all, or dropped, with the connection closed as soon as the request is read.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // Set by [endless]: the body never finishes
+    pub endless: bool,
+    // Set by [stalled]: nothing is sent back
+    pub stalled: bool,
+    // Set by [dropped]: the connection is closed without a response
+    pub dropped: bool,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: &str) -> MockResponse {
+        MockResponse { status, headers: Vec::new(), body: String::from(body), endless: false, stalled: false, dropped: false }
+    }
+
+    // A response without a Content-Length whose body goes on until the client stops reading,
+    // like a misbehaving server's would
+    pub fn endless(status: u16) -> MockResponse {
+        MockResponse { status, headers: Vec::new(), body: String::new(), endless: true, stalled: false, dropped: false }
+    }
+
+    // No response at all: the connection is held open, without a word, until the client hangs up
+    pub fn stalled() -> MockResponse {
+        MockResponse { status: 0, headers: Vec::new(), body: String::new(), endless: false, stalled: true, dropped: false }
+    }
+
+    // The connection is closed once the request has been read, as a flaky network would
+    pub fn dropped() -> MockResponse {
+        MockResponse { status: 0, headers: Vec::new(), body: String::new(), endless: false, stalled: false, dropped: true }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+}
+
+// A request's header names and values, in the order they were sent
+type Headers = Vec<(String, String)>;
+
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+    headers: Arc<Mutex<Vec<Headers>>>,
+}
+
+impl MockServer {
+    pub fn start(responses: Vec<MockResponse>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should have bound a local port");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let recorded_headers = Arc::clone(&headers);
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = match listener.accept() {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+
+                let (request_headers, body) = read_request(&mut stream);
+                recorded.lock().unwrap().push(body);
+                recorded_headers.lock().unwrap().push(request_headers);
+
+                if response.dropped {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+
+                // Reading returns once the client has hung up
+                if response.stalled {
+                    let _ = stream.read(&mut [0; 1]);
+                    continue;
+                }
+
+                let mut raw = format!("HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nConnection: close\r\n", response.status);
+
+                if !response.endless {
+                    raw.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+                }
+
+                for (name, value) in &response.headers {
+                    raw.push_str(&format!("{}: {}\r\n", name, value));
+                }
+
+                raw.push_str("\r\n");
+                raw.push_str(&response.body);
+
+                let _ = stream.write_all(raw.as_bytes());
+
+                // Writing fails once the client has hung up
+                if response.endless {
+                    let chunk = [b' '; 64 * 1024];
+                    while stream.write_all(&chunk).is_ok() {}
+                }
+            }
+        });
+
+        MockServer { url, requests, headers }
+    }
+
+    // The bodies of the requests received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // The value of header [name] on each request received so far
+    pub fn request_header(&self, name: &str) -> Vec<Option<String>> {
+        self.headers.lock().unwrap().iter()
+            .map(|headers| {
+                headers.iter()
+                    .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.clone())
+            })
+            .collect()
+    }
+}
+
+// The headers and body of one request
+fn read_request(stream: &mut std::net::TcpStream) -> (Headers, String) {
+    let mut reader = BufReader::new(stream);
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+
+            headers.push((String::from(name.trim()), String::from(value.trim())));
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    let _ = reader.read_exact(&mut body);
+
+    (headers, String::from_utf8_lossy(&body).into_owned())
+}
+
+// The URL of a port that nothing is listening on, so that connecting to it is refused
+pub fn refusing_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("should have bound a local port");
+
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+// A client that talks to the mock server directly, even if the environment has a proxy set
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .no_proxy()
+        .build()
+        .expect("should have built a client")
+}
@@ -0,0 +1,272 @@
+// Falls back to other models when the one a chat request asked for can't answer it
+// (--model-fallback). When OpenAI doesn't know the model (model_not_found), says it's overloaded
+// (engine_overloaded), or is still rate limiting it once the transport has run out of retries,
+// the same request is sent to the next model in the chain, and so on until one answers. Which
+// model answered is kept, so that the report can say which models the output came from and its
+// cost can be worked out at their prices.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::open_ai::*;
+use crate::rate_limits::RateLimits;
+use crate::transport::ChatTransport;
+
+const MODEL_NOT_FOUND: &str = "model_not_found";
+const ENGINE_OVERLOADED: &str = "engine_overloaded";
+// A 429 for running out of credit rather than being rate limited, which no other model will fix
+const INSUFFICIENT_QUOTA: &str = "insufficient_quota";
+
+// How much of a run one model answered
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ModelUsage {
+    pub model: String,
+    pub requests: u32,
+    pub usage: OpenAiUsage,
+}
+
+pub struct FallbackTransport<T> {
+    inner: T,
+    // The models to try, in order, after the one a request asked for
+    chain: Vec<String>,
+    // Every model that answered a request, in the order they first did
+    models_used: Mutex<Vec<ModelUsage>>,
+}
+
+impl<T: ChatTransport> FallbackTransport<T> {
+    pub fn new(inner: T, chain: Vec<String>) -> FallbackTransport<T> {
+        FallbackTransport { inner, chain, models_used: Mutex::new(Vec::new()) }
+    }
+
+    fn record(&self, model: &str, response: &OpenAiResponse) {
+        let mut models_used = self.models_used.lock().unwrap();
+        let usage = response.usage.unwrap_or_default();
+
+        match models_used.iter_mut().find(|used| used.model == model) {
+            Some(used) => {
+                used.requests += 1;
+                used.usage.add(&usage);
+            }
+            None => models_used.push(ModelUsage { model: String::from(model), requests: 1, usage }),
+        }
+    }
+}
+
+// Whether another model might answer a request that failed with [error]
+pub fn should_fall_back(error: &ManifestoError) -> bool {
+    match error {
+        ManifestoError::Api { code: Some(code), .. } if code == MODEL_NOT_FOUND || code == ENGINE_OVERLOADED => true,
+        ManifestoError::Api { status: 429, code, .. } => code.as_deref() != Some(INSUFFICIENT_QUOTA),
+        _ => false,
+    }
+}
+
+impl<T: ChatTransport> ChatTransport for FallbackTransport<T> {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        let mut model = &body.model;
+        let mut result = self.inner.post_chat(body);
+
+        for next in self.chain.iter().filter(|next| **next != body.model) {
+            let error = match &result {
+                Err(e) if should_fall_back(e) => e.to_string(),
+                _ => break,
+            };
+
+            diagnostics::warn(format!("{} couldn't answer ({}); trying {} instead", model, error, next));
+
+            let mut fallback = body.clone();
+            fallback.model = next.clone();
+            // OpenAI didn't act on the failed request, but it won't take its key with a different
+            // body either
+            fallback.idempotency_key = body.idempotency_key.as_ref().map(|key| format!("{}-{}", key, next));
+
+            model = next;
+            result = self.inner.post_chat(&fallback);
+        }
+
+        if let Ok(response) = &result {
+            self.record(model, response);
+        }
+
+        result
+    }
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        self.inner.post_moderation(body)
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        self.inner.post_embeddings(body)
+    }
+
+    fn last_rate_limits(&self) -> Option<RateLimits> {
+        self.inner.last_rate_limits()
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        self.inner.total_usage()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        self.inner.cut_off_replies()
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        self.inner.system_fingerprints()
+    }
+
+    fn total_request_duration(&self) -> Duration {
+        self.inner.total_request_duration()
+    }
+
+    fn models_used(&self) -> Vec<ModelUsage> {
+        self.models_used.lock().unwrap().clone()
+    }
+}
+
+// The models in a --model-fallback list, in order and without repeats
+pub fn parse_chain(list: &str) -> Result<Vec<String>, &'static str> {
+    let mut chain: Vec<String> = Vec::new();
+
+    for model in list.split(',').map(str::trim) {
+        if model.is_empty() {
+            return Err("--model-fallback needs a comma-separated list of models");
+        }
+
+        if !chain.iter().any(|existing| existing == model) {
+            chain.push(String::from(model));
+        }
+    }
+
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prices::PriceTable;
+    use crate::transport::{fixtures, MockTransport};
+
+    fn request(model: &str) -> OpenAiRequestBody {
+        ChatRequestBuilder::new().model(model).user("Summarise this").build().unwrap()
+    }
+
+    fn chain(models: &[&str]) -> Vec<String> {
+        models.iter().map(|model| String::from(*model)).collect()
+    }
+
+    // Answers as [available] models do, and with a 404 for any other
+    fn only_serving(available: &'static [&'static str]) -> MockTransport {
+        MockTransport::with_handler(move |request| {
+            let model = request["model"].as_str().unwrap();
+
+            match available.contains(&model) {
+                true => (200, fixtures::chat_completion(&format!("Summary from {}", model))),
+                false => (404, fixtures::api_error(MODEL_NOT_FOUND, &format!("The model `{}` does not exist", model))),
+            }
+        })
+    }
+
+    #[test]
+    fn falls_back_to_the_next_model_that_answers() {
+        let transport = FallbackTransport::new(only_serving(&["gpt-4o"]), chain(&["gpt-4o", "gpt-4o-mini"]));
+
+        let response = transport.post_chat(&request(GPT_4_MODEL_NAME)).expect("should have fallen back to gpt-4o");
+
+        assert_eq!(response.to_string(), "Summary from gpt-4o");
+
+        let requests = transport.inner.requests();
+        let models: Vec<&str> = requests.iter().map(|request| request["model"].as_str().unwrap()).collect();
+        assert_eq!(models, vec![GPT_4_MODEL_NAME, "gpt-4o"]);
+
+        // The output (and its tokens) are put down to the model that answered
+        let models_used = transport.models_used();
+        assert_eq!(models_used.len(), 1);
+        assert_eq!((models_used[0].model.as_str(), models_used[0].requests), ("gpt-4o", 1));
+        assert_eq!(models_used[0].usage, transport.total_usage());
+    }
+
+    #[test]
+    fn costs_are_worked_out_at_the_answering_models_price() {
+        let transport = FallbackTransport::new(only_serving(&["gpt-4o-mini"]), chain(&["gpt-4o", "gpt-4o-mini"]));
+        transport.post_chat(&request(GPT_4_MODEL_NAME)).unwrap();
+
+        let prices = PriceTable::default();
+        let usage = transport.total_usage();
+
+        assert_eq!(
+            prices.estimated_cost_usd_by_model(&usage, &transport.models_used(), GPT_4_MODEL_NAME),
+            prices.estimated_cost_usd(&usage, "gpt-4o-mini"),
+        );
+        assert_ne!(
+            prices.estimated_cost_usd_by_model(&usage, &transport.models_used(), GPT_4_MODEL_NAME),
+            prices.estimated_cost_usd(&usage, GPT_4_MODEL_NAME),
+        );
+    }
+
+    #[test]
+    fn requests_that_get_an_answer_are_sent_once() {
+        let transport = FallbackTransport::new(only_serving(&[GPT_4_MODEL_NAME]), chain(&["gpt-4o"]));
+
+        transport.post_chat(&request(GPT_4_MODEL_NAME)).unwrap();
+
+        assert_eq!(transport.inner.requests().len(), 1);
+        assert_eq!(transport.models_used()[0].model, GPT_4_MODEL_NAME);
+    }
+
+    #[test]
+    fn the_last_error_is_returned_when_no_model_answers() {
+        let transport = FallbackTransport::new(only_serving(&[]), chain(&["gpt-4o", "gpt-4o-mini"]));
+
+        let error = transport.post_chat(&request(GPT_4_MODEL_NAME)).err().expect("no model should have answered");
+
+        assert!(error.to_string().contains("gpt-4o-mini"), "{}", error);
+        assert_eq!(transport.inner.requests().len(), 3);
+        assert!(transport.models_used().is_empty());
+    }
+
+    #[test]
+    fn other_errors_fail_straight_away() {
+        let transport = FallbackTransport::new(
+            MockTransport::new().respond(400, &fixtures::api_error("invalid_request_error", "Bad request")),
+            chain(&["gpt-4o"]),
+        );
+
+        assert!(transport.post_chat(&request(GPT_4_MODEL_NAME)).is_err());
+        assert_eq!(transport.inner.requests().len(), 1);
+    }
+
+    #[test]
+    fn fallbacks_get_keys_of_their_own() {
+        let transport = FallbackTransport::new(only_serving(&["gpt-4o"]), chain(&["gpt-4o"]));
+        let mut body = request(GPT_4_MODEL_NAME);
+        body.idempotency_key = Some(String::from("manifest-o-abc-1"));
+
+        transport.post_chat(&body).unwrap();
+
+        assert_eq!(
+            transport.inner.idempotency_keys(),
+            vec![Some(String::from("manifest-o-abc-1")), Some(String::from("manifest-o-abc-1-gpt-4o"))]
+        );
+    }
+
+    #[test]
+    fn knows_which_errors_another_model_might_not_have() {
+        let api_error = |status, code: &str| ManifestoError::Api { status, code: Some(String::from(code)), message: String::new() };
+
+        assert!(should_fall_back(&api_error(404, MODEL_NOT_FOUND)));
+        assert!(should_fall_back(&api_error(503, ENGINE_OVERLOADED)));
+        assert!(should_fall_back(&api_error(429, "rate_limit_exceeded")));
+        assert!(!should_fall_back(&api_error(429, INSUFFICIENT_QUOTA)));
+        assert!(!should_fall_back(&api_error(401, "invalid_api_key")));
+        assert!(!should_fall_back(&ManifestoError::Http(String::from("connection refused"))));
+    }
+
+    #[test]
+    fn parses_chains() {
+        assert_eq!(parse_chain("gpt-4o, gpt-4o-mini,gpt-4o"), Ok(chain(&["gpt-4o", "gpt-4o-mini"])));
+        assert!(parse_chain("gpt-4o,,gpt-4o-mini").is_err());
+    }
+}
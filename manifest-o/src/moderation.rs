@@ -0,0 +1,134 @@
+use serde::Serialize;
+use crate::chunking;
+use crate::error::ManifestoError;
+use crate::open_ai::*;
+use crate::transport::ChatTransport;
+
+// The moderation endpoint takes far fewer tokens per input than the completion models do, so
+// documents are screened in chunks of this size.
+const MODERATION_CHUNK_TOKENS: usize = 8000;
+
+// A chunk of the document that moderation flagged, and why
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FlaggedChunk {
+    pub chunk_index: usize,
+    // (category, score) pairs, highest score first
+    pub categories: Vec<(String, f64)>,
+}
+
+// Screens the document with OpenAI's moderation endpoint and returns every chunk that was
+// flagged. With a [threshold], a category counts as flagged when its score is at least the
+// threshold; without one, OpenAI's own per-category decisions are used.
+pub fn moderate(transport: &impl ChatTransport, document: &str, threshold: Option<f64>) -> Result<Vec<FlaggedChunk>, ManifestoError> {
+    let mut flagged = Vec::new();
+
+    for (chunk_index, chunk) in chunking::split_into_chunks(document, MODERATION_CHUNK_TOKENS).iter().enumerate() {
+        let response = transport.post_moderation(&ModerationRequest {
+            model: MODERATION_MODEL_NAME,
+            input: chunk,
+        })?;
+
+        let result = response.results.first()
+            .ok_or(ManifestoError::EmptyResponse)?;
+
+        let mut categories: Vec<(String, f64)> = result.category_scores.iter()
+            .filter(|(category, score)| match threshold {
+                Some(threshold) => **score >= threshold,
+                None => result.categories.get(*category).copied().unwrap_or(false),
+            })
+            .map(|(category, score)| (category.clone(), *score))
+            .collect();
+
+        if categories.is_empty() {
+            continue;
+        }
+
+        categories.sort_by(|a, b| b.1.total_cmp(&a.1));
+        flagged.push(FlaggedChunk { chunk_index, categories });
+    }
+
+    Ok(flagged)
+}
+
+pub fn format_flagged_chunks(flagged: &[FlaggedChunk]) -> String {
+    flagged.iter()
+        .map(|chunk| {
+            let categories: Vec<String> = chunk.categories.iter()
+                .map(|(category, score)| format!("{} ({:.2})", category, score))
+                .collect();
+
+            format!("Chunk {}: {}", chunk.chunk_index + 1, categories.join(", "))
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    #[test]
+    fn clean_documents_pass() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::moderation(&[&[("violence", 0.01), ("hate", 0.02)]]));
+
+        let flagged = moderate(&transport, "We will build more schools", None)
+            .expect("should have moderated the document");
+
+        assert!(flagged.is_empty());
+
+        let requests = transport.requests();
+        assert_eq!(requests[0]["model"], MODERATION_MODEL_NAME);
+        assert_eq!(requests[0]["input"], "We will build more schools");
+        assert_eq!(transport.total_usage(), OpenAiUsage::default());
+    }
+
+    #[test]
+    fn flagged_documents_report_categories() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::moderation(&[&[("violence", 0.7), ("hate", 0.9), ("sexual", 0.01)]]));
+
+        let flagged = moderate(&transport, "Something awful", None)
+            .expect("should have moderated the document");
+
+        assert_eq!(flagged, vec![FlaggedChunk {
+            chunk_index: 0,
+            categories: vec![(String::from("hate"), 0.9), (String::from("violence"), 0.7)],
+        }]);
+        assert_eq!(format_flagged_chunks(&flagged), "Chunk 1: hate (0.90), violence (0.70)");
+    }
+
+    #[test]
+    fn partially_flagged_documents_report_only_flagged_chunks() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::moderation(&[&[("violence", 0.01)]]))
+            .respond(200, &fixtures::moderation(&[&[("violence", 0.8)]]));
+        let document = format!("{}\n\n{}", "a ".repeat(MODERATION_CHUNK_TOKENS * 2), "b ".repeat(10));
+
+        let flagged = moderate(&transport, &document, None)
+            .expect("should have moderated the document");
+
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(flagged, vec![FlaggedChunk {
+            chunk_index: 1,
+            categories: vec![(String::from("violence"), 0.8)],
+        }]);
+    }
+
+    #[test]
+    fn threshold_overrides_openais_decision() {
+        let scores: &[(&str, f64)] = &[("violence", 0.3), ("hate", 0.6)];
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::moderation(&[scores]))
+            .respond(200, &fixtures::moderation(&[scores]));
+
+        let strict = moderate(&transport, "Borderline", Some(0.2))
+            .expect("should have moderated the document");
+        let lenient = moderate(&transport, "Borderline", Some(0.95))
+            .expect("should have moderated the document");
+
+        assert_eq!(strict[0].categories.len(), 2);
+        assert!(lenient.is_empty());
+    }
+}
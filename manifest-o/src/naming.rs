@@ -0,0 +1,157 @@
+// Output names from document titles (--name-from-title), for batches of files whose own names
+// say nothing about what's in them. A document's title is its front matter's party and year, or
+// its first `# ` heading, and only if it has neither is the model asked for one. The title is
+// then made into a file name: lower case, with anything that isn't a letter or digit turned into
+// a dash.
+
+use crate::chunking;
+use crate::error::ManifestoError;
+use crate::front_matter::Metadata;
+use crate::open_ai::GPT_35_MODEL_NAME;
+use crate::summary;
+use crate::transport::ChatTransport;
+
+// Long enough for a party name and a year, short enough to read in a directory listing
+const MAX_NAME_CHARS: usize = 60;
+// Only the start of a document is sent when asking for its title
+const TITLE_CONTEXT_TOKENS: usize = 1000;
+const TITLE_SYSTEM_PROMPT: &str = "You give documents short, descriptive titles";
+const TITLE_INSTRUCTION: &str = "Reply with a title of at most eight words for the following manifesto, naming the party and year if it gives them, and nothing else.";
+
+// Where the title comes from for a document that doesn't have one of its own
+pub trait TitleSource {
+    fn title(&self, document: &str) -> Result<String, ManifestoError>;
+}
+
+// The model names the document from its start. A cheaper model is plenty for that.
+impl<T: ChatTransport> TitleSource for T {
+    fn title(&self, document: &str) -> Result<String, ManifestoError> {
+        let start = chunking::split_into_chunks(document, TITLE_CONTEXT_TOKENS).into_iter().next().unwrap_or_default();
+        let reply = summary::complete(self, GPT_35_MODEL_NAME, TITLE_SYSTEM_PROMPT, TITLE_INSTRUCTION, &start, 1, false)?
+            .swap_remove(0);
+
+        Ok(String::from(reply.trim().trim_matches(['"', '\''])))
+    }
+}
+
+// The title [body] gives itself, if any: "<party> <year>" from its front matter, or else its
+// first `# ` heading
+pub fn document_title(metadata: &Metadata, body: &str) -> Option<String> {
+    if let Some(party) = metadata.get("party").filter(|party| !party.trim().is_empty()) {
+        return Some(match metadata.get("year") {
+            Some(year) => format!("{} {}", party.trim(), year.trim()),
+            None => String::from(party.trim()),
+        });
+    }
+
+    body.lines()
+        .find_map(|line| line.trim().strip_prefix("# "))
+        .map(str::trim)
+        .filter(|heading| !heading.is_empty())
+        .map(String::from)
+}
+
+// [title] as a file name, e.g. "Labour's Plan: 2024" becomes "labours-plan-2024". Letters and
+// digits from any script are kept; everything else that could trip up a path (slashes, dots,
+// spaces, ...) becomes a single dash. Long titles are cut at a word boundary where there is one.
+// None if nothing is left.
+pub fn slugify(title: &str) -> Option<String> {
+    let mut slug = String::new();
+
+    for c in title.chars().filter(|c| !matches!(c, '\'' | '\u{2019}')) {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    if slug.chars().count() > MAX_NAME_CHARS {
+        let cut: String = slug.chars().take(MAX_NAME_CHARS + 1).collect();
+
+        slug = match cut.rfind('-') {
+            Some(end) if end > 0 => String::from(&cut[..end]),
+            _ => cut.chars().take(MAX_NAME_CHARS).collect(),
+        };
+    }
+
+    let slug = slug.trim_end_matches('-');
+
+    (!slug.is_empty()).then(|| String::from(slug))
+}
+
+// [name], or the first of [name]-2, [name]-3, ... that isn't [taken]
+pub fn unique_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(name) {
+        return String::from(name);
+    }
+
+    (2..)
+        .map(|n| format!("{}-{}", name, n))
+        .find(|candidate| !taken(candidate))
+        .expect("there should always be a free name")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    #[test]
+    fn slugifies_punctuation_and_spacing() {
+        assert_eq!(slugify("Labour's Plan: 2024").as_deref(), Some("labours-plan-2024"));
+        assert_eq!(slugify("  A/B \\ C..D  ").as_deref(), Some("a-b-c-d"));
+        assert_eq!(slugify("../../etc/passwd").as_deref(), Some("etc-passwd"));
+        assert_eq!(slugify("?!*"), None);
+        assert_eq!(slugify(""), None);
+    }
+
+    #[test]
+    fn slugifies_unicode() {
+        assert_eq!(slugify("Déclaration de Principes").as_deref(), Some("déclaration-de-principes"));
+        assert_eq!(slugify("ÖVP Programm 2024").as_deref(), Some("övp-programm-2024"));
+        assert_eq!(slugify("自由民主党 2024").as_deref(), Some("自由民主党-2024"));
+        assert_eq!(slugify("Party 🎉 Manifesto").as_deref(), Some("party-manifesto"));
+    }
+
+    #[test]
+    fn cuts_long_titles_at_a_word_boundary() {
+        let title = "A Very Long Manifesto Title That Goes On And On About Everything The Party Wants";
+        let slug = slugify(title).expect("should have made a slug");
+
+        assert!(slug.chars().count() <= MAX_NAME_CHARS);
+        assert_eq!(slug, "a-very-long-manifesto-title-that-goes-on-and-on-about");
+
+        // One long word has no boundary, so it's cut where it is
+        let slug = slugify(&"é".repeat(100)).expect("should have made a slug");
+        assert_eq!(slug, "é".repeat(MAX_NAME_CHARS));
+    }
+
+    #[test]
+    fn suffixes_names_that_are_taken() {
+        let taken = ["labour-2024", "labour-2024-2"];
+
+        assert_eq!(unique_name("green-2024", |name| taken.contains(&name)), "green-2024");
+        assert_eq!(unique_name("labour-2024", |name| taken.contains(&name)), "labour-2024-3");
+    }
+
+    #[test]
+    fn prefers_front_matter_then_headings() {
+        let metadata = Metadata::from([(String::from("party"), String::from("Example Party")), (String::from("year"), String::from("2024"))]);
+
+        assert_eq!(document_title(&metadata, "# Our Plan").as_deref(), Some("Example Party 2024"));
+        assert_eq!(document_title(&Metadata::new(), "Intro\n\n#  Our Plan \n# Later").as_deref(), Some("Our Plan"));
+        assert_eq!(document_title(&Metadata::new(), "## Only a subheading"), None);
+    }
+
+    #[test]
+    fn asks_the_model_with_the_start_of_the_document() {
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("\"Green Party 2024\"\n"));
+
+        assert_eq!(transport.title(&"word ".repeat(5000)).expect("should have got a title"), "Green Party 2024");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request["model"], GPT_35_MODEL_NAME);
+        assert!(request["messages"][2]["content"].as_str().unwrap().len() < 5000);
+    }
+}
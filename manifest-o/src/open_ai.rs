@@ -0,0 +1,495 @@
+use serde::{ Serialize, Deserialize, Deserializer };
+use std::collections::HashMap;
+use std::fmt;
+
+pub const GPT_35_MODEL_NAME: &str = "gpt-3.5-turbo";
+pub const GPT_4_MODEL_NAME: &str = "gpt-4-turbo";
+pub const MODERATION_MODEL_NAME: &str = "omni-moderation-latest";
+pub const EMBEDDING_MODEL_NAME: &str = "text-embedding-3-small";
+const FINISH_REASON_LENGTH: &str = "length";
+const FINISH_REASON_CONTENT_FILTER: &str = "content_filter";
+
+// The most each chat model will reply with, whatever its context length, which is what OpenAI
+// checks max_tokens against
+const MAX_OUTPUT_TOKENS: &[(&str, u32)] = &[
+    ("gpt-3.5-turbo", 4096),
+    ("gpt-4", 8192),
+    ("gpt-4-turbo", 4096),
+    ("gpt-4o", 16384),
+    ("gpt-4o-mini", 16384),
+];
+
+// Build these with [ChatRequestBuilder], which checks that they're complete
+#[derive(Serialize, Clone)]
+pub struct OpenAiRequestBody {
+    pub model: String,
+    pub messages: Vec<OpenAiRequestMessage>,
+    // How many choices to generate; OpenAI defaults to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    // OpenAI defaults to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    // Requests with the same key are routed together, so they're more likely to hit OpenAI's
+    // prompt cache for a shared prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_cache_key: Option<String>,
+    // The most tokens the reply can have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    // Up to 4 sequences that end the reply where they'd appear (without including them)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    // An ID for the end user (or tenant) behind the request, for OpenAI's abuse monitoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    // Asks OpenAI to sample deterministically, so that the same request (with the same seed)
+    // gets the same reply, as long as the system_fingerprint doesn't change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // Sent as the Idempotency-Key header rather than in the body (see [crate::idempotency])
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+}
+
+// Fields that every chat request in the run gets (from --max-tokens, --stop, --user-id and --seed),
+// unless the request sets them itself
+#[derive(Clone, Debug, Default)]
+pub struct ChatDefaults {
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    pub user: Option<String>,
+    pub seed: Option<u64>,
+}
+
+impl ChatDefaults {
+    pub fn apply_to(&self, body: &mut OpenAiRequestBody) {
+        if body.max_tokens.is_none() {
+            body.max_tokens = self.max_tokens;
+        }
+
+        if body.stop.is_none() {
+            body.stop.clone_from(&self.stop);
+        }
+
+        if body.user.is_none() {
+            body.user.clone_from(&self.user);
+        }
+
+        if body.seed.is_none() {
+            body.seed = self.seed;
+        }
+    }
+}
+
+// Owns its content, so that messages can be built from formatted strings. Copying the text is
+// nothing next to the request itself: a 100 KB manifesto copies in microseconds, and the
+// request takes seconds.
+#[derive(Serialize, Clone)]
+pub struct OpenAiRequestMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+// Who a message is from, as far as the model is concerned
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    // Not sent by anything yet, but here so that tool calls can be built
+    #[allow(dead_code)]
+    Tool,
+}
+
+// Puts together an [OpenAiRequestBody] one message at a time, e.g.
+// `ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).system(prompt).user(text).build()`
+#[derive(Clone, Default)]
+pub struct ChatRequestBuilder {
+    model: Option<String>,
+    messages: Vec<OpenAiRequestMessage>,
+    n: Option<u32>,
+    temperature: Option<f32>,
+    prompt_cache_key: Option<String>,
+    stop: Option<Vec<String>>,
+    user_id: Option<String>,
+}
+
+impl ChatRequestBuilder {
+    pub fn new() -> ChatRequestBuilder {
+        ChatRequestBuilder::default()
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> ChatRequestBuilder {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn message(mut self, role: Role, content: impl Into<String>) -> ChatRequestBuilder {
+        self.messages.push(OpenAiRequestMessage { role, content: content.into() });
+        self
+    }
+
+    pub fn system(self, content: impl Into<String>) -> ChatRequestBuilder {
+        self.message(Role::System, content)
+    }
+
+    pub fn user(self, content: impl Into<String>) -> ChatRequestBuilder {
+        self.message(Role::User, content)
+    }
+
+    #[allow(dead_code)]
+    pub fn assistant(self, content: impl Into<String>) -> ChatRequestBuilder {
+        self.message(Role::Assistant, content)
+    }
+
+    pub fn n(mut self, n: u32) -> ChatRequestBuilder {
+        self.n = Some(n);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn temperature(mut self, temperature: f32) -> ChatRequestBuilder {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn prompt_cache_key(mut self, key: impl Into<String>) -> ChatRequestBuilder {
+        self.prompt_cache_key = Some(key.into());
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn stop(mut self, stop: Vec<String>) -> ChatRequestBuilder {
+        self.stop = Some(stop);
+        self
+    }
+
+    // Sets the request's `user` field (not to be confused with [user], which adds a message)
+    #[allow(dead_code)]
+    pub fn user_id(mut self, user_id: impl Into<String>) -> ChatRequestBuilder {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    // Fails if no model was set or there are no messages, neither of which OpenAI accepts
+    pub fn build(self) -> Result<OpenAiRequestBody, &'static str> {
+        let model = self.model.ok_or("A chat request needs a model")?;
+
+        if self.messages.is_empty() {
+            return Err("A chat request needs at least one message");
+        }
+
+        Ok(OpenAiRequestBody {
+            model,
+            messages: self.messages,
+            n: self.n,
+            temperature: self.temperature,
+            prompt_cache_key: self.prompt_cache_key,
+            max_tokens: None,
+            stop: self.stop,
+            user: self.user_id,
+            seed: None,
+            idempotency_key: None,
+        })
+    }
+}
+
+// Checks that [model] can reply with [max_tokens] tokens, so that a --max-tokens it can't honour
+// fails before anything is sent. Models without a known cap aren't checked.
+pub fn check_max_tokens(model: &str, max_tokens: u32) -> Result<(), String> {
+    let Some((_, cap)) = MAX_OUTPUT_TOKENS.iter().find(|(name, _)| *name == model) else {
+        eprintln!("Note: the most {} can reply with isn't known, so --max-tokens wasn't checked against it", model);
+        return Ok(());
+    };
+
+    if max_tokens > *cap {
+        return Err(format!("{} can reply with at most {} tokens, but --max-tokens is {}", model, cap, max_tokens));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenAiResponse {
+    pub choices: Vec<OpenAiResponseMessage>,
+    pub usage: Option<OpenAiUsage>,
+    // Identifies the backend configuration that answered. Replies to the same seeded request
+    // only match while this stays the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+}
+
+impl OpenAiResponse {
+    // How many of the choices were cut off before the model finished them
+    pub fn cut_off_choices(&self) -> u32 {
+        self.choices.iter()
+            .filter(|choice| choice.finish_reason.as_deref() == Some(FINISH_REASON_LENGTH))
+            .count() as u32
+    }
+
+    // Whether the model declined to answer at all: every choice was stopped by OpenAI's content
+    // filter, so whatever content they have is partial (or empty)
+    pub fn content_filtered(&self) -> bool {
+        !self.choices.is_empty() && self.choices.iter().all(OpenAiResponseMessage::content_filtered)
+    }
+
+    // Every choice under a numbered heading with its finish_reason, for telling the candidates
+    // in an n > 1 reply apart
+    pub fn format_all(&self) -> String {
+        self.choices.iter().enumerate()
+            .map(|(i, choice)| format!(
+                "--- Choice {} (finish_reason: {}) ---\n{}",
+                i + 1,
+                choice.finish_reason.as_deref().unwrap_or("unknown"),
+                choice.message.content.trim_end()
+            ))
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    }
+}
+
+impl fmt::Display for OpenAiResponse {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, choice) in self.choices.iter().enumerate() {
+            if i > 0 {
+                writeln!(formatter)?;
+            }
+
+            write!(formatter, "{}", choice.message.content)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Token counts for a request. For n > 1, completion_tokens covers every choice. Embedding
+// requests only have prompt tokens.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpenAiUsage {
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl OpenAiUsage {
+    pub fn add(&mut self, other: &OpenAiUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenAiResponseMessage {
+    pub message: OpenAiResponseMessageContent,
+    // Why the model stopped: "stop" when it finished, "length" when it ran into max_tokens (or
+    // its own cap) and was cut off, or "content_filter" when it declined to go on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+}
+
+impl OpenAiResponseMessage {
+    pub fn content_filtered(&self) -> bool {
+        self.finish_reason.as_deref() == Some(FINISH_REASON_CONTENT_FILTER)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenAiResponseMessageContent {
+    // Null when the content filter stopped the reply before it started
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// The body OpenAI sends back alongside a non-2xx status
+#[derive(Deserialize)]
+pub struct OpenAiErrorResponse {
+    pub error: OpenAiErrorDetails,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAiErrorDetails {
+    pub message: String,
+    pub code: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ModerationRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a str,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub categories: HashMap<String, bool>,
+    pub category_scores: HashMap<String, f64>,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingRequest<'a> {
+    pub model: &'a str,
+    pub input: Vec<&'a str>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<Embedding>,
+    pub usage: Option<OpenAiUsage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Embedding {
+    // Which of the inputs this is the embedding for
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roles_serialize_in_lowercase() {
+        let roles = [Role::System, Role::User, Role::Assistant, Role::Tool];
+
+        assert_eq!(
+            serde_json::to_value(roles).expect("should have serialized the roles"),
+            serde_json::json!(["system", "user", "assistant", "tool"])
+        );
+    }
+
+    #[test]
+    fn format_all_numbers_every_choice() {
+        let response: OpenAiResponse = serde_json::from_value(serde_json::json!({
+            "choices": [
+                { "message": { "content": "First\n" }, "finish_reason": "stop" },
+                { "message": { "content": "Second" }, "finish_reason": "length" },
+                { "message": { "content": "Third" } }
+            ],
+            "usage": null
+        })).expect("should have parsed the response");
+
+        assert_eq!(
+            response.format_all(),
+            "--- Choice 1 (finish_reason: stop) ---\nFirst\n\n--- Choice 2 (finish_reason: length) ---\nSecond\n\n--- Choice 3 (finish_reason: unknown) ---\nThird"
+        );
+    }
+
+    #[test]
+    fn builder_serializes_like_a_hand_built_body() {
+        let body = ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .system("Be brief")
+            .user("Hi")
+            .build()
+            .expect("should have built the request");
+
+        assert_eq!(
+            serde_json::to_string(&body).expect("should have serialized the request"),
+            r#"{"model":"gpt-4-turbo","messages":[{"role":"system","content":"Be brief"},{"role":"user","content":"Hi"}]}"#
+        );
+    }
+
+    #[test]
+    fn builder_includes_optional_fields_when_set() {
+        let body = ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .user("Hi")
+            .assistant("Hello")
+            .n(2)
+            .temperature(0.5)
+            .prompt_cache_key("key")
+            .build()
+            .expect("should have built the request");
+        let json = serde_json::to_value(&body).expect("should have serialized the request");
+
+        assert_eq!(json["messages"][1]["role"], "assistant");
+        assert_eq!(json["n"], 2);
+        assert_eq!(json["temperature"], 0.5);
+        assert_eq!(json["prompt_cache_key"], "key");
+    }
+
+    #[test]
+    fn stop_sequences_serialize_as_a_list() {
+        let body = |stop: Option<Vec<&str>>| {
+            let mut builder = ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).user("Hi");
+
+            if let Some(stop) = stop {
+                builder = builder.stop(stop.into_iter().map(String::from).collect());
+            }
+
+            serde_json::to_string(&builder.build().expect("should have built the request"))
+                .expect("should have serialized the request")
+        };
+
+        assert!(!body(None).contains("stop"));
+        assert!(body(Some(vec!["END"])).ends_with(r#","stop":["END"]}"#));
+        assert!(body(Some(vec!["END", "\n\n", "---"])).ends_with(r#","stop":["END","\n\n","---"]}"#));
+    }
+
+    #[test]
+    fn defaults_fill_in_unset_fields() {
+        let defaults = ChatDefaults {
+            max_tokens: Some(500),
+            stop: Some(vec![String::from("END")]),
+            user: Some(String::from("tenant-1a2b")),
+            seed: Some(42),
+        };
+        let mut body = ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).user("Hi").build().unwrap();
+        let mut own_stop = ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).user("Hi").stop(vec![String::from("DONE")]).build().unwrap();
+
+        defaults.apply_to(&mut body);
+        defaults.apply_to(&mut own_stop);
+
+        let json = serde_json::to_value(&body).expect("should have serialized the request");
+        assert_eq!(json["user"], "tenant-1a2b");
+        assert_eq!(json["stop"], serde_json::json!(["END"]));
+        assert_eq!(json["max_tokens"], 500);
+        assert_eq!(json["seed"], 42);
+        assert_eq!(own_stop.stop, Some(vec![String::from("DONE")]));
+        assert_eq!(own_stop.user.as_deref(), Some("tenant-1a2b"));
+    }
+
+    #[test]
+    fn max_tokens_is_checked_against_the_model() {
+        assert!(check_max_tokens(GPT_4_MODEL_NAME, 4096).is_ok());
+
+        let error = check_max_tokens(GPT_4_MODEL_NAME, 10_000).expect_err("should have rejected max_tokens");
+        assert_eq!(error, "gpt-4-turbo can reply with at most 4096 tokens, but --max-tokens is 10000");
+
+        assert!(check_max_tokens("gpt-9", 1_000_000).is_ok());
+    }
+
+    #[test]
+    fn builder_takes_formatted_messages() {
+        let mut builder = ChatRequestBuilder::new().model(GPT_4_MODEL_NAME);
+
+        for part in 1..=3 {
+            builder = builder.user(format!("This is part {} of 3", part));
+        }
+
+        let body = builder.build().expect("should have built the request");
+        let json = serde_json::to_value(&body).expect("should have serialized the request");
+
+        assert_eq!(json["messages"][0]["content"], "This is part 1 of 3");
+        assert_eq!(json["messages"][2]["content"], "This is part 3 of 3");
+    }
+
+    #[test]
+    fn builder_needs_a_model_and_a_message() {
+        assert!(ChatRequestBuilder::new().user("Hi").build().is_err());
+        assert!(ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).build().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+// Local checks on the summary once it's back, for replies that look wrong before anyone reads
+// them: ones that were cut off by max_tokens, or that are much shorter than was asked for
+// (usually because the model misread the instruction and replied with a sentence or two).
+
+use std::fmt;
+use crate::prompt_template::count_paragraphs;
+
+// Used for the minimum word count when there's no --min-words. Four paragraphs of 25 words each
+// is well short of a real summary, but a long way past a one-sentence reply.
+const MIN_WORDS_PER_PARAGRAPH: usize = 25;
+
+#[derive(Default, Debug)]
+pub struct Expectations {
+    // How many paragraphs were asked for, if that's known
+    pub paragraphs: Option<usize>,
+    pub min_words: Option<usize>,
+}
+
+impl Expectations {
+    // [min_words] defaults to a number derived from [paragraphs]
+    pub fn new(paragraphs: Option<usize>, min_words: Option<usize>) -> Expectations {
+        Expectations {
+            paragraphs,
+            min_words: min_words.or(paragraphs.map(|paragraphs| paragraphs * MIN_WORDS_PER_PARAGRAPH)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OutputIssue {
+    // The reply hit the max_tokens limit (its finish_reason was "length")
+    CutOff,
+    TooFewParagraphs { expected: usize, found: usize },
+    TooFewWords { min: usize, found: usize },
+}
+
+// Phrased so that it reads the same in a warning and when it's sent back to the model
+impl fmt::Display for OutputIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputIssue::CutOff => write!(f, "the reply was cut off before it finished"),
+            OutputIssue::TooFewParagraphs { expected, found } =>
+                write!(f, "it has {} paragraph(s) where {} were asked for", found, expected),
+            OutputIssue::TooFewWords { min, found } =>
+                write!(f, "it has only {} words, where at least {} were expected", found, min),
+        }
+    }
+}
+
+pub fn check_summary(summary: &str, cut_off: bool, expectations: &Expectations) -> Vec<OutputIssue> {
+    let mut issues = Vec::new();
+
+    if cut_off {
+        issues.push(OutputIssue::CutOff);
+    }
+
+    if let Some(expected) = expectations.paragraphs {
+        let found = count_paragraphs(summary);
+
+        if found < expected {
+            issues.push(OutputIssue::TooFewParagraphs { expected, found });
+        }
+    }
+
+    if let Some(min) = expectations.min_words {
+        let found = summary.split_whitespace().count();
+
+        if found < min {
+            issues.push(OutputIssue::TooFewWords { min, found });
+        }
+    }
+
+    issues
+}
+
+// One issue per line, as a list
+pub fn format_issues(issues: &[OutputIssue]) -> String {
+    issues.iter()
+        .map(|issue| format!("  - {}", issue))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paragraphs(count: usize, words_each: usize) -> String {
+        vec![vec!["word"; words_each].join(" "); count].join("\n\n")
+    }
+
+    #[test]
+    fn passes_a_summary_that_meets_every_expectation() {
+        let expectations = Expectations::new(Some(4), None);
+
+        assert_eq!(check_summary(&paragraphs(4, 30), false, &expectations), Vec::new());
+    }
+
+    #[test]
+    fn flags_replies_that_were_cut_off() {
+        assert_eq!(check_summary(&paragraphs(4, 30), true, &Expectations::new(Some(4), None)), vec![OutputIssue::CutOff]);
+        assert_eq!(check_summary("Anything", true, &Expectations::default()), vec![OutputIssue::CutOff]);
+    }
+
+    #[test]
+    fn flags_too_few_paragraphs() {
+        let expectations = Expectations::new(Some(4), Some(10));
+
+        assert_eq!(
+            check_summary(&paragraphs(2, 30), false, &expectations),
+            vec![OutputIssue::TooFewParagraphs { expected: 4, found: 2 }]
+        );
+        // More than were asked for isn't a sign of a short reply
+        assert_eq!(check_summary(&paragraphs(6, 30), false, &expectations), Vec::new());
+    }
+
+    #[test]
+    fn min_words_defaults_to_a_number_per_paragraph() {
+        let expectations = Expectations::new(Some(4), None);
+
+        assert_eq!(expectations.min_words, Some(100));
+        assert_eq!(
+            check_summary(&paragraphs(4, 10), false, &expectations),
+            vec![OutputIssue::TooFewWords { min: 100, found: 40 }]
+        );
+    }
+
+    #[test]
+    fn explicit_min_words_wins() {
+        let expectations = Expectations::new(Some(1), Some(50));
+
+        assert_eq!(
+            check_summary(&paragraphs(1, 40), false, &expectations),
+            vec![OutputIssue::TooFewWords { min: 50, found: 40 }]
+        );
+        assert_eq!(Expectations::new(None, None).min_words, None);
+    }
+
+    #[test]
+    fn formats_issues_as_a_list() {
+        let issues = vec![OutputIssue::CutOff, OutputIssue::TooFewParagraphs { expected: 4, found: 1 }];
+
+        assert_eq!(
+            format_issues(&issues),
+            "  - the reply was cut off before it finished\n  - it has 1 paragraph(s) where 4 were asked for"
+        );
+    }
+}
@@ -0,0 +1,308 @@
+// The steps a document goes through between being read and being summarised: tidying up what
+// decoding left behind, splitting off its front matter, cleaning, truncating and planning its
+// chunks. Each is a [PipelineStage], and a [Pipeline] runs them in order. main assembles the
+// pipeline from the flags; a step of your own (dropping the candidates' biographies, say) can be
+// slotted in with [Pipeline::insert_after], and every stage after it works on what it leaves.
+// The manifest_o library exposes it (see lib.rs), for programs of your own.
+
+use regex::Regex;
+use crate::chunking::{self, Chunk};
+use crate::cleaning;
+use crate::decoding;
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::front_matter::{self, Metadata};
+use crate::truncation::{self, HeadTail};
+
+pub const ENCODING_FIXUP: &str = "encoding-fixup";
+pub const FRONT_MATTER: &str = "front-matter";
+pub const CLEANING: &str = "cleaning";
+pub const TRUNCATION: &str = "truncation";
+pub const CHUNK_PLANNING: &str = "chunk-planning";
+
+// A document part way through the pipeline
+#[derive(Debug, Default, PartialEq)]
+pub struct Document {
+    // What will be sent to the model, as the stages so far have left it
+    pub text: String,
+    // Filled in by the front matter stage
+    pub metadata: Metadata,
+    // Filled in by the chunk planning stage. The summary uses these chunks rather than planning
+    // its own (unless it has to fall back to smaller ones).
+    pub chunks: Option<Vec<Chunk>>,
+}
+
+impl Document {
+    pub fn new(text: impl Into<String>) -> Document {
+        Document { text: text.into(), ..Document::default() }
+    }
+}
+
+pub trait PipelineStage: Send + Sync {
+    // What [Pipeline::insert_after] finds the stage by
+    fn name(&self) -> &str;
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError>;
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn PipelineStage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    // Adds [stage] after every stage so far
+    pub fn push(&mut self, stage: impl PipelineStage + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    // Adds [stage] straight after the stage called [name], failing if there isn't one (e.g.
+    // after "cleaning" when --no-clean left it out)
+    pub fn insert_after(&mut self, name: &str, stage: impl PipelineStage + 'static) -> Result<(), String> {
+        let index = self.stages.iter().position(|existing| existing.name() == name)
+            .ok_or_else(|| format!("There's no pipeline stage called {}", name))?;
+
+        self.stages.insert(index + 1, Box::new(stage));
+
+        Ok(())
+    }
+
+    // The stages' names, in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    pub fn run(&self, doc: Document) -> Result<Document, ManifestoError> {
+        self.stages.iter().try_fold(doc, |doc, stage| stage.process(doc))
+    }
+}
+
+// Drops the control characters that decoding leaves out of the files it reads, for text that
+// arrives some other way
+pub struct EncodingFixup;
+
+impl PipelineStage for EncodingFixup {
+    fn name(&self) -> &str {
+        ENCODING_FIXUP
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        Ok(Document { text: decoding::strip_control_chars(&doc.text), ..doc })
+    }
+}
+
+// Splits the front matter off into [Document::metadata], so that it's never sent to the model
+pub struct FrontMatter;
+
+impl PipelineStage for FrontMatter {
+    fn name(&self) -> &str {
+        FRONT_MATTER
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        let (metadata, body) = front_matter::split_front_matter(&doc.text);
+
+        Ok(Document { text: String::from(body), metadata, ..doc })
+    }
+}
+
+// Removes running headers, footers and page numbers (see [cleaning]). Notes what it saved (see
+// [diagnostics::note]): in full for --dry-run ([full_report]), or otherwise in a line when it
+// saved anything.
+pub struct Cleaning {
+    pub full_report: bool,
+}
+
+impl PipelineStage for Cleaning {
+    fn name(&self) -> &str {
+        CLEANING
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        let cleaned = cleaning::clean(&doc.text);
+
+        if self.full_report {
+            diagnostics::note(cleaning::format_report(&cleaned));
+        } else if cleaned.tokens_saved() > 0 {
+            diagnostics::note(format!(
+                "Cleaning saved ~{} tokens ({} -> {})",
+                cleaned.tokens_saved(), cleaned.tokens_before, cleaned.tokens_after
+            ));
+        }
+
+        Ok(Document { text: cleaned.text, ..doc })
+    }
+}
+
+// Cuts the middle out of documents that are too long (--truncate head-tail), with a warning,
+// since the model never sees that part
+pub struct Truncation(pub HeadTail);
+
+impl PipelineStage for Truncation {
+    fn name(&self) -> &str {
+        TRUNCATION
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        let Some(text) = truncation::truncate_head_tail(&doc.text, &self.0) else {
+            return Ok(doc);
+        };
+
+        diagnostics::warn(format!(
+            "Truncated the manifesto from ~{} to ~{} tokens, keeping its start and end",
+            chunking::estimate_tokens(&doc.text), chunking::estimate_tokens(&text)
+        ));
+
+        Ok(Document { text, ..doc })
+    }
+}
+
+// Splits the text into the chunks that are summarised on their own (--chunk-tokens), along its
+// sections where it has headings
+pub struct ChunkPlanning {
+    pub chunk_tokens: usize,
+    pub heading_pattern: Regex,
+    // Whether each section gets chunks of its own (--per-section)
+    pub per_section: bool,
+}
+
+impl PipelineStage for ChunkPlanning {
+    fn name(&self) -> &str {
+        CHUNK_PLANNING
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        // Packed into chunks of several sections each, unless each is to get its own
+        let chunks = chunking::split_into_section_chunks(&doc.text, &self.heading_pattern, self.chunk_tokens, !self.per_section);
+
+        Ok(Document { chunks: Some(chunks), ..doc })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sections::DEFAULT_HEADING_PATTERN;
+
+    const FIXTURES: [&str; 2] = [
+        include_str!("../fixtures/manifesto_with_front_matter.txt"),
+        include_str!("../test_input"),
+    ];
+
+    // What manifest-o printed for each fixture, before there was a pipeline, with
+    // `--dry-run --chunk-tokens 60`: the text that would be sent to the model (on stdout) and its
+    // chunk plan (the end of stderr)
+    const EXPECTED: [(&str, &str); 2] = [
+        (
+            include_str!("../fixtures/pipeline/manifesto_with_front_matter.prepared.txt"),
+            include_str!("../fixtures/pipeline/manifesto_with_front_matter.chunks.txt"),
+        ),
+        (
+            include_str!("../fixtures/pipeline/test_input.prepared.txt"),
+            include_str!("../fixtures/pipeline/test_input.chunks.txt"),
+        ),
+    ];
+
+    const HEAD_TAIL: HeadTail = HeadTail { max_tokens: 120, head_percent: 70, tail_percent: 30 };
+
+    fn heading_pattern() -> Regex {
+        Regex::new(DEFAULT_HEADING_PATTERN).unwrap()
+    }
+
+    // What main builds with no flags but --chunk-tokens
+    fn default_pipeline(chunk_tokens: usize) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(EncodingFixup);
+        pipeline.push(FrontMatter);
+        pipeline.push(Cleaning { full_report: false });
+        pipeline.push(ChunkPlanning { chunk_tokens, heading_pattern: heading_pattern(), per_section: false });
+        pipeline
+    }
+
+    #[test]
+    fn the_default_pipeline_prepares_documents_as_before() {
+        for (fixture, (prepared, chunk_plan)) in FIXTURES.into_iter().zip(EXPECTED) {
+            let decoded = decoding::decode_input(fixture.as_bytes(), None).unwrap().text;
+            let doc = default_pipeline(60).run(Document::new(decoded)).expect("should have run the pipeline");
+            let chunks = doc.chunks.expect("should have planned the chunks");
+
+            // Each was printed with a newline after it
+            assert_eq!(format!("{}\n", doc.text), prepared);
+            assert_eq!(format!("{}\n", chunking::format_chunk_plan(&chunks)), chunk_plan);
+        }
+    }
+
+    #[test]
+    fn encoding_fixup_drops_control_characters_but_not_whitespace() {
+        let doc = EncodingFixup.process(Document::new("Tunnels\u{7} for\tall\u{c}\n")).unwrap();
+
+        assert_eq!(doc.text, "Tunnels for\tall\u{c}\n");
+    }
+
+    #[test]
+    fn front_matter_moves_into_the_metadata() {
+        let doc = FrontMatter.process(Document::new(FIXTURES[0])).unwrap();
+
+        assert_eq!(doc.metadata.get("party").map(String::as_str), Some("Example Party"));
+        assert!(doc.text.starts_with("Example Party Manifesto 2024"));
+    }
+
+    #[test]
+    fn cleaning_removes_page_furniture() {
+        let doc = FrontMatter.process(Document::new(FIXTURES[0])).unwrap();
+        let doc = Cleaning { full_report: false }.process(doc).unwrap();
+
+        assert!(!doc.text.contains("Example Party Manifesto 2024"));
+        assert!(!doc.text.contains("Page 3 of 4"));
+        assert!(doc.text.contains("## Housing"));
+    }
+
+    #[test]
+    fn truncation_only_cuts_long_documents() {
+        let short = Truncation(HEAD_TAIL).process(Document::new("Tunnels for all.")).unwrap();
+        assert_eq!(short.text, "Tunnels for all.");
+
+        let long = Truncation(HEAD_TAIL).process(Document::new(FIXTURES[1])).unwrap();
+        assert!(long.text.contains(truncation::TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn chunk_planning_follows_the_sections() {
+        let doc = FrontMatter.process(Document::new(FIXTURES[0])).unwrap();
+        let doc = Cleaning { full_report: false }.process(doc).unwrap();
+        let doc = ChunkPlanning { chunk_tokens: 200, heading_pattern: heading_pattern(), per_section: true }.process(doc).unwrap();
+
+        let chunks = doc.chunks.expect("should have planned the chunks");
+        let headings: Vec<&str> = chunks.iter().map(|chunk| chunk.sections[0].as_str()).collect();
+        assert_eq!(headings, vec!["Transport", "Health", "Housing", "Education"]);
+    }
+
+    struct RenameClinics;
+
+    impl PipelineStage for RenameClinics {
+        fn name(&self) -> &str {
+            "rename-clinics"
+        }
+
+        fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+            Ok(Document { text: doc.text.replace("walk-in clinic", "walk-in surgery"), ..doc })
+        }
+    }
+
+    #[test]
+    fn stages_can_be_inserted_after_others_by_name() {
+        let mut pipeline = default_pipeline(40);
+
+        pipeline.insert_after(CLEANING, RenameClinics).expect("should have found the cleaning stage");
+        assert!(pipeline.insert_after("spellcheck", RenameClinics).is_err());
+        assert_eq!(pipeline.names(), vec![ENCODING_FIXUP, FRONT_MATTER, CLEANING, "rename-clinics", CHUNK_PLANNING]);
+
+        // Chunking comes later, so it sees the inserted stage's changes
+        let doc = pipeline.run(Document::new(FIXTURES[0])).unwrap();
+        assert!(doc.chunks.unwrap().iter().any(|chunk| chunk.text.contains("walk-in surgery")));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// Runs [f] over every item on up to [jobs] threads, returning the results in the same order as
+// the items. Once any call fails, no new items are started and the first failure (in item order)
+// is returned.
+pub fn map_ordered<T, R, E, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+    F: Fn(&T) -> Result<R, E> + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next_item = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<R, E>>>> = Mutex::new((0..items.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let i = next_item.fetch_add(1, Ordering::SeqCst);
+
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+
+                    let result = f(item);
+
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    // Items after a failure may never have been started, but the failure itself comes first
+    results.into_inner().unwrap()
+        .into_iter()
+        .map_while(|result| result)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn keeps_results_in_order() {
+        let items: Vec<u64> = (0..20).collect();
+
+        // Later items finish first, so the results only come out in order if they're reassembled
+        let results: Result<Vec<u64>, ()> = map_ordered(&items, 4, |i| {
+            thread::sleep(Duration::from_millis(20 - i));
+            Ok(i * 10)
+        });
+
+        assert_eq!(results, Ok(items.iter().map(|i| i * 10).collect()));
+    }
+
+    #[test]
+    fn never_exceeds_the_job_count() {
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let items: Vec<u32> = (0..12).collect();
+
+        let _: Result<Vec<()>, ()> = map_ordered(&items, 3, |_| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn returns_the_first_failure() {
+        let items: Vec<u32> = (0..10).collect();
+
+        let results = map_ordered(&items, 3, |i| if *i >= 4 { Err(*i) } else { Ok(*i) });
+
+        assert_eq!(results, Err(4));
+    }
+}
@@ -0,0 +1,145 @@
+// Per-model prices for cost estimates (--max-cost and the --batch report), in USD per million
+// tokens. OpenAI's list prices change often, so the built-in table can be overridden, or added
+// to, with --price-file, a JSON file like
+//
+//     { "gpt-4o": { "input": 2.5, "output": 10.0 } }
+//
+// A model without a known price has no estimate rather than an estimate of zero.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::model_fallback::ModelUsage;
+use crate::open_ai::OpenAiUsage;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    // Per million prompt tokens
+    pub input: f64,
+    // Per million completion tokens. Embedding models don't have any.
+    #[serde(default)]
+    pub output: f64,
+}
+
+impl ModelPrice {
+    // What [usage] costs in USD at this price
+    pub fn cost_usd(&self, usage: &OpenAiUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output) / 1_000_000.0
+    }
+}
+
+// OpenAI's list prices when this was last updated
+const BUILT_IN_PRICES: &[(&str, ModelPrice)] = &[
+    ("gpt-3.5-turbo", ModelPrice { input: 0.5, output: 1.5 }),
+    ("gpt-4", ModelPrice { input: 30.0, output: 60.0 }),
+    ("gpt-4-turbo", ModelPrice { input: 10.0, output: 30.0 }),
+    ("gpt-4o", ModelPrice { input: 2.5, output: 10.0 }),
+    ("gpt-4o-mini", ModelPrice { input: 0.15, output: 0.6 }),
+    ("text-embedding-3-small", ModelPrice { input: 0.02, output: 0.0 }),
+    ("text-embedding-3-large", ModelPrice { input: 0.13, output: 0.0 }),
+    ("omni-moderation-latest", ModelPrice { input: 0.0, output: 0.0 }),
+];
+
+#[derive(Default, Debug)]
+pub struct PriceTable {
+    // From --price-file, and checked before the built-in prices
+    overrides: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    pub fn load(path: &Path) -> Result<PriceTable, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+
+        PriceTable::parse(&json)
+    }
+
+    fn parse(json: &str) -> Result<PriceTable, String> {
+        let overrides: HashMap<String, ModelPrice> = serde_json::from_str(json)
+            .map_err(|e| format!("expected an object mapping models to input and output prices: {}", e))?;
+
+        if let Some((model, _)) = overrides.iter().find(|(_, price)| price.input < 0.0 || price.output < 0.0) {
+            return Err(format!("the prices for {} can't be negative", model));
+        }
+
+        Ok(PriceTable { overrides })
+    }
+
+    pub fn lookup(&self, model: &str) -> Option<ModelPrice> {
+        self.overrides.get(model).copied().or_else(|| {
+            BUILT_IN_PRICES.iter()
+                .find(|(name, _)| *name == model)
+                .map(|(_, price)| *price)
+        })
+    }
+
+    // What [usage] costs in USD if it was all spent on [model], or None for a model without a
+    // known price
+    pub fn estimated_cost_usd(&self, usage: &OpenAiUsage, model: &str) -> Option<f64> {
+        self.lookup(model).map(|price| price.cost_usd(usage))
+    }
+
+    // Like [estimated_cost_usd], for a run whose requests didn't all go to [model]
+    // (--model-fallback): the usage each model in [models_used] answered with is priced at its
+    // own price, and whatever's left of [usage] at [model]'s
+    pub fn estimated_cost_usd_by_model(&self, usage: &OpenAiUsage, models_used: &[ModelUsage], model: &str) -> Option<f64> {
+        let mut rest = *usage;
+        let mut cost = 0.0;
+
+        for used in models_used {
+            cost += self.estimated_cost_usd(&used.usage, &used.model)?;
+            rest.prompt_tokens = rest.prompt_tokens.saturating_sub(used.usage.prompt_tokens);
+            rest.completion_tokens = rest.completion_tokens.saturating_sub(used.usage.completion_tokens);
+            rest.total_tokens = rest.total_tokens.saturating_sub(used.usage.total_tokens);
+        }
+
+        if rest != OpenAiUsage::default() {
+            cost += self.estimated_cost_usd(&rest, model)?;
+        }
+
+        Some(cost)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_built_in_prices() {
+        let prices = PriceTable::default();
+
+        assert_eq!(prices.lookup("gpt-4o"), Some(ModelPrice { input: 2.5, output: 10.0 }));
+
+        let usage = OpenAiUsage { prompt_tokens: 1000, completion_tokens: 100, total_tokens: 1100 };
+        assert_eq!(prices.estimated_cost_usd(&usage, "gpt-4-turbo"), Some(0.013));
+    }
+
+    #[test]
+    fn price_file_wins_over_built_in_prices() {
+        let prices = PriceTable::parse(r#"{ "gpt-4o": { "input": 1.0, "output": 4.0 }, "my-model": { "input": 3.0 } }"#)
+            .expect("should have parsed the prices");
+
+        assert_eq!(prices.lookup("gpt-4o"), Some(ModelPrice { input: 1.0, output: 4.0 }));
+        assert_eq!(prices.lookup("my-model"), Some(ModelPrice { input: 3.0, output: 0.0 }));
+        assert_eq!(prices.lookup("gpt-4-turbo"), Some(ModelPrice { input: 10.0, output: 30.0 }));
+    }
+
+    #[test]
+    fn unknown_models_have_no_estimate() {
+        let prices = PriceTable::default();
+        let usage = OpenAiUsage { prompt_tokens: 1000, completion_tokens: 100, total_tokens: 1100 };
+
+        assert_eq!(prices.lookup("gpt-9"), None);
+        assert_eq!(prices.estimated_cost_usd(&usage, "gpt-9"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_and_negative_prices() {
+        assert!(PriceTable::parse(r#"{ "gpt-4o": { "input": "cheap" } }"#).is_err());
+        assert!(PriceTable::parse(r#"{ "gpt-4o": { "input": 1.0, "cached": 0.5 } }"#).is_err());
+        assert!(PriceTable::parse(r#"{ "gpt-4o": { "input": -1.0 } }"#).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// A progress display for chunked runs, so that a 50 chunk summary doesn't sit silent for ten
+// minutes. It's one line on stderr, redrawn in place every time a chunk request starts or
+// finishes (and every second in between, so that the time keeps ticking), e.g.
+//
+//     [#########---------------]  12/32 chunks | 3 in flight, the longest for 14s | 48210 tokens | ~$1.45
+//
+// With --jobs, every chunk in flight shares the one bar. It's only drawn when stderr is a
+// terminal and neither --quiet nor --json is set; otherwise the counts are kept but never shown.
+
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::model_fallback::ModelUsage;
+use crate::open_ai::{OpenAiUsage, GPT_4_MODEL_NAME};
+use crate::prices::PriceTable;
+
+const BAR_WIDTH: usize = 24;
+// How often the display is redrawn while nothing starts or finishes
+const TICK: Duration = Duration::from_secs(1);
+// Moves back to the start of the line and clears it
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+// How far a chunked run has got
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgressState {
+    // Chunks that are done, including ones an earlier run finished
+    pub completed: usize,
+    pub total: usize,
+    // The chunks being summarised right now, by index, and when each was started
+    pub in_flight: BTreeMap<usize, Instant>,
+    // Every token used so far
+    pub usage: OpenAiUsage,
+    // Which models [usage] went on, with --model-fallback (see [ChatTransport::models_used])
+    pub models_used: Vec<ModelUsage>,
+}
+
+pub struct Progress<'a> {
+    state: Mutex<ProgressState>,
+    // Whether the display is drawn at all (see [Progress::should_show])
+    visible: bool,
+    // For the cost so far, which is left off when a model's price isn't known
+    prices: &'a PriceTable,
+}
+
+impl Progress<'_> {
+    pub fn new(visible: bool, prices: &PriceTable) -> Progress<'_> {
+        Progress { state: Mutex::new(ProgressState::default()), visible, prices }
+    }
+
+    // Whether to draw the display: only for a person watching the terminal, so never under
+    // --quiet or --json, or when stderr is going to a file or another program
+    pub fn should_show(quiet: bool, json: bool) -> bool {
+        !quiet && !json && io::stderr().is_terminal()
+    }
+
+    // Counts [total] chunks, [already_completed] of which an earlier run finished, while [f]
+    // summarises them. The display is cleared again afterwards, however [f] went.
+    pub fn track<R>(&self, total: usize, already_completed: usize, f: impl FnOnce() -> R) -> R {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.completed = already_completed;
+            state.total = total;
+            state.in_flight.clear();
+            self.draw(&state);
+        }
+
+        if !self.visible {
+            return f();
+        }
+
+        let result = thread::scope(|scope| {
+            // Dropping the sender stops the ticking
+            let (stop, stopped) = mpsc::channel::<()>();
+            scope.spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+                    self.draw(&self.state.lock().unwrap());
+                }
+            });
+
+            let result = f();
+            drop(stop);
+
+            result
+        });
+
+        eprint!("{}", CLEAR_LINE);
+
+        result
+    }
+
+    pub fn chunk_started(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.insert(index, Instant::now());
+        self.draw(&state);
+    }
+
+    // [usage] is every token used so far, not just the chunk's, and [models_used] the models it
+    // went on. With --jobs, chunks can finish in a different order than they read the usage, so
+    // an older figure is ignored.
+    pub fn chunk_finished(&self, index: usize, usage: OpenAiUsage, models_used: Vec<ModelUsage>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(&index);
+        state.completed += 1;
+
+        if usage.total_tokens >= state.usage.total_tokens {
+            state.usage = usage;
+            state.models_used = models_used;
+        }
+
+        self.draw(&state);
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> ProgressState {
+        self.state.lock().unwrap().clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn estimated_cost_usd(&self) -> Option<f64> {
+        self.cost_of(&self.state.lock().unwrap())
+    }
+
+    fn cost_of(&self, state: &ProgressState) -> Option<f64> {
+        self.prices.estimated_cost_usd_by_model(&state.usage, &state.models_used, GPT_4_MODEL_NAME)
+    }
+
+    fn draw(&self, state: &ProgressState) {
+        if !self.visible {
+            return;
+        }
+
+        let cost = self.cost_of(state);
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "{}{}", CLEAR_LINE, format_line(state, cost, Instant::now()));
+        let _ = stderr.flush();
+    }
+}
+
+// The display's text, without the escape codes that draw it
+pub fn format_line(state: &ProgressState, cost_usd: Option<f64>, now: Instant) -> String {
+    let filled = (state.completed * BAR_WIDTH).checked_div(state.total).unwrap_or(0).min(BAR_WIDTH);
+    let mut parts = vec![format!(
+        "[{}{}] {:>3}/{} chunks",
+        "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), state.completed, state.total
+    )];
+
+    let longest = state.in_flight.values().map(|started| now.saturating_duration_since(*started)).max();
+
+    match (state.in_flight.len(), longest) {
+        (1, Some(elapsed)) => {
+            let index = state.in_flight.keys().next().unwrap();
+            parts.push(format!("chunk {} for {}s", index + 1, elapsed.as_secs()));
+        }
+        (count, Some(elapsed)) => parts.push(format!("{} in flight, the longest for {}s", count, elapsed.as_secs())),
+        _ => {}
+    }
+
+    parts.push(format!("{} tokens", state.usage.total_tokens));
+
+    if let Some(cost) = cost_usd {
+        parts.push(format!("~${:.2}", cost));
+    }
+
+    parts.join(" | ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_chunks_as_they_finish() {
+        let prices = PriceTable::default();
+        let progress = Progress::new(false, &prices);
+
+        progress.track(4, 1, || {
+            progress.chunk_started(1);
+            progress.chunk_started(2);
+            assert_eq!(progress.state().in_flight.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+
+            progress.chunk_finished(2, OpenAiUsage { prompt_tokens: 1000, completion_tokens: 100, total_tokens: 1100 }, Vec::new());
+        });
+
+        let state = progress.state();
+        assert_eq!((state.completed, state.total), (2, 4));
+        assert_eq!(state.in_flight.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(state.usage.total_tokens, 1100);
+        assert_eq!(progress.estimated_cost_usd(), Some(0.013));
+    }
+
+    #[test]
+    fn costs_follow_the_models_that_answered() {
+        let prices = PriceTable::default();
+        let progress = Progress::new(false, &prices);
+        let usage = OpenAiUsage { prompt_tokens: 1000, completion_tokens: 100, total_tokens: 1100 };
+
+        progress.track(1, 0, || {
+            progress.chunk_started(0);
+            progress.chunk_finished(0, usage, vec![ModelUsage { model: String::from("gpt-4o"), requests: 1, usage }]);
+        });
+
+        assert_eq!(progress.estimated_cost_usd(), prices.estimated_cost_usd(&usage, "gpt-4o"));
+    }
+
+    #[test]
+    fn formats_one_chunk_in_flight() {
+        let now = Instant::now();
+        let state = ProgressState {
+            completed: 12,
+            total: 48,
+            in_flight: BTreeMap::from([(12, now - Duration::from_secs(14))]),
+            usage: OpenAiUsage { prompt_tokens: 40000, completion_tokens: 8210, total_tokens: 48210 },
+            models_used: Vec::new(),
+        };
+
+        assert_eq!(
+            format_line(&state, Some(1.454), now),
+            "[######------------------]  12/48 chunks | chunk 13 for 14s | 48210 tokens | ~$1.45"
+        );
+    }
+
+    #[test]
+    fn formats_several_chunks_in_flight() {
+        let now = Instant::now();
+        let state = ProgressState {
+            completed: 0,
+            total: 3,
+            in_flight: BTreeMap::from([(0, now - Duration::from_secs(9)), (1, now - Duration::from_secs(2))]),
+            usage: OpenAiUsage::default(),
+            models_used: Vec::new(),
+        };
+
+        assert_eq!(
+            format_line(&state, None, now),
+            "[------------------------]   0/3 chunks | 2 in flight, the longest for 9s | 0 tokens"
+        );
+    }
+}
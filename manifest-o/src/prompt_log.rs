@@ -0,0 +1,97 @@
+// An audit trail of everything sent to OpenAI (--save-prompt). Each request is written to the
+// file as one line of JSON before it's sent: when it was sent, where to, the headers (with the
+// key redacted) and the full body, which holds the model and messages. The key itself is only
+// ever in the client's default headers, so it can't end up in the file.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const REDACTED_AUTHORIZATION: &str = "Bearer [REDACTED]";
+
+// Shared by every transport in the run (including --jobs threads), so writes are serialised to
+// keep each line whole
+pub struct PromptLog {
+    file: Mutex<File>,
+    // The x-request-id header sent with every request (--send-request-id), if any
+    request_id_header: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PromptRecord<'a, B: Serialize> {
+    sent_at: String,
+    url: &'a str,
+    headers: BTreeMap<&'static str, &'a str>,
+    body: &'a B,
+}
+
+impl PromptLog {
+    // Starts a fresh log at [path], replacing any log from an earlier run
+    pub fn create(path: &Path, request_id_header: Option<&str>) -> io::Result<PromptLog> {
+        Ok(PromptLog {
+            file: Mutex::new(File::create(path)?),
+            request_id_header: request_id_header.map(String::from),
+        })
+    }
+
+    // Appends [body] as it's about to be posted to [url]
+    pub fn record<B: Serialize>(&self, url: &str, body: &B) -> io::Result<()> {
+        let mut headers = BTreeMap::from([("authorization", REDACTED_AUTHORIZATION)]);
+
+        if let Some(request_id) = &self.request_id_header {
+            headers.insert(crate::REQUEST_ID_HEADER, request_id.as_str());
+        }
+
+        let record = PromptRecord {
+            sent_at: httpdate::fmt_http_date(SystemTime::now()),
+            url,
+            headers,
+            body,
+        };
+        let line = serde_json::to_string(&record)?;
+
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use crate::open_ai::ChatRequestBuilder;
+
+    #[test]
+    fn records_one_redacted_line_per_request() {
+        let dir = tempfile::tempdir().expect("should have created a temp dir");
+        let path = dir.path().join("prompts.jsonl");
+        let log = PromptLog::create(&path, Some("job-1")).expect("should have created the log");
+
+        let body = |content| ChatRequestBuilder::new()
+            .model("gpt-4-turbo")
+            .user(content)
+            .build()
+            .expect("should have built the request");
+        log.record("https://api.openai.com/v1/chat/completions", &body("Summarise this")).expect("should have recorded the request");
+        log.record("https://api.openai.com/v1/moderations", &body("And this")).expect("should have recorded the request");
+
+        let contents = fs::read_to_string(&path).expect("should have written the log");
+        let records: Vec<serde_json::Value> = contents.lines()
+            .map(|line| serde_json::from_str(line).expect("each line should be JSON"))
+            .collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["url"], "https://api.openai.com/v1/chat/completions");
+        assert_eq!(records[0]["headers"]["authorization"], REDACTED_AUTHORIZATION);
+        assert_eq!(records[0]["headers"][crate::REQUEST_ID_HEADER], "job-1");
+        assert_eq!(records[0]["body"]["model"], "gpt-4-turbo");
+        assert_eq!(records[0]["body"]["messages"][0]["content"], "Summarise this");
+        assert_eq!(records[1]["url"], "https://api.openai.com/v1/moderations");
+        assert_eq!(records[1]["body"]["messages"][0]["content"], "And this");
+    }
+}
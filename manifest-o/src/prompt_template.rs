@@ -0,0 +1,297 @@
+// Prompts kept in a file (--prompt-template) so that they can be tuned and version controlled
+// rather than passed as flags. The file has a [system] section and a [user] section:
+//
+//     [system]
+//     You are a journalist writing for {party}'s supporters.
+//     [user]
+//     Summarise {filename} ({paragraphs} paragraphs, in {language}) in four paragraphs:
+//
+//     {document}
+//
+// A file without any section headers is all user template, and without a [system] section the
+// default system prompt is used. Placeholders are `{name}`; write `{{` and `}}` for literal
+// braces. The user template must have a `{document}`, which is filled in with the document (or,
+// for chunked runs, each chunk of it). The other variables are:
+//
+//   - `{filename}`: the input file's name
+//   - `{paragraphs}`: how many paragraphs the whole document has
+//   - `{language}`: the `language` (or `lang`) field of the document's front matter
+//   - any other front matter field, by name
+//
+// A variable the document doesn't have renders as nothing, with a warning.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::mem;
+use std::path::Path;
+use crate::diagnostics;
+use crate::front_matter::Metadata;
+
+const DOCUMENT_VARIABLE: &str = "document";
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PromptTemplate {
+    system: Option<Vec<Segment>>,
+    user: Vec<Segment>,
+}
+
+// A template with everything but the document filled in, for one input file
+pub struct BoundTemplate<'a> {
+    template: &'a PromptTemplate,
+    variables: HashMap<String, String>,
+    system: Option<String>,
+}
+
+impl PromptTemplate {
+    pub fn load(path: &Path) -> Result<PromptTemplate, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+
+        PromptTemplate::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<PromptTemplate, String> {
+        let mut sections: Vec<(&str, String)> = Vec::new();
+
+        if text.lines().any(|line| section_name(line).is_some()) {
+            for line in text.lines() {
+                if let Some(name) = section_name(line) {
+                    if sections.iter().any(|(existing, _)| *existing == name) {
+                        return Err(format!("there's more than one [{}] section", name));
+                    }
+
+                    sections.push((name, String::new()));
+                    continue;
+                }
+
+                match sections.last_mut() {
+                    Some((_, body)) => {
+                        body.push_str(line);
+                        body.push('\n');
+                    }
+                    None if line.trim().is_empty() => {}
+                    None => return Err(String::from("there's text before the first [system] or [user] header")),
+                }
+            }
+        } else {
+            sections.push(("user", String::from(text)));
+        }
+
+        let section = |name| sections.iter().find(|(existing, _)| *existing == name).map(|(_, body)| body.trim());
+
+        let user = parse_placeholders(section("user").ok_or("there's no [user] section")?)?;
+        let system = section("system").map(parse_placeholders).transpose()?;
+
+        if !has_variable(&user, DOCUMENT_VARIABLE) {
+            return Err(String::from("the user template has no {document} placeholder, so the manifesto would never be sent"));
+        }
+
+        if system.as_ref().is_some_and(|system| has_variable(system, DOCUMENT_VARIABLE)) {
+            return Err(String::from("{document} can only go in the user template"));
+        }
+
+        Ok(PromptTemplate { system, user })
+    }
+
+    // Fills in the variables for one document, warning about any it doesn't have. [document] is
+    // the whole document, which {paragraphs} is counted from.
+    pub fn bind(&self, file_name: &str, document: &str, metadata: &Metadata) -> BoundTemplate<'_> {
+        let mut variables: HashMap<String, String> = metadata.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        if let Some(language) = metadata.get("language").or_else(|| metadata.get("lang")) {
+            variables.insert(String::from("language"), language.clone());
+        }
+
+        variables.insert(String::from("filename"), String::from(file_name));
+        variables.insert(String::from("paragraphs"), count_paragraphs(document).to_string());
+
+        let used: BTreeSet<&str> = self.system.iter().flatten().chain(&self.user)
+            .filter_map(|segment| match segment {
+                Segment::Variable(name) => Some(name.as_str()),
+                Segment::Text(_) => None,
+            })
+            .collect();
+
+        for name in used {
+            if name != DOCUMENT_VARIABLE && !variables.contains_key(name) {
+                diagnostics::warn(format!("the document has no '{}' for the prompt template", name));
+            }
+        }
+
+        let system = self.system.as_ref().map(|system| render(system, &variables, ""));
+
+        BoundTemplate { template: self, variables, system }
+    }
+}
+
+impl BoundTemplate<'_> {
+    // The rendered system prompt, or None to use the default one
+    pub fn system(&self) -> Option<&str> {
+        self.system.as_deref()
+    }
+
+    // The user message for [text], which is the whole document or one chunk of it
+    pub fn user(&self, text: &str) -> String {
+        render(&self.template.user, &self.variables, text)
+    }
+}
+
+// The section a `[system]` or `[user]` header line starts, if [line] is one
+fn section_name(line: &str) -> Option<&'static str> {
+    match line.trim() {
+        "[system]" => Some("system"),
+        "[user]" => Some("user"),
+        _ => None,
+    }
+}
+
+fn parse_placeholders(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                text.push(c);
+            }
+            '{' => {
+                let mut name = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some('{') | None => return Err(String::from("a '{' is never closed (write {{ for a literal brace)")),
+                        Some(c) => name.push(c),
+                    }
+                }
+
+                if name.trim().is_empty() {
+                    return Err(String::from("there's an empty {} placeholder"));
+                }
+
+                if !text.is_empty() {
+                    segments.push(Segment::Text(mem::take(&mut text)));
+                }
+
+                segments.push(Segment::Variable(String::from(name.trim())));
+            }
+            '}' => return Err(String::from("a '}' was never opened (write }} for a literal brace)")),
+            c => text.push(c),
+        }
+    }
+
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+
+    Ok(segments)
+}
+
+fn has_variable(segments: &[Segment], name: &str) -> bool {
+    segments.iter().any(|segment| matches!(segment, Segment::Variable(variable) if variable == name))
+}
+
+fn render(segments: &[Segment], variables: &HashMap<String, String>, document: &str) -> String {
+    let mut rendered = String::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => rendered.push_str(text),
+            Segment::Variable(name) if name == DOCUMENT_VARIABLE => rendered.push_str(document),
+            Segment::Variable(name) => rendered.push_str(variables.get(name).map_or("", String::as_str)),
+        }
+    }
+
+    rendered
+}
+
+// Runs of non-blank lines
+pub(crate) fn count_paragraphs(text: &str) -> usize {
+    let mut count = 0;
+    let mut in_paragraph = false;
+
+    for line in text.lines() {
+        let blank = line.trim().is_empty();
+
+        if !blank && !in_paragraph {
+            count += 1;
+        }
+
+        in_paragraph = !blank;
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DOCUMENT: &str = "We will build trains.\n\nWe will plant trees.\n";
+
+    #[test]
+    fn renders_every_variable() {
+        let template = PromptTemplate::parse(
+            "[system]\nYou write for {party}.\n[user]\nSummarise {filename} ({paragraphs} paragraphs, {language}):\n\n{document}\n"
+        ).expect("should have parsed the template");
+        let metadata = Metadata::from([
+            (String::from("party"), String::from("Example Party")),
+            (String::from("lang"), String::from("English")),
+        ]);
+
+        let bound = template.bind("manifesto.txt", DOCUMENT, &metadata);
+
+        assert_eq!(bound.system(), Some("You write for Example Party."));
+        assert_eq!(bound.user(DOCUMENT), format!("Summarise manifesto.txt (2 paragraphs, English):\n\n{}", DOCUMENT));
+    }
+
+    #[test]
+    fn missing_optional_variables_render_as_nothing() {
+        let template = PromptTemplate::parse("Summarise this {language} manifesto:\n{document}")
+            .expect("should have parsed the template");
+
+        let bound = template.bind("manifesto.txt", DOCUMENT, &Metadata::new());
+
+        assert_eq!(bound.system(), None);
+        assert_eq!(bound.user("Trains."), "Summarise this  manifesto:\nTrains.");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let template = PromptTemplate::parse("Reply as {{\"summary\": \"...\"}} for {{document}}:\n{document}")
+            .expect("should have parsed the template");
+
+        let bound = template.bind("manifesto.txt", DOCUMENT, &Metadata::new());
+
+        assert_eq!(bound.user("Trains."), "Reply as {\"summary\": \"...\"} for {document}:\nTrains.");
+    }
+
+    #[test]
+    fn rejects_templates_without_the_document() {
+        let error = PromptTemplate::parse("[system]\n{document}\n[user]\nSummarise {filename}")
+            .expect_err("should have rejected the template");
+
+        assert!(error.contains("{document}"));
+        assert!(PromptTemplate::parse("Summarise {{document}}").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        assert!(PromptTemplate::parse("Summarise {document").is_err());
+        assert!(PromptTemplate::parse("Summarise } {document}").is_err());
+        assert!(PromptTemplate::parse("Summarise {} {document}").is_err());
+        assert!(PromptTemplate::parse("Preamble\n[user]\n{document}").is_err());
+        assert!(PromptTemplate::parse("[user]\n{document}\n[user]\n{document}").is_err());
+        assert!(PromptTemplate::parse("[system]\nYou are a journalist").is_err());
+    }
+}
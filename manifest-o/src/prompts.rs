@@ -0,0 +1,91 @@
+// Prompts per kind of document (--prompts). A JSON file maps file extensions to replacements for
+// the default prompts, e.g.
+//
+//     { "md": { "instruction": "Please summarise the following policy notes:" } }
+//
+// so that a batch over a mixed folder can treat each kind of file differently. Files with any
+// other extension use the default prompts.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Prompt {
+    // Replaces the system prompt for every summary request
+    pub system: Option<String>,
+    // Replaces the instruction to summarise the whole document
+    pub instruction: Option<String>,
+}
+
+#[derive(Default, Debug)]
+pub struct PromptConfig {
+    // Keyed by lower-case extension, without the dot
+    by_extension: HashMap<String, Prompt>,
+}
+
+impl PromptConfig {
+    pub fn load(path: &Path) -> Result<PromptConfig, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+
+        PromptConfig::parse(&json)
+    }
+
+    fn parse(json: &str) -> Result<PromptConfig, String> {
+        let raw: HashMap<String, Prompt> = serde_json::from_str(json)
+            .map_err(|e| format!("expected an object mapping extensions to prompts: {}", e))?;
+
+        Ok(PromptConfig {
+            by_extension: raw.into_iter()
+                .map(|(extension, prompt)| (extension.trim_start_matches('.').to_lowercase(), prompt))
+                .collect(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_extension.is_empty()
+    }
+
+    // The prompt for the file at [path], if its extension has one
+    pub fn prompt_for(&self, path: &Path) -> Option<&Prompt> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+
+        self.by_extension.get(&extension)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn picks_prompts_by_extension() {
+        let config = PromptConfig::parse(r#"{
+            "md": { "instruction": "Summarise these notes:" },
+            ".TXT": { "system": "You are terse", "instruction": "Summarise:" }
+        }"#).expect("should have parsed the config");
+
+        assert_eq!(config.prompt_for(Path::new("notes/health.md")), Some(&Prompt {
+            system: None,
+            instruction: Some(String::from("Summarise these notes:")),
+        }));
+        assert_eq!(
+            config.prompt_for(Path::new("manifesto.Txt")).and_then(|prompt| prompt.system.as_deref()),
+            Some("You are terse")
+        );
+        assert_eq!(config.prompt_for(Path::new("manifesto.pdf")), None);
+        assert_eq!(config.prompt_for(Path::new("README")), None);
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        for json in [r#"["md"]"#, r#"{ "md": { "instructions": "typo" } }"#, "not json"] {
+            if PromptConfig::parse(json).is_ok() {
+                panic!("Should have rejected {}", json);
+            }
+        }
+    }
+}
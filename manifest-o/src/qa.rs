@@ -0,0 +1,287 @@
+// Question answering (--ask): rather than summarising the manifesto, answers specific questions
+// using only what the manifesto says. Every question is asked in the same request so that the
+// document is only sent once. Long manifestos are handled map-reduce style: each chunk is asked
+// every question, then the answers from each chunk are combined.
+
+use serde::Serialize;
+use crate::chunking;
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::open_ai::GPT_4_MODEL_NAME;
+use crate::pool;
+use crate::summary;
+use crate::transport::ChatTransport;
+
+const QA_SYSTEM_PROMPT: &str = "You are a careful researcher who answers questions about the manifestos of political parties using only the text you are given";
+const ASK_INSTRUCTION: &str = "Answer each of the numbered questions below using only the document that follows. If the document doesn't address a question, answer \"not addressed\". Reply with one answer per question, each starting with \"Answer N:\" where N is the question's number.";
+const SYNTHESIS_INSTRUCTION: &str = "Each numbered question below was answered separately from several parts of one document. Combine the answers to each question into a single answer, using only what they say. If none of them address a question, answer \"not addressed\". Reply with one answer per question, each starting with \"Answer N:\" where N is the question's number.";
+pub const NOT_ADDRESSED: &str = "not addressed";
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct QuestionAnswer {
+    pub question: String,
+    pub answer: String,
+}
+
+// Answers every question from the manifesto. With [chunk_tokens], each chunk (up to [jobs] at a
+// time) is asked every question and the answers are then combined. If the manifesto turns out to
+// be too long for the model, it's asked in chunks instead, as with summaries. With
+// [cache_prompt], the manifesto comes before the questions so that asking different questions
+// about it can reuse OpenAI's prompt cache.
+pub fn answer_questions(transport: &impl ChatTransport, manifesto: &str, questions: &[String], chunk_tokens: Option<usize>, jobs: usize, cache_prompt: bool) -> Result<Vec<QuestionAnswer>, ManifestoError> {
+    let result = match chunk_tokens {
+        Some(chunk_tokens) => answer_in_chunks(transport, manifesto, questions, chunk_tokens, jobs, cache_prompt),
+        None => ask(transport, ASK_INSTRUCTION, questions, manifesto, cache_prompt),
+    };
+
+    let answers = match (result, chunk_tokens) {
+        (Err(ManifestoError::Api { code: Some(code), message, .. }), None) if code == summary::CONTEXT_LENGTH_EXCEEDED => {
+            let chunk_tokens = summary::fallback_chunk_tokens(&message);
+
+            eprintln!("The manifesto is too long for the model; asking in chunks of ~{} tokens", chunk_tokens);
+            answer_in_chunks(transport, manifesto, questions, chunk_tokens, jobs, cache_prompt)?
+        }
+        (result, _) => result?,
+    };
+
+    Ok(questions.iter().cloned().zip(answers)
+        .map(|(question, answer)| QuestionAnswer { question, answer })
+        .collect())
+}
+
+// Pairs each question with its answer, one after another
+pub fn format_answers(answers: &[QuestionAnswer]) -> String {
+    answers.iter()
+        .map(|qa| format!("Q: {}\nA: {}", qa.question, qa.answer))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+fn answer_in_chunks(transport: &impl ChatTransport, manifesto: &str, questions: &[String], chunk_tokens: usize, jobs: usize, cache_prompt: bool) -> Result<Vec<String>, ManifestoError> {
+    let chunks = chunking::split_into_chunks(manifesto, chunk_tokens);
+
+    if chunks.len() <= 1 {
+        return ask(transport, ASK_INSTRUCTION, questions, manifesto, cache_prompt);
+    }
+
+    let chunk_answers = pool::map_ordered(&chunks, jobs, |chunk| ask(transport, ASK_INSTRUCTION, questions, chunk, cache_prompt))?;
+
+    // Only questions that some chunk addressed need combining; the rest aren't addressed at all
+    let partial_answers: Vec<Vec<&str>> = (0..questions.len())
+        .map(|i| {
+            chunk_answers.iter()
+                .map(|answers| answers[i].as_str())
+                .filter(|answer| !is_not_addressed(answer))
+                .collect()
+        })
+        .collect();
+    let addressed: Vec<usize> = (0..questions.len())
+        .filter(|i| !partial_answers[*i].is_empty())
+        .collect();
+
+    let mut answers = vec![String::from(NOT_ADDRESSED); questions.len()];
+
+    if addressed.is_empty() {
+        return Ok(answers);
+    }
+
+    let synthesis_questions: Vec<String> = addressed.iter().map(|i| questions[*i].clone()).collect();
+    let synthesis_text = addressed.iter().enumerate()
+        .map(|(n, i)| {
+            let parts: Vec<String> = partial_answers[*i].iter().map(|answer| format!("- {}", answer)).collect();
+            format!("Answers to question {}:\n{}", n + 1, parts.join("\n"))
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    let synthesised = ask(transport, SYNTHESIS_INSTRUCTION, &synthesis_questions, &synthesis_text, false)?;
+
+    for (i, answer) in addressed.into_iter().zip(synthesised) {
+        answers[i] = answer;
+    }
+
+    Ok(answers)
+}
+
+// Asks every question about [text] in one request, returning an answer for each question
+fn ask(transport: &impl ChatTransport, instruction: &str, questions: &[String], text: &str, cache_prompt: bool) -> Result<Vec<String>, ManifestoError> {
+    let reply = summary::complete(transport, GPT_4_MODEL_NAME, QA_SYSTEM_PROMPT, &question_prompt(instruction, questions), text, 1, cache_prompt)?
+        .swap_remove(0);
+
+    Ok(parse_answers(&reply, questions.len()))
+}
+
+fn question_prompt(instruction: &str, questions: &[String]) -> String {
+    let numbered: Vec<String> = questions.iter().enumerate()
+        .map(|(i, question)| format!("Question {}: {}", i + 1, question))
+        .collect();
+
+    format!("{}\n\n{}", instruction, numbered.join("\n"))
+}
+
+// Splits a reply into the answers that follow each "Answer N:" line. An answer can run over
+// several lines. Any question the model skipped is treated as not addressed.
+fn parse_answers(reply: &str, question_count: usize) -> Vec<String> {
+    let mut answers: Vec<Option<String>> = vec![None; question_count];
+    let mut current: Option<usize> = None;
+
+    for line in reply.lines() {
+        let numbered = line.trim().strip_prefix("Answer ")
+            .and_then(|rest| rest.split_once(':'))
+            .and_then(|(n, answer)| Some((n.trim().parse::<usize>().ok()?, answer)));
+
+        match (numbered, current) {
+            (Some((n, answer)), _) if (1..=question_count).contains(&n) => {
+                answers[n - 1] = Some(String::from(answer.trim()));
+                current = Some(n - 1);
+            }
+            // An answer to a question that wasn't asked
+            (Some(_), _) => current = None,
+            (None, Some(i)) => {
+                let answer = answers[i].get_or_insert_with(String::new);
+                answer.push('\n');
+                answer.push_str(line);
+            }
+            (None, None) => {}
+        }
+    }
+
+    answers.into_iter()
+        .enumerate()
+        .map(|(i, answer)| match answer.map(|answer| String::from(answer.trim())) {
+            Some(answer) if !answer.is_empty() => answer,
+            _ => {
+                diagnostics::warn(format!("no answer was given for question {}", i + 1));
+                String::from(NOT_ADDRESSED)
+            }
+        })
+        .collect()
+}
+
+fn is_not_addressed(answer: &str) -> bool {
+    answer.trim().trim_end_matches('.').eq_ignore_ascii_case(NOT_ADDRESSED)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    fn questions(questions: &[&str]) -> Vec<String> {
+        questions.iter().map(|question| String::from(*question)).collect()
+    }
+
+    #[test]
+    fn asks_every_question_in_one_request() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Answer 1: Free buses.\nAnswer 2: not addressed"));
+
+        let answers = answer_questions(&transport, "We will make buses free.", &questions(&["Transport?", "Defence?"]), None, 1, false)
+            .expect("should have answered the questions");
+
+        assert_eq!(answers, vec![
+            QuestionAnswer { question: String::from("Transport?"), answer: String::from("Free buses.") },
+            QuestionAnswer { question: String::from("Defence?"), answer: String::from(NOT_ADDRESSED) },
+        ]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["messages"][0]["content"], QA_SYSTEM_PROMPT);
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            format!("{}\n\nQuestion 1: Transport?\nQuestion 2: Defence?", ASK_INSTRUCTION)
+        );
+        assert_eq!(requests[0]["messages"][2]["content"], "We will make buses free.");
+    }
+
+    #[test]
+    fn chunked_answers_are_synthesised() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Answer 1: Free buses.\nAnswer 2: not addressed\nAnswer 3: Not addressed."))
+            .respond(200, &fixtures::chat_completion("Answer 1: More trains.\nAnswer 2: not addressed\nAnswer 3: A bigger navy."))
+            .respond(200, &fixtures::chat_completion("Answer 1: Free buses and more trains.\nAnswer 2: A bigger navy."));
+
+        let answers = answer_questions(
+            &transport,
+            "We will make buses free.\n\nWe will build more trains and ships.",
+            &questions(&["Transport?", "Housing?", "Defence?"]),
+            Some(10),
+            1,
+            false,
+        ).expect("should have answered the questions");
+
+        let answers: Vec<&str> = answers.iter().map(|qa| qa.answer.as_str()).collect();
+        assert_eq!(answers, vec!["Free buses and more trains.", NOT_ADDRESSED, "A bigger navy."]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["messages"][2]["content"], "We will make buses free.\n\n");
+        assert_eq!(requests[1]["messages"][2]["content"], "We will build more trains and ships.");
+        // Housing wasn't addressed by any chunk, so it's left out of the synthesis
+        assert_eq!(
+            requests[2]["messages"][1]["content"],
+            format!("{}\n\nQuestion 1: Transport?\nQuestion 2: Defence?", SYNTHESIS_INSTRUCTION)
+        );
+        assert_eq!(
+            requests[2]["messages"][2]["content"],
+            "Answers to question 1:\n- Free buses.\n- More trains.\n\nAnswers to question 2:\n- A bigger navy."
+        );
+    }
+
+    #[test]
+    fn skips_synthesis_when_nothing_is_addressed() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Answer 1: not addressed"))
+            .respond(200, &fixtures::chat_completion("Answer 1: not addressed"));
+
+        let answers = answer_questions(&transport, "First half.\n\nSecond half.", &questions(&["Defence?"]), Some(4), 1, false)
+            .expect("should have answered the questions");
+
+        assert_eq!(answers[0].answer, NOT_ADDRESSED);
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn cached_prompts_put_the_manifesto_first() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Answer 1: Free buses."))
+            .respond(200, &fixtures::chat_completion("Answer 1: not addressed"));
+
+        answer_questions(&transport, "We will make buses free.", &questions(&["Transport?"]), None, 1, true)
+            .expect("should have answered the questions");
+        answer_questions(&transport, "We will make buses free.", &questions(&["Defence?"]), None, 1, true)
+            .expect("should have answered the questions");
+
+        let requests = transport.requests();
+        // The system prompt and manifesto are the same for both questions, so form a cacheable prefix
+        for request in &requests {
+            assert_eq!(request["messages"][0]["content"], QA_SYSTEM_PROMPT);
+            assert_eq!(request["messages"][1]["content"], "We will make buses free.");
+        }
+        assert_eq!(requests[0]["messages"][2]["content"], format!("{}\n\nQuestion 1: Transport?", ASK_INSTRUCTION));
+        assert_eq!(requests[1]["messages"][2]["content"], format!("{}\n\nQuestion 1: Defence?", ASK_INSTRUCTION));
+        assert!(requests[0]["prompt_cache_key"].is_string());
+        assert_eq!(requests[0]["prompt_cache_key"], requests[1]["prompt_cache_key"]);
+    }
+
+    #[test]
+    fn parses_multi_line_and_missing_answers() {
+        let reply = "Sure!\nAnswer 2: Free buses,\nand more trains.\nAnswer 9: out of range";
+
+        assert_eq!(parse_answers(reply, 3), vec![
+            String::from(NOT_ADDRESSED),
+            String::from("Free buses,\nand more trains."),
+            String::from(NOT_ADDRESSED),
+        ]);
+    }
+
+    #[test]
+    fn formats_answers_after_their_questions() {
+        let answers = vec![
+            QuestionAnswer { question: String::from("Transport?"), answer: String::from("Free buses.") },
+            QuestionAnswer { question: String::from("Defence?"), answer: String::from(NOT_ADDRESSED) },
+        ];
+
+        assert_eq!(format_answers(&answers), "Q: Transport?\nA: Free buses.\n\nQ: Defence?\nA: not addressed");
+    }
+}
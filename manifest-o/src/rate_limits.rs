@@ -0,0 +1,93 @@
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+const RETRY_AFTER: &str = "retry-after";
+const REMAINING_REQUESTS: &str = "x-ratelimit-remaining-requests";
+const REMAINING_TOKENS: &str = "x-ratelimit-remaining-tokens";
+
+// What OpenAI's response headers said about our rate limit budget
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimits {
+    pub fn from_headers(headers: &HeaderMap) -> RateLimits {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        RateLimits {
+            remaining_requests: header(REMAINING_REQUESTS).and_then(|v| v.trim().parse().ok()),
+            remaining_tokens: header(REMAINING_TOKENS).and_then(|v| v.trim().parse().ok()),
+            retry_after: header(RETRY_AFTER).and_then(|v| parse_retry_after(v, SystemTime::now())),
+        }
+    }
+}
+
+// retry-after is either a number of seconds or an HTTP date to wait until. Dates in the past
+// mean "retry now".
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+
+    let retry_at = httpdate::parse_http_date(value).ok()?;
+
+    Some(retry_at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("20", SystemTime::now()), Some(Duration::from_secs(20)));
+        assert_eq!(parse_retry_after("0.5", SystemTime::now()), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parses_retry_after_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn ignores_unparseable_retry_after() {
+        assert_eq!(parse_retry_after("soon", SystemTime::now()), None);
+        assert_eq!(parse_retry_after("-1", SystemTime::now()), None);
+    }
+
+    #[test]
+    fn reads_limits_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(REMAINING_REQUESTS, HeaderValue::from_static("59"));
+        headers.insert(REMAINING_TOKENS, HeaderValue::from_static("149000"));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("2"));
+
+        assert_eq!(RateLimits::from_headers(&headers), RateLimits {
+            remaining_requests: Some(59),
+            remaining_tokens: Some(149000),
+            retry_after: Some(Duration::from_secs(2)),
+        });
+    }
+
+    #[test]
+    fn missing_headers_are_none() {
+        assert_eq!(RateLimits::from_headers(&HeaderMap::new()), RateLimits::default());
+    }
+}
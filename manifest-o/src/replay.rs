@@ -0,0 +1,383 @@
+// Recording and replaying OpenAI traffic, for working on prompts offline (--record and
+// --replay). Recording wraps a real transport and saves every request with its response (or
+// API error) as a numbered JSON file. Replaying serves those responses back without touching
+// the network, matching each request on a fingerprint of what was sent. Both sit at the
+// [ChatTransport] layer, so everything built on a transport behaves the same when replayed.
+//
+// Only request bodies are recorded. The key lives in the client's headers, which transports
+// never see, so recordings can't contain it.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::atomic_write;
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::model_fallback::ModelUsage;
+use crate::open_ai::*;
+use crate::rate_limits::RateLimits;
+use crate::state;
+use crate::transport::{record_system_fingerprint, ChatTransport, CHAT_PATH, EMBEDDINGS_PATH, MODERATIONS_PATH};
+
+// Request fields that can change between runs without changing the response
+const UNFINGERPRINTED_FIELDS: [&str; 1] = ["prompt_cache_key"];
+
+#[derive(Serialize, Deserialize)]
+struct Recording {
+    endpoint: String,
+    fingerprint: String,
+    request: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RecordedError>,
+}
+
+// An error response from OpenAI, which is replayed as the same [ManifestoError::Api]
+#[derive(Serialize, Deserialize)]
+struct RecordedError {
+    status: u16,
+    code: Option<String>,
+    message: String,
+}
+
+// Identifies a request by its endpoint and everything in its body that affects the response
+// (for chat, the model, messages and number of choices). Keys are sorted when the body is
+// serialized, so the same request always gets the same fingerprint.
+fn fingerprint<B: Serialize>(endpoint: &str, body: &B) -> String {
+    let mut body = serde_json::to_value(body).expect("request bodies should always serialize");
+
+    if let Some(fields) = body.as_object_mut() {
+        for field in UNFINGERPRINTED_FIELDS {
+            fields.remove(field);
+        }
+    }
+
+    state::input_hash(&format!("{}\n{}", endpoint, body))
+}
+
+// Saves each request and its outcome to [dir], numbered in the order they finished. Shared by
+// every transport in the run.
+pub struct Recorder {
+    dir: PathBuf,
+    next: Mutex<usize>,
+}
+
+impl Recorder {
+    // Numbering carries on after any recordings already in [dir]
+    pub fn create(dir: &Path) -> io::Result<Recorder> {
+        fs::create_dir_all(dir)?;
+
+        let existing = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "json"))
+            .count();
+
+        Ok(Recorder { dir: dir.to_path_buf(), next: Mutex::new(existing + 1) })
+    }
+
+    fn record<B: Serialize, R: Serialize>(&self, endpoint: &str, body: &B, result: &Result<R, ManifestoError>) {
+        let (response, error) = match result {
+            Ok(response) => (Some(serde_json::to_value(response).expect("responses should always serialize")), None),
+            Err(ManifestoError::Api { status, code, message }) => {
+                (None, Some(RecordedError { status: *status, code: code.clone(), message: message.clone() }))
+            }
+            // Nothing came back from OpenAI, so there's nothing to replay
+            Err(_) => return,
+        };
+
+        let recording = Recording {
+            endpoint: String::from(endpoint),
+            fingerprint: fingerprint(endpoint, body),
+            request: serde_json::to_value(body).expect("request bodies should always serialize"),
+            response,
+            error,
+        };
+
+        let path = {
+            let mut next = self.next.lock().unwrap();
+            let path = self.dir.join(format!("{:04}-{}.json", *next, endpoint.trim_start_matches('/').replace('/', "-")));
+            *next += 1;
+            path
+        };
+
+        let json = serde_json::to_string_pretty(&recording).expect("recordings should always serialize");
+
+        // A run killed part way through a write mustn't leave a recording that --replay can't read
+        if let Err(e) = atomic_write::write(&path, json) {
+            diagnostics::warn(format!("couldn't record {}: {}", path.display(), e));
+        }
+    }
+}
+
+// Passes every request on to [inner], recording each one as it completes
+pub struct RecordingTransport<T: ChatTransport> {
+    inner: T,
+    recorder: Arc<Recorder>,
+}
+
+impl<T: ChatTransport> RecordingTransport<T> {
+    pub fn new(inner: T, recorder: Arc<Recorder>) -> RecordingTransport<T> {
+        RecordingTransport { inner, recorder }
+    }
+}
+
+impl<T: ChatTransport> ChatTransport for RecordingTransport<T> {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        let result = self.inner.post_chat(body);
+        self.recorder.record(CHAT_PATH, body, &result);
+        result
+    }
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        let result = self.inner.post_moderation(body);
+        self.recorder.record(MODERATIONS_PATH, body, &result);
+        result
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        let result = self.inner.post_embeddings(body);
+        self.recorder.record(EMBEDDINGS_PATH, body, &result);
+        result
+    }
+
+    fn last_rate_limits(&self) -> Option<RateLimits> {
+        self.inner.last_rate_limits()
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        self.inner.total_usage()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        self.inner.cut_off_replies()
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        self.inner.system_fingerprints()
+    }
+
+    fn total_request_duration(&self) -> Duration {
+        self.inner.total_request_duration()
+    }
+
+    fn models_used(&self) -> Vec<ModelUsage> {
+        self.inner.models_used()
+    }
+}
+
+// Every recording in a directory, by fingerprint. A request that was made more than once gets
+// its recordings back in the order they were made. Shared by every transport in the run.
+pub struct Replayer {
+    recordings: Mutex<HashMap<String, VecDeque<Recording>>>,
+}
+
+impl Replayer {
+    pub fn open(dir: &Path) -> Result<Replayer, String> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("Couldn't read {}: {}", dir.display(), e))?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        paths.sort();
+
+        let mut recordings: HashMap<String, VecDeque<Recording>> = HashMap::new();
+
+        for path in paths {
+            let recording: Recording = fs::read_to_string(&path).ok()
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .ok_or_else(|| format!("{} isn't a recording", path.display()))?;
+
+            recordings.entry(recording.fingerprint.clone()).or_default().push_back(recording);
+        }
+
+        Ok(Replayer { recordings: Mutex::new(recordings) })
+    }
+
+    fn take(&self, fingerprint: &str) -> Option<Recording> {
+        self.recordings.lock().unwrap().get_mut(fingerprint)?.pop_front()
+    }
+}
+
+// Answers every request from recordings, and fails any request that wasn't recorded
+pub struct ReplayTransport {
+    replayer: Arc<Replayer>,
+    total_usage: Mutex<OpenAiUsage>,
+    cut_off_replies: AtomicU32,
+    system_fingerprints: Mutex<Vec<String>>,
+}
+
+impl ReplayTransport {
+    pub fn new(replayer: Arc<Replayer>) -> ReplayTransport {
+        ReplayTransport {
+            replayer,
+            total_usage: Mutex::new(OpenAiUsage::default()),
+            cut_off_replies: AtomicU32::new(0),
+            system_fingerprints: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn replay<B: Serialize, R: DeserializeOwned>(&self, endpoint: &str, body: &B) -> Result<R, ManifestoError> {
+        let fingerprint = fingerprint(endpoint, body);
+
+        let recording = self.replayer.take(&fingerprint).ok_or_else(|| ManifestoError::ReplayMiss(format!(
+            "No recording of this {} request (fingerprint {}). Record it again with --record.",
+            endpoint, fingerprint
+        )))?;
+
+        match (recording.response, recording.error) {
+            (_, Some(error)) => Err(ManifestoError::Api { status: error.status, code: error.code, message: error.message }),
+            (Some(response), None) => serde_json::from_value(response.clone())
+                .map_err(|_| ManifestoError::Deserialize(response.to_string())),
+            (None, None) => Err(ManifestoError::ReplayMiss(format!("The recording {} has no response", fingerprint))),
+        }
+    }
+}
+
+impl ChatTransport for ReplayTransport {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        let response: OpenAiResponse = self.replay(CHAT_PATH, body)?;
+
+        if let Some(usage) = &response.usage {
+            self.total_usage.lock().unwrap().add(usage);
+        }
+
+        self.cut_off_replies.fetch_add(response.cut_off_choices(), Ordering::SeqCst);
+        record_system_fingerprint(&self.system_fingerprints, &response);
+
+        Ok(response)
+    }
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        self.replay(MODERATIONS_PATH, body)
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        let response: EmbeddingResponse = self.replay(EMBEDDINGS_PATH, body)?;
+
+        if let Some(usage) = &response.usage {
+            self.total_usage.lock().unwrap().add(usage);
+        }
+
+        Ok(response)
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        *self.total_usage.lock().unwrap()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        self.cut_off_replies.load(Ordering::SeqCst)
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        self.system_fingerprints.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::summary::{self, SummaryOptions};
+    use crate::transport::{fixtures, ReqwestTransport};
+
+    fn chunked<'a>() -> SummaryOptions<'a> {
+        SummaryOptions { chunk_tokens: Some(4), ..SummaryOptions::default() }
+    }
+
+    #[test]
+    fn replays_recorded_runs_without_the_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifesto = "First half.\n\nSecond half.";
+        let server = MockServer::start(vec![
+            MockResponse::new(200, &fixtures::chat_completion("Part one")),
+            MockResponse::new(200, &fixtures::chat_completion("Part two")),
+            MockResponse::new(200, &fixtures::chat_completion("Combined")),
+        ]);
+
+        let recorder = Arc::new(Recorder::create(dir.path()).unwrap());
+        let recording = RecordingTransport::new(ReqwestTransport::new(mock_server::client(), &server.url, None, false), recorder);
+        let recorded = summary::summarise(&recording, manifesto, &chunked()).expect("should have summarised the manifesto");
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+        assert!(dir.path().join("0001-chat-completions.json").exists());
+
+        let replaying = ReplayTransport::new(Arc::new(Replayer::open(dir.path()).unwrap()));
+        let replayed = summary::summarise(&replaying, manifesto, &chunked()).expect("should have replayed the summary");
+
+        assert_eq!(replayed, recorded);
+        assert_eq!(replayed, vec!["Combined"]);
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(replaying.total_usage(), recording.total_usage());
+    }
+
+    #[test]
+    fn replays_api_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start(vec![
+            MockResponse::new(401, &fixtures::api_error("invalid_api_key", "Incorrect API key provided")),
+        ]);
+
+        let recording = RecordingTransport::new(
+            ReqwestTransport::new(mock_server::client(), &server.url, None, false),
+            Arc::new(Recorder::create(dir.path()).unwrap()),
+        );
+        summary::summarise(&recording, "Vote for us", &SummaryOptions::default()).expect_err("should have failed");
+
+        let replaying = ReplayTransport::new(Arc::new(Replayer::open(dir.path()).unwrap()));
+
+        match summary::summarise(&replaying, "Vote for us", &SummaryOptions::default()) {
+            Err(ManifestoError::Api { status: 401, code: Some(code), .. }) => assert_eq!(code, "invalid_api_key"),
+            _ => panic!("Should have replayed the API error"),
+        }
+    }
+
+    #[test]
+    fn ignores_writes_cut_off_part_way() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start(vec![MockResponse::new(200, &fixtures::chat_completion("Summary"))]);
+
+        let recording = RecordingTransport::new(
+            ReqwestTransport::new(mock_server::client(), &server.url, None, false),
+            Arc::new(Recorder::create(dir.path()).unwrap()),
+        );
+        summary::summarise(&recording, "Vote for us", &SummaryOptions::default()).expect("should have summarised the manifesto");
+        // What a run killed mid-write leaves behind: a half-written temp file next to the recordings
+        fs::write(dir.path().join("0002-chat-completions.json.123-0.tmp"), "{\"endpoint\": \"/chat/comp").unwrap();
+
+        let replaying = ReplayTransport::new(Arc::new(Replayer::open(dir.path()).expect("should have ignored the temp file")));
+        let replayed = summary::summarise(&replaying, "Vote for us", &SummaryOptions::default()).expect("should have replayed the summary");
+
+        assert_eq!(replayed, vec!["Summary"]);
+        assert_eq!(*Recorder::create(dir.path()).unwrap().next.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn fails_on_requests_that_werent_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let replaying = ReplayTransport::new(Arc::new(Replayer::open(dir.path()).unwrap()));
+
+        match summary::summarise(&replaying, "Vote for us", &SummaryOptions::default()) {
+            Err(ManifestoError::ReplayMiss(message)) => assert!(message.contains("--record")),
+            _ => panic!("Should have failed on the missing recording"),
+        }
+    }
+
+    #[test]
+    fn fingerprints_ignore_the_cache_key() {
+        let body = || ChatRequestBuilder::new().model(GPT_4_MODEL_NAME).user("Hi");
+        let plain = body().build().expect("should have built the request");
+        let cached = body().prompt_cache_key("key").build().expect("should have built the request");
+
+        assert_eq!(fingerprint(CHAT_PATH, &plain), fingerprint(CHAT_PATH, &cached));
+        assert_ne!(fingerprint(CHAT_PATH, &plain), fingerprint(MODERATIONS_PATH, &plain));
+    }
+}
@@ -0,0 +1,400 @@
+use crate::diff::DiffStats;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::front_matter::Metadata;
+use crate::model_fallback::ModelUsage;
+use crate::open_ai::OpenAiUsage;
+use crate::prices::PriceTable;
+use crate::qa::QuestionAnswer;
+use crate::rate_limits::RateLimits;
+use crate::sections::SectionSummary;
+use crate::summary::Critique;
+use crate::transport::ChatTransport;
+use crate::watch::{FileStatus, PolledFile};
+
+// Everything about a run that's printed with --json. Most runs start from [RunReport::new] and
+// fill in the fields that apply to them with struct update syntax.
+#[derive(Serialize, Default)]
+pub struct RunReport {
+    // The chosen summary (the first candidate unless --pick-best chose another)
+    pub summary: String,
+    // Every candidate, when more than one was asked for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<String>,
+    // Per-section summaries from --summarize-sections, in which case [summary] is all of them
+    // under their headings, or from --per-section, in which case [summary] is the overview
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<SectionSummary>,
+    // From --critique or --revise. When the summary was revised, [summary] is the revision.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub critique: Option<Critique>,
+    // Set when Ctrl+C stopped the run early, in which case [summary] is only what was finished
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool,
+    // Set when the summary was made offline (--offline or --fallback-offline), in which case
+    // [summary] is sentences picked from the manifesto rather than written by the model
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub extractive: bool,
+    pub usage: OpenAiUsage,
+    // With --model-fallback, every model that answered, and how much of [usage] each did
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelUsage>,
+    // Time spent waiting on OpenAI, across every request
+    pub duration_ms: u128,
+    pub rate_limits: Option<RateLimits>,
+    // Every backend configuration that answered (see [ChatTransport::system_fingerprints])
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub system_fingerprints: Vec<String>,
+    // Answers to --ask questions, in which case [summary] is all of them after their questions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub answers: Vec<QuestionAnswer>,
+    // The document's front matter
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    // From --topic, which the summary only covers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
+    // From --diff-from: how many paragraphs changed, in which case [summary] is of the changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<DiffStats>,
+    // The SHA-256 of the text that was sent to the model: the document after its front matter
+    // was split off and it was cleaned (and truncated), not the file as it was read
+    pub source_sha256: String,
+}
+
+impl RunReport {
+    // A report of [summary], with everything [transport] knows about the run's requests
+    pub fn new(summary: String, transport: &impl ChatTransport, metadata: Metadata, source_sha256: &str) -> RunReport {
+        RunReport {
+            summary,
+            usage: transport.total_usage(),
+            models: transport.models_used(),
+            duration_ms: transport.total_request_duration().as_millis(),
+            rate_limits: transport.last_rate_limits(),
+            system_fingerprints: transport.system_fingerprints(),
+            metadata,
+            source_sha256: String::from(source_sha256),
+            ..RunReport::default()
+        }
+    }
+}
+
+// One input's row in the --report for a --batch run
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BatchReportRow {
+    pub file: String,
+    pub status: &'static str,
+    // The model the requests asked for, or with --model-fallback, every model that answered
+    // them, joined with "+"
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // Estimated from [PriceTable], at the price of the model each token was spent on, or None if
+    // one of them has no known price
+    pub cost_usd: Option<f64>,
+    pub elapsed_ms: u128,
+    pub output_path: String,
+    // The fingerprint of the text the summary was made from (see [RunReport::source_sha256]),
+    // which cached files keep from when they were summarised
+    pub source_sha256: Option<String>,
+    // The name the output was given from the document's title, under --name-from-title
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub error: Option<String>,
+    // The file's front matter. In the CSV, each field gets a column of its own.
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
+
+impl BatchReportRow {
+    pub fn new(polled: &PolledFile, model: &str, prices: &PriceTable) -> BatchReportRow {
+        BatchReportRow {
+            file: polled.file_name.clone(),
+            status: match polled.status {
+                FileStatus::Ok => "ok",
+                FileStatus::Failed => "failed",
+                FileStatus::Cached => "cached",
+            },
+            model: match polled.models_used.is_empty() {
+                true => String::from(model),
+                false => polled.models_used.iter().map(|used| used.model.as_str()).collect::<Vec<_>>().join("+"),
+            },
+            prompt_tokens: polled.usage.prompt_tokens,
+            completion_tokens: polled.usage.completion_tokens,
+            cost_usd: prices.estimated_cost_usd_by_model(&polled.usage, &polled.models_used, model),
+            elapsed_ms: polled.elapsed.as_millis(),
+            output_path: polled.output_path.display().to_string(),
+            source_sha256: polled.source_sha256.clone(),
+            name: polled.name.clone(),
+            error: polled.error.clone(),
+            metadata: polled.metadata.clone(),
+        }
+    }
+}
+
+// A one-line summary of a batch, e.g. "3 files: 1 ok, 1 cached, 1 failed; 150 tokens, $0.0025".
+// The cost is "unknown" if any file's is.
+pub fn format_batch_summary(rows: &[BatchReportRow]) -> String {
+    let count = |status| rows.iter().filter(|row| row.status == status).count();
+    let tokens: u64 = rows.iter().map(|row| row.prompt_tokens + row.completion_tokens).sum();
+    let cost = match rows.iter().map(|row| row.cost_usd).sum::<Option<f64>>() {
+        Some(cost) => format!("${:.4}", cost),
+        None => String::from("cost unknown"),
+    };
+
+    format!(
+        "{} files: {} ok, {} cached, {} failed; {} tokens, {}",
+        rows.len(), count("ok"), count("cached"), count("failed"), tokens, cost
+    )
+}
+
+// Writes the rows as CSV or JSON, depending on whether [path] ends in .csv or .json
+pub fn write_batch_report(path: &Path, rows: &[BatchReportRow]) -> io::Result<()> {
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => batch_report_csv(rows),
+        Some("json") => serde_json::to_string_pretty(rows).expect("report rows should always serialize"),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "the report must be a .csv or .json file")),
+    };
+
+    fs::write(path, contents)
+}
+
+fn batch_report_csv(rows: &[BatchReportRow]) -> String {
+    let metadata_keys: BTreeSet<&String> = rows.iter().flat_map(|row| row.metadata.keys()).collect();
+
+    // The name column is only there when outputs were named after titles
+    let named = rows.iter().any(|row| row.name.is_some());
+
+    let mut csv = String::from("file,status,model,prompt_tokens,completion_tokens,cost_usd,elapsed_ms,output_path,source_sha256,error");
+
+    if named {
+        csv.push_str(",name");
+    }
+
+    for key in &metadata_keys {
+        csv.push(',');
+        csv.push_str(&csv_field(key));
+    }
+
+    csv.push('\n');
+
+    for row in rows {
+        let fields = [
+            csv_field(&row.file),
+            String::from(row.status),
+            csv_field(&row.model),
+            row.prompt_tokens.to_string(),
+            row.completion_tokens.to_string(),
+            row.cost_usd.map(|cost| format!("{:.6}", cost)).unwrap_or_else(|| String::from("unknown")),
+            row.elapsed_ms.to_string(),
+            csv_field(&row.output_path),
+            String::from(row.source_sha256.as_deref().unwrap_or("")),
+            csv_field(row.error.as_deref().unwrap_or("")),
+        ];
+
+        csv.push_str(&fields.join(","));
+
+        if named {
+            csv.push(',');
+            csv.push_str(&csv_field(row.name.as_deref().unwrap_or("")));
+        }
+
+        for key in &metadata_keys {
+            csv.push(',');
+            csv.push_str(&csv_field(row.metadata.get(*key).map_or("", String::as_str)));
+        }
+
+        csv.push('\n');
+    }
+
+    csv
+}
+
+// Quotes a field (doubling any quotes inside it) if it contains anything that would otherwise
+// break up the row
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoding::ReadOptions;
+    use crate::open_ai::GPT_4_MODEL_NAME;
+    use crate::state;
+    use crate::watch::{Summarised, WatchOptions, Watcher};
+    use tokio_util::sync::CancellationToken;
+    use std::time::Duration;
+
+    // Runs a batch over three files where the second fails with an awkward error message
+    fn three_file_batch(dir: &Path) -> Vec<BatchReportRow> {
+        let options = WatchOptions {
+            dir: dir.join("in"),
+            output_dir: dir.join("out"),
+            poll_interval: Duration::from_secs(1),
+            output_extension: "txt",
+            read_options: ReadOptions::default(),
+            verbose: false,
+            jobs: 1,
+        };
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        fs::write(options.dir.join("b.txt"), "second").unwrap();
+        fs::write(options.dir.join("c.txt"), "third").unwrap();
+
+        let mut watcher = Watcher::open(options).expect("should have opened the watcher");
+        let polled = watcher.poll(&CancellationToken::new(), &|_, contents, output_path| {
+            if contents == "second" {
+                return Err(String::from("Rate limited, \"slow down\"\ntry later"));
+            }
+
+            fs::write(output_path, contents).unwrap();
+            Ok(Summarised {
+                usage: OpenAiUsage { prompt_tokens: 1000, completion_tokens: 100, total_tokens: 1100 },
+                models_used: Vec::new(),
+                source_sha256: state::input_hash(contents),
+            })
+        }).expect("should have polled the batch");
+
+        polled.iter().map(|file| BatchReportRow::new(file, GPT_4_MODEL_NAME, &PriceTable::default())).collect()
+    }
+
+    #[test]
+    fn csv_report_has_a_row_per_file_and_escapes_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = three_file_batch(dir.path());
+        let report_path = dir.path().join("report.csv");
+
+        write_batch_report(&report_path, &rows).expect("should have written the report");
+        let csv = fs::read_to_string(&report_path).unwrap();
+        let out = dir.path().join("out");
+        let lines: Vec<String> = csv.lines().map(|line| line.replace(&*out.to_string_lossy(), "OUT")).collect();
+
+        // Elapsed times vary, so check everything around them
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "file,status,model,prompt_tokens,completion_tokens,cost_usd,elapsed_ms,output_path,source_sha256,error");
+        assert!(lines[1].starts_with("a.txt,ok,gpt-4-turbo,1000,100,0.013000,"));
+        assert!(lines[1].ends_with(&format!(",OUT/a.txt.summary.txt,{},", state::input_hash("first"))));
+        assert!(lines[2].starts_with("b.txt,failed,gpt-4-turbo,0,0,0.000000,"));
+        assert!(lines[2].ends_with(",OUT/b.txt.summary.txt,,\"Rate limited, \"\"slow down\"\""));
+        assert_eq!(lines[3], "try later\"");
+        assert!(lines[4].starts_with("c.txt,ok,"));
+    }
+
+    #[test]
+    fn json_report_has_a_row_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = three_file_batch(dir.path());
+        let report_path = dir.path().join("report.json");
+
+        write_batch_report(&report_path, &rows).expect("should have written the report");
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+
+        assert_eq!(json.as_array().map(Vec::len), Some(3));
+        assert_eq!(json[0]["status"], "ok");
+        assert_eq!(json[0]["prompt_tokens"], 1000);
+        assert_eq!(json[0]["source_sha256"], state::input_hash("first"));
+        assert_eq!(json[1]["status"], "failed");
+        assert_eq!(json[1]["error"], "Rate limited, \"slow down\"\ntry later");
+        assert_eq!(json[2]["error"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn cached_files_are_reported_on_the_next_run() {
+        let dir = tempfile::tempdir().unwrap();
+        three_file_batch(dir.path());
+
+        let options = WatchOptions {
+            dir: dir.path().join("in"),
+            output_dir: dir.path().join("out"),
+            poll_interval: Duration::from_secs(1),
+            output_extension: "txt",
+            read_options: ReadOptions::default(),
+            verbose: false,
+            jobs: 1,
+        };
+        let mut watcher = Watcher::open(options).expect("should have reopened the watcher");
+        let polled = watcher.poll(&CancellationToken::new(), &|_, contents, _| {
+            Ok(Summarised { usage: OpenAiUsage::default(), models_used: Vec::new(), source_sha256: state::input_hash(contents) })
+        }).unwrap();
+        let rows: Vec<BatchReportRow> = polled.iter().map(|file| BatchReportRow::new(file, GPT_4_MODEL_NAME, &PriceTable::default())).collect();
+        let statuses: Vec<&str> = rows.iter().map(|row| row.status).collect();
+
+        assert_eq!(statuses, vec!["cached", "ok", "cached"]);
+        // Cached files still have the fingerprint from when they were summarised
+        assert_eq!(rows[0].source_sha256, Some(state::input_hash("first")));
+        assert_eq!(rows[1].source_sha256, Some(state::input_hash("second")));
+    }
+
+    #[test]
+    fn summary_line_totals_the_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = three_file_batch(dir.path());
+
+        assert_eq!(format_batch_summary(&rows), "3 files: 2 ok, 0 cached, 1 failed; 2200 tokens, $0.0260");
+    }
+
+    #[test]
+    fn summary_line_says_when_the_cost_is_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rows = three_file_batch(dir.path());
+        rows[2].cost_usd = None;
+
+        assert_eq!(format_batch_summary(&rows), "3 files: 2 ok, 0 cached, 1 failed; 2200 tokens, cost unknown");
+    }
+
+    #[test]
+    fn csv_report_has_a_column_per_metadata_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rows = three_file_batch(dir.path());
+        rows[0].metadata.insert(String::from("party"), String::from("Example, Party"));
+        rows[2].metadata.insert(String::from("year"), String::from("2024"));
+        let report_path = dir.path().join("report.csv");
+
+        write_batch_report(&report_path, &rows).expect("should have written the report");
+        let csv = fs::read_to_string(&report_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert!(lines[0].ends_with(",error,party,year"));
+        assert!(lines[1].ends_with(",\"Example, Party\","));
+        assert!(lines[4].ends_with(",,2024"));
+    }
+
+    #[test]
+    fn reports_names_given_from_titles() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rows = three_file_batch(dir.path());
+        rows[0].name = Some(String::from("green-party-2024"));
+        rows[0].metadata.insert(String::from("party"), String::from("Green Party"));
+        let report_path = dir.path().join("report.csv");
+
+        write_batch_report(&report_path, &rows).expect("should have written the report");
+        let csv = fs::read_to_string(&report_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert!(lines[0].ends_with(",error,name,party"));
+        assert!(lines[1].ends_with(",green-party-2024,Green Party"));
+        assert!(lines[4].ends_with(",,"));
+
+        // Without names, there's no field for them either
+        let rows = three_file_batch(tempfile::tempdir().unwrap().path());
+        let json = serde_json::to_value(&rows).unwrap();
+        assert!(json[0].get("name").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_report_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+
+        if write_batch_report(&dir.path().join("report.txt"), &[]).is_ok() {
+            panic!("Should have rejected a .txt report");
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// A record of every request a run made, written as a JSON run report (--run-report) for
+// support tickets: when each request was sent, OpenAI's ID for it, how it went and what it used.
+// Only metadata is kept. Neither the key nor any of the document (request or response bodies)
+// goes in the report, so it can be shared as is; each document is only identified by its
+// source-sha256 fingerprint.
+
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use crate::atomic_write;
+use crate::attribution::Attribution;
+use crate::open_ai::OpenAiUsage;
+
+const PROVIDER: &str = "openai";
+
+// Shared by every transport in the run (including --jobs threads)
+pub struct RequestLog {
+    started_at: SystemTime,
+    // Who the run was billed to, masked as it is in --print-config
+    attribution: Attribution,
+    requests: Mutex<Vec<RequestRecord>>,
+    // The source-sha256 of every document that was summarised
+    sources: Mutex<Vec<String>>,
+}
+
+// One logical request, including any rate-limit retries of it
+#[derive(Serialize, Clone, Debug)]
+pub struct RequestRecord {
+    pub sent_at: String,
+    pub endpoint: String,
+    pub model: Option<String>,
+    // OpenAI's x-request-id for the last attempt, which is what support will ask for
+    pub openai_request_id: Option<String>,
+    // None if no response came back at all
+    pub status: Option<u16>,
+    // Time spent waiting on every attempt, not counting waits between retries
+    pub latency_ms: u128,
+    pub usage: Option<OpenAiUsage>,
+    // Prompt tokens that OpenAI served from its prompt cache
+    pub cached_tokens: Option<u64>,
+    // Which backend configuration answered a chat request (see [OpenAiResponse])
+    pub system_fingerprint: Option<String>,
+    // Rate limit (429) retries
+    pub retries: u32,
+    // Retries of attempts that got no response at all (--net-retries)
+    pub net_retries: u32,
+    // OpenAI's error code, or why the request failed without a response
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RunReport<'a> {
+    started_at: String,
+    finished_at: String,
+    provider: &'static str,
+    #[serde(skip_serializing_if = "Attribution::is_empty")]
+    attribution: &'a Attribution,
+    // "succeeded" or "failed"
+    outcome: &'static str,
+    error: Option<&'a str>,
+    usage: OpenAiUsage,
+    cached_tokens: u64,
+    retries: u32,
+    net_retries: u32,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    source_sha256: &'a [String],
+    requests: &'a [RequestRecord],
+}
+
+impl RequestLog {
+    pub fn new(attribution: Attribution) -> RequestLog {
+        RequestLog { started_at: SystemTime::now(), attribution, requests: Mutex::new(Vec::new()), sources: Mutex::new(Vec::new()) }
+    }
+
+    pub fn record(&self, request: RequestRecord) {
+        self.requests.lock().unwrap().push(request);
+    }
+
+    // Notes that the document with [source_sha256] was summarised, so the report can tie the run
+    // back to it
+    pub fn record_source(&self, source_sha256: &str) {
+        self.sources.lock().unwrap().push(String::from(source_sha256));
+    }
+
+    // Writes the run report to [path], replacing it in one step (via a temporary file) so that
+    // a reader never sees half of it. [error] is why the run failed, if it did.
+    pub fn write_report(&self, path: &Path, error: Option<&str>) -> io::Result<()> {
+        let requests = self.requests.lock().unwrap();
+        let sources = self.sources.lock().unwrap();
+
+        let mut usage = OpenAiUsage::default();
+        for request in requests.iter() {
+            if let Some(request_usage) = &request.usage {
+                usage.add(request_usage);
+            }
+        }
+
+        let report = RunReport {
+            started_at: httpdate::fmt_http_date(self.started_at),
+            finished_at: httpdate::fmt_http_date(SystemTime::now()),
+            provider: PROVIDER,
+            attribution: &self.attribution,
+            outcome: if error.is_some() { "failed" } else { "succeeded" },
+            error,
+            usage,
+            cached_tokens: requests.iter().filter_map(|request| request.cached_tokens).sum(),
+            retries: requests.iter().map(|request| request.retries).sum(),
+            net_retries: requests.iter().map(|request| request.net_retries).sum(),
+            source_sha256: &sources,
+            requests: &requests,
+        };
+
+        let json = serde_json::to_string_pretty(&report)?;
+
+        atomic_write::write(path, json)
+    }
+}
+
+impl RequestRecord {
+    // A record for a request that's about to be sent, to be filled in as it completes
+    pub fn start(endpoint: &str, model: Option<String>) -> RequestRecord {
+        RequestRecord {
+            sent_at: httpdate::fmt_http_date(SystemTime::now()),
+            endpoint: String::from(endpoint),
+            model,
+            openai_request_id: None,
+            status: None,
+            latency_ms: 0,
+            usage: None,
+            cached_tokens: None,
+            system_fingerprint: None,
+            retries: 0,
+            net_retries: 0,
+            error: None,
+        }
+    }
+
+    // Fills in the usage, cache hits and system fingerprint from a successful response body
+    pub fn read_usage(&mut self, body: &str) {
+        let Ok(response) = serde_json::from_str::<serde_json::Value>(body) else {
+            return;
+        };
+
+        self.usage = serde_json::from_value(response["usage"].clone()).ok();
+        self.cached_tokens = response["usage"]["prompt_tokens_details"]["cached_tokens"].as_u64();
+        self.system_fingerprint = response["system_fingerprint"].as_str().map(String::from);
+    }
+
+    pub fn add_latency(&mut self, latency: Duration) {
+        self.latency_ms += latency.as_millis();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::sync::Arc;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::summary::{self, SummaryOptions};
+    use crate::transport::{fixtures, ChatTransport, ReqwestTransport};
+
+    const MANIFESTO: &str = "We will build a secret tunnel.\n\nWe will also plant trees.";
+
+    #[test]
+    fn reports_every_request_without_the_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.json");
+        let server = MockServer::start(vec![
+            MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "0"),
+            MockResponse::new(200, &fixtures::chat_completion("The tunnel part"))
+                .header("x-request-id", "req_1"),
+            MockResponse::new(200, &fixtures::chat_completion("The trees part"))
+                .header("x-request-id", "req_2"),
+            MockResponse::new(200, &fixtures::chat_completion("Tunnels and trees"))
+                .header("x-request-id", "req_3"),
+        ]);
+        let log = Arc::new(RequestLog::new(Attribution::default()));
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_request_log(Some(Arc::clone(&log)));
+        let options = SummaryOptions { chunk_tokens: Some(10), ..SummaryOptions::default() };
+
+        summary::summarise(&transport, MANIFESTO, &options).expect("should have summarised the manifesto");
+        log.record_source(&crate::state::input_hash(MANIFESTO));
+        log.write_report(&path, None).expect("should have written the report");
+
+        let json = fs::read_to_string(&path).expect("should have written the report");
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(report["provider"], "openai");
+        assert_eq!(report["outcome"], "succeeded");
+        assert!(report["started_at"].is_string());
+        assert!(report["finished_at"].is_string());
+        assert_eq!(report["retries"], 1);
+        assert_eq!(report["usage"]["total_tokens"], transport.total_usage().total_tokens);
+        assert_eq!(report["source_sha256"][0], crate::state::input_hash(MANIFESTO));
+
+        let requests = report["requests"].as_array().expect("should have listed the requests");
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["retries"], 1);
+        assert_eq!(requests[0]["status"], 200);
+        assert_eq!(requests[0]["endpoint"], "/chat/completions");
+        assert_eq!(requests[0]["model"], "gpt-4-turbo");
+        assert_eq!(requests[2]["openai_request_id"], "req_3");
+        assert!(requests[2]["latency_ms"].is_u64());
+        assert!(requests[2]["usage"]["prompt_tokens"].is_u64());
+
+        assert!(!json.contains("tunnel"));
+        assert!(!json.contains("trees"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn reports_failed_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.json");
+        let server = MockServer::start(vec![
+            MockResponse::new(401, &fixtures::api_error("invalid_api_key", "Incorrect API key provided")),
+        ]);
+        let log = Arc::new(RequestLog::new(Attribution::default()));
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_request_log(Some(Arc::clone(&log)));
+
+        summary::summarise(&transport, MANIFESTO, &SummaryOptions::default()).expect_err("should have failed");
+        log.write_report(&path, Some("Failed to summarise the manifesto")).expect("should have written the report");
+
+        let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(report["outcome"], "failed");
+        assert!(report.get("source_sha256").is_none());
+        assert!(report.get("attribution").is_none());
+        assert_eq!(report["error"], "Failed to summarise the manifesto");
+        assert_eq!(report["requests"][0]["status"], 401);
+        assert_eq!(report["requests"][0]["error"], "invalid_api_key");
+    }
+}
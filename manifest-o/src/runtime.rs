@@ -0,0 +1,35 @@
+// The tokio runtime that requests to OpenAI run on. main installs it, sized for --jobs, before
+// anything is sent; anything else that needs it (the subcommands and tests) gets a default one the
+// first time a request is made. Everything above the transports is synchronous, so requests are
+// waited on with [block_on] from ordinary threads (the main thread, --jobs threads and the
+// watcher's), never from inside the runtime itself.
+
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+// Requests spend nearly all of their time waiting on OpenAI, so a few threads can drive any
+// number of them
+const MAX_WORKER_THREADS: usize = 4;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+// Starts the runtime with a worker thread per job, up to a limit. Does nothing if it's already
+// running.
+pub fn install(jobs: usize) {
+    RUNTIME.get_or_init(|| build(jobs));
+}
+
+// Runs [future] on the runtime, blocking the calling thread until it's done
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.get_or_init(|| build(1)).block_on(future)
+}
+
+fn build(jobs: usize) -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(jobs.clamp(1, MAX_WORKER_THREADS))
+        .thread_name("manifest-o-requests")
+        .enable_all()
+        .build()
+        .expect("Failed to start the runtime for requests")
+}
@@ -0,0 +1,53 @@
+// Keeps the OpenAI key out of logs, panics and error messages. The key is only ever formatted
+// as [redacted]; the real value has to be asked for with [SecretString::expose], which should
+// only happen where the Authorization header is built.
+
+use std::fmt;
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Default, PartialEq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: String) -> SecretString {
+        SecretString(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> SecretString {
+        SecretString(String::from(secret))
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn never_formats_the_secret() {
+        let secret = SecretString::from("sk-very-secret");
+
+        assert_eq!(format!("{}", secret), REDACTED);
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(format!("{:#?}", Some(&secret)), format!("Some(\n    {},\n)", REDACTED));
+        assert_eq!(secret.expose(), "sk-very-secret");
+    }
+}
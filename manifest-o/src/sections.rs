@@ -0,0 +1,145 @@
+use regex::Regex;
+use serde::Serialize;
+
+// Markdown headings, short lines in all caps (common in manifestos exported from PDFs), or short
+// numbered headings without a full stop, like "2. Health" or "3.1 Our NHS"
+pub const DEFAULT_HEADING_PATTERN: &str = r"^(#{1,6}\s+\S.*|[A-Z][A-Z0-9 ,&'-]{2,78}[A-Z0-9]|\d{1,2}(\.\d{1,2})*\.?\s+[A-Z][^.!?:;]{1,60})$";
+
+// Used for any text that comes before the first heading
+pub const PREAMBLE_HEADING: &str = "Preamble";
+
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    pub heading: String,
+    pub body: String,
+}
+
+#[derive(Serialize)]
+pub struct SectionSummary {
+    pub heading: String,
+    pub summary: String,
+}
+
+// Splits the document into sections at every line matching [heading_pattern]. Returns None if
+// there are no headings, since the whole document is then one big section. Sections with no
+// content (like a heading immediately followed by another) are dropped.
+pub fn split_into_sections(text: &str, heading_pattern: &Regex) -> Option<Vec<Section>> {
+    let mut sections: Vec<Section> = Vec::new();
+    let mut heading = String::from(PREAMBLE_HEADING);
+    let mut body = String::new();
+    let mut found_heading = false;
+
+    for line in text.lines() {
+        if heading_pattern.is_match(line.trim()) {
+            found_heading = true;
+            push_section(&mut sections, heading, std::mem::take(&mut body));
+            heading = String::from(line.trim().trim_start_matches('#').trim());
+        } else {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+
+    push_section(&mut sections, heading, body);
+
+    if found_heading {
+        Some(sections)
+    } else {
+        None
+    }
+}
+
+fn push_section(sections: &mut Vec<Section>, heading: String, body: String) {
+    if !body.trim().is_empty() {
+        sections.push(Section { heading, body: String::from(body.trim()) });
+    }
+}
+
+// Lays the section summaries out under their original headings
+pub fn format_section_summaries(summaries: &[SectionSummary]) -> String {
+    summaries.iter()
+        .map(|section| format!("## {}\n\n{}", section.heading, section.summary.trim_end()))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn default_pattern() -> Regex {
+        Regex::new(DEFAULT_HEADING_PATTERN).unwrap()
+    }
+
+    fn section(heading: &str, body: &str) -> Section {
+        Section { heading: String::from(heading), body: String::from(body) }
+    }
+
+    #[test]
+    fn splits_on_markdown_headings() {
+        let text = "Intro text\n# Healthcare\nFree clinics.\nMore nurses.\n## Education\nSmaller classes.";
+
+        assert_eq!(split_into_sections(text, &default_pattern()), Some(vec![
+            section("Preamble", "Intro text"),
+            section("Healthcare", "Free clinics.\nMore nurses."),
+            section("Education", "Smaller classes."),
+        ]));
+    }
+
+    #[test]
+    fn splits_on_all_caps_headings() {
+        let text = "HEALTHCARE\nFree clinics for all.\nJOBS & THE ECONOMY\nA jobs guarantee.";
+
+        assert_eq!(split_into_sections(text, &default_pattern()), Some(vec![
+            section("HEALTHCARE", "Free clinics for all."),
+            section("JOBS & THE ECONOMY", "A jobs guarantee."),
+        ]));
+    }
+
+    #[test]
+    fn splits_on_numbered_headings() {
+        let text = "1. Health\nFree clinics.\n1. We will hire more nurses.\n2.1 Our Schools\nSmaller classes.";
+
+        assert_eq!(split_into_sections(text, &default_pattern()), Some(vec![
+            section("1. Health", "Free clinics.\n1. We will hire more nurses."),
+            section("2.1 Our Schools", "Smaller classes."),
+        ]));
+    }
+
+    #[test]
+    fn drops_empty_sections() {
+        let text = "# Part one\n# Healthcare\nFree clinics.";
+
+        assert_eq!(split_into_sections(text, &default_pattern()), Some(vec![
+            section("Healthcare", "Free clinics."),
+        ]));
+    }
+
+    #[test]
+    fn no_headings_means_no_sections() {
+        let text = "We will build more houses.\nAnd more schools. A lot of them.";
+
+        assert_eq!(split_into_sections(text, &default_pattern()), None);
+    }
+
+    #[test]
+    fn uses_custom_patterns() {
+        let pattern = Regex::new(r"^Chapter \d+").unwrap();
+        let text = "Chapter 1: Health\nClinics.\nChapter 2: Jobs\nFactories.";
+
+        assert_eq!(split_into_sections(text, &pattern), Some(vec![
+            section("Chapter 1: Health", "Clinics."),
+            section("Chapter 2: Jobs", "Factories."),
+        ]));
+    }
+
+    #[test]
+    fn formats_summaries_under_headings() {
+        let summaries = vec![
+            SectionSummary { heading: String::from("Healthcare"), summary: String::from("Clinics.\n") },
+            SectionSummary { heading: String::from("Jobs"), summary: String::from("Factories.") },
+        ];
+
+        assert_eq!(format_section_summaries(&summaries), "## Healthcare\n\nClinics.\n\n## Jobs\n\nFactories.");
+    }
+}
@@ -0,0 +1,229 @@
+// Progress for long chunked runs, saved after every chunk so that a run that dies part way
+// through can pick up where it left off with --resume.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use crate::atomic_write;
+use crate::diagnostics;
+use crate::open_ai::OpenAiUsage;
+
+#[derive(Serialize, Deserialize, Default)]
+struct RunState {
+    // SHA256 of the document being summarised
+    input_hash: String,
+    model: String,
+    chunk_tokens: usize,
+    // Whether the chunks were planned one section at a time for --per-section, in which case
+    // their summaries are also the section digests and can't stand in for packed chunks
+    #[serde(default)]
+    per_section: bool,
+    // One entry per planned chunk, filled in as each is summarised
+    chunk_summaries: Vec<Option<String>>,
+    // Tokens used by everything recorded so far, including any earlier runs
+    usage: OpenAiUsage,
+}
+
+pub struct Checkpoint {
+    path: PathBuf,
+    state: Mutex<RunState>,
+    // The usage from earlier runs that this run resumed from
+    resumed_usage: OpenAiUsage,
+}
+
+pub fn state_path(output_or_input_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.manifest-o.state.json", output_or_input_path))
+}
+
+pub fn input_hash(document: &str) -> String {
+    Sha256::digest(document.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Checkpoint {
+    // Starts a checkpoint at [path]. With [resume], any state already there for the same
+    // document and model is picked up; otherwise the run starts from scratch.
+    pub fn open(path: PathBuf, document: &str, model: &str, resume: bool) -> Checkpoint {
+        let input_hash = input_hash(document);
+        let fresh = RunState {
+            input_hash: input_hash.clone(),
+            model: String::from(model),
+            ..RunState::default()
+        };
+
+        let state = if resume {
+            match fs::read_to_string(&path).ok().and_then(|json| serde_json::from_str::<RunState>(&json).ok()) {
+                Some(state) if state.input_hash == input_hash && state.model == model => {
+                    eprintln!("Resuming from {}", path.display());
+                    state
+                }
+                Some(_) => {
+                    diagnostics::warn(format!("{} is for a different document or model; starting over", path.display()));
+                    fresh
+                }
+                None => fresh,
+            }
+        } else {
+            fresh
+        };
+
+        Checkpoint {
+            path,
+            resumed_usage: state.usage,
+            state: Mutex::new(state),
+        }
+    }
+
+    // Sets up for a run of [chunk_count] chunks of [chunk_tokens], planned per section if
+    // [per_section]. Anything recorded for a different plan is thrown away, since those chunks
+    // won't line up with these.
+    pub fn plan(&self, chunk_tokens: usize, chunk_count: usize, per_section: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.chunk_tokens != chunk_tokens || state.chunk_summaries.len() != chunk_count || state.per_section != per_section {
+            state.chunk_tokens = chunk_tokens;
+            state.per_section = per_section;
+            state.chunk_summaries = vec![None; chunk_count];
+        }
+    }
+
+    pub fn completed_chunk(&self, index: usize) -> Option<String> {
+        self.state.lock().unwrap().chunk_summaries.get(index).cloned().flatten()
+    }
+
+    // Every chunk summary recorded so far, in order
+    pub fn completed_chunks(&self) -> Vec<String> {
+        self.state.lock().unwrap().chunk_summaries.iter().flatten().cloned().collect()
+    }
+
+    // How many chunks the run was planned with, or 0 if it wasn't chunked
+    pub fn planned_chunk_count(&self) -> usize {
+        self.state.lock().unwrap().chunk_summaries.len()
+    }
+
+    pub fn completed_chunk_count(&self) -> usize {
+        self.state.lock().unwrap().chunk_summaries.iter().filter(|summary| summary.is_some()).count()
+    }
+
+    // Records a finished chunk and saves the state. [run_usage] is everything this run has
+    // used so far.
+    pub fn record_chunk(&self, index: usize, summary: &str, run_usage: OpenAiUsage) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.chunk_summaries[index] = Some(String::from(summary));
+        state.usage = self.resumed_usage;
+        state.usage.add(&run_usage);
+
+        let json = serde_json::to_string_pretty(&*state).expect("run state should always serialize");
+
+        // Written whole or not at all, since the run can be killed at any point
+        atomic_write::write(&self.path, json)
+    }
+
+    // The usage from any earlier runs that this one resumed
+    pub fn resumed_usage(&self) -> OpenAiUsage {
+        self.resumed_usage
+    }
+
+    // Deletes the state after a successful run, unless it should be kept
+    pub fn finish(&self, keep: bool) {
+        if keep {
+            return;
+        }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                diagnostics::warn(format!("couldn't remove {}: {}", self.path.display(), e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_and_resumes_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.state.json");
+        let usage = OpenAiUsage { prompt_tokens: 1, completion_tokens: 2, total_tokens: 3 };
+
+        let checkpoint = Checkpoint::open(path.clone(), "doc", "model", false);
+        checkpoint.plan(100, 3, false);
+        checkpoint.record_chunk(0, "First", usage).unwrap();
+
+        let resumed = Checkpoint::open(path.clone(), "doc", "model", true);
+        resumed.plan(100, 3, false);
+
+        assert_eq!(resumed.completed_chunk(0), Some(String::from("First")));
+        assert_eq!(resumed.completed_chunk(1), None);
+        assert_eq!(resumed.completed_chunk_count(), 1);
+        assert_eq!(resumed.resumed_usage(), usage);
+    }
+
+    #[test]
+    fn resumes_from_the_last_complete_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.state.json");
+
+        let checkpoint = Checkpoint::open(path.clone(), "doc", "model", false);
+        checkpoint.plan(100, 3, false);
+        checkpoint.record_chunk(0, "First", OpenAiUsage::default()).unwrap();
+        // A run killed while recording the second chunk only gets as far as the temp file
+        fs::write(dir.path().join("run.state.json.123-0.tmp"), "{\"input_hash\": \"ab").unwrap();
+
+        let resumed = Checkpoint::open(path.clone(), "doc", "model", true);
+        resumed.plan(100, 3, false);
+
+        assert_eq!(resumed.completed_chunk(0), Some(String::from("First")));
+        assert_eq!(resumed.completed_chunk_count(), 1);
+    }
+
+    #[test]
+    fn ignores_state_for_other_documents_and_plans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.state.json");
+
+        let checkpoint = Checkpoint::open(path.clone(), "doc", "model", false);
+        checkpoint.plan(100, 3, false);
+        checkpoint.record_chunk(0, "First", OpenAiUsage::default()).unwrap();
+
+        let other_document = Checkpoint::open(path.clone(), "other doc", "model", true);
+        other_document.plan(100, 3, false);
+        assert_eq!(other_document.completed_chunk_count(), 0);
+
+        let other_plan = Checkpoint::open(path.clone(), "doc", "model", true);
+        other_plan.plan(50, 6, false);
+        assert_eq!(other_plan.completed_chunk_count(), 0);
+
+        let per_section = Checkpoint::open(path.clone(), "doc", "model", true);
+        per_section.plan(100, 3, true);
+        assert_eq!(per_section.completed_chunk_count(), 0);
+
+        let not_resuming = Checkpoint::open(path.clone(), "doc", "model", false);
+        not_resuming.plan(100, 3, false);
+        assert_eq!(not_resuming.completed_chunk_count(), 0);
+    }
+
+    #[test]
+    fn finish_removes_the_state_unless_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.state.json");
+
+        let checkpoint = Checkpoint::open(path.clone(), "doc", "model", false);
+        checkpoint.plan(100, 1, false);
+        checkpoint.record_chunk(0, "First", OpenAiUsage::default()).unwrap();
+
+        checkpoint.finish(true);
+        assert!(path.exists());
+
+        checkpoint.finish(false);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,1351 @@
+use regex::Regex;
+use serde::Serialize;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use crate::chunk_cache::ChunkCache;
+use crate::chunking::{self, Chunk};
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::idempotency::Ledger;
+use crate::open_ai::*;
+use crate::pool;
+use crate::progress::Progress;
+use crate::prompt_template::BoundTemplate;
+use crate::prompts::Prompt;
+use crate::sections::{Section, SectionSummary};
+use crate::state::{self, Checkpoint};
+use crate::topics;
+use crate::transport::ChatTransport;
+
+const SYSTEM_PROMPT: &str = "You are an experienced political journalist that writes four-paragraph summaries of the manifestos of political parties";
+const SUMMARY_INSTRUCTION: &str = "Please summarise the following manifesto:";
+const CHUNK_INSTRUCTION: &str = "The following is one part of a longer manifesto. Please summarise this part, keeping every policy it mentions:";
+const COMBINE_INSTRUCTION: &str = "The following are summaries of consecutive parts of one manifesto. Please combine them into a single summary of the whole manifesto:";
+const SECTION_INSTRUCTION: &str = "The following is one section of a manifesto. Please summarise it in one paragraph:";
+const PICK_BEST_SYSTEM_PROMPT: &str = "You are an editor at a political news outlet who chooses which summary of a manifesto to publish";
+const PICK_BEST_INSTRUCTION: &str = "Below are several candidate summaries of the same manifesto. Judge them on accuracy, coverage of the major policies, neutral tone, and clarity. Reply with \"Best: <candidate number>\" on the first line, followed by a short explanation of your choice.";
+const CRITIQUE_SYSTEM_PROMPT: &str = "You are a careful fact-checker at a political news outlet who reviews summaries of manifestos before they're published";
+const CRITIQUE_INSTRUCTION: &str = "Below are a manifesto (or summaries of its parts) and a summary of it. Check the summary against the manifesto. List every claim in the summary that the manifesto doesn't support, and every major policy area in the manifesto that the summary leaves out. If there are none of either, say so.";
+const REVISE_INSTRUCTION: &str = "Below are a manifesto (or summaries of its parts), a summary of it, and a critique of that summary. Rewrite the summary so that it fixes everything the critique raises, keeping its length and style. Reply with only the revised summary.";
+const RETRY_INSTRUCTION: &str = "Below are a manifesto (or summaries of its parts), a summary of it, and the problems with that summary. Write the summary again so that it has none of those problems, following the original instructions. Reply with only the new summary.";
+// Stands in for a chunk's summary when --skip-filtered leaves it out, so that the combined
+// summary can say there's a gap
+const FILTERED_CHUNK_NOTE: &str = "[The model declined to summarise this part of the manifesto due to its content policy.]";
+
+// How many paragraphs [SYSTEM_PROMPT] asks for
+pub const DEFAULT_PARAGRAPHS: usize = 4;
+
+// How many times a chunk request that's slower than --chunk-timeout is sent again
+const MAX_CHUNK_RETRIES: u32 = 2;
+
+// Used for automatic chunking when the API doesn't tell us the model's context length
+const DEFAULT_FALLBACK_CHUNK_TOKENS: usize = 3000;
+pub(crate) const CONTEXT_LENGTH_EXCEEDED: &str = "context_length_exceeded";
+
+pub struct SummaryOptions<'a> {
+    // Summarise in chunks of about this many tokens, rather than all at once
+    pub chunk_tokens: Option<usize>,
+    // How many chunk requests can be in flight at once
+    pub jobs: usize,
+    // How many candidate summaries to ask the model for
+    pub candidates: u32,
+    // Where chunked runs save their progress (and find progress from earlier runs)
+    pub checkpoint: Option<&'a Checkpoint>,
+    // Replacements for the default prompts, e.g. from --prompts
+    pub prompt: Option<&'a Prompt>,
+    // From --prompt-template: replaces the system prompt and every request that sends the
+    // manifesto or a chunk of it
+    pub prompt_template: Option<&'a BoundTemplate<'a>>,
+    // Chunks follow the sections that start at lines matching this, when there are any
+    pub heading_pattern: Option<&'a Regex>,
+    // Give each section its own chunks rather than packing several into one, so that every
+    // chunk summary is about exactly one section (for --per-section)
+    pub per_section: bool,
+    // Lay out requests about the manifesto (or its chunks) for OpenAI's prompt caching
+    pub cache_prompt: bool,
+    // Set (by the Ctrl+C handler) to stop sending requests. Requests already in flight finish,
+    // and their chunks are saved to the checkpoint.
+    pub cancelled: Option<&'a CancellationToken>,
+    // Gives chunk requests idempotency keys, and remembers which have completed so that none is
+    // sent twice
+    pub ledger: Option<&'a Ledger>,
+    // Set with --chunk-cache: chunk summaries from earlier runs, so that only chunks that have
+    // changed are sent
+    pub chunk_cache: Option<&'a ChunkCache>,
+    // Set with --chunk-timeout: how long to wait for a chunk's summary before sending the
+    // request again (needs a [ledger])
+    pub chunk_timeout: Option<Duration>,
+    // Set with --topic: summarise only what the manifesto says about these
+    pub topics: &'a [String],
+    // Skip the chunks that don't look like they're about any of the [topics] (unless --no-skip)
+    pub skip_off_topic: bool,
+    // Set with --skip-filtered: a chunk that the content filter stops the model summarising is
+    // left out (with a note in its place) rather than failing the run
+    pub skip_filtered: bool,
+    // Chunks the pipeline has already planned (see [crate::pipeline::ChunkPlanning]) for
+    // [chunk_tokens], used rather than planning them again
+    pub planned_chunks: Option<&'a [Chunk]>,
+    // Counts (and, if it's visible, shows) the chunks as they're summarised
+    pub progress: Option<&'a Progress<'a>>,
+}
+
+impl Default for SummaryOptions<'_> {
+    fn default() -> Self {
+        SummaryOptions {
+            chunk_tokens: None,
+            jobs: 1,
+            candidates: 1,
+            checkpoint: None,
+            prompt: None,
+            prompt_template: None,
+            heading_pattern: None,
+            per_section: false,
+            cache_prompt: false,
+            cancelled: None,
+            ledger: None,
+            chunk_cache: None,
+            chunk_timeout: None,
+            topics: &[],
+            skip_off_topic: false,
+            skip_filtered: false,
+            planned_chunks: None,
+            progress: None,
+        }
+    }
+}
+
+// Summarises the manifesto, splitting it into chunks if asked to, and returns every candidate
+// summary the model came up with. If the API rejects the request for being too long, the run is
+// re-planned with (smaller) chunks rather than failing outright.
+pub fn summarise(transport: &impl ChatTransport, manifesto: &str, options: &SummaryOptions) -> Result<Vec<String>, ManifestoError> {
+    let chunk_tokens = options.chunk_tokens;
+
+    let result = match chunk_tokens {
+        Some(chunk_tokens) => get_chunked_manifesto_summary(transport, manifesto, chunk_tokens, options),
+        None => get_manifesto_summary(transport, manifesto, options),
+    };
+
+    let message = match result {
+        Err(ManifestoError::Api { code: Some(ref code), ref message, .. }) if code == CONTEXT_LENGTH_EXCEEDED => message.clone(),
+        other => return other,
+    };
+
+    let retry_chunk_tokens = match chunk_tokens {
+        None => {
+            let chunk_tokens = fallback_chunk_tokens(&message);
+
+            eprintln!("The manifesto is too long for the model; retrying in chunks of ~{} tokens", chunk_tokens);
+            chunk_tokens
+        }
+        Some(chunk_tokens) => {
+            let chunk_tokens = chunk_tokens / 2;
+
+            eprintln!("A chunk was too long for the model; retrying in chunks of ~{} tokens", chunk_tokens);
+            chunk_tokens
+        }
+    };
+
+    if retry_chunk_tokens == 0 {
+        return Err(ManifestoError::ContextLengthExceeded(message));
+    }
+
+    match get_chunked_manifesto_summary(transport, manifesto, retry_chunk_tokens, options) {
+        Err(ManifestoError::Api { code: Some(ref code), message, .. }) if code == CONTEXT_LENGTH_EXCEEDED =>
+            Err(ManifestoError::ContextLengthExceeded(message)),
+        other => other,
+    }
+}
+
+pub fn get_manifesto_summary(transport: &impl ChatTransport, manifesto: &str, options: &SummaryOptions) -> Result<Vec<String>, ManifestoError> {
+    if let Some(template) = options.prompt_template {
+        return complete_from_template(transport, template, options, manifesto, options.candidates);
+    }
+
+    let instruction = options.prompt
+        .and_then(|prompt| prompt.instruction.as_deref())
+        .unwrap_or(SUMMARY_INSTRUCTION);
+
+    complete(transport, GPT_4_MODEL_NAME, system_prompt(options), &focused(instruction, options), manifesto, options.candidates, options.cache_prompt)
+}
+
+// Summarises each chunk on its own (up to [jobs] at a time), then asks for a single summary of
+// those summaries. Only that last step produces multiple candidates. Each chunk's prompt says
+// which sections it's from. With a checkpoint, each chunk summary is saved as it finishes and
+// chunks finished by an earlier run are skipped.
+pub fn get_chunked_manifesto_summary(transport: &impl ChatTransport, manifesto: &str, chunk_tokens: usize, options: &SummaryOptions) -> Result<Vec<String>, ManifestoError> {
+    let chunks = chunks_for(manifesto, chunk_tokens, options);
+
+    if chunks.len() <= 1 {
+        return get_manifesto_summary(transport, manifesto, options);
+    }
+
+    let chunks = skip_off_topic(chunks, options);
+    let chunk_summaries = summarise_chunks(transport, &chunks, chunk_tokens, options)?;
+    check_cancelled(options)?;
+
+    complete(transport, GPT_4_MODEL_NAME, system_prompt(options), &focused(COMBINE_INSTRUCTION, options), &chunk_summaries.join("\n\n"), options.candidates, false)
+}
+
+// Summarises the manifesto one section at a time, then combines those summaries into an
+// overview (as in [get_chunked_manifesto_summary]). Along with the overview's candidates,
+// returns each section's summary under its heading; a section that needed more than one chunk
+// gets the summaries of all of its parts. Text before the first heading only goes into the
+// overview.
+pub fn summarise_per_section(transport: &impl ChatTransport, manifesto: &str, chunk_tokens: usize, options: &SummaryOptions) -> Result<(Vec<String>, Vec<SectionSummary>), ManifestoError> {
+    let options = SummaryOptions { per_section: true, ..*options };
+    let chunks = skip_off_topic(chunks_for(manifesto, chunk_tokens, &options), &options);
+    let chunk_summaries = summarise_chunks(transport, &chunks, chunk_tokens, &options)?;
+    check_cancelled(&options)?;
+
+    let mut sections: Vec<SectionSummary> = Vec::new();
+
+    for (chunk, summary) in chunks.iter().zip(&chunk_summaries) {
+        let Some(heading) = chunk.sections.first() else {
+            continue;
+        };
+
+        match sections.last_mut() {
+            Some(section) if chunk.part.is_some_and(|(n, _)| n > 1) => {
+                section.summary.push_str("\n\n");
+                section.summary.push_str(summary);
+            }
+            _ => sections.push(SectionSummary { heading: heading.clone(), summary: summary.clone() }),
+        }
+    }
+
+    let overview = complete(transport, GPT_4_MODEL_NAME, system_prompt(&options), &focused(COMBINE_INSTRUCTION, &options), &chunk_summaries.join("\n\n"), options.candidates, false)?;
+
+    Ok((overview, sections))
+}
+
+// Summarises each chunk on its own (up to [jobs] at a time), saving each to the checkpoint (if
+// any) as it finishes and skipping any that an earlier run already finished
+fn summarise_chunks(transport: &impl ChatTransport, chunks: &[Chunk], chunk_tokens: usize, options: &SummaryOptions) -> Result<Vec<String>, ManifestoError> {
+    let mut completed = 0;
+
+    if let Some(checkpoint) = options.checkpoint {
+        checkpoint.plan(chunk_tokens, chunks.len(), options.per_section);
+
+        completed = checkpoint.completed_chunk_count();
+
+        if completed > 0 {
+            eprintln!("{} of {} chunks were already summarised; skipping them", completed, chunks.len());
+        }
+    }
+
+    let indexed_chunks: Vec<(usize, &Chunk)> = chunks.iter().enumerate().collect();
+
+    let summarise_chunk = |i: usize, chunk: &Chunk| -> Result<String, ManifestoError> {
+        let builder = match options.prompt_template {
+            Some(template) => template_request(template, options, &chunk.text),
+            None => chat_request(GPT_4_MODEL_NAME, system_prompt(options), &chunk_instruction(chunk, options.topics), &chunk.text, options.cache_prompt),
+        };
+
+        let cached = options.chunk_cache.map(|cache| {
+            let key = cache.key(&builder.clone().build().expect("requests should always have a model and messages"), &chunk.text);
+            (cache, key)
+        });
+
+        if let Some(summary) = cached.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            return Ok(summary);
+        }
+
+        match send_chunk(transport, builder, i, options) {
+            // Not cached, so that the chunk is tried again next time
+            Err(ManifestoError::ContentFiltered) if options.skip_filtered => {
+                eprintln!("The model declined to summarise chunk {} due to its policy; leaving it out", i + 1);
+                Ok(String::from(FILTERED_CHUNK_NOTE))
+            }
+            Ok(summary) => {
+                if let Some((cache, key)) = &cached {
+                    if let Err(e) = cache.put(key, GPT_4_MODEL_NAME, &summary) {
+                        diagnostics::warn(format!("couldn't save chunk {} to the chunk cache: {}", i + 1, e));
+                    }
+                }
+
+                Ok(summary)
+            }
+            result => result,
+        }
+    };
+
+    let summarise_tracked_chunk = |i: usize, chunk: &Chunk| -> Result<String, ManifestoError> {
+        let Some(progress) = options.progress else {
+            return summarise_chunk(i, chunk);
+        };
+
+        progress.chunk_started(i);
+        let summary = summarise_chunk(i, chunk)?;
+        progress.chunk_finished(i, transport.total_usage(), transport.models_used());
+
+        Ok(summary)
+    };
+
+    let summarise_all = || pool::map_ordered(&indexed_chunks, options.jobs, |(i, chunk)| {
+        let Some(checkpoint) = options.checkpoint else {
+            check_cancelled(options)?;
+            return summarise_tracked_chunk(*i, chunk);
+        };
+
+        if let Some(summary) = checkpoint.completed_chunk(*i) {
+            return Ok(summary);
+        }
+
+        check_cancelled(options)?;
+        let summary = summarise_tracked_chunk(*i, chunk)?;
+
+        if let Err(e) = checkpoint.record_chunk(*i, &summary, transport.total_usage()) {
+            diagnostics::warn(format!("couldn't save progress: {}", e));
+        }
+
+        Ok(summary)
+    });
+
+    match options.progress {
+        Some(progress) => progress.track(chunks.len(), completed, summarise_all),
+        None => summarise_all(),
+    }
+}
+
+fn check_cancelled(options: &SummaryOptions) -> Result<(), ManifestoError> {
+    match options.cancelled {
+        Some(cancelled) if cancelled.is_cancelled() => Err(ManifestoError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+// How the manifesto will be split up for chunked summaries: along its sections if there's a
+// heading pattern, otherwise purely by size
+pub fn plan_chunks(manifesto: &str, chunk_tokens: usize, options: &SummaryOptions) -> Vec<Chunk> {
+    match options.heading_pattern {
+        Some(heading_pattern) => chunking::split_into_section_chunks(manifesto, heading_pattern, chunk_tokens, !options.per_section),
+        None => chunking::split_into_unlabelled_chunks(manifesto, chunk_tokens),
+    }
+}
+
+// The pipeline's chunks, when they were planned at this size (the automatic fallback to smaller
+// chunks plans its own)
+fn chunks_for(manifesto: &str, chunk_tokens: usize, options: &SummaryOptions) -> Vec<Chunk> {
+    match options.planned_chunks.filter(|_| options.chunk_tokens == Some(chunk_tokens)) {
+        Some(chunks) => chunks.to_vec(),
+        None => plan_chunks(manifesto, chunk_tokens, options),
+    }
+}
+
+// Leaves out every chunk from a section that doesn't look like it's about any of the --topics,
+// reporting how much that saved. If none of them look relevant, the heuristic is assumed to
+// have missed and they're all kept.
+fn skip_off_topic(chunks: Vec<Chunk>, options: &SummaryOptions) -> Vec<Chunk> {
+    if options.topics.is_empty() || !options.skip_off_topic {
+        return chunks;
+    }
+
+    let chunk_count = chunks.len();
+    let (relevant, skipped): (Vec<Chunk>, Vec<Chunk>) = chunks.into_iter()
+        .partition(|chunk| topics::is_relevant(chunk, options.topics));
+
+    if skipped.is_empty() {
+        return relevant;
+    }
+
+    if relevant.is_empty() {
+        eprintln!("None of the chunks look like they're about {}; summarising all of them", topics::format_topics(options.topics));
+        return skipped;
+    }
+
+    let saved_tokens: usize = skipped.iter().map(|chunk| chunking::estimate_tokens(&chunk.text)).sum();
+    eprintln!(
+        "Skipped {} of {} chunks that don't look like they're about {} (~{} tokens saved; --no-skip keeps them)",
+        skipped.len(), chunk_count, topics::format_topics(options.topics), saved_tokens
+    );
+
+    relevant
+}
+
+// The instruction for one chunk. With --topics, only what it says about those is kept, rather
+// than every policy.
+fn chunk_instruction(chunk: &Chunk, topics: &[String]) -> String {
+    if !topics.is_empty() {
+        let part = chunk.label().unwrap_or_else(|| String::from("one part"));
+
+        return format!("The following is {} from a longer manifesto. Please summarise it. {}", part, topics::focus_instruction(topics));
+    }
+
+    match chunk.label() {
+        Some(label) => format!("The following is {} from a longer manifesto. Please summarise it, keeping every policy it mentions:", label),
+        None => String::from(CHUNK_INSTRUCTION),
+    }
+}
+
+// Summarises each section on its own (up to [jobs] at a time), keeping the original headings.
+pub fn summarise_sections(transport: &impl ChatTransport, sections: &[Section], jobs: usize) -> Result<Vec<SectionSummary>, ManifestoError> {
+    pool::map_ordered(sections, jobs, |section| {
+        Ok(SectionSummary {
+            heading: section.heading.clone(),
+            summary: complete_one(transport, SECTION_INSTRUCTION, &section.body)?,
+        })
+    })
+}
+
+pub struct PickedCandidate {
+    // Index into the candidates that were picked from
+    pub index: usize,
+    // The model's explanation of why it picked that candidate
+    pub reasoning: String,
+}
+
+// Asks a cheaper model to choose the best of several candidate summaries. If the reply doesn't
+// say which candidate won, the first one is used.
+pub fn pick_best(transport: &impl ChatTransport, candidates: &[String]) -> Result<PickedCandidate, ManifestoError> {
+    let reply = complete(transport, GPT_35_MODEL_NAME, PICK_BEST_SYSTEM_PROMPT, PICK_BEST_INSTRUCTION, &format_candidates(candidates), 1, false)?
+        .swap_remove(0);
+
+    match parse_pick(&reply, candidates.len()) {
+        Some(index) => Ok(PickedCandidate { index, reasoning: reply }),
+        None => {
+            diagnostics::warn("couldn't tell which candidate was picked; using the first one");
+            Ok(PickedCandidate { index: 0, reasoning: reply })
+        }
+    }
+}
+
+// Lays the candidates out one after another, each under a numbered heading
+pub fn format_candidates(candidates: &[String]) -> String {
+    candidates.iter().enumerate()
+        .map(|(i, candidate)| format!("=== Candidate {} ===\n{}", i + 1, candidate.trim_end()))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+// Finds the "Best: N" line in the reply, returning the zero-based index of candidate N
+fn parse_pick(reply: &str, candidate_count: usize) -> Option<usize> {
+    reply.lines()
+        .find_map(|line| line.trim().strip_prefix("Best:"))
+        .and_then(|n| n.trim().trim_matches(|c: char| !c.is_ascii_digit()).parse::<usize>().ok())
+        .filter(|n| (1..=candidate_count).contains(n))
+        .map(|n| n - 1)
+}
+
+// A critique of a summary, and whether the summary was then revised to address it
+#[derive(Serialize)]
+pub struct Critique {
+    pub text: String,
+    pub revised: bool,
+}
+
+// Asks the model to check [summary] against [source] (the manifesto, or the summaries of its
+// chunks) for claims the source doesn't support and major policies it leaves out
+pub fn critique(transport: &impl ChatTransport, source: &str, summary: &str) -> Result<String, ManifestoError> {
+    let text = format_for_critique(source, summary, None);
+
+    Ok(complete(transport, GPT_4_MODEL_NAME, CRITIQUE_SYSTEM_PROMPT, CRITIQUE_INSTRUCTION, &text, 1, false)?.swap_remove(0))
+}
+
+// Rewrites [summary] to address [critique] (from [critique])
+pub fn revise(transport: &impl ChatTransport, source: &str, summary: &str, critique: &str, options: &SummaryOptions) -> Result<String, ManifestoError> {
+    let text = format_for_critique(source, summary, Some(critique));
+
+    Ok(complete(transport, GPT_4_MODEL_NAME, system_prompt(options), REVISE_INSTRUCTION, &text, 1, false)?.swap_remove(0))
+}
+
+// Asks for the summary again, telling the model what was wrong with [summary] (from the
+// [crate::output_checks]). [problems] is one problem per line.
+pub fn retry(transport: &impl ChatTransport, source: &str, summary: &str, problems: &str, options: &SummaryOptions) -> Result<String, ManifestoError> {
+    let text = format!("{}\n\n=== Problems ===\n{}", format_for_critique(source, summary, None), problems);
+
+    Ok(complete(transport, GPT_4_MODEL_NAME, system_prompt(options), &focused(RETRY_INSTRUCTION, options), &text, 1, false)?.swap_remove(0))
+}
+
+// Lays out everything a critique or revision needs, each under its own heading
+fn format_for_critique(source: &str, summary: &str, critique: Option<&str>) -> String {
+    let mut text = format!("=== Manifesto ===\n{}\n\n=== Summary ===\n{}", source.trim_end(), summary.trim_end());
+
+    if let Some(critique) = critique {
+        text.push_str(&format!("\n\n=== Critique ===\n{}", critique.trim_end()));
+    }
+
+    text
+}
+
+// The default system prompt, asking for [paragraphs] paragraphs rather than four
+pub fn system_prompt_for_paragraphs(paragraphs: usize) -> String {
+    SYSTEM_PROMPT.replace("four-paragraph", &format!("{}-paragraph", paragraphs))
+}
+
+// [instruction], narrowed down to the --topics if there are any
+fn focused(instruction: &str, options: &SummaryOptions) -> String {
+    if options.topics.is_empty() {
+        return String::from(instruction);
+    }
+
+    format!("{}. {}", instruction.trim_end().trim_end_matches([':', '.']), topics::focus_instruction(options.topics))
+}
+
+fn system_prompt<'a>(options: &'a SummaryOptions) -> &'a str {
+    options.prompt_template
+        .and_then(|template| template.system())
+        .or_else(|| options.prompt.and_then(|prompt| prompt.system.as_deref()))
+        .unwrap_or(SYSTEM_PROMPT)
+}
+
+fn complete_one(transport: &impl ChatTransport, instruction: &str, text: &str) -> Result<String, ManifestoError> {
+    Ok(complete(transport, GPT_4_MODEL_NAME, SYSTEM_PROMPT, instruction, text, 1, false)?.swap_remove(0))
+}
+
+// Sends one request and returns the content of every choice (always at least one). With
+// [cache], [text] comes straight after the system prompt (ahead of the instruction) so that
+// requests about the same text share a prefix that OpenAI can cache, and they're tagged with a
+// prompt_cache_key derived from the text.
+pub(crate) fn complete(transport: &impl ChatTransport, model: &str, system_prompt: &str, instruction: &str, text: &str, candidates: u32, cache: bool) -> Result<Vec<String>, ManifestoError> {
+    send(transport, chat_request(model, system_prompt, instruction, text, cache), candidates)
+}
+
+// The request [complete] sends
+fn chat_request(model: &str, system_prompt: &str, instruction: &str, text: &str, cache: bool) -> ChatRequestBuilder {
+    let builder = ChatRequestBuilder::new().model(model).system(system_prompt);
+
+    if cache {
+        builder.user(text).user(instruction).prompt_cache_key(prompt_cache_key(text))
+    } else {
+        builder.user(instruction).user(text)
+    }
+}
+
+// Sends the --prompt-template's user message for [text] (the whole manifesto or one chunk of it)
+fn complete_from_template(transport: &impl ChatTransport, template: &BoundTemplate, options: &SummaryOptions, text: &str, candidates: u32) -> Result<Vec<String>, ManifestoError> {
+    send(transport, template_request(template, options, text), candidates)
+}
+
+// The template's wording is left alone, so --topics are asked for in a message of their own
+fn template_request(template: &BoundTemplate, options: &SummaryOptions, text: &str) -> ChatRequestBuilder {
+    let builder = ChatRequestBuilder::new()
+        .model(GPT_4_MODEL_NAME)
+        .system(system_prompt(options))
+        .user(template.user(text));
+
+    if options.topics.is_empty() {
+        builder
+    } else {
+        builder.user(topics::focus_instruction(options.topics))
+    }
+}
+
+// Sends the request and returns the content of every choice (always at least one)
+fn send(transport: &impl ChatTransport, mut builder: ChatRequestBuilder, candidates: u32) -> Result<Vec<String>, ManifestoError> {
+    if candidates > 1 {
+        builder = builder.n(candidates);
+    }
+
+    let req = builder.build().expect("requests should always have a model and messages");
+
+    choice_contents(transport.post_chat(&req)?)
+}
+
+// Candidates that the content filter stopped are dropped, as long as there are others
+fn choice_contents(resp: OpenAiResponse) -> Result<Vec<String>, ManifestoError> {
+    if resp.choices.is_empty() {
+        return Err(ManifestoError::EmptyResponse);
+    }
+
+    if resp.content_filtered() {
+        return Err(ManifestoError::ContentFiltered);
+    }
+
+    let filtered = resp.choices.iter().filter(|choice| choice.content_filtered()).count();
+
+    if filtered > 0 {
+        eprintln!("The content filter stopped {} of {} candidates; leaving them out", filtered, resp.choices.len());
+    }
+
+    Ok(resp.choices.into_iter()
+        .filter(|choice| !choice.content_filtered())
+        .map(|choice| choice.message.content)
+        .collect())
+}
+
+// Sends the request for chunk [index] and returns its summary. With a [Ledger], the request
+// carries an idempotency key and isn't sent at all if that key already completed in this run.
+// With a --chunk-timeout as well, a request that's slower than that is sent again (with the
+// same key), but only after waiting one more timeout for the original: a slow reply that
+// arrives in that time is used, and the retry is never sent. Past [MAX_CHUNK_RETRIES], it waits
+// for whichever attempt finishes first.
+fn send_chunk(transport: &impl ChatTransport, builder: ChatRequestBuilder, index: usize, options: &SummaryOptions) -> Result<String, ManifestoError> {
+    let Some(ledger) = options.ledger else {
+        return Ok(send(transport, builder, 1)?.swap_remove(0));
+    };
+
+    let mut req = builder.build().expect("requests should always have a model and messages");
+    let key = ledger.key(Some(index), &req);
+    req.idempotency_key = Some(key.clone());
+
+    if let Some(summary) = ledger.completed(&key) {
+        return Ok(summary);
+    }
+
+    let attempt = || -> Result<String, ManifestoError> {
+        let summary = choice_contents(transport.post_chat(&req)?)?.swap_remove(0);
+        ledger.record(&key, &summary);
+
+        Ok(summary)
+    };
+
+    let Some(timeout) = options.chunk_timeout else {
+        return attempt();
+    };
+
+    // Attempts that are still running when an answer comes back are waited for (and their
+    // replies ignored) before this returns, since a scoped thread can't be left behind
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        let mut retries = 0;
+
+        loop {
+            let sender = sender.clone();
+            scope.spawn(move || {
+                let _ = sender.send(attempt());
+            });
+
+            if let Ok(result) = receiver.recv_timeout(timeout) {
+                return result;
+            }
+
+            if retries == MAX_CHUNK_RETRIES {
+                break;
+            }
+
+            eprintln!("Chunk {} took more than {:.1}s; retrying if it isn't back in another {:.1}s", index + 1, timeout.as_secs_f64(), timeout.as_secs_f64());
+
+            if let Ok(result) = receiver.recv_timeout(timeout) {
+                ledger.note_late_reply(&key);
+
+                return result;
+            }
+
+            retries += 1;
+        }
+
+        receiver.recv().expect("every attempt should send its result")
+    })
+}
+
+// Short, but unique enough that different documents don't share a cache key
+fn prompt_cache_key(text: &str) -> String {
+    format!("manifest-o-{}", &state::input_hash(text)[..16])
+}
+
+// The chunk size to retry with after the whole document was too long for the model, given the
+// API's error message: half the model's context, so there's room for the instructions and reply
+pub(crate) fn fallback_chunk_tokens(message: &str) -> usize {
+    max_context_from_error(message)
+        .map_or(DEFAULT_FALLBACK_CHUNK_TOKENS, |max_context| max_context / 2)
+}
+
+// OpenAI's error reads like "This model's maximum context length is 8192 tokens. However, ..."
+fn max_context_from_error(message: &str) -> Option<usize> {
+    let (_, rest) = message.split_once("maximum context length is ")?;
+
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::prices::PriceTable;
+    use crate::transport::{fixtures, MockTransport, ReqwestTransport};
+
+    const TOO_LONG: &str = "This model's maximum context length is 20 tokens. However, your messages resulted in 90 tokens. Please reduce the length of the messages.";
+
+    fn chunked<'a>(chunk_tokens: usize) -> SummaryOptions<'a> {
+        SummaryOptions {
+            chunk_tokens: Some(chunk_tokens),
+            ..SummaryOptions::default()
+        }
+    }
+
+    fn long_manifesto() -> String {
+        (1..=4).map(|i| format!("Policy number {} is a good policy.", i)).collect::<Vec<_>>().join("\n\n")
+    }
+
+    #[test]
+    fn cancelling_abandons_every_chunk_in_flight() {
+        let server = MockServer::start(vec![MockResponse::stalled()]);
+        let cancelled = CancellationToken::new();
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_cancellation(cancelled.clone());
+        let options = SummaryOptions { jobs: 4, ..chunked(10) };
+        let started = Instant::now();
+
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                while server.requests().is_empty() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                cancelled.cancel();
+            });
+
+            summarise(&transport, &long_manifesto(), &options)
+        });
+
+        assert!(matches!(result, Err(ManifestoError::Cancelled)));
+        // None of the chunks waited for a response that was never coming
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn summarises_manifesto_through_transport() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Four paragraphs"));
+
+        let summary = summarise(&transport, "Vote for us", &SummaryOptions::default())
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summary, vec!["Four paragraphs"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["model"], GPT_4_MODEL_NAME);
+        assert_eq!(requests[0]["messages"][2]["content"], "Vote for us");
+    }
+
+    #[test]
+    fn prompt_overrides_replace_the_defaults() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Notes"))
+            .respond(200, &fixtures::chat_completion("Part one"))
+            .respond(200, &fixtures::chat_completion("Part two"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+        let prompt = Prompt {
+            system: Some(String::from("You are terse")),
+            instruction: Some(String::from("Summarise these notes:")),
+        };
+        let options = SummaryOptions { prompt: Some(&prompt), ..SummaryOptions::default() };
+
+        summarise(&transport, "Vote for us", &options).expect("should have summarised the notes");
+        get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &options)
+            .expect("should have summarised the notes");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0]["messages"][0]["content"], "You are terse");
+        assert_eq!(requests[0]["messages"][1]["content"], "Summarise these notes:");
+        // Chunks keep their own instructions, but use the replacement system prompt
+        assert_eq!(requests[1]["messages"][0]["content"], "You are terse");
+        assert_eq!(requests[1]["messages"][1]["content"], CHUNK_INSTRUCTION);
+        assert_eq!(requests[3]["messages"][1]["content"], COMBINE_INSTRUCTION);
+    }
+
+    #[test]
+    fn surfaces_api_errors() {
+        let transport = MockTransport::new()
+            .respond(401, &fixtures::api_error("invalid_api_key", "Incorrect API key provided"));
+
+        match summarise(&transport, "Vote for us", &SummaryOptions::default()) {
+            Err(ManifestoError::Api { status: 401, .. }) => (),
+            _ => panic!("Should have surfaced the API error"),
+        }
+    }
+
+    #[test]
+    fn rejects_responses_without_choices() {
+        let transport = MockTransport::new()
+            .respond(200, r#"{"choices": []}"#);
+
+        match summarise(&transport, "Vote for us", &SummaryOptions::default()) {
+            Err(ManifestoError::EmptyResponse) => (),
+            _ => panic!("Should have rejected the empty response"),
+        }
+    }
+
+    #[test]
+    fn explains_content_filter_refusals() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::content_filtered_chat_completion());
+
+        match summarise(&transport, "Vote for us", &SummaryOptions::default()) {
+            Err(e @ ManifestoError::ContentFiltered) => assert!(e.to_string().contains("declined to summarize this content due to its policy")),
+            _ => panic!("Should have said the content was filtered"),
+        }
+    }
+
+    #[test]
+    fn drops_filtered_candidates_when_others_remain() {
+        let mut response: serde_json::Value = serde_json::from_str(&fixtures::chat_completion_choices(&["One", "Two"])).unwrap();
+        response["choices"][0]["finish_reason"] = serde_json::json!("content_filter");
+        let transport = MockTransport::new().respond(200, &response.to_string());
+        let options = SummaryOptions { candidates: 2, ..SummaryOptions::default() };
+
+        assert_eq!(summarise(&transport, "Vote for us", &options).expect("should have summarised the manifesto"), vec!["Two"]);
+    }
+
+    #[test]
+    fn skip_filtered_leaves_out_declined_chunks() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Part one"))
+            .respond(200, &fixtures::content_filtered_chat_completion())
+            .respond(200, &fixtures::chat_completion("Combined"));
+        let options = SummaryOptions { skip_filtered: true, ..SummaryOptions::default() };
+
+        let summary = get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &options)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summary, vec!["Combined"]);
+        assert_eq!(transport.requests()[2]["messages"][2]["content"], format!("Part one\n\n{}", FILTERED_CHUNK_NOTE));
+
+        // Without it, the run fails
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Part one"))
+            .respond(200, &fixtures::content_filtered_chat_completion());
+
+        match get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &SummaryOptions::default()) {
+            Err(ManifestoError::ContentFiltered) => (),
+            _ => panic!("Should have said the chunk was filtered"),
+        }
+    }
+
+    #[test]
+    fn chunked_summary_combines_chunk_summaries() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Part one"))
+            .respond(200, &fixtures::chat_completion("Part two"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+
+        let summary = get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &SummaryOptions::default())
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summary, vec!["Combined"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["messages"][2]["content"], "First half.\n\n");
+        assert_eq!(requests[1]["messages"][2]["content"], "Second half.");
+        assert_eq!(requests[2]["messages"][1]["content"], COMBINE_INSTRUCTION);
+        assert_eq!(requests[2]["messages"][2]["content"], "Part one\n\nPart two");
+    }
+
+    // Echoes each chunk back as its summary, taking [first_chunk_delay] over the first chunk
+    // ("First half.") the first time it's asked for
+    fn slow_first_chunk_transport(first_chunk_delay: u64) -> MockTransport {
+        let first_chunk_requests = std::sync::atomic::AtomicUsize::new(0);
+
+        MockTransport::with_handler(move |request| {
+            let content = request["messages"][2]["content"].as_str().unwrap().trim().to_string();
+
+            if content == "First half." {
+                let attempt = first_chunk_requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                if attempt == 0 {
+                    std::thread::sleep(Duration::from_millis(first_chunk_delay));
+                }
+
+                return (200, fixtures::chat_completion(&format!("{} (attempt {})", content, attempt + 1)));
+            }
+
+            (200, fixtures::chat_completion(&content))
+        })
+    }
+
+    fn timed_out<'a>(ledger: &'a Ledger, timeout_ms: u64) -> SummaryOptions<'a> {
+        SummaryOptions { ledger: Some(ledger), chunk_timeout: Some(Duration::from_millis(timeout_ms)), ..SummaryOptions::default() }
+    }
+
+    #[test]
+    fn late_chunk_replies_are_used_rather_than_retried() {
+        let ledger = Ledger::new("run", true);
+        // Times out after 200ms, but replies before the retry would go out at 400ms
+        let transport = slow_first_chunk_transport(300);
+
+        let summary = get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &timed_out(&ledger, 200))
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summary, vec!["First half. (attempt 1)\n\nSecond half."]);
+
+        // One paid request per key, and the combining request (which isn't a chunk) has none
+        let keys = transport.idempotency_keys();
+        assert_eq!(keys.len(), 3);
+        assert!(keys[0].is_some() && keys[1].is_some());
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[2], None);
+    }
+
+    #[test]
+    fn retried_chunks_keep_their_key_and_the_late_reply_is_ignored() {
+        let ledger = Ledger::new("run", true);
+        // Times out after 100ms and is retried at 200ms, well before the original replies
+        let transport = slow_first_chunk_transport(600);
+
+        let summary = get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &timed_out(&ledger, 100))
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summary, vec!["First half. (attempt 2)\n\nSecond half."]);
+
+        let keys = transport.idempotency_keys();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[0], keys[1]);
+        assert_eq!(ledger.completed(keys[0].as_deref().unwrap()), Some(String::from("First half. (attempt 2)")));
+    }
+
+    #[test]
+    fn chunks_already_completed_in_the_run_are_not_sent_again() {
+        let ledger = Ledger::new("run", true);
+        let transport = slow_first_chunk_transport(0);
+        let options = SummaryOptions { ledger: Some(&ledger), ..SummaryOptions::default() };
+
+        get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &options)
+            .expect("should have summarised the manifesto");
+        let summary = get_chunked_manifesto_summary(&transport, "First half.\n\nSecond half.", 4, &options)
+            .expect("should have summarised the manifesto again");
+
+        assert_eq!(summary, vec!["First half. (attempt 1)\n\nSecond half."]);
+        // Only the combining request was sent the second time
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[test]
+    fn only_changed_chunks_are_sent_again_with_a_chunk_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::open(dir.path(), &ChatDefaults::default()).unwrap();
+        let options = SummaryOptions { chunk_cache: Some(&cache), ..SummaryOptions::default() };
+        let echo = || MockTransport::with_handler(|request| {
+            (200, fixtures::chat_completion(request["messages"][2]["content"].as_str().unwrap().trim()))
+        });
+
+        let first_run = echo();
+        get_chunked_manifesto_summary(&first_run, "Tunnels.\n\nBridges.\n\nCanals.", 3, &options)
+            .expect("should have summarised the manifesto");
+        assert_eq!(first_run.requests().len(), 4);
+
+        let second_run = echo();
+        let summary = get_chunked_manifesto_summary(&second_run, "Tunnels.\n\nFerries.\n\nCanals.", 3, &options)
+            .expect("should have summarised the edited manifesto");
+
+        // Just the edited chunk and the combining request
+        let requests = second_run.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["messages"][2]["content"], "Ferries.\n\n");
+        assert_eq!(requests[1]["messages"][2]["content"], "Tunnels.\n\nFerries.\n\nCanals.");
+        assert_eq!(summary, vec!["Tunnels.\n\nFerries.\n\nCanals."]);
+        assert_eq!(cache.stats(), (2, 6));
+    }
+
+    #[test]
+    fn chunk_prompts_name_their_sections() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Health part"))
+            .respond(200, &fixtures::chat_completion("Transport part"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+        let heading_pattern = Regex::new(crate::sections::DEFAULT_HEADING_PATTERN).unwrap();
+        let options = SummaryOptions { heading_pattern: Some(&heading_pattern), ..SummaryOptions::default() };
+
+        get_chunked_manifesto_summary(&transport, "# Health\nFree clinics.\n# Transport\nFree buses.", 6, &options)
+            .expect("should have summarised the manifesto");
+
+        let requests = transport.requests();
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            "The following is the section on \"Health\" from a longer manifesto. Please summarise it, keeping every policy it mentions:"
+        );
+        assert_eq!(requests[0]["messages"][2]["content"], "Health\n\nFree clinics.\n\n");
+        assert_eq!(requests[1]["messages"][2]["content"], "Transport\n\nFree buses.\n\n");
+        assert_eq!(requests[2]["messages"][1]["content"], COMBINE_INSTRUCTION);
+    }
+
+    const LABELLED_MANIFESTO: &str = "# Health\nFree clinics.\n# Transport\nFree buses.\n# Housing\nCheap homes.";
+
+    fn topic_options<'a>(heading_pattern: &'a Regex, topics: &'a [String]) -> SummaryOptions<'a> {
+        SummaryOptions { heading_pattern: Some(heading_pattern), topics, skip_off_topic: true, ..SummaryOptions::default() }
+    }
+
+    #[test]
+    fn topic_prompts_name_every_topic() {
+        let transport = MockTransport::new().respond(200, &fixtures::chat_completion("Focused"));
+        let topics = vec![String::from("health"), String::from("cost of living")];
+        let options = SummaryOptions { topics: &topics, ..SummaryOptions::default() };
+
+        summarise(&transport, "Vote for us", &options).expect("should have summarised the manifesto");
+
+        assert_eq!(
+            transport.requests()[0]["messages"][1]["content"],
+            "Please summarise the following manifesto. Only cover what it says about \"health\" and \"cost of living\", leaving out everything else. If it says nothing about them, say so in one sentence."
+        );
+    }
+
+    #[test]
+    fn off_topic_sections_are_skipped() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Health part"))
+            .respond(200, &fixtures::chat_completion("Housing part"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+        let heading_pattern = Regex::new(crate::sections::DEFAULT_HEADING_PATTERN).unwrap();
+        let topics = vec![String::from("health"), String::from("homes")];
+
+        get_chunked_manifesto_summary(&transport, LABELLED_MANIFESTO, 6, &topic_options(&heading_pattern, &topics))
+            .expect("should have summarised the manifesto");
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            "The following is the section on \"Health\" from a longer manifesto. Please summarise it. Only cover what it says about \"health\" and \"homes\", leaving out everything else. If it says nothing about them, say so in one sentence."
+        );
+        assert_eq!(requests[1]["messages"][2]["content"], "Housing\n\nCheap homes.\n\n");
+        assert!(requests[2]["messages"][1]["content"].as_str().unwrap().starts_with("The following are summaries of consecutive parts of one manifesto. Please combine them into a single summary of the whole manifesto. Only cover"));
+        assert_eq!(requests[2]["messages"][2]["content"], "Health part\n\nHousing part");
+    }
+
+    #[test]
+    fn no_skip_summarises_every_section() {
+        let transport = MockTransport::with_handler(|_| (200, fixtures::chat_completion("Part")));
+        let heading_pattern = Regex::new(crate::sections::DEFAULT_HEADING_PATTERN).unwrap();
+        let topics = vec![String::from("health")];
+        let options = SummaryOptions { skip_off_topic: false, ..topic_options(&heading_pattern, &topics) };
+
+        get_chunked_manifesto_summary(&transport, LABELLED_MANIFESTO, 6, &options)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[test]
+    fn every_chunk_is_kept_when_none_look_relevant() {
+        let transport = MockTransport::with_handler(|_| (200, fixtures::chat_completion("Part")));
+        let heading_pattern = Regex::new(crate::sections::DEFAULT_HEADING_PATTERN).unwrap();
+        let topics = vec![String::from("defence")];
+
+        get_chunked_manifesto_summary(&transport, LABELLED_MANIFESTO, 6, &topic_options(&heading_pattern, &topics))
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[test]
+    fn per_section_summaries_join_the_parts_of_long_sections() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Intro"))
+            .respond(200, &fixtures::chat_completion("Health part 1"))
+            .respond(200, &fixtures::chat_completion("Health part 2"))
+            .respond(200, &fixtures::chat_completion("Transport"))
+            .respond(200, &fixtures::chat_completion("Overview"));
+        let heading_pattern = Regex::new(crate::sections::DEFAULT_HEADING_PATTERN).unwrap();
+        let options = SummaryOptions { heading_pattern: Some(&heading_pattern), ..SummaryOptions::default() };
+        let manifesto = "Vote for us.\n# Health\nFree clinics in every single town.\nMore nurses in every hospital.\n# Transport\nFree buses.";
+
+        let (overview, sections) = summarise_per_section(&transport, manifesto, 12, &options)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(overview, vec!["Overview"]);
+        assert_eq!(sections.len(), 2);
+        assert_eq!((sections[0].heading.as_str(), sections[0].summary.as_str()), ("Health", "Health part 1\n\nHealth part 2"));
+        assert_eq!((sections[1].heading.as_str(), sections[1].summary.as_str()), ("Transport", "Transport"));
+        assert_eq!(transport.requests()[4]["messages"][2]["content"], "Intro\n\nHealth part 1\n\nHealth part 2\n\nTransport");
+    }
+
+    #[test]
+    fn falls_back_to_chunking_when_context_is_exceeded() {
+        let manifesto = long_manifesto();
+        // The error reports a 20 token context, so the retry uses 10 token chunks: one per policy
+        let transport = MockTransport::new()
+            .respond(400, &fixtures::api_error(CONTEXT_LENGTH_EXCEEDED, TOO_LONG))
+            .respond(200, &fixtures::chat_completion("One"))
+            .respond(200, &fixtures::chat_completion("Two"))
+            .respond(200, &fixtures::chat_completion("Three"))
+            .respond(200, &fixtures::chat_completion("Four"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+
+        let summary = summarise(&transport, &manifesto, &SummaryOptions::default())
+            .expect("should have recovered by chunking");
+
+        assert_eq!(summary, vec!["Combined"]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(requests[0]["messages"][2]["content"], manifesto.as_str());
+        assert_eq!(requests[1]["messages"][1]["content"], CHUNK_INSTRUCTION);
+    }
+
+    #[test]
+    fn shrinks_chunks_when_already_chunking() {
+        let manifesto = long_manifesto();
+        let transport = MockTransport::new()
+            .respond(400, &fixtures::api_error(CONTEXT_LENGTH_EXCEEDED, TOO_LONG))
+            .respond(200, &fixtures::chat_completion("One"))
+            .respond(200, &fixtures::chat_completion("Two"))
+            .respond(200, &fixtures::chat_completion("Three"))
+            .respond(200, &fixtures::chat_completion("Four"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+
+        // 20 token chunks fit two policies each, and halving that gives one policy per chunk
+        let summary = summarise(&transport, &manifesto, &chunked(20))
+            .expect("should have recovered with smaller chunks");
+
+        assert_eq!(summary, vec!["Combined"]);
+        assert_eq!(transport.requests().len(), 6);
+    }
+
+    #[test]
+    fn gives_up_after_shrinking_once() {
+        let transport = MockTransport::new()
+            .respond(400, &fixtures::api_error(CONTEXT_LENGTH_EXCEEDED, TOO_LONG))
+            .respond(400, &fixtures::api_error(CONTEXT_LENGTH_EXCEEDED, TOO_LONG));
+
+        match summarise(&transport, &long_manifesto(), &chunked(20)) {
+            Err(ManifestoError::ContextLengthExceeded(_)) => (),
+            _ => panic!("Should have given up with a clear error"),
+        }
+    }
+
+    #[test]
+    fn reads_max_context_from_error() {
+        assert_eq!(max_context_from_error(TOO_LONG), Some(20));
+        assert_eq!(max_context_from_error("Something else went wrong"), None);
+    }
+
+    #[test]
+    fn parallel_chunks_are_reassembled_in_order() {
+        let manifesto = (1..=8).map(|i| format!("Policy {}", i)).collect::<Vec<_>>().join("\n\n");
+        let transport = MockTransport::with_handler(|request| {
+            let content = request["messages"][2]["content"].as_str().unwrap().trim().to_string();
+
+            // Make the earlier chunks slower so that they finish last
+            if let Some(n) = content.strip_prefix("Policy ").and_then(|n| n.parse::<u64>().ok()) {
+                std::thread::sleep(std::time::Duration::from_millis(40 - n * 5));
+                (200, fixtures::chat_completion(&format!("Summary {}", n)))
+            } else {
+                (200, fixtures::chat_completion(&content))
+            }
+        });
+        let options = SummaryOptions { chunk_tokens: Some(3), jobs: 3, ..SummaryOptions::default() };
+
+        let summary = summarise(&transport, &manifesto, &options)
+            .expect("should have summarised the manifesto");
+
+        let expected = (1..=8).map(|i| format!("Summary {}", i)).collect::<Vec<_>>().join("\n\n");
+        assert_eq!(summary, vec![expected]);
+        assert_eq!(transport.requests().len(), 9);
+        assert_eq!(transport.max_in_flight(), 3);
+    }
+
+    #[test]
+    fn asks_for_every_candidate() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion_choices(&["One", "Two", "Three"]));
+        let options = SummaryOptions { candidates: 3, ..SummaryOptions::default() };
+
+        let summaries = summarise(&transport, "Vote for us", &options)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summaries, vec!["One", "Two", "Three"]);
+        assert_eq!(transport.requests()[0]["n"], 3);
+        assert_eq!(transport.total_usage().completion_tokens, 60);
+    }
+
+    #[test]
+    fn single_candidate_requests_leave_n_out() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("One"));
+
+        summarise(&transport, "Vote for us", &SummaryOptions::default())
+            .expect("should have summarised the manifesto");
+
+        assert!(transport.requests()[0].get("n").is_none());
+    }
+
+    #[test]
+    fn only_the_combine_step_has_multiple_candidates() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Part one"))
+            .respond(200, &fixtures::chat_completion("Part two"))
+            .respond(200, &fixtures::chat_completion_choices(&["Combined one", "Combined two"]));
+        let options = SummaryOptions { chunk_tokens: Some(4), candidates: 2, ..SummaryOptions::default() };
+
+        let summaries = summarise(&transport, "First half.\n\nSecond half.", &options)
+            .expect("should have summarised the manifesto");
+
+        assert_eq!(summaries, vec!["Combined one", "Combined two"]);
+
+        let requests = transport.requests();
+        assert!(requests[0].get("n").is_none());
+        assert_eq!(requests[2]["n"], 2);
+        assert_eq!(transport.total_usage().completion_tokens, 80);
+    }
+
+    #[test]
+    fn formats_candidates_with_headings() {
+        let formatted = format_candidates(&[String::from("First\n"), String::from("Second")]);
+
+        assert_eq!(formatted, "=== Candidate 1 ===\nFirst\n\n=== Candidate 2 ===\nSecond");
+    }
+
+    #[test]
+    fn pick_best_sends_candidates_with_rubric() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Best: 2\nIt covers more policies."));
+        let candidates = vec![String::from("First"), String::from("Second")];
+
+        let picked = pick_best(&transport, &candidates)
+            .expect("should have picked a candidate");
+
+        assert_eq!(picked.index, 1);
+        assert_eq!(picked.reasoning, "Best: 2\nIt covers more policies.");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request["model"], GPT_35_MODEL_NAME);
+        assert_eq!(request["messages"][1]["content"], PICK_BEST_INSTRUCTION);
+        assert_eq!(request["messages"][2]["content"], format_candidates(&candidates).as_str());
+    }
+
+    #[test]
+    fn pick_best_falls_back_to_first_candidate() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("They're all great"));
+        let candidates = vec![String::from("First"), String::from("Second")];
+
+        let picked = pick_best(&transport, &candidates)
+            .expect("should have picked a candidate");
+
+        assert_eq!(picked.index, 0);
+    }
+
+    #[test]
+    fn critique_sends_the_source_and_the_summary() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("The summary invents a tax cut."));
+
+        let critique = critique(&transport, "We will build trains.", "They'll build trains and cut taxes.")
+            .expect("should have critiqued the summary");
+
+        assert_eq!(critique, "The summary invents a tax cut.");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request["messages"][0]["content"], CRITIQUE_SYSTEM_PROMPT);
+        assert_eq!(request["messages"][1]["content"], CRITIQUE_INSTRUCTION);
+        assert_eq!(
+            request["messages"][2]["content"],
+            "=== Manifesto ===\nWe will build trains.\n\n=== Summary ===\nThey'll build trains and cut taxes."
+        );
+    }
+
+    #[test]
+    fn revise_sends_the_critique_too() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("They'll build trains."));
+
+        let revised = revise(&transport, "We will build trains.", "Trains and tax cuts.", "No tax cuts.", &SummaryOptions::default())
+            .expect("should have revised the summary");
+
+        assert_eq!(revised, "They'll build trains.");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request["messages"][1]["content"], REVISE_INSTRUCTION);
+        assert!(request["messages"][2]["content"].as_str().unwrap().ends_with("=== Critique ===\nNo tax cuts."));
+    }
+
+    #[test]
+    fn parses_picks() {
+        assert_eq!(parse_pick("Best: 3\nBecause", 3), Some(2));
+        assert_eq!(parse_pick("Thinking...\n  Best: **1**", 3), Some(0));
+        assert_eq!(parse_pick("Best: 4", 3), None);
+        assert_eq!(parse_pick("Best: 0", 3), None);
+        assert_eq!(parse_pick("No idea", 3), None);
+    }
+
+    #[test]
+    fn summarises_each_section() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("About clinics"))
+            .respond(200, &fixtures::chat_completion("About factories"));
+        let sections = vec![
+            Section { heading: String::from("Healthcare"), body: String::from("Clinics.") },
+            Section { heading: String::from("Jobs"), body: String::from("Factories.") },
+        ];
+
+        let summaries = summarise_sections(&transport, &sections, 1)
+            .expect("should have summarised every section");
+
+        assert_eq!(summaries[0].heading, "Healthcare");
+        assert_eq!(summaries[0].summary, "About clinics");
+        assert_eq!(summaries[1].heading, "Jobs");
+        assert_eq!(summaries[1].summary, "About factories");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0]["messages"][1]["content"], SECTION_INSTRUCTION);
+        assert_eq!(requests[1]["messages"][2]["content"], "Factories.");
+    }
+
+    #[test]
+    fn progress_counts_every_chunk_and_its_tokens() {
+        let manifesto = long_manifesto();
+        let transport = MockTransport::with_handler(|_| (200, fixtures::chat_completion("Summary")));
+        let prices = PriceTable::default();
+        let progress = Progress::new(false, &prices);
+        let options = SummaryOptions { jobs: 3, progress: Some(&progress), ..chunked(10) };
+
+        summarise(&transport, &manifesto, &options).expect("should have summarised the manifesto");
+
+        let state = progress.state();
+        assert_eq!((state.completed, state.total), (4, 4));
+        assert!(state.in_flight.is_empty());
+
+        // The combining request comes after the chunks, so it isn't counted
+        assert_eq!(state.usage.completion_tokens * 5, transport.total_usage().completion_tokens * 4);
+        assert!(progress.estimated_cost_usd().is_some_and(|cost| cost > 0.0));
+    }
+
+    #[test]
+    fn progress_stops_counting_at_a_failed_chunk() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("One"))
+            .respond(500, &fixtures::api_error("server_error", "Oops"));
+        let prices = PriceTable::default();
+        let progress = Progress::new(false, &prices);
+        let options = SummaryOptions { progress: Some(&progress), ..chunked(10) };
+
+        assert!(summarise(&transport, &long_manifesto(), &options).is_err());
+
+        let state = progress.state();
+        assert_eq!((state.completed, state.total), (1, 4));
+        assert_eq!(state.in_flight.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(state.usage, transport.total_usage());
+    }
+
+    #[test]
+    fn resumed_runs_only_request_the_remaining_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifesto.state.json");
+        let manifesto = long_manifesto();
+
+        let failing = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("One"))
+            .respond(200, &fixtures::chat_completion("Two"))
+            .respond(500, &fixtures::api_error("server_error", "Oops"));
+        let checkpoint = Checkpoint::open(path.clone(), &manifesto, GPT_4_MODEL_NAME, false);
+        let options = SummaryOptions { checkpoint: Some(&checkpoint), ..chunked(10) };
+
+        if summarise(&failing, &manifesto, &options).is_ok() {
+            panic!("Should have failed on the third chunk");
+        }
+
+        let resuming = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Three"))
+            .respond(200, &fixtures::chat_completion("Four"))
+            .respond(200, &fixtures::chat_completion("Combined"));
+        let checkpoint = Checkpoint::open(path.clone(), &manifesto, GPT_4_MODEL_NAME, true);
+        let prices = PriceTable::default();
+        let progress = Progress::new(false, &prices);
+        let options = SummaryOptions { checkpoint: Some(&checkpoint), progress: Some(&progress), ..chunked(10) };
+
+        let summary = summarise(&resuming, &manifesto, &options)
+            .expect("should have finished the remaining chunks");
+
+        assert_eq!(summary, vec!["Combined"]);
+        assert_eq!((progress.state().completed, progress.state().total), (4, 4));
+        assert_eq!(checkpoint.resumed_usage().completion_tokens, 40);
+
+        let requests = resuming.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["messages"][2]["content"], "Policy number 3 is a good policy.\n\n");
+        assert_eq!(requests[2]["messages"][2]["content"], "One\n\nTwo\n\nThree\n\nFour");
+    }
+}
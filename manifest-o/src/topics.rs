@@ -0,0 +1,99 @@
+// Topic-focused summaries (--topic): the prompts ask for only what the manifesto says about the
+// topics, and chunks from sections that don't look like they're about any of them are skipped
+// rather than paid for.
+//
+// Whether a chunk looks relevant is a cheap guess. A chunk is relevant if a word in its section
+// headings or its text starts with one of the topics' words ("tax" matches "taxes" and
+// "taxation"). It's only a guess, so a chunk is only ever skipped when it's from a named section,
+// and --no-skip turns skipping off entirely.
+
+use crate::chunking::Chunk;
+
+// Topic words shorter than this (like "of" in "cost of living") say nothing about the topic
+const MIN_KEYWORD_LEN: usize = 3;
+
+// The topics as a phrase, e.g. `"health", "transport" and "housing"`
+pub fn format_topics(topics: &[String]) -> String {
+    let quoted: Vec<String> = topics.iter().map(|topic| format!("\"{}\"", topic)).collect();
+
+    match quoted.as_slice() {
+        [] => String::new(),
+        [topic] => topic.clone(),
+        [init @ .., last] => format!("{} and {}", init.join(", "), last),
+    }
+}
+
+// Added to each summary instruction to keep the summary to the topics
+pub fn focus_instruction(topics: &[String]) -> String {
+    format!(
+        "Only cover what it says about {}, leaving out everything else. If it says nothing about them, say so in one sentence.",
+        format_topics(topics)
+    )
+}
+
+// Whether [chunk] looks like it's about any of [topics]. Chunks that aren't from any named
+// section are always kept.
+pub fn is_relevant(chunk: &Chunk, topics: &[String]) -> bool {
+    if chunk.sections.is_empty() {
+        return true;
+    }
+
+    let keywords: Vec<String> = topics.iter().flat_map(|topic| words(topic)).filter(|word| word.len() >= MIN_KEYWORD_LEN).collect();
+
+    // A topic with no usable words can't be scanned for, so nothing is skipped for it
+    if keywords.is_empty() {
+        return true;
+    }
+
+    chunk.sections.iter().map(String::as_str).chain([chunk.text.as_str()])
+        .flat_map(words)
+        .any(|word| keywords.iter().any(|keyword| word.starts_with(keyword.as_str())))
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn topics(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    fn chunk(sections: &[&str], text: &str) -> Chunk {
+        Chunk { text: String::from(text), sections: topics(sections), part: None }
+    }
+
+    #[test]
+    fn formats_one_or_more_topics() {
+        assert_eq!(format_topics(&topics(&["health"])), "\"health\"");
+        assert_eq!(format_topics(&topics(&["health", "transport", "housing"])), "\"health\", \"transport\" and \"housing\"");
+    }
+
+    #[test]
+    fn sections_matching_a_topic_are_relevant() {
+        let topics = topics(&["Health", "tax"]);
+
+        assert!(is_relevant(&chunk(&["Healthcare"], "Free clinics."), &topics));
+        assert!(is_relevant(&chunk(&["Our economy"], "We will cut taxes."), &topics));
+        assert!(!is_relevant(&chunk(&["Transport"], "Free buses."), &topics));
+    }
+
+    #[test]
+    fn chunks_outside_any_section_are_kept() {
+        assert!(is_relevant(&chunk(&[], "Vote for us."), &topics(&["health"])));
+    }
+
+    #[test]
+    fn short_topic_words_are_ignored() {
+        let topics = topics(&["cost of living"]);
+
+        assert!(!is_relevant(&chunk(&["Transport"], "Free buses of all kinds."), &topics));
+        assert!(is_relevant(&chunk(&["Prices"], "Living costs will fall."), &topics));
+        assert!(is_relevant(&chunk(&["Transport"], "Free buses."), &self::topics(&["EU"])));
+    }
+}
@@ -0,0 +1,129 @@
+// Publishes a summary in more than one language (--language en,cy,pl). The first language is the
+// summary's own; it's then translated into each of the others, one request per language. Only
+// the summary is sent, never the manifesto, so each language costs a fraction of summarising
+// again. A translation that fails doesn't stop the others.
+
+use crate::error::ManifestoError;
+use crate::open_ai::GPT_4_MODEL_NAME;
+use crate::pool;
+use crate::summary;
+use crate::transport::ChatTransport;
+
+const TRANSLATION_SYSTEM_PROMPT: &str = "You are a translator who works on political writing for a general audience";
+
+// The names the model is told to translate into. Any other code is passed on as it is.
+const LANGUAGE_NAMES: [(&str, &str); 12] = [
+    ("cy", "Welsh"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("ga", "Irish"),
+    ("gd", "Scottish Gaelic"),
+    ("it", "Italian"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("uk", "Ukrainian"),
+];
+
+// One language's version of the summary, or why there isn't one
+pub struct Translation {
+    pub language: String,
+    pub text: Result<String, ManifestoError>,
+}
+
+// Parses a comma-separated list of language codes, e.g. "en,cy,pl"
+pub fn parse_languages(list: &str) -> Result<Vec<String>, &'static str> {
+    let mut languages: Vec<String> = Vec::new();
+
+    for code in list.split(',').map(|code| code.trim().to_lowercase()) {
+        if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphabetic() || c == '-') {
+            return Err("--language needs a comma-separated list of language codes, e.g. en,cy,pl");
+        }
+
+        if languages.contains(&code) {
+            return Err("--language lists the same language more than once");
+        }
+
+        languages.push(code);
+    }
+
+    Ok(languages)
+}
+
+// e.g. "Welsh (cy)", or just the code for languages without a known name
+pub fn describe(code: &str) -> String {
+    match LANGUAGE_NAMES.iter().find(|(known, _)| *known == code) {
+        Some((_, name)) => format!("{} ({})", name, code),
+        None => String::from(code),
+    }
+}
+
+fn instruction(code: &str) -> String {
+    let language = match LANGUAGE_NAMES.iter().find(|(known, _)| *known == code) {
+        Some((_, name)) => String::from(*name),
+        None => format!("the language with the code \"{}\"", code),
+    };
+
+    format!(
+        "Translate the following summary of a manifesto into {}. Keep its paragraphs and meaning exactly as they are, without adding, leaving out or commenting on anything. Reply with only the translation:",
+        language
+    )
+}
+
+// Translates [summary] into each of [languages], up to [jobs] at a time, in the same order
+pub fn translate(transport: &impl ChatTransport, summary: &str, languages: &[String], jobs: usize) -> Vec<Translation> {
+    let translated: Result<Vec<Translation>, ()> = pool::map_ordered(languages, jobs, |language| {
+        let text = summary::complete(transport, GPT_4_MODEL_NAME, TRANSLATION_SYSTEM_PROMPT, &instruction(language), summary, 1, false)
+            .map(|mut texts| texts.swap_remove(0));
+
+        Ok(Translation { language: language.clone(), text })
+    });
+
+    translated.expect("translations are never an Err, so that one failing doesn't stop the rest")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{fixtures, MockTransport};
+
+    #[test]
+    fn parses_comma_separated_codes() {
+        assert_eq!(parse_languages("en, CY,pl").unwrap(), vec!["en", "cy", "pl"]);
+        assert!(parse_languages("en,,cy").is_err());
+        assert!(parse_languages("en,cy,en").is_err());
+        assert!(parse_languages("en;cy").is_err());
+    }
+
+    #[test]
+    fn sends_the_summary_to_be_translated_into_each_language() {
+        let transport = MockTransport::new()
+            .respond(200, &fixtures::chat_completion("Twneli i bawb"))
+            .respond(200, &fixtures::chat_completion("Tunele dla wszystkich"));
+
+        let translations = translate(&transport, "Tunnels for everyone", &[String::from("cy"), String::from("pl")], 1);
+
+        assert_eq!(translations[0].language, "cy");
+        assert_eq!(translations[0].text.as_deref().unwrap(), "Twneli i bawb");
+        assert_eq!(translations[1].text.as_deref().unwrap(), "Tunele dla wszystkich");
+
+        let requests = transport.requests();
+        assert!(requests[0]["messages"][1]["content"].as_str().unwrap().contains("into Welsh"));
+        assert!(requests[1]["messages"][1]["content"].as_str().unwrap().contains("into Polish"));
+        assert!(requests.iter().all(|request| request["messages"][2]["content"] == "Tunnels for everyone"));
+    }
+
+    #[test]
+    fn one_failed_translation_keeps_the_others() {
+        let transport = MockTransport::new()
+            .respond(500, &fixtures::api_error("server_error", "Something broke"))
+            .respond(200, &fixtures::chat_completion("Tunele dla wszystkich"));
+
+        let translations = translate(&transport, "Tunnels for everyone", &[String::from("cy"), String::from("pl")], 1);
+
+        assert!(translations[0].text.is_err());
+        assert_eq!(translations[1].text.as_deref().unwrap(), "Tunele dla wszystkich");
+    }
+}
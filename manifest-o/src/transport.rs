@@ -0,0 +1,1117 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use crate::diagnostics;
+use crate::error::ManifestoError;
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::key_rotation::KeyRotation;
+use crate::log_with_request_id;
+use crate::model_fallback::ModelUsage;
+use crate::open_ai::*;
+use crate::prompt_log::PromptLog;
+use crate::request_log::{RequestLog, RequestRecord};
+use crate::rate_limits::RateLimits;
+use crate::runtime;
+
+// How many times a rate-limited (429) request is retried before giving up
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+// Used when a 429 doesn't come with a retry-after header, doubling on each retry
+const INITIAL_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+// How long to wait before retrying a request that got no response (--net-retries), doubling on
+// each retry. Dropped connections tend to come back quickly, so this is much shorter than the
+// rate limit backoff.
+const INITIAL_NET_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// Comfortably more than any model's context window, even as JSON
+pub const DEFAULT_MAX_REQUEST_BYTES: u64 = 2 * 1024 * 1024;
+// Room for a big batch of embeddings, which are the longest replies OpenAI sends
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub(crate) const CHAT_PATH: &str = "/chat/completions";
+pub(crate) const MODERATIONS_PATH: &str = "/moderations";
+pub(crate) const EMBEDDINGS_PATH: &str = "/embeddings";
+// The header OpenAI puts its ID for each request in
+const OPENAI_REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Sends requests to OpenAI and hands back the parsed responses. Everything that talks to the
+// API goes through this so that it can be tested without the network. Transports are shared
+// between the threads used for --jobs.
+pub trait ChatTransport: Sync {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError>;
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError>;
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError>;
+
+    // The rate limit budget reported alongside the most recent response, if any
+    fn last_rate_limits(&self) -> Option<RateLimits> {
+        None
+    }
+
+    // The tokens used by every successful response so far
+    fn total_usage(&self) -> OpenAiUsage {
+        OpenAiUsage::default()
+    }
+
+    // How many chat replies so far were cut off for being too long
+    fn cut_off_replies(&self) -> u32 {
+        0
+    }
+
+    // Every distinct system_fingerprint the chat replies so far came with, in the order they
+    // were first seen. More than one means the backend changed part way through the run.
+    fn system_fingerprints(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // The time spent waiting on requests so far
+    fn total_request_duration(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    // Every model that answered a chat request so far, with its share of the usage, for
+    // transports that keep track (see [crate::model_fallback]). Empty otherwise.
+    fn models_used(&self) -> Vec<ModelUsage> {
+        Vec::new()
+    }
+}
+
+// Lets main pick a transport at runtime (real, recording or replaying)
+impl ChatTransport for Box<dyn ChatTransport> {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        (**self).post_chat(body)
+    }
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        (**self).post_moderation(body)
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        (**self).post_embeddings(body)
+    }
+
+    fn last_rate_limits(&self) -> Option<RateLimits> {
+        (**self).last_rate_limits()
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        (**self).total_usage()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        (**self).cut_off_replies()
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        (**self).system_fingerprints()
+    }
+
+    fn total_request_duration(&self) -> Duration {
+        (**self).total_request_duration()
+    }
+
+    fn models_used(&self) -> Vec<ModelUsage> {
+        (**self).models_used()
+    }
+}
+
+// Hard limits on the bodies going each way (--max-request-bytes and --max-response-bytes). A
+// request over its limit is never sent, since it can only be a chunking bug that would hang for
+// minutes before OpenAI rejected it, and a response is only read up to its limit, so that a
+// misbehaving server can't run us out of memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeLimits {
+    pub max_request_bytes: u64,
+    pub max_response_bytes: u64,
+}
+
+impl Default for SizeLimits {
+    fn default() -> SizeLimits {
+        SizeLimits { max_request_bytes: DEFAULT_MAX_REQUEST_BYTES, max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES }
+    }
+}
+
+// Sends requests asynchronously on the [runtime], waiting on each one from the calling thread so
+// that it can be used like any other transport
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+    base_url: String,
+    request_id: Option<String>,
+    verbose: bool,
+    last_rate_limits: Mutex<Option<RateLimits>>,
+    total_usage: Mutex<OpenAiUsage>,
+    cut_off_replies: AtomicU32,
+    system_fingerprints: Mutex<Vec<String>>,
+    total_request_duration: Mutex<Duration>,
+    // When a 429 asks us to wait, every thread holds off until then, not just the one that got it.
+    // Shared with every other transport given it by [with_shared_pause].
+    paused_until: Arc<Mutex<Option<Instant>>>,
+    // Where every request is recorded before it's sent (--save-prompt)
+    prompt_log: Option<Arc<PromptLog>>,
+    // Where every request's outcome is recorded for the --run-report
+    request_log: Option<Arc<RequestLog>>,
+    // Filled in on every chat request before it's sent
+    chat_defaults: ChatDefaults,
+    size_limits: SizeLimits,
+    // Once cancelled, requests in flight are abandoned and no more are sent
+    cancelled: CancellationToken,
+    // Set with more than one --key-file: requests take turns with each key rather than using
+    // the client's
+    key_rotation: Option<Arc<KeyRotation>>,
+    // How many times a request that got no response at all is retried (--net-retries)
+    net_retries: u32,
+}
+
+// What came back from one attempt at a request
+struct RawResponse {
+    status: u16,
+    rate_limits: RateLimits,
+    // OpenAI's ID for the request
+    openai_request_id: Option<String>,
+    text: String,
+}
+
+// Why an attempt at a request didn't get a whole response
+enum AttemptError {
+    // The connection failed (refused, reset, DNS or TLS trouble). [sent] is whether the request
+    // might have reached OpenAI; only a failure to connect means that it certainly didn't.
+    Network { message: String, sent: bool },
+    Failed(ManifestoError),
+}
+
+impl From<AttemptError> for ManifestoError {
+    fn from(error: AttemptError) -> ManifestoError {
+        match error {
+            AttemptError::Network { message, .. } => ManifestoError::Http(message),
+            AttemptError::Failed(e) => e,
+        }
+    }
+}
+
+impl ReqwestTransport {
+    pub fn new(client: reqwest::Client, base_url: &str, request_id: Option<String>, verbose: bool) -> ReqwestTransport {
+        ReqwestTransport {
+            client,
+            base_url: String::from(base_url),
+            request_id,
+            verbose,
+            last_rate_limits: Mutex::new(None),
+            total_usage: Mutex::new(OpenAiUsage::default()),
+            cut_off_replies: AtomicU32::new(0),
+            system_fingerprints: Mutex::new(Vec::new()),
+            total_request_duration: Mutex::new(Duration::ZERO),
+            paused_until: Arc::new(Mutex::new(None)),
+            prompt_log: None,
+            request_log: None,
+            chat_defaults: ChatDefaults::default(),
+            size_limits: SizeLimits::default(),
+            cancelled: CancellationToken::new(),
+            key_rotation: None,
+            net_retries: 0,
+        }
+    }
+
+    pub fn with_request_log(mut self, request_log: Option<Arc<RequestLog>>) -> ReqwestTransport {
+        self.request_log = request_log;
+        self
+    }
+
+    pub fn with_prompt_log(mut self, prompt_log: Option<Arc<PromptLog>>) -> ReqwestTransport {
+        self.prompt_log = prompt_log;
+        self
+    }
+
+    // Makes this transport hold off after a 429 for as long as any other transport sharing
+    // [paused_until] was asked to, e.g. for every file of a --watch or --batch run
+    pub fn with_shared_pause(mut self, paused_until: Arc<Mutex<Option<Instant>>>) -> ReqwestTransport {
+        self.paused_until = paused_until;
+        self
+    }
+
+    pub fn with_chat_defaults(mut self, chat_defaults: ChatDefaults) -> ReqwestTransport {
+        self.chat_defaults = chat_defaults;
+        self
+    }
+
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> ReqwestTransport {
+        self.size_limits = size_limits;
+        self
+    }
+
+    // Abandons requests in flight, and refuses to send any more, once [cancelled] is cancelled
+    pub fn with_cancellation(mut self, cancelled: CancellationToken) -> ReqwestTransport {
+        self.cancelled = cancelled;
+        self
+    }
+
+    pub fn with_key_rotation(mut self, key_rotation: Option<Arc<KeyRotation>>) -> ReqwestTransport {
+        self.key_rotation = key_rotation;
+        self
+    }
+
+    pub fn with_net_retries(mut self, net_retries: u32) -> ReqwestTransport {
+        self.net_retries = net_retries;
+        self
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        let paused_until = *self.paused_until.lock().unwrap();
+
+        if let Some(wait) = paused_until.and_then(|until| until.checked_duration_since(Instant::now())) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn pause_for(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut paused_until = self.paused_until.lock().unwrap();
+
+        if paused_until.is_none_or(|current| current < until) {
+            *paused_until = Some(until);
+        }
+    }
+
+    // Rate-limited requests are retried, waiting for as long as the retry-after header asks
+    // (or with exponential backoff if it's missing). So are requests that got no response (up to
+    // [net_retries] times), as long as they can't have been sent or have an [idempotency_key]
+    // that stops OpenAI acting on them twice. Retries send exactly the same body (and key), so
+    // the request is only recorded in the prompt log once.
+    fn post<B: Serialize, R: DeserializeOwned>(&self, path: &str, body: &B, idempotency_key: Option<&str>) -> Result<R, ManifestoError> {
+        if self.cancelled.is_cancelled() {
+            return Err(ManifestoError::Cancelled);
+        }
+
+        let json = serde_json::to_vec(body).map_err(|e| ManifestoError::Http(e.to_string()))?;
+
+        if json.len() as u64 > self.size_limits.max_request_bytes {
+            return Err(ManifestoError::RequestTooLarge { bytes: json.len() as u64, limit: self.size_limits.max_request_bytes });
+        }
+
+        if let Some(prompt_log) = &self.prompt_log {
+            prompt_log.record(&format!("{}{}", self.base_url, path), body)
+                .map_err(|e| ManifestoError::PromptLog(e.to_string()))?;
+        }
+
+        let model = self.request_log.as_ref()
+            .and_then(|_| serde_json::to_value(body).ok())
+            .and_then(|body| body["model"].as_str().map(String::from));
+        let mut record = RequestRecord::start(path, model);
+
+        let result = runtime::block_on(async {
+            tokio::select! {
+                result = self.post_with_retries(path, json, idempotency_key, &mut record) => result,
+                _ = self.cancelled.cancelled() => Err(ManifestoError::Cancelled),
+            }
+        });
+
+        if let Some(request_log) = &self.request_log {
+            // OpenAI's error messages can quote the request, so only its code is kept
+            record.error = match &result {
+                Ok(_) => None,
+                Err(ManifestoError::Api { code, .. }) => code.clone(),
+                Err(e) => Some(e.to_string()),
+            };
+
+            request_log.record(record);
+        }
+
+        result
+    }
+
+    async fn post_with_retries<R: DeserializeOwned>(&self, path: &str, json: Vec<u8>, idempotency_key: Option<&str>, record: &mut RequestRecord) -> Result<R, ManifestoError> {
+        let mut retries = 0;
+        let mut net_retries = 0;
+
+        loop {
+            self.wait_for_rate_limit().await;
+
+            let started = Instant::now();
+            let response = self.send_once(path, json.clone(), idempotency_key).await;
+            record.add_latency(started.elapsed());
+
+            let response = match response {
+                Err(AttemptError::Network { message, sent }) if net_retries < self.net_retries && (!sent || idempotency_key.is_some()) => {
+                    let wait = INITIAL_NET_RETRY_BACKOFF * 2_u32.pow(net_retries);
+
+                    diagnostics::note(format!("Couldn't reach OpenAI ({}); retrying in {:.1}s", message, wait.as_secs_f64()));
+                    tokio::time::sleep(wait).await;
+
+                    net_retries += 1;
+                    record.net_retries = net_retries;
+                    continue;
+                }
+                response => response?,
+            };
+
+            let RawResponse { status, rate_limits, openai_request_id, text } = response;
+            let retry_after = rate_limits.retry_after;
+
+            *self.last_rate_limits.lock().unwrap() = Some(rate_limits);
+            record.status = Some(status);
+            record.openai_request_id = openai_request_id;
+
+            if status == 429 && retries < MAX_RATE_LIMIT_RETRIES {
+                let wait = retry_after.unwrap_or(INITIAL_RATE_LIMIT_BACKOFF * 2_u32.pow(retries));
+
+                diagnostics::note(format!("Rate limited by OpenAI; retrying in {:.1}s", wait.as_secs_f64()));
+                self.pause_for(wait);
+
+                retries += 1;
+                record.retries = retries;
+                continue;
+            }
+
+            if (200..300).contains(&status) {
+                record.read_usage(&text);
+            }
+
+            return parse_response(status, &text);
+        }
+    }
+
+    async fn send_once(&self, path: &str, json: Vec<u8>, idempotency_key: Option<&str>) -> Result<RawResponse, AttemptError> {
+        let request_id = self.request_id.as_deref();
+        let url = format!("{}{}", self.base_url, path);
+
+        log_with_request_id(request_id, &format!("POST {}", url));
+
+        let started = Instant::now();
+
+        let mut req = self.client.post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json);
+
+        if let Some(idempotency_key) = idempotency_key {
+            req = req.header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+        }
+
+        // Replaces the client's default Authorization header
+        if let Some(key_rotation) = &self.key_rotation {
+            let (key_number, header) = key_rotation.next();
+            req = req.header(reqwest::header::AUTHORIZATION, header);
+
+            if self.verbose {
+                eprintln!("Using OpenAI key {}", key_number);
+            }
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| AttemptError::Network { message: e.to_string(), sent: !e.is_connect() })?;
+
+        let status = resp.status().as_u16();
+        let rate_limits = RateLimits::from_headers(resp.headers());
+        let openai_request_id = resp.headers().get(OPENAI_REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        log_with_request_id(request_id, &format!("Response status={}", resp.status()));
+
+        if self.verbose {
+            eprintln!(
+                "Rate limit budget remaining: {} requests, {} tokens",
+                rate_limits.remaining_requests.map_or(String::from("?"), |n| n.to_string()),
+                rate_limits.remaining_tokens.map_or(String::from("?"), |n| n.to_string()),
+            );
+        }
+
+        // By now the request was certainly sent
+        let text = read_body(resp, self.size_limits.max_response_bytes).await.map_err(|e| match e {
+            ManifestoError::Http(message) => AttemptError::Network { message, sent: true },
+            e => AttemptError::Failed(e),
+        })?;
+
+        let elapsed = started.elapsed();
+        *self.total_request_duration.lock().unwrap() += elapsed;
+
+        diagnostics::note(format!("POST {} completed in {:.1}s", path, elapsed.as_secs_f64()));
+
+        Ok(RawResponse { status, rate_limits, openai_request_id, text })
+    }
+}
+
+// [resp]'s body as text, as long as it's no more than [limit] bytes. The body is read as it
+// arrives, and reading stops at the first chunk that takes it past the limit, however long it
+// goes on for.
+async fn read_body(mut resp: reqwest::Response, limit: u64) -> Result<String, ManifestoError> {
+    if resp.content_length().is_some_and(|length| length > limit) {
+        return Err(ManifestoError::ResponseTooLarge { limit });
+    }
+
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = resp.chunk().await.map_err(|e| ManifestoError::Http(e.to_string()))? {
+        bytes.extend_from_slice(&chunk);
+
+        if bytes.len() as u64 > limit {
+            return Err(ManifestoError::ResponseTooLarge { limit });
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+impl ChatTransport for ReqwestTransport {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        let mut body = body.clone();
+        self.chat_defaults.apply_to(&mut body);
+
+        let response: OpenAiResponse = self.post(CHAT_PATH, &body, body.idempotency_key.as_deref())?;
+        record_usage(&self.total_usage, &response);
+
+        if self.verbose && response.choices.len() > 1 {
+            eprintln!("{}", response.format_all());
+        }
+
+        self.cut_off_replies.fetch_add(response.cut_off_choices(), Ordering::SeqCst);
+        record_system_fingerprint(&self.system_fingerprints, &response);
+
+        Ok(response)
+    }
+
+    // Moderation is free, so it doesn't count towards usage, but it does share the rate limiting
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        self.post(MODERATIONS_PATH, body, None)
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        let response: EmbeddingResponse = self.post(EMBEDDINGS_PATH, body, None)?;
+
+        if let Some(usage) = &response.usage {
+            self.total_usage.lock().unwrap().add(usage);
+        }
+
+        Ok(response)
+    }
+
+    fn last_rate_limits(&self) -> Option<RateLimits> {
+        self.last_rate_limits.lock().unwrap().clone()
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        *self.total_usage.lock().unwrap()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        self.cut_off_replies.load(Ordering::SeqCst)
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        self.system_fingerprints.lock().unwrap().clone()
+    }
+
+    fn total_request_duration(&self) -> Duration {
+        *self.total_request_duration.lock().unwrap()
+    }
+}
+
+fn record_usage(total_usage: &Mutex<OpenAiUsage>, response: &OpenAiResponse) {
+    if let Some(usage) = &response.usage {
+        total_usage.lock().unwrap().add(usage);
+    }
+}
+
+// Keeps track of the distinct system fingerprints, warning when one changes, since replies to
+// seeded requests are only reproducible on the same backend
+pub(crate) fn record_system_fingerprint(fingerprints: &Mutex<Vec<String>>, response: &OpenAiResponse) {
+    let Some(fingerprint) = &response.system_fingerprint else {
+        return;
+    };
+
+    let mut fingerprints = fingerprints.lock().unwrap();
+
+    if fingerprints.contains(fingerprint) {
+        return;
+    }
+
+    if let Some(previous) = fingerprints.last() {
+        eprintln!(
+            "Warning: OpenAI's system fingerprint changed from {} to {} during the run, so replies may not be reproducible even with --seed",
+            previous, fingerprint
+        );
+    }
+
+    fingerprints.push(fingerprint.clone());
+}
+
+// Turns a raw status and body into either the response or the error that OpenAI described
+pub fn parse_response<R: DeserializeOwned>(status: u16, text: &str) -> Result<R, ManifestoError> {
+    if !(200..300).contains(&status) {
+        return Err(match serde_json::from_str::<OpenAiErrorResponse>(text) {
+            Ok(error) => ManifestoError::Api {
+                status,
+                code: error.error.code,
+                message: error.error.message,
+            },
+            Err(_) => ManifestoError::Api {
+                status,
+                code: None,
+                message: String::from(text),
+            },
+        });
+    }
+
+    serde_json::from_str::<R>(text)
+        .map_err(|_| ManifestoError::Deserialize(String::from(text)))
+}
+
+// The status [MockTransport] uses for a request that never got a response
+#[cfg(test)]
+const CONNECTION_FAILED: u16 = 0;
+
+// Records every request it's given and replays canned (status, body) responses in order, or
+// builds each response from its request with a handler when the order isn't predictable (e.g.
+// with --jobs).
+#[cfg(test)]
+type MockHandler = Box<dyn Fn(&serde_json::Value) -> (u16, String) + Send + Sync>;
+
+#[cfg(test)]
+pub struct MockTransport {
+    responses: Mutex<std::collections::VecDeque<(u16, String)>>,
+    handler: Option<MockHandler>,
+    requests: Mutex<Vec<serde_json::Value>>,
+    // The idempotency key of each chat request, which isn't part of its body
+    idempotency_keys: Mutex<Vec<Option<String>>>,
+    total_usage: Mutex<OpenAiUsage>,
+    cut_off_replies: AtomicU32,
+    system_fingerprints: Mutex<Vec<String>>,
+    in_flight: std::sync::atomic::AtomicUsize,
+    max_in_flight: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport {
+            responses: Mutex::new(std::collections::VecDeque::new()),
+            handler: None,
+            requests: Mutex::new(Vec::new()),
+            idempotency_keys: Mutex::new(Vec::new()),
+            total_usage: Mutex::new(OpenAiUsage::default()),
+            cut_off_replies: AtomicU32::new(0),
+            system_fingerprints: Mutex::new(Vec::new()),
+            in_flight: std::sync::atomic::AtomicUsize::new(0),
+            max_in_flight: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_handler(handler: impl Fn(&serde_json::Value) -> (u16, String) + Send + Sync + 'static) -> MockTransport {
+        MockTransport {
+            handler: Some(Box::new(handler)),
+            ..MockTransport::new()
+        }
+    }
+
+    pub fn respond(self, status: u16, body: &str) -> MockTransport {
+        self.responses.lock().unwrap().push_back((status, String::from(body)));
+        self
+    }
+
+    // The next request gets no response at all, as when OpenAI can't be reached
+    pub fn fail_to_connect(self, message: &str) -> MockTransport {
+        self.respond(CONNECTION_FAILED, message)
+    }
+
+    pub fn requests(&self) -> Vec<serde_json::Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn idempotency_keys(&self) -> Vec<Option<String>> {
+        self.idempotency_keys.lock().unwrap().clone()
+    }
+
+    // The most requests that were ever being handled at once
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl MockTransport {
+    fn post<B: Serialize, R: DeserializeOwned>(&self, body: &B) -> Result<R, ManifestoError> {
+        let body = serde_json::to_value(body).expect("request bodies should always serialize");
+        self.requests.lock().unwrap().push(body.clone());
+
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+        let (status, text) = match &self.handler {
+            Some(handler) => handler(&body),
+            None => self.responses.lock().unwrap().pop_front()
+                .expect("MockTransport ran out of responses"),
+        };
+
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        if status == CONNECTION_FAILED {
+            return Err(ManifestoError::Http(text));
+        }
+
+        parse_response(status, &text)
+    }
+}
+
+#[cfg(test)]
+impl ChatTransport for MockTransport {
+    fn post_chat(&self, body: &OpenAiRequestBody) -> Result<OpenAiResponse, ManifestoError> {
+        self.idempotency_keys.lock().unwrap().push(body.idempotency_key.clone());
+
+        let response = self.post(body)?;
+        record_usage(&self.total_usage, &response);
+        self.cut_off_replies.fetch_add(response.cut_off_choices(), Ordering::SeqCst);
+        record_system_fingerprint(&self.system_fingerprints, &response);
+
+        Ok(response)
+    }
+
+    fn post_moderation(&self, body: &ModerationRequest) -> Result<ModerationResponse, ManifestoError> {
+        self.post(body)
+    }
+
+    fn post_embeddings(&self, body: &EmbeddingRequest) -> Result<EmbeddingResponse, ManifestoError> {
+        let response: EmbeddingResponse = self.post(body)?;
+
+        if let Some(usage) = &response.usage {
+            self.total_usage.lock().unwrap().add(usage);
+        }
+
+        Ok(response)
+    }
+
+    fn total_usage(&self) -> OpenAiUsage {
+        *self.total_usage.lock().unwrap()
+    }
+
+    fn cut_off_replies(&self) -> u32 {
+        self.cut_off_replies.load(Ordering::SeqCst)
+    }
+
+    fn system_fingerprints(&self) -> Vec<String> {
+        self.system_fingerprints.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    pub fn chat_completion(content: &str) -> String {
+        chat_completion_choices(&[content])
+    }
+
+    // A reply from the backend configuration [fingerprint]
+    pub fn chat_completion_with_fingerprint(content: &str, fingerprint: &str) -> String {
+        let mut response: serde_json::Value = serde_json::from_str(&chat_completion(content)).unwrap();
+        response["system_fingerprint"] = serde_json::json!(fingerprint);
+
+        response.to_string()
+    }
+
+    // A reply that ran into max_tokens
+    pub fn cut_off_chat_completion(content: &str) -> String {
+        chat_completion(content).replace(r#""finish_reason":"stop""#, r#""finish_reason":"length""#)
+    }
+
+    // A reply that OpenAI's content filter stopped before it started
+    pub fn content_filtered_chat_completion() -> String {
+        let mut response: serde_json::Value = serde_json::from_str(&chat_completion("")).unwrap();
+        response["choices"][0]["message"]["content"] = serde_json::Value::Null;
+        response["choices"][0]["finish_reason"] = serde_json::json!("content_filter");
+
+        response.to_string()
+    }
+
+    // A response with one choice per entry, using 20 completion tokens per choice
+    pub fn chat_completion_choices(contents: &[&str]) -> String {
+        let choices: Vec<serde_json::Value> = contents.iter().enumerate()
+            .map(|(i, content)| serde_json::json!({
+                "index": i,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            }))
+            .collect();
+        let completion_tokens = 20 * contents.len();
+
+        serde_json::json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "choices": choices,
+            "usage": {
+                "prompt_tokens": 10,
+                "completion_tokens": completion_tokens,
+                "total_tokens": 10 + completion_tokens
+            }
+        }).to_string()
+    }
+
+    // A moderation response with one result per entry, each given as (category, score) pairs.
+    // A result is flagged if any of its scores is at least 0.5.
+    pub fn moderation(results: &[&[(&str, f64)]]) -> String {
+        let results: Vec<serde_json::Value> = results.iter()
+            .map(|scores| {
+                let categories: serde_json::Map<String, serde_json::Value> = scores.iter()
+                    .map(|(category, score)| (category.to_string(), serde_json::json!(*score >= 0.5)))
+                    .collect();
+                let category_scores: serde_json::Map<String, serde_json::Value> = scores.iter()
+                    .map(|(category, score)| (category.to_string(), serde_json::json!(score)))
+                    .collect();
+
+                serde_json::json!({
+                    "flagged": scores.iter().any(|(_, score)| *score >= 0.5),
+                    "categories": categories,
+                    "category_scores": category_scores,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "id": "modr-123",
+            "model": "omni-moderation-latest",
+            "results": results,
+        }).to_string()
+    }
+
+    // An embeddings response with one embedding per entry, listed in reverse order (as the API
+    // is allowed to) with 5 prompt tokens per input
+    pub fn embeddings(vectors: &[&[f32]]) -> String {
+        let data: Vec<serde_json::Value> = vectors.iter().enumerate().rev()
+            .map(|(i, vector)| serde_json::json!({
+                "object": "embedding",
+                "index": i,
+                "embedding": vector,
+            }))
+            .collect();
+
+        serde_json::json!({
+            "object": "list",
+            "data": data,
+            "model": "text-embedding-3-small",
+            "usage": { "prompt_tokens": 5 * vectors.len(), "total_tokens": 5 * vectors.len() }
+        }).to_string()
+    }
+
+    pub fn api_error(code: &str, message: &str) -> String {
+        serde_json::json!({
+            "error": { "message": message, "type": "invalid_request_error", "param": null, "code": code }
+        }).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use crate::mock_server::{self, MockResponse, MockServer};
+    use crate::secret::SecretString;
+
+    fn request_body() -> OpenAiRequestBody {
+        ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .user("Hi")
+            .build()
+            .expect("should have built the request")
+    }
+
+    #[test]
+    fn sends_the_chat_defaults() {
+        let server = MockServer::start(vec![MockResponse::new(200, &fixtures::chat_completion("Hello"))]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_chat_defaults(ChatDefaults { stop: Some(vec![String::from("END")]), user: Some(String::from("tenant-1a2b")), ..ChatDefaults::default() });
+
+        transport.post_chat(&request_body()).expect("should have sent the request");
+
+        let sent: serde_json::Value = serde_json::from_str(&server.requests()[0]).expect("should have sent JSON");
+        assert_eq!(sent["user"], "tenant-1a2b");
+        assert_eq!(sent["stop"], serde_json::json!(["END"]));
+    }
+
+    #[test]
+    fn sends_the_seed_and_tracks_system_fingerprints() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, &fixtures::chat_completion_with_fingerprint("One", "fp_1")),
+            MockResponse::new(200, &fixtures::chat_completion_with_fingerprint("Two", "fp_1")),
+            MockResponse::new(200, &fixtures::chat_completion_with_fingerprint("Three", "fp_2")),
+        ]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_chat_defaults(ChatDefaults { seed: Some(42), ..ChatDefaults::default() });
+
+        for _ in 0..3 {
+            transport.post_chat(&request_body()).expect("should have sent the request");
+        }
+
+        let sent: serde_json::Value = serde_json::from_str(&server.requests()[0]).expect("should have sent JSON");
+        assert_eq!(sent["seed"], 42);
+        assert_eq!(transport.system_fingerprints(), vec!["fp_1", "fp_2"]);
+    }
+
+    #[test]
+    fn sends_the_idempotency_key_as_a_header_on_every_attempt() {
+        let server = MockServer::start(vec![
+            MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "0"),
+            MockResponse::new(200, &fixtures::chat_completion("Hello")),
+        ]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false);
+        let mut body = request_body();
+        body.idempotency_key = Some(String::from("manifest-o-1234"));
+
+        transport.post_chat(&body).expect("should have sent the request");
+
+        let expected = Some(String::from("manifest-o-1234"));
+        assert_eq!(server.request_header(IDEMPOTENCY_KEY_HEADER), vec![expected.clone(), expected]);
+        assert!(!server.requests()[0].contains("manifest-o-1234"));
+    }
+
+    // Sends one chat request (with [idempotency_key]) to [url], returning how it went and the
+    // run report
+    fn send_with_net_retries(url: &str, net_retries: u32, idempotency_key: Option<&str>) -> (Result<OpenAiResponse, ManifestoError>, serde_json::Value) {
+        let log = Arc::new(RequestLog::new(crate::attribution::Attribution::default()));
+        let transport = ReqwestTransport::new(mock_server::client(), url, None, false)
+            .with_request_log(Some(Arc::clone(&log)))
+            .with_net_retries(net_retries);
+        let mut body = request_body();
+        body.idempotency_key = idempotency_key.map(String::from);
+
+        let result = transport.post_chat(&body);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.json");
+        log.write_report(&path, None).expect("should have written the report");
+
+        (result, serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn retries_connections_that_are_refused() {
+        let (result, report) = send_with_net_retries(&mock_server::refusing_url(), 2, None);
+
+        assert!(matches!(result, Err(ManifestoError::Http(_))));
+        assert_eq!(report["net_retries"], 2);
+        assert_eq!(report["retries"], 0);
+        assert_eq!(report["requests"][0]["net_retries"], 2);
+        assert!(report["requests"][0]["status"].is_null());
+    }
+
+    #[test]
+    fn only_retries_dropped_connections_with_an_idempotency_key() {
+        let responses = || vec![MockResponse::dropped(), MockResponse::new(200, &fixtures::chat_completion("Hello"))];
+
+        // The request may have reached OpenAI, so sending it again could pay for it twice
+        let server = MockServer::start(responses());
+        let (result, report) = send_with_net_retries(&server.url, 2, None);
+        assert!(result.is_err());
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(report["net_retries"], 0);
+
+        let server = MockServer::start(responses());
+        let (result, report) = send_with_net_retries(&server.url, 2, Some("manifest-o-1234"));
+        assert_eq!(result.expect("should have retried the request").choices[0].message.content, "Hello");
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!((report["net_retries"].as_u64(), report["retries"].as_u64()), (Some(1), Some(0)));
+    }
+
+    #[test]
+    fn takes_turns_with_each_key_instead_of_the_clients() {
+        let server = MockServer::start((0..3).map(|_| MockResponse::new(200, &fixtures::chat_completion("Hello"))).collect());
+        let mut default_headers = reqwest::header::HeaderMap::new();
+        default_headers.insert(reqwest::header::AUTHORIZATION, reqwest::header::HeaderValue::from_static("Bearer sk-client"));
+        let client = reqwest::Client::builder().no_proxy().default_headers(default_headers).build().unwrap();
+        let keys = [SecretString::from("sk-one"), SecretString::from("sk-two")];
+        let transport = ReqwestTransport::new(client, &server.url, None, false)
+            .with_key_rotation(Some(Arc::new(KeyRotation::new(&keys))));
+
+        for _ in 0..3 {
+            transport.post_chat(&request_body()).expect("should have sent the request");
+        }
+
+        let bearer = |key: &str| Some(format!("Bearer {}", key));
+        assert_eq!(server.request_header("authorization"), vec![bearer("sk-one"), bearer("sk-two"), bearer("sk-one")]);
+    }
+
+    #[test]
+    fn abandons_requests_in_flight_when_cancelled() {
+        let server = MockServer::start(vec![MockResponse::stalled()]);
+        let cancelled = CancellationToken::new();
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_cancellation(cancelled.clone());
+        let started = Instant::now();
+
+        let result = thread::scope(|scope| {
+            // Ctrl+C is pressed once OpenAI has the request
+            scope.spawn(|| {
+                while server.requests().is_empty() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                cancelled.cancel();
+            });
+
+            transport.post_chat(&request_body())
+        });
+
+        assert!(matches!(result, Err(ManifestoError::Cancelled)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn sends_nothing_once_cancelled() {
+        let server = MockServer::start(vec![MockResponse::new(200, &fixtures::chat_completion("Hello"))]);
+        let cancelled = CancellationToken::new();
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_cancellation(cancelled.clone());
+        cancelled.cancel();
+
+        assert!(matches!(transport.post_chat(&request_body()), Err(ManifestoError::Cancelled)));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn refuses_to_send_requests_over_the_size_limit() {
+        let server = MockServer::start(vec![MockResponse::new(200, &fixtures::chat_completion("Hello"))]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_size_limits(SizeLimits { max_request_bytes: 1024, ..SizeLimits::default() });
+        let body = ChatRequestBuilder::new()
+            .model(GPT_4_MODEL_NAME)
+            .user("manifesto ".repeat(200))
+            .build()
+            .expect("should have built the request");
+
+        match transport.post_chat(&body) {
+            Err(e @ ManifestoError::RequestTooLarge { limit: 1024, .. }) => assert!(e.to_string().contains("--chunk-tokens")),
+            _ => panic!("Should have refused to send the request"),
+        }
+
+        assert!(server.requests().is_empty());
+        assert!(transport.post_chat(&request_body()).is_ok());
+    }
+
+    #[test]
+    fn stops_reading_responses_over_the_size_limit() {
+        let server = MockServer::start(vec![MockResponse::endless(200)]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_size_limits(SizeLimits { max_response_bytes: 1024 * 1024, ..SizeLimits::default() });
+
+        match transport.post_chat(&request_body()) {
+            Err(ManifestoError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024 * 1024),
+            _ => panic!("Should have stopped reading the response"),
+        }
+    }
+
+    #[test]
+    fn retries_after_the_requested_wait_on_429() {
+        let server = MockServer::start(vec![
+            MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "0"),
+            MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            MockResponse::new(200, &fixtures::chat_completion("Finally"))
+                .header("x-ratelimit-remaining-requests", "4")
+                .header("x-ratelimit-remaining-tokens", "1000"),
+        ]);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false);
+
+        let response = transport.post_chat(&request_body())
+            .expect("should have succeeded after retrying");
+
+        assert_eq!(response.choices[0].message.content, "Finally");
+        assert_eq!(server.requests().len(), 3);
+        assert!(transport.total_request_duration() > Duration::ZERO);
+        assert_eq!(transport.last_rate_limits(), Some(RateLimits {
+            remaining_requests: Some(4),
+            remaining_tokens: Some(1000),
+            retry_after: None,
+        }));
+    }
+
+    #[test]
+    fn gives_up_after_too_many_429s() {
+        let responses = (0..=MAX_RATE_LIMIT_RETRIES)
+            .map(|_| MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "0")
+                .header("x-ratelimit-remaining-requests", "0"))
+            .collect();
+        let server = MockServer::start(responses);
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false);
+
+        match transport.post_chat(&request_body()) {
+            Err(ManifestoError::Api { status: 429, .. }) => (),
+            _ => panic!("Should have given up with the rate limit error"),
+        }
+
+        assert_eq!(transport.last_rate_limits().and_then(|limits| limits.remaining_requests), Some(0));
+    }
+
+    #[test]
+    fn records_each_request_once_before_sending() {
+        let server = MockServer::start(vec![
+            MockResponse::new(429, &fixtures::api_error("rate_limit_exceeded", "Slow down"))
+                .header("retry-after", "0"),
+            MockResponse::new(200, &fixtures::chat_completion("Finally")),
+        ]);
+        let dir = tempfile::tempdir().expect("should have created a temp dir");
+        let log_path = dir.path().join("prompts.jsonl");
+        let prompt_log = PromptLog::create(&log_path, None).expect("should have created the prompt log");
+        let transport = ReqwestTransport::new(mock_server::client(), &server.url, None, false)
+            .with_prompt_log(Some(Arc::new(prompt_log)));
+
+        transport.post_chat(&request_body()).expect("should have succeeded after retrying");
+
+        let log = std::fs::read_to_string(&log_path).expect("should have written the prompt log");
+        let records: Vec<serde_json::Value> = log.lines()
+            .map(|line| serde_json::from_str(line).expect("each line should be JSON"))
+            .collect();
+        let sent: serde_json::Value = serde_json::from_str(&server.requests()[0]).expect("should have sent JSON");
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["url"], format!("{}{}", server.url, CHAT_PATH));
+        assert_eq!(records[0]["body"], sent);
+    }
+
+    #[test]
+    fn parses_successful_response() {
+        let response = parse_response::<OpenAiResponse>(200, &fixtures::chat_completion("A summary"))
+            .expect("should have parsed the response");
+
+        assert_eq!(response.choices[0].message.content, "A summary");
+    }
+
+    #[test]
+    fn parses_every_choice() {
+        let response = parse_response::<OpenAiResponse>(200, &fixtures::chat_completion_choices(&["One", "Two", "Three"]))
+            .expect("should have parsed the response");
+
+        let contents: Vec<&str> = response.choices.iter().map(|c| c.message.content.as_str()).collect();
+        assert_eq!(contents, vec!["One", "Two", "Three"]);
+        assert_eq!(response.usage.map(|usage| usage.completion_tokens), Some(60));
+        assert_eq!(response.to_string(), "One\nTwo\nThree");
+    }
+
+    #[test]
+    fn parses_api_errors() {
+        match parse_response::<OpenAiResponse>(400, &fixtures::api_error("context_length_exceeded", "Too long")) {
+            Err(ManifestoError::Api { status, code, message }) => {
+                assert_eq!(status, 400);
+                assert_eq!(code.as_deref(), Some("context_length_exceeded"));
+                assert_eq!(message, "Too long");
+            }
+            _ => panic!("Should have been an API error"),
+        }
+    }
+
+    #[test]
+    fn keeps_unparseable_error_bodies() {
+        match parse_response::<OpenAiResponse>(502, "Bad gateway") {
+            Err(ManifestoError::Api { status: 502, code: None, message }) => assert_eq!(message, "Bad gateway"),
+            _ => panic!("Should have been an API error"),
+        }
+    }
+
+    #[test]
+    fn rejects_garbage_success_bodies() {
+        if parse_response::<OpenAiResponse>(200, "not json").is_ok() {
+            panic!("Should have failed to deserialize");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Cuts documents that are a little too long for the model down to size without chunking them
+// (--truncate head-tail). The start and end are kept, since that's where manifestos tend to set
+// out and sum up their case, and the middle is replaced with a marker.
+
+use crate::chunking::{self, CHARS_PER_TOKEN};
+
+pub const TRUNCATION_MARKER: &str = "[...]";
+
+// How much of the document to keep: [head_percent] of [max_tokens] from the start and
+// [tail_percent] from the end
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeadTail {
+    pub max_tokens: usize,
+    pub head_percent: usize,
+    pub tail_percent: usize,
+}
+
+impl HeadTail {
+    // Parses proportions like "70:30" (head:tail), which can't add up to more than 100
+    pub fn parse_proportions(proportions: &str) -> Option<(usize, usize)> {
+        let (head, tail) = proportions.split_once(':')?;
+        let head: usize = head.trim().parse().ok()?;
+        let tail: usize = tail.trim().parse().ok()?;
+
+        (head + tail <= 100).then_some((head, tail))
+    }
+}
+
+// Returns the text with its middle cut out so that it fits in [options.max_tokens] (estimated),
+// or None if it already fits. Cuts are moved to the nearest whitespace so that words aren't
+// split.
+pub fn truncate_head_tail(text: &str, options: &HeadTail) -> Option<String> {
+    if chunking::estimate_tokens(text) <= options.max_tokens {
+        return None;
+    }
+
+    let max_chars = options.max_tokens * CHARS_PER_TOKEN;
+    let head_chars = max_chars * options.head_percent / 100;
+    let tail_chars = max_chars * options.tail_percent / 100;
+
+    let chars: Vec<char> = text.chars().collect();
+
+    let mut head_end = head_chars;
+    if let Some(space) = chars[..head_end].iter().rposition(|c| c.is_whitespace()) {
+        head_end = space;
+    }
+
+    let mut tail_start = chars.len() - tail_chars;
+    if let Some(space) = chars[tail_start..].iter().position(|c| c.is_whitespace()) {
+        tail_start += space;
+    }
+
+    let head: String = chars[..head_end].iter().collect();
+    let tail: String = chars[tail_start..].iter().collect();
+
+    Some(format!("{}\n\n{}\n\n{}", head.trim_end(), TRUNCATION_MARKER, tail.trim_start()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(max_tokens: usize, head_percent: usize, tail_percent: usize) -> HeadTail {
+        HeadTail { max_tokens, head_percent, tail_percent }
+    }
+
+    #[test]
+    fn leaves_documents_that_fit_alone() {
+        assert_eq!(truncate_head_tail("We will build trains.", &options(100, 70, 30)), None);
+    }
+
+    #[test]
+    fn keeps_the_head_and_tail_in_proportion() {
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+
+        // 5 tokens is 20 chars: 12 from the head and 8 from the tail, backed off to whole words
+        let truncated = truncate_head_tail(text, &options(5, 60, 40)).expect("should have truncated the text");
+
+        assert_eq!(truncated, "one two\n\n[...]\n\ntwelve");
+    }
+
+    #[test]
+    fn parses_proportions() {
+        assert_eq!(HeadTail::parse_proportions("70:30"), Some((70, 30)));
+        assert_eq!(HeadTail::parse_proportions("50:20"), Some((50, 20)));
+        assert_eq!(HeadTail::parse_proportions("80:30"), None);
+        assert_eq!(HeadTail::parse_proportions("70"), None);
+    }
+}
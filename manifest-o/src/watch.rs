@@ -0,0 +1,675 @@
+// Watch mode: polls a directory and summarises every document that is new or has changed since
+// it was last summarised. The content hash of everything summarised is kept in the output
+// directory, so restarting the watcher doesn't redo work, along with each summary's source-sha256
+// fingerprint, so that it can still be reported for files that are skipped as already done.
+//
+// With a [TitleSource] (--name-from-title), each summary is named after its document's title
+// rather than its file name. The name each file was given is kept in the output directory too, so
+// a file keeps its name when it changes, and a new file never takes a name that's already used.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use crate::atomic_write;
+use crate::decoding::{self, ReadOptions};
+use crate::diagnostics;
+use crate::front_matter::{self, Metadata};
+use crate::model_fallback::ModelUsage;
+use crate::naming::{self, TitleSource};
+use crate::open_ai::OpenAiUsage;
+use crate::pool;
+use crate::state;
+
+const PROCESSED_FILE_NAME: &str = ".manifest-o.processed.json";
+const NAMES_FILE_NAME: &str = ".manifest-o.names.json";
+const FINGERPRINTS_FILE_NAME: &str = ".manifest-o.fingerprints.json";
+// How often a sleeping watcher checks whether it has been stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    pub dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub poll_interval: Duration,
+    // Summaries are written to <output_dir>/<file name>.summary.<output_extension>
+    pub output_extension: &'static str,
+    // How the files in [dir] are read and decoded
+    pub read_options: ReadOptions,
+    // Log the encoding each file was decoded from
+    pub verbose: bool,
+    // How many files are summarised at once (--jobs)
+    pub jobs: usize,
+}
+
+// A file that a poll is going to summarise
+struct PendingFile {
+    // Where the file is in the poll's results
+    index: usize,
+    path: PathBuf,
+    // The file's decoded text, or why it couldn't be decoded
+    text: Result<String, String>,
+    hash: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileStatus {
+    Ok,
+    Failed,
+    // Already summarised with the same contents, so skipped
+    Cached,
+}
+
+// What a poll did with one file
+pub struct PolledFile {
+    pub file_name: String,
+    pub output_path: PathBuf,
+    // The name the output was given from the document's title, under --name-from-title
+    pub name: Option<String>,
+    pub status: FileStatus,
+    pub usage: OpenAiUsage,
+    // The models that answered, when --model-fallback sent requests to more than one
+    pub models_used: Vec<ModelUsage>,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+    // The file's front matter, if it could be read
+    pub metadata: Metadata,
+    // The fingerprint of the text its summary was made from, if it has one
+    pub source_sha256: Option<String>,
+}
+
+// What summarising one file handed back
+pub struct Summarised {
+    pub usage: OpenAiUsage,
+    // See [ChatTransport::models_used]
+    pub models_used: Vec<ModelUsage>,
+    // The SHA-256 of the text that was sent to the model, after cleaning
+    pub source_sha256: String,
+}
+
+pub struct Watcher<'a> {
+    options: WatchOptions,
+    // File name to the hash of the contents that were last summarised
+    processed: BTreeMap<String, String>,
+    // File name to the hash of contents that failed (or the error, if the file couldn't be
+    // read). These are retried once the file changes (or the watcher restarts) rather than on
+    // every poll.
+    failed: BTreeMap<String, String>,
+    // Where titles come from for documents without one of their own, when outputs are named
+    // after titles
+    titles: Option<&'a dyn TitleSource>,
+    // File name to the name its output was given from its title
+    names: BTreeMap<String, String>,
+    // File name to the source-sha256 of its last summary
+    fingerprints: BTreeMap<String, String>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn open(options: WatchOptions) -> io::Result<Watcher<'a>> {
+        fs::create_dir_all(&options.output_dir)?;
+
+        let processed = read_json(&options.output_dir.join(PROCESSED_FILE_NAME));
+        let names = read_json(&options.output_dir.join(NAMES_FILE_NAME));
+        let fingerprints = read_json(&options.output_dir.join(FINGERPRINTS_FILE_NAME));
+
+        Ok(Watcher {
+            options,
+            processed,
+            failed: BTreeMap::new(),
+            titles: None,
+            names,
+            fingerprints,
+        })
+    }
+
+    // Names each output after its document's title, asking [titles] for any document that
+    // doesn't have one of its own
+    pub fn with_titles(mut self, titles: &'a dyn TitleSource) -> Watcher<'a> {
+        self.titles = Some(titles);
+        self
+    }
+
+    // Polls until [stop] is set. Files that are being summarised when [stop] is set are
+    // finished before returning.
+    pub fn run<F>(&mut self, stop: &CancellationToken, summarise: F)
+    where
+        F: Fn(&Path, &str, &Path) -> Result<Summarised, String> + Sync,
+    {
+        while !stop.is_cancelled() {
+            if let Err(e) = self.poll(stop, &summarise) {
+                eprintln!("Failed to read {}: {}", self.options.dir.display(), e);
+            }
+
+            let next_poll = Instant::now() + self.options.poll_interval;
+
+            while !stop.is_cancelled() && Instant::now() < next_poll {
+                thread::sleep(STOP_CHECK_INTERVAL);
+            }
+        }
+    }
+
+    // Summarises every new or changed file in the directory once, by calling [summarise] with its
+    // path, its contents and the path its summary should be written to. [summarise] hands back
+    // the tokens it used and the fingerprint of what it summarised. Up to [WatchOptions::jobs] files are summarised at once, started in
+    // name order. A failure (or panic) on one file is logged and doesn't stop the others, and
+    // each file is recorded as soon as it's done, so a restart picks up where it left off.
+    // Returns what happened to every file, in name order, including the ones that were skipped.
+    pub fn poll<F>(&mut self, stop: &CancellationToken, summarise: &F) -> io::Result<Vec<PolledFile>>
+    where
+        F: Fn(&Path, &str, &Path) -> Result<Summarised, String> + Sync,
+    {
+        let mut file_names: Vec<String> = Vec::new();
+
+        for entry in fs::read_dir(&self.options.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+
+            if entry.file_type()?.is_file() && !file_name.starts_with('.') {
+                file_names.push(file_name);
+            }
+        }
+
+        file_names.sort();
+
+        let mut polled: Vec<Option<PolledFile>> = Vec::new();
+        let mut pending: Vec<PendingFile> = Vec::new();
+
+        for file_name in file_names {
+            if stop.is_cancelled() {
+                break;
+            }
+
+            let mut polled_file = PolledFile {
+                file_name: file_name.clone(),
+                output_path: self.output_path(&file_name),
+                name: None,
+                status: FileStatus::Cached,
+                usage: OpenAiUsage::default(),
+                models_used: Vec::new(),
+                elapsed: Duration::ZERO,
+                error: None,
+                metadata: Metadata::new(),
+                source_sha256: None,
+            };
+
+            let path = self.options.dir.join(&file_name);
+            let bytes = match decoding::read_limited(&path, self.options.read_options.max_bytes) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    // Files that can't be read have no hash, so they're tracked by their error
+                    // instead to avoid logging the same failure every poll
+                    if self.failed.get(&file_name) != Some(&e) {
+                        eprintln!("Failed to read {}: {}", file_name, e);
+                        polled_file.status = FileStatus::Failed;
+                        polled_file.error = Some(e.clone());
+                        polled.push(Some(polled_file));
+                        self.failed.insert(file_name, e);
+                    }
+
+                    continue;
+                }
+            };
+            let hash = state::input_hash(&String::from_utf8_lossy(&bytes));
+            let decoded = decoding::decode_input(&bytes, self.options.read_options.encoding);
+
+            if let Ok(decoded) = &decoded {
+                polled_file.metadata = front_matter::split_front_matter(&decoded.text).0;
+
+                if self.options.verbose {
+                    eprintln!("Read {} as {}", file_name, decoded.encoding.name());
+                }
+            }
+
+            if let Some(name) = self.names.get(&file_name).filter(|_| self.titles.is_some()) {
+                polled_file.output_path = self.named_output_path(name);
+                polled_file.name = Some(name.clone());
+            }
+
+            if self.processed.get(&file_name) == Some(&hash) {
+                polled_file.source_sha256 = self.fingerprints.get(&file_name).cloned();
+                polled.push(Some(polled_file));
+                continue;
+            }
+
+            if self.failed.get(&file_name) == Some(&hash) {
+                continue;
+            }
+
+            if let (Some(titles), Ok(decoded), None) = (self.titles, &decoded, &polled_file.name) {
+                if let Some(name) = self.name_from_title(titles, &file_name, &decoded.text) {
+                    polled_file.output_path = self.named_output_path(&name);
+                    polled_file.name = Some(name);
+                }
+            }
+
+            pending.push(PendingFile { index: polled.len(), path, text: decoded.map(|decoded| decoded.text), hash });
+            polled.push(Some(polled_file));
+        }
+
+        let processed_path = self.options.output_dir.join(PROCESSED_FILE_NAME);
+        let fingerprints_path = self.options.output_dir.join(FINGERPRINTS_FILE_NAME);
+        let recorded = Mutex::new((&mut self.processed, &mut self.failed, &mut self.fingerprints, &mut polled));
+
+        let _: Result<Vec<()>, ()> = pool::map_ordered(&pending, self.options.jobs, |pending| {
+            // Files that weren't started before [stop] was set are left out
+            if stop.is_cancelled() {
+                recorded.lock().unwrap().3[pending.index] = None;
+                return Ok(());
+            }
+
+            let output_path = recorded.lock().unwrap().3[pending.index].as_ref()
+                .expect("pending files should have been polled")
+                .output_path.clone();
+
+            let start = Instant::now();
+            let result = match &pending.text {
+                Ok(text) => panic::catch_unwind(AssertUnwindSafe(|| summarise(&pending.path, text, &output_path)))
+                    .unwrap_or_else(|_| Err(String::from("summarising it panicked"))),
+                Err(e) => Err(e.clone()),
+            };
+            let elapsed = start.elapsed();
+
+            // Everything about one file is recorded (and logged) together, however many are
+            // being summarised at once
+            let mut recorded = recorded.lock().unwrap();
+            let (processed, failed, fingerprints, polled) = &mut *recorded;
+            let polled_file = polled[pending.index].as_mut().expect("pending files should have been polled");
+            polled_file.elapsed = elapsed;
+
+            match result {
+                Ok(summarised) => {
+                    eprintln!("Summarised {} to {}", polled_file.file_name, output_path.display());
+                    polled_file.status = FileStatus::Ok;
+                    polled_file.usage = summarised.usage;
+                    polled_file.models_used = summarised.models_used;
+                    failed.remove(&polled_file.file_name);
+                    fingerprints.insert(polled_file.file_name.clone(), summarised.source_sha256.clone());
+                    save_json(&fingerprints_path, fingerprints, "source fingerprints");
+                    polled_file.source_sha256 = Some(summarised.source_sha256);
+                    processed.insert(polled_file.file_name.clone(), pending.hash.clone());
+                    save_json(&processed_path, processed, "processed file hashes");
+                }
+                Err(e) => {
+                    eprintln!("Failed to summarise {}: {}", polled_file.file_name, e);
+                    polled_file.status = FileStatus::Failed;
+                    polled_file.error = Some(e);
+                    failed.insert(polled_file.file_name.clone(), pending.hash.clone());
+                }
+            }
+
+            Ok(())
+        });
+
+        Ok(polled.into_iter().flatten().collect())
+    }
+
+    fn output_path(&self, file_name: &str) -> PathBuf {
+        self.options.output_dir.join(format!("{}.summary.{}", file_name, self.options.output_extension))
+    }
+
+    fn named_output_path(&self, name: &str) -> PathBuf {
+        self.options.output_dir.join(format!("{}.summary.{}", name, self.options.output_extension))
+    }
+
+    // Gives [file_name] a name made from [text]'s title that no other file has, and saves it. If
+    // no title can be found, it's None and the output is named after the file as usual.
+    fn name_from_title(&mut self, titles: &dyn TitleSource, file_name: &str, text: &str) -> Option<String> {
+        let (metadata, body) = front_matter::split_front_matter(text);
+
+        let title = match naming::document_title(&metadata, body) {
+            Some(title) => title,
+            None => match titles.title(body) {
+                Ok(title) => title,
+                Err(e) => {
+                    eprintln!("Couldn't get a title for {}, so it's named after the file: {}", file_name, e);
+                    return None;
+                }
+            },
+        };
+
+        let name = naming::unique_name(&naming::slugify(&title)?, |name| self.names.values().any(|taken| taken == name));
+
+        self.names.insert(String::from(file_name), name.clone());
+        save_json(&self.options.output_dir.join(NAMES_FILE_NAME), &self.names, "output names");
+
+        Some(name)
+    }
+}
+
+// A map saved by [save_json], or an empty one if there isn't one (or it can't be read)
+fn read_json(path: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+// Failing to save only means that files may be summarised (or named) again after a restart, so
+// it's a warning rather than an error
+fn save_json(path: &Path, map: &BTreeMap<String, String>, description: &str) {
+    let json = serde_json::to_string_pretty(map).expect("maps of strings should always serialize");
+
+    if let Err(e) = atomic_write::write(path, json) {
+        diagnostics::warn(format!("couldn't save the {}: {}", description, e));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::open_ai::GPT_4_MODEL_NAME;
+    use crate::summary;
+    use crate::transport::{fixtures, MockTransport};
+
+    fn options(dir: &Path) -> WatchOptions {
+        WatchOptions {
+            dir: dir.join("in"),
+            output_dir: dir.join("out"),
+            poll_interval: Duration::from_millis(10),
+            output_extension: "txt",
+            read_options: ReadOptions::default(),
+            verbose: false,
+            jobs: 1,
+        }
+    }
+
+    // Summarises by upper-casing the document, failing on anything containing "bad"
+    fn fake_summarise(calls: &Mutex<Vec<String>>) -> impl Fn(&Path, &str, &Path) -> Result<Summarised, String> + Sync + '_ {
+        |_, contents, output_path| {
+            calls.lock().unwrap().push(String::from(contents));
+
+            if contents.contains("bad") {
+                return Err(String::from("bad document"));
+            }
+
+            fs::write(output_path, contents.to_uppercase()).map_err(|e| e.to_string())?;
+
+            Ok(Summarised {
+                usage: OpenAiUsage { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15 },
+                models_used: Vec::new(),
+                source_sha256: state::input_hash(contents),
+            })
+        }
+    }
+
+    // The number of files that were summarised successfully
+    fn ok_count(polled: io::Result<Vec<PolledFile>>) -> usize {
+        polled.expect("should have polled the directory").iter()
+            .filter(|file| file.status == FileStatus::Ok)
+            .count()
+    }
+
+    #[test]
+    fn summarises_new_and_changed_files_between_polls() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 1);
+
+        fs::write(options.dir.join("b.txt"), "second").unwrap();
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 1);
+
+        fs::write(options.dir.join("a.txt"), "first, edited").unwrap();
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 1);
+
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 0);
+
+        assert_eq!(calls.into_inner().unwrap(), vec!["first", "second", "first, edited"]);
+        assert_eq!(fs::read_to_string(options.output_dir.join("a.txt.summary.txt")).unwrap(), "FIRST, EDITED");
+        assert_eq!(fs::read_to_string(options.output_dir.join("b.txt.summary.txt")).unwrap(), "SECOND");
+    }
+
+    #[test]
+    fn failures_dont_stop_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path());
+        options.read_options.max_bytes = 64;
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "bad").unwrap();
+        fs::write(options.dir.join("b.txt"), "good").unwrap();
+        fs::write(options.dir.join("c.txt"), [b'a'; 100]).unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 1);
+        assert!(options.output_dir.join("b.txt.summary.txt").exists());
+
+        // Failed files are only retried (or reported) once they change
+        assert!(watcher.poll(&stop, &fake_summarise(&calls)).unwrap().iter().all(|file| file.status == FileStatus::Cached));
+        fs::write(options.dir.join("a.txt"), "fixed").unwrap();
+        assert_eq!(ok_count(watcher.poll(&stop, &fake_summarise(&calls))), 1);
+
+        assert_eq!(calls.into_inner().unwrap(), vec!["bad", "good", "fixed"]);
+    }
+
+    #[test]
+    fn restarting_skips_files_that_were_already_summarised() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+        watcher.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        fs::write(options.dir.join("b.txt"), "second").unwrap();
+        let mut restarted = Watcher::open(options.clone()).expect("should have reopened the watcher");
+        restarted.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        assert_eq!(calls.into_inner().unwrap(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn reports_cached_and_failed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+        watcher.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        fs::write(options.dir.join("b.txt"), "bad").unwrap();
+        let polled = watcher.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        assert_eq!(polled.len(), 2);
+        assert_eq!(polled[0].status, FileStatus::Cached);
+        assert_eq!(polled[0].usage, OpenAiUsage::default());
+        assert_eq!(polled[1].status, FileStatus::Failed);
+        assert_eq!(polled[1].error.as_deref(), Some("bad document"));
+    }
+
+    // Titles every document "Fallback Title", or fails for ones containing "untitled"
+    struct FakeTitles;
+
+    impl TitleSource for FakeTitles {
+        fn title(&self, document: &str) -> Result<String, crate::error::ManifestoError> {
+            if document.contains("untitled") {
+                return Err(crate::error::ManifestoError::EmptyResponse);
+            }
+
+            Ok(String::from("Fallback Title"))
+        }
+    }
+
+    #[test]
+    fn names_outputs_after_titles() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("final_v3 (2).txt"), "---\nparty: Green Party\nyear: 2024\n---\nbody").unwrap();
+        fs::write(options.dir.join("other.txt"), "# Green Party 2024\nbody").unwrap();
+        fs::write(options.dir.join("plain.txt"), "no title here").unwrap();
+        fs::write(options.dir.join("untitled.txt"), "untitled").unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher").with_titles(&FakeTitles);
+
+        let polled = watcher.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        let names: Vec<Option<&str>> = polled.iter().map(|file| file.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("green-party-2024"), Some("green-party-2024-2"), Some("fallback-title"), None]);
+        assert!(options.output_dir.join("green-party-2024.summary.txt").exists());
+        assert!(options.output_dir.join("green-party-2024-2.summary.txt").exists());
+        assert!(options.output_dir.join("fallback-title.summary.txt").exists());
+        assert!(options.output_dir.join("untitled.txt.summary.txt").exists());
+    }
+
+    #[test]
+    fn files_keep_their_names_across_changes_and_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "# Green Party 2024\nfirst").unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        Watcher::open(options.clone()).expect("should have opened the watcher").with_titles(&FakeTitles)
+            .poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        fs::write(options.dir.join("a.txt"), "# Green Party 2024\nedited").unwrap();
+        fs::write(options.dir.join("b.txt"), "# Green Party 2024\nanother").unwrap();
+        let mut restarted = Watcher::open(options.clone()).expect("should have reopened the watcher").with_titles(&FakeTitles);
+        let polled = restarted.poll(&stop, &fake_summarise(&calls)).unwrap();
+
+        assert_eq!(polled[0].name.as_deref(), Some("green-party-2024"));
+        assert_eq!(polled[1].name.as_deref(), Some("green-party-2024-2"));
+        assert_eq!(fs::read_to_string(options.output_dir.join("green-party-2024.summary.txt")).unwrap(), "# GREEN PARTY 2024\nEDITED");
+
+        // Unchanged files still report the name they were given
+        let polled = restarted.poll(&stop, &fake_summarise(&calls)).unwrap();
+        assert_eq!(polled[0].status, FileStatus::Cached);
+        assert_eq!(polled[0].output_path, options.output_dir.join("green-party-2024.summary.txt"));
+    }
+
+    // Summarises each document with one request to [transport], which fails for documents
+    // containing "bad". Documents containing "panic" panic instead.
+    fn summarise_with(transport: &MockTransport) -> impl Fn(&Path, &str, &Path) -> Result<Summarised, String> + Sync + '_ {
+        |_, contents, output_path| {
+            if contents.contains("panic") {
+                panic!("couldn't summarise {}", contents);
+            }
+
+            let summary = summary::complete(transport, GPT_4_MODEL_NAME, "system", "Summarise", contents, 1, false)
+                .map_err(|e| e.to_string())?
+                .swap_remove(0);
+            fs::write(output_path, summary).map_err(|e| e.to_string())?;
+
+            Ok(Summarised { usage: OpenAiUsage::default(), models_used: Vec::new(), source_sha256: state::input_hash(contents) })
+        }
+    }
+
+    #[test]
+    fn summarises_up_to_jobs_files_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path());
+        options.jobs = 3;
+        fs::create_dir(&options.dir).unwrap();
+
+        for i in 0..9 {
+            fs::write(options.dir.join(format!("{}.txt", i)), format!("document {}", i)).unwrap();
+        }
+
+        // Records when each file was being summarised
+        let handled: Mutex<Vec<(Instant, Instant)>> = Mutex::new(Vec::new());
+        let transport = MockTransport::with_handler(move |request| {
+            thread::sleep(Duration::from_millis(50));
+            let text = request["messages"][2]["content"].as_str().unwrap().to_uppercase();
+
+            (200, fixtures::chat_completion(&text))
+        });
+        let transport = &transport;
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+
+        let polled = watcher.poll(&CancellationToken::new(), &|path, contents, output_path| {
+            let start = Instant::now();
+            let result = summarise_with(transport)(path, contents, output_path);
+            handled.lock().unwrap().push((start, Instant::now()));
+
+            result
+        }).unwrap();
+
+        assert_eq!(transport.max_in_flight(), 3);
+
+        // Three at a time, so the nine files took three rounds rather than nine
+        let handled = handled.into_inner().unwrap();
+        let first_start = handled.iter().map(|(start, _)| *start).min().unwrap();
+        let last_end = handled.iter().map(|(_, end)| *end).max().unwrap();
+        assert!(last_end - first_start < Duration::from_millis(9 * 50));
+
+        // Every file is reported, in name order, with only its own summary
+        let file_names: Vec<&str> = polled.iter().map(|file| file.file_name.as_str()).collect();
+        assert_eq!(file_names, vec!["0.txt", "1.txt", "2.txt", "3.txt", "4.txt", "5.txt", "6.txt", "7.txt", "8.txt"]);
+
+        for (i, file) in polled.iter().enumerate() {
+            assert_eq!(file.status, FileStatus::Ok);
+            assert_eq!(fs::read_to_string(&file.output_path).unwrap(), format!("DOCUMENT {}", i));
+        }
+    }
+
+    #[test]
+    fn failed_and_panicking_files_dont_stop_other_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path());
+        options.jobs = 2;
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        fs::write(options.dir.join("b.txt"), "bad").unwrap();
+        fs::write(options.dir.join("c.txt"), "panic").unwrap();
+        fs::write(options.dir.join("d.txt"), "fourth").unwrap();
+        let transport = MockTransport::with_handler(|request| {
+            match request["messages"][2]["content"].as_str().unwrap() {
+                "bad" => (500, String::from(r#"{"error":{"message":"server error"}}"#)),
+                text => (200, fixtures::chat_completion(text)),
+            }
+        });
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+
+        let polled = watcher.poll(&CancellationToken::new(), &summarise_with(&transport)).unwrap();
+
+        let statuses: Vec<FileStatus> = polled.iter().map(|file| file.status).collect();
+        assert_eq!(statuses, vec![FileStatus::Ok, FileStatus::Failed, FileStatus::Failed, FileStatus::Ok]);
+        assert_eq!(polled[2].error.as_deref(), Some("summarising it panicked"));
+
+        // The failures are remembered like any other, and the successes are cached
+        let polled = watcher.poll(&CancellationToken::new(), &summarise_with(&transport)).unwrap();
+        assert!(polled.iter().all(|file| file.status == FileStatus::Cached));
+    }
+
+    #[test]
+    fn stops_after_the_in_flight_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir(&options.dir).unwrap();
+        fs::write(options.dir.join("a.txt"), "first").unwrap();
+        fs::write(options.dir.join("b.txt"), "second").unwrap();
+        let stop = CancellationToken::new();
+        let calls = Mutex::new(Vec::new());
+        let mut watcher = Watcher::open(options.clone()).expect("should have opened the watcher");
+
+        watcher.run(&stop, |_, contents, output_path| {
+            // Simulates SIGINT arriving while the first file is being summarised
+            stop.cancel();
+            calls.lock().unwrap().push(String::from(contents));
+            fs::write(output_path, contents).map_err(|e| e.to_string())?;
+
+            Ok(Summarised { usage: OpenAiUsage::default(), models_used: Vec::new(), source_sha256: state::input_hash(contents) })
+        });
+
+        assert_eq!(calls.into_inner().unwrap(), vec!["first"]);
+        assert!(options.output_dir.join("a.txt.summary.txt").exists());
+    }
+}
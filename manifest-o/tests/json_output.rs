@@ -0,0 +1,98 @@
+// --json promises scripts that stdout carries exactly one JSON document, whatever happens, so
+// these run the real binary and parse everything it prints to stdout as a single JSON value.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MANIFESTO: &str = include_str!("../fixtures/manifesto_with_front_matter.txt");
+
+struct Run {
+    succeeded: bool,
+    stdout: serde_json::Value,
+}
+
+fn manifest_o(args: &[&str]) -> Run {
+    let output = Command::new(env!("CARGO_BIN_EXE_manifest-o"))
+        .args(args)
+        .env_remove("OPENAI_API_KEY")
+        .env_remove("MANIFESTO_JSON")
+        .output()
+        .expect("should have run manifest-o");
+    let stdout = String::from_utf8(output.stdout).expect("stdout should be UTF-8");
+    let json = serde_json::from_str(&stdout)
+        .unwrap_or_else(|e| panic!("stdout wasn't a single JSON value ({}):\n{}", e, stdout));
+
+    Run { succeeded: output.status.success(), stdout: json }
+}
+
+fn manifesto_in(dir: &Path) -> PathBuf {
+    let path = dir.join("manifesto.txt");
+    fs::write(&path, MANIFESTO).unwrap();
+    path
+}
+
+#[test]
+fn success_prints_the_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = manifesto_in(dir.path());
+
+    let run = manifest_o(&[&path.to_string_lossy(), "--offline", "--json"]);
+
+    assert!(run.succeeded);
+    assert!(run.stdout["summary"].as_str().unwrap().contains("tunnel"));
+    assert_eq!(run.stdout["metadata"]["party"], "Example Party");
+    assert!(run.stdout["usage"].is_object());
+    assert!(run.stdout["warnings"].is_array());
+    assert_eq!(run.stdout["errors"], serde_json::json!([]));
+}
+
+#[test]
+fn api_failures_print_an_error_object() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = manifesto_in(dir.path());
+    let replay_dir = dir.path().join("recordings");
+    fs::create_dir(&replay_dir).unwrap();
+
+    // Nothing was recorded, so the summary request fails
+    let run = manifest_o(&[&path.to_string_lossy(), "--replay", &replay_dir.to_string_lossy(), "--json"]);
+
+    assert!(!run.succeeded);
+    assert!(run.stdout.get("summary").is_none());
+
+    let errors: Vec<&str> = run.stdout["errors"].as_array().unwrap().iter().map(|error| error.as_str().unwrap()).collect();
+    assert!(errors[0].contains("No recording"), "{:?}", errors);
+    assert_eq!(errors.last(), Some(&"Failed to summarise the manifesto"));
+}
+
+#[test]
+fn bad_input_prints_an_error_object() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.txt");
+
+    let run = manifest_o(&[&missing.to_string_lossy(), "--api-key", "sk-test", "--json"]);
+    assert!(!run.succeeded);
+    assert!(run.stdout["errors"][0].as_str().unwrap().starts_with("Couldn't read"));
+
+    // Even when the args themselves are wrong
+    let run = manifest_o(&["manifesto.txt", "--api-key", "sk-test", "--json", "--jobs", "none"]);
+    assert!(!run.succeeded);
+    assert_eq!(run.stdout["errors"].as_array().unwrap().len(), 1);
+
+    let run = manifest_o(&["manifesto.txt", "--json", "--dry-run"]);
+    assert!(!run.succeeded);
+    assert!(run.stdout["errors"][0].as_str().unwrap().contains("--dry-run"));
+}
+
+#[test]
+fn unwritable_output_prints_an_error_object() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = manifesto_in(dir.path());
+    let output = dir.path().join("no-such-dir").join("summary.json");
+
+    let run = manifest_o(&[&path.to_string_lossy(), "--offline", "--json", "--output", &output.to_string_lossy()]);
+
+    assert!(!run.succeeded);
+    assert!(run.stdout["errors"][0].as_str().unwrap().starts_with("Couldn't write"));
+    assert_eq!(run.stdout["errors"][1], "Failed to write the output file");
+}
@@ -0,0 +1,57 @@
+// The pipeline as a program of your own would use it, through the manifest_o library rather than
+// from inside the crate
+
+use manifest_o::pipeline::{ChunkPlanning, Cleaning, FrontMatter, CHUNK_PLANNING, CLEANING, FRONT_MATTER};
+use manifest_o::sections::DEFAULT_HEADING_PATTERN;
+use manifest_o::{Document, ManifestoError, Pipeline, PipelineStage};
+use regex::Regex;
+
+const MANIFESTO: &str = include_str!("../fixtures/manifesto_with_front_matter.txt");
+
+// Drops the health section, the way a step of your own might drop the candidates' biographies
+struct DropHealth;
+
+impl PipelineStage for DropHealth {
+    fn name(&self) -> &str {
+        "drop-health"
+    }
+
+    fn process(&self, doc: Document) -> Result<Document, ManifestoError> {
+        let text = match (doc.text.find("## Health"), doc.text.find("## Housing")) {
+            (Some(start), Some(end)) => format!("{}{}", &doc.text[..start], &doc.text[end..]),
+            _ => return Err(ManifestoError::Deserialize(String::from("no health section to drop"))),
+        };
+
+        Ok(Document { text, ..doc })
+    }
+}
+
+fn pipeline() -> Pipeline {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(FrontMatter);
+    pipeline.push(Cleaning { full_report: false });
+    pipeline.push(ChunkPlanning { chunk_tokens: 200, heading_pattern: Regex::new(DEFAULT_HEADING_PATTERN).unwrap(), per_section: true });
+    pipeline
+}
+
+#[test]
+fn stages_of_your_own_can_be_slotted_in() {
+    let mut pipeline = pipeline();
+    pipeline.insert_after(CLEANING, DropHealth).expect("should have found the cleaning stage");
+
+    assert_eq!(pipeline.names(), vec![FRONT_MATTER, CLEANING, "drop-health", CHUNK_PLANNING]);
+
+    let doc = pipeline.run(Document::new(MANIFESTO)).expect("should have run the pipeline");
+    let headings: Vec<&str> = doc.chunks.as_deref().unwrap().iter().map(|chunk| chunk.sections[0].as_str()).collect();
+
+    assert_eq!(doc.metadata.get("party").map(String::as_str), Some("Example Party"));
+    assert_eq!(headings, vec!["Transport", "Housing", "Education"]);
+}
+
+#[test]
+fn a_failing_stage_stops_the_pipeline() {
+    let mut pipeline = pipeline();
+    pipeline.insert_after(CLEANING, DropHealth).unwrap();
+
+    assert!(pipeline.run(Document::new("No sections here.")).is_err());
+}